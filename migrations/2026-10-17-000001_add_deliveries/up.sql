@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE deliveries (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id TEXT NOT NULL,
+  book_id uuid NOT NULL,
+  chapter_ids uuid[] NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT NOW(),
+  CONSTRAINT fk_book_id FOREIGN KEY(book_id) REFERENCES books(id) ON DELETE CASCADE
+);
+
+CREATE INDEX deliveries_user_id ON deliveries (user_id);
\ No newline at end of file
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE deliveries
+DROP COLUMN artifact_bucket,
+DROP COLUMN artifact_key,
+DROP COLUMN artifact_size,
+DROP COLUMN artifact_format;
\ No newline at end of file
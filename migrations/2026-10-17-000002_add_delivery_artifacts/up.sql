@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE deliveries
+ADD COLUMN artifact_bucket TEXT,
+ADD COLUMN artifact_key TEXT,
+ADD COLUMN artifact_size BIGINT,
+ADD COLUMN artifact_format TEXT;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX chapters_fetch_failed;
+ALTER TABLE chapters DROP COLUMN status;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE chapters ADD COLUMN status TEXT NOT NULL DEFAULT 'fetched';
+CREATE INDEX chapters_fetch_failed ON chapters (book_id) WHERE status = 'fetch_failed';
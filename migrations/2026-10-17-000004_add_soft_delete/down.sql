@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX chapters_deleted_at;
+DROP INDEX books_deleted_at;
+ALTER TABLE chapters DROP COLUMN deleted_at;
+ALTER TABLE books DROP COLUMN deleted_at;
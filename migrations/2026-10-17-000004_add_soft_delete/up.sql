@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE books ADD COLUMN deleted_at TIMESTAMPTZ;
+ALTER TABLE chapters ADD COLUMN deleted_at TIMESTAMPTZ;
+CREATE INDEX books_deleted_at ON books (deleted_at) WHERE deleted_at IS NOT NULL;
+CREATE INDEX chapters_deleted_at ON chapters (deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS subscriptions_book_id;
+DROP INDEX IF EXISTS books_metadata;
+DROP INDEX IF EXISTS chapters_book_id_published_at;
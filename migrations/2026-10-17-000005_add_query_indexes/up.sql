@@ -0,0 +1,11 @@
+-- Your SQL goes here
+-- Embedded migrations run inside a transaction, which rules out CREATE INDEX
+-- CONCURRENTLY. On a large live table, create these by hand with CONCURRENTLY
+-- first; IF NOT EXISTS makes this migration a no-op afterwards.
+
+-- Polling and notification queries select a book's chapters by publish time.
+CREATE INDEX IF NOT EXISTS chapters_book_id_published_at ON chapters (book_id, published_at DESC);
+-- create_book looks books up by their metadata.
+CREATE INDEX IF NOT EXISTS books_metadata ON books (metadata);
+-- The subscriptions primary key leads with user_id, so only book_id lookups need an index.
+CREATE INDEX IF NOT EXISTS subscriptions_book_id ON subscriptions (book_id);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE books
+DROP COLUMN chapter_count,
+DROP COLUMN latest_chapter_published_at;
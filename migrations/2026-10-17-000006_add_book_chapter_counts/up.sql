@@ -0,0 +1,15 @@
+-- Your SQL goes here
+ALTER TABLE books
+ADD COLUMN chapter_count BIGINT NOT NULL DEFAULT 0,
+ADD COLUMN latest_chapter_published_at TIMESTAMPTZ;
+
+UPDATE books
+SET chapter_count = counts.chapter_count,
+    latest_chapter_published_at = counts.latest_chapter_published_at
+FROM (
+    SELECT book_id, count(*) AS chapter_count, max(published_at) AS latest_chapter_published_at
+    FROM chapters
+    WHERE deleted_at IS NULL
+    GROUP BY book_id
+) AS counts
+WHERE books.id = counts.book_id;
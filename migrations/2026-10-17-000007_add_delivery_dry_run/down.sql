@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE deliveries
+DROP COLUMN dry_run;
\ No newline at end of file
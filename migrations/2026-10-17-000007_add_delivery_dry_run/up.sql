@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE deliveries
+ADD COLUMN dry_run BOOLEAN NOT NULL DEFAULT FALSE;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+SELECT diesel_manage_updated_at('subscriptions');
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- The updated_at column was dropped but its trigger was left behind, failing
+-- every update of a subscription.
+DROP TRIGGER IF EXISTS set_updated_at ON subscriptions;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE delivery_methods
+ADD COLUMN feed_token TEXT UNIQUE;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE chapters
+ADD COLUMN word_count BIGINT;
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE subscriptions
+DROP COLUMN pushover_notified_chapter_id;
+
+ALTER TABLE delivery_methods
+DROP COLUMN pushover_combine_books;
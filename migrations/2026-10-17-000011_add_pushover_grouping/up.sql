@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE delivery_methods
+ADD COLUMN pushover_combine_books BOOLEAN NOT NULL DEFAULT TRUE;
+
+ALTER TABLE subscriptions
+ADD COLUMN pushover_notified_chapter_id UUID;
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE deliveries
+DROP COLUMN degraded;
+
+ALTER TABLE delivery_methods
+DROP COLUMN kindle_inline_fallback;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE delivery_methods
+ADD COLUMN kindle_inline_fallback BOOLEAN NOT NULL DEFAULT TRUE;
+
+ALTER TABLE deliveries
+ADD COLUMN degraded BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE deliveries
+ADD COLUMN redelivery_of UUID REFERENCES deliveries (id) ON DELETE SET NULL;
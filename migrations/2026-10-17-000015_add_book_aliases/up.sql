@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE TABLE book_aliases(
+    metadata JSONB PRIMARY KEY,
+    book_id UUID NOT NULL REFERENCES books (id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE books
+DROP COLUMN last_checked_at,
+DROP COLUMN last_success_at,
+DROP COLUMN last_error;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE books
+ADD COLUMN last_checked_at TIMESTAMPTZ,
+ADD COLUMN last_success_at TIMESTAMPTZ,
+ADD COLUMN last_error TEXT;
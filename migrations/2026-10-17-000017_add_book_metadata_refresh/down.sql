@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE book_metadata_changes;
+
+ALTER TABLE books
+DROP COLUMN metadata_refreshed_at;
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+ALTER TABLE books
+ADD COLUMN metadata_refreshed_at TIMESTAMPTZ;
+
+CREATE TABLE book_metadata_changes(
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    book_id UUID NOT NULL REFERENCES books (id) ON DELETE CASCADE,
+    old_name TEXT NOT NULL,
+    new_name TEXT NOT NULL,
+    old_author TEXT NOT NULL,
+    new_author TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
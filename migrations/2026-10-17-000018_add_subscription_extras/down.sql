@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE subscriptions
+DROP COLUMN include_author_notes,
+DROP COLUMN include_comment_count;
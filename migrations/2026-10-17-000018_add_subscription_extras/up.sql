@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE subscriptions
+ADD COLUMN include_author_notes TEXT NOT NULL DEFAULT 'omit',
+ADD COLUMN include_comment_count BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,11 @@
+-- This file should undo anything in `up.sql`
+INSERT INTO chapters (id, name, author, created_at, updated_at, book_id, published_at, metadata, status, word_count, ordinal)
+SELECT id, name, author, created_at, updated_at, book_id, published_at, metadata, status, word_count, ordinal
+FROM chapters_archive;
+
+INSERT INTO chapter_bodies (key, bucket, chapter_id)
+SELECT body_key, body_bucket, id
+FROM chapters_archive
+WHERE body_key IS NOT NULL;
+
+DROP TABLE chapters_archive;
@@ -0,0 +1,23 @@
+-- Your SQL goes here
+-- Chapters delivered to every subscriber long ago are moved here by the check
+-- loop, so the chapters table only holds what deliveries and deduplication
+-- still read. Nothing is moved until CEREAL_ARCHIVE_CHAPTERS_AFTER_DAYS is set,
+-- and reverting this migration moves every archived chapter back.
+CREATE TABLE chapters_archive(
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL,
+    author TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL,
+    book_id UUID NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+    published_at TIMESTAMPTZ NOT NULL,
+    metadata JSONB NOT NULL,
+    status TEXT NOT NULL,
+    word_count BIGINT,
+    ordinal INTEGER,
+    body_bucket TEXT,
+    body_key TEXT,
+    archived_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX chapters_archive_book_id_published_at ON chapters_archive (book_id, published_at DESC);
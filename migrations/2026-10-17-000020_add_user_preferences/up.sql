@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE TABLE user_preferences (
+    user_id TEXT PRIMARY KEY NOT NULL,
+    default_format TEXT CHECK (default_format IN ('epub', 'html')),
+    default_grouping_quantity BIGINT CHECK (default_grouping_quantity > 0),
+    output_profile TEXT,
+    combine_deliveries BOOLEAN,
+    quiet_hours_start SMALLINT CHECK (quiet_hours_start BETWEEN 0 AND 23),
+    quiet_hours_end SMALLINT CHECK (quiet_hours_end BETWEEN 0 AND 23),
+    timezone TEXT,
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    updated_at timestamptz NOT NULL DEFAULT NOW(),
+    CHECK ((quiet_hours_start IS NULL) = (quiet_hours_end IS NULL))
+);
+
+SELECT diesel_manage_updated_at('user_preferences');
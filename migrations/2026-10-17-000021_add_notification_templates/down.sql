@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE user_preferences
+DROP COLUMN kindle_subject_template,
+DROP COLUMN pushover_template,
+DROP COLUMN pushover_combined_template;
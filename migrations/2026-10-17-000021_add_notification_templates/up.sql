@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE user_preferences
+ADD COLUMN kindle_subject_template TEXT,
+ADD COLUMN pushover_template TEXT,
+ADD COLUMN pushover_combined_template TEXT;
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+-- The positions within each feed aren't kept, so the book wide ordinals stay.
+SELECT 1;
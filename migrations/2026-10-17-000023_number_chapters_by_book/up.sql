@@ -0,0 +1,22 @@
+-- Your SQL goes here
+-- Ordinals were positions within the feed a chapter was found in. Number each
+-- book's chapters from zero in the order they were sorted in, so ordinals
+-- order a whole book.
+WITH numbered AS (
+    SELECT id, archived,
+        (row_number() OVER (
+            PARTITION BY book_id ORDER BY published_at, ordinal NULLS FIRST, id
+        ) - 1)::INTEGER AS ordinal
+    FROM (
+        SELECT id, book_id, published_at, ordinal, FALSE AS archived FROM chapters
+        UNION ALL
+        SELECT id, book_id, published_at, ordinal, TRUE AS archived FROM chapters_archive
+    ) AS book_chapters
+), renumbered AS (
+    UPDATE chapters SET ordinal = numbered.ordinal
+    FROM numbered
+    WHERE chapters.id = numbered.id AND NOT numbered.archived
+)
+UPDATE chapters_archive SET ordinal = numbered.ordinal
+FROM numbered
+WHERE chapters_archive.id = numbered.id AND numbered.archived;
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM deliveries WHERE test;
+ALTER TABLE deliveries
+DROP CONSTRAINT deliveries_book_id_check,
+ALTER COLUMN book_id SET NOT NULL,
+DROP COLUMN test;
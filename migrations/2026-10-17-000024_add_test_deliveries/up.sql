@@ -0,0 +1,8 @@
+-- Your SQL goes here
+-- Test deliveries are sent to check a delivery method and are of no book.
+ALTER TABLE deliveries
+ADD COLUMN test BOOLEAN NOT NULL DEFAULT FALSE,
+ALTER COLUMN book_id DROP NOT NULL,
+ADD CONSTRAINT deliveries_book_id_check CHECK (test OR book_id IS NOT NULL);
+
+CREATE INDEX deliveries_test_user_id_created_at ON deliveries (user_id, created_at) WHERE test;
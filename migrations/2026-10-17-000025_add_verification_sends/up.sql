@@ -0,0 +1,10 @@
+-- Your SQL goes here
+-- Verification codes sent to each kindle email or pushover key, whichever
+-- user asked for them, so a stranger's address can't be sent codes endlessly.
+CREATE TABLE verification_sends(
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    target TEXT NOT NULL,
+    sent_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX verification_sends_target_sent_at ON verification_sends (target, sent_at);
@@ -0,0 +1,12 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX deliveries_pushover_receipt;
+
+ALTER TABLE deliveries
+DROP COLUMN pushover_receipt,
+DROP COLUMN pushover_acknowledged_at,
+DROP COLUMN pushover_cancelled_at;
+
+ALTER TABLE subscriptions
+DROP COLUMN pushover_priority,
+DROP COLUMN pushover_retry,
+DROP COLUMN pushover_expire;
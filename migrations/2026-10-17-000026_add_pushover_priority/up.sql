@@ -0,0 +1,20 @@
+-- Your SQL goes here
+-- Subscriptions can raise their pushover announcements to emergency priority,
+-- which Pushover repeats every `pushover_retry` seconds until acknowledged or
+-- `pushover_expire` seconds have passed.
+ALTER TABLE subscriptions
+ADD COLUMN pushover_priority SMALLINT,
+ADD COLUMN pushover_retry INTEGER,
+ADD COLUMN pushover_expire INTEGER;
+
+-- The receipt of a delivery's emergency announcement, polled until it is
+-- acknowledged, expires or is cancelled.
+ALTER TABLE deliveries
+ADD COLUMN pushover_receipt TEXT,
+ADD COLUMN pushover_acknowledged_at TIMESTAMPTZ,
+ADD COLUMN pushover_cancelled_at TIMESTAMPTZ;
+
+CREATE INDEX deliveries_pushover_receipt ON deliveries (created_at)
+WHERE pushover_receipt IS NOT NULL
+    AND pushover_acknowledged_at IS NULL
+    AND pushover_cancelled_at IS NULL;
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE user_preferences
+DROP COLUMN skip_snoozed_chapters;
+
+ALTER TABLE subscriptions
+DROP COLUMN snoozed_until;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- Subscriptions aren't delivered until `snoozed_until` has passed.
+ALTER TABLE subscriptions
+ADD COLUMN snoozed_until TIMESTAMPTZ;
+
+-- Whether chapters published while a subscription was snoozed are skipped,
+-- except the latest group, rather than all delivered when it ends.
+ALTER TABLE user_preferences
+ADD COLUMN skip_snoozed_chapters BOOLEAN;
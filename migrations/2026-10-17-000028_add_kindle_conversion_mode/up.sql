@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- Whether chapters are converted to an epub by cereal, or sent as HTML for
+-- Amazon to convert.
+ALTER TABLE delivery_methods
+ADD COLUMN kindle_conversion_mode TEXT NOT NULL DEFAULT 'cereal';
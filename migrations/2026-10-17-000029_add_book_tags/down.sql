@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX books_tags_idx;
+
+ALTER TABLE books
+DROP COLUMN content_warnings,
+DROP COLUMN tags;
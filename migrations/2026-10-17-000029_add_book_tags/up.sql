@@ -0,0 +1,8 @@
+-- Your SQL goes here
+-- The tags and content warnings a book's site lists for it. Only RoyalRoad
+-- books have any.
+ALTER TABLE books
+ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}',
+ADD COLUMN content_warnings TEXT[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX books_tags_idx ON books USING GIN (tags);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE books
+DROP COLUMN check_problem,
+DROP COLUMN next_check_at;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+-- When a book is next checked, for books whose site asked to be retried later
+-- or whose last check failed in a way retrying soon won't fix, and why.
+ALTER TABLE books
+ADD COLUMN next_check_at TIMESTAMPTZ,
+ADD COLUMN check_problem TEXT;
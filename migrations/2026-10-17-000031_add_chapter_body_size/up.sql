@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- The stored size of each chapter body in bytes, null for bodies stored
+-- before sizes were recorded.
+ALTER TABLE chapter_bodies
+ADD COLUMN size BIGINT;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- Paused subscriptions keep their place in the book but get no deliveries,
+-- and books only paused subscriptions follow are not checked.
+ALTER TABLE subscriptions
+ADD COLUMN active BOOLEAN NOT NULL DEFAULT TRUE;
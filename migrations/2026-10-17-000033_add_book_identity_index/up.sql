@@ -0,0 +1,11 @@
+-- Your SQL goes here
+-- A RoyalRoad book's metadata may hold the session cookie its chapters are
+-- fetched with, so books are looked up by their metadata without it.
+CREATE FUNCTION book_identity(metadata JSONB) RETURNS JSONB AS $$
+    SELECT CASE
+        WHEN jsonb_typeof(metadata) = 'object' THEN metadata #- '{RoyalRoad,session_cookie}'
+        ELSE metadata
+    END
+$$ LANGUAGE SQL IMMUTABLE STRICT;
+
+CREATE INDEX IF NOT EXISTS books_identity ON books (book_identity(metadata));
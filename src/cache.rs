@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+
+use crate::clock::SharedClock;
+
+/// A map whose entries are forgotten a fixed time after they are inserted.
+#[derive(Debug)]
+pub struct TtlCache<K, V> {
+    ttl: chrono::Duration,
+    clock: SharedClock,
+    entries: Mutex<HashMap<K, (V, DateTime<Utc>)>>,
+}
+
+impl<K: Eq + Hash, V: Clone> TtlCache<K, V> {
+    pub fn new(ttl: chrono::Duration, clock: SharedClock) -> Self {
+        Self {
+            ttl,
+            clock,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The value inserted for `key`, unless it has expired.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let now = self.clock.now();
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((value, expires_at)) if *expires_at > now => Some(value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Inserts `value`, dropping any expired entries so the map doesn't grow
+    /// with keys which are never read again.
+    pub fn insert(&self, key: K, value: V) {
+        let now = self.clock.now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (_, expires_at)| *expires_at > now);
+        entries.insert(key, (value, now + self.ttl));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn forgets_expired_entries() {
+        let clock = ManualClock::new(Utc::now());
+        let cache = TtlCache::new(chrono::Duration::minutes(10), clock.clone());
+        cache.insert("first", 1);
+        clock.advance(chrono::Duration::minutes(5));
+        cache.insert("second", 2);
+        assert_eq!(cache.get(&"first"), Some(1));
+
+        clock.advance(chrono::Duration::minutes(5));
+        assert_eq!(cache.get(&"first"), None);
+        assert_eq!(cache.get(&"second"), Some(2));
+
+        cache.insert("third", 3);
+        clock.advance(chrono::Duration::minutes(5));
+        assert_eq!(cache.get(&"second"), None);
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+    }
+}
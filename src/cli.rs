@@ -0,0 +1,68 @@
+use clap::{Args, Parser, Subcommand};
+use uuid::Uuid;
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// What to run, the API server and background loops when omitted.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run pending migrations, then serve the API and run the background loops.
+    Serve(ServeArgs),
+    /// Run pending database migrations and exit.
+    Migrate,
+    /// Check for new chapters once and exit.
+    Check(CheckArgs),
+    /// Deliver a user's unsent chapters once and exit.
+    Deliver {
+        /// The user to deliver to.
+        #[arg(long)]
+        user: String,
+    },
+    /// Send a test message to verify delivery credentials.
+    SendTest(SendTestArgs),
+    /// Number existing RoyalRoad chapters in the order of their fiction's
+    /// table of contents and exit.
+    NumberChapters,
+}
+
+#[derive(Args, Default)]
+pub struct ServeArgs {
+    /// Start even if calibre, mailgun, pushover or the storage bucket fail
+    /// their preflight checks, leaving the features which use them broken.
+    #[arg(long)]
+    pub allow_degraded: bool,
+    /// Don't contact the storage bucket in the preflight or readiness checks.
+    #[arg(long)]
+    pub skip_bucket_check: bool,
+    /// Don't run pending migrations, for when the schema is managed separately.
+    /// Also set by `CEREAL_SKIP_MIGRATIONS=true`.
+    #[arg(long)]
+    pub skip_migrations: bool,
+}
+
+#[derive(Args)]
+#[group(required = true, multiple = false)]
+pub struct CheckArgs {
+    /// The book to check.
+    #[arg(long)]
+    pub book: Option<Uuid>,
+    /// Check every book with subscribers.
+    #[arg(long)]
+    pub all: bool,
+}
+
+#[derive(Args)]
+#[group(required = true, multiple = false)]
+pub struct SendTestArgs {
+    /// Email address to send a test email to through Mailgun.
+    #[arg(long)]
+    pub email: Option<String>,
+    /// Pushover user key to send a test notification to.
+    #[arg(long)]
+    pub pushover: Option<String>,
+}
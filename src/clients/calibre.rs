@@ -5,6 +5,54 @@ use tokio::process::Command;
 use tracing::info;
 use uuid::Uuid;
 
+use crate::clients::covers;
+use crate::config;
+
+/// The profile ebooks are converted with unless a user picks another.
+pub const DEFAULT_OUTPUT_PROFILE: &str = "kindle_oasis";
+
+/// The output profiles calibre's ebook-convert accepts.
+pub const OUTPUT_PROFILES: &[&str] = &[
+    "cybook_opus",
+    "cybookg3",
+    "default",
+    "generic_eink",
+    "generic_eink_hd",
+    "generic_eink_large",
+    "hanlinv3",
+    "hanlinv5",
+    "illiad",
+    "ipad",
+    "ipad3",
+    "irexdr1000",
+    "irexdr800",
+    "jetbook5",
+    "kindle",
+    "kindle_dx",
+    "kindle_fire",
+    "kindle_oasis",
+    "kindle_pw",
+    "kindle_pw3",
+    "kindle_scribe",
+    "kindle_voyage",
+    "kobo",
+    "msreader",
+    "mobipocket",
+    "nook",
+    "nook_color",
+    "nook_hd_plus",
+    "pocketbook_900",
+    "pocketbook_hd",
+    "pocketbook_inkpad3",
+    "pocketbook_lux",
+    "sony",
+    "sony-landscape",
+    "sony300",
+    "sony900",
+    "sonyt3",
+    "tablet",
+];
+
 #[tracing::instrument(
 name = "Converting to mobi",
 err,
@@ -21,17 +69,54 @@ pub async fn generate_epub(
     book_title: &str,
     author: &str,
 ) -> Result<Vec<u8>> {
+    let in_path = temp_path(input_extension);
+    fs::write(&in_path, body)?;
+    convert_file(
+        &in_path,
+        cover_title,
+        book_title,
+        author,
+        DEFAULT_OUTPUT_PROFILE,
+    )
+    .await
+}
+
+/// A unique path in the temp directory for a conversion input or output.
+pub fn temp_path(extension: &str) -> String {
     let file_name: String = rand::thread_rng()
         .sample_iter(rand::distributions::Alphanumeric)
         .take(30)
         .map(char::from)
         .collect();
-    let in_path = format!("/tmp/{}.{}", file_name, input_extension);
-    let out_path = format!("/tmp/{}.epub", file_name);
-    fs::write(&in_path, body)?;
-    let output = Command::new("ebook-convert")
-        .arg(&in_path)
-        .arg(&out_path)
+    format!("/tmp/{}.{}", file_name, extension)
+}
+
+/// Converts the file at `in_path` to an epub, removing the input file afterwards.
+/// The cover is reused from the cover cache when one is configured.
+#[tracing::instrument(
+name = "Converting file to epub",
+err,
+level = "info"
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn convert_file(
+    in_path: &str,
+    cover_title: &str,
+    book_title: &str,
+    author: &str,
+    output_profile: &str,
+) -> Result<Vec<u8>> {
+    let out_path = temp_path("epub");
+    let mut command = Command::new(&config::get().ebook_convert);
+    command.arg(in_path).arg(&out_path);
+    if let Some(cache) = &config::get().cover_cache {
+        if let Some(cover) = covers::cover(cache, cover_title, author).await {
+            command.arg("--cover").arg(cover);
+        }
+    }
+    let output = command
         .arg("--filter-css")
         .arg(r#""font-family,color,background""#)
         .arg("--authors")
@@ -41,7 +126,7 @@ pub async fn generate_epub(
         .arg("--series")
         .arg(book_title)
         .arg("--output-profile")
-        .arg("kindle_oasis")
+        .arg(output_profile)
         .output()
         .await
         .with_context(|| "Failed to spawn ebook-convert. Perhaps calibre is not installed?")?;
@@ -54,7 +139,7 @@ pub async fn generate_epub(
         bail!("Calibre conversion failed with status {:?}", output.status);
     }
     let bytes = fs::read(&out_path)?;
-    fs::remove_file(&in_path)?;
+    fs::remove_file(in_path)?;
     fs::remove_file(&out_path)?;
     Ok(bytes)
 }
@@ -65,3 +150,11 @@ pub async fn generate_kindle_email_validation_epub(code: &str) -> Result<Vec<u8>
 
     return generate_epub("txt", &body, title, title, "Cereal").await;
 }
+
+/// A one page book sent to check that deliveries reach a kindle.
+pub async fn generate_test_delivery_epub() -> Result<Vec<u8>> {
+    let body = "This is a test delivery from cereal. New chapters will be delivered to this kindle the same way.";
+    let title = "Cereal Test Delivery";
+
+    generate_epub("txt", body, title, title, "Cereal").await
+}
@@ -0,0 +1,209 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::clients::calibre::temp_path;
+use crate::config::CoverCacheConfig;
+
+/// Draws calibre's default text cover, the one ebook-convert would generate,
+/// reading its arguments from the environment to avoid quoting them.
+const CREATE_COVER: &str = "import os\n\
+from calibre.ebooks.covers import create_cover\n\
+with open(os.environ['COVER_PATH'], 'wb') as f:\n    \
+f.write(create_cover(os.environ['COVER_TITLE'], [os.environ['COVER_AUTHOR']]))";
+
+/// The total time spent drawing covers and how many were drawn, so a cache hit
+/// can be credited with the average generation time.
+static GENERATED: Mutex<(Duration, u32)> = Mutex::new((Duration::ZERO, 0));
+
+/// The path of a cover for `cover_title` and `author`, drawn with calibre and
+/// kept in the cache on the first request for them. Returns `None` when the
+/// cover could not be drawn, leaving ebook-convert to generate its own.
+pub async fn cover(cache: &CoverCacheConfig, cover_title: &str, author: &str) -> Option<PathBuf> {
+    match cached_cover(cache, cover_title, author).await {
+        Ok(path) => Some(path),
+        Err(e) => {
+            warn!(error = ?e, "Failed to generate a cover.");
+            None
+        }
+    }
+}
+
+async fn cached_cover(
+    cache: &CoverCacheConfig,
+    cover_title: &str,
+    author: &str,
+) -> Result<PathBuf> {
+    let path = cache
+        .dir
+        .join(format!("{}.jpg", cover_key(cover_title, author)));
+    if let Ok(file) = fs::File::options().write(true).open(&path) {
+        // The modification time records when a cover was last used, so the
+        // least recently used covers are evicted first.
+        file.set_modified(SystemTime::now())?;
+        metrics::counter!("cover_cache_total", "result" => "hit").increment(1);
+        let (total, count) = *GENERATED.lock().unwrap();
+        if count > 0 {
+            let saved = total / count;
+            metrics::counter!("cover_cache_saved_milliseconds_total")
+                .increment(saved.as_millis() as u64);
+        }
+        return Ok(path);
+    }
+    metrics::counter!("cover_cache_total", "result" => "miss").increment(1);
+
+    let started = Instant::now();
+    let out_path = temp_path("jpg");
+    generate_cover(&cache.calibre_debug, &out_path, cover_title, author).await?;
+    let elapsed = started.elapsed();
+    {
+        let mut generated = GENERATED.lock().unwrap();
+        generated.0 += elapsed;
+        generated.1 += 1;
+    }
+
+    // Copied under a unique name and renamed into place, so a conversion
+    // never reads a partly written cover.
+    fs::create_dir_all(&cache.dir)?;
+    let partial = cache.dir.join(
+        Path::new(&temp_path("part"))
+            .file_name()
+            .expect("temp paths have a file name"),
+    );
+    fs::copy(&out_path, &partial)?;
+    fs::remove_file(&out_path)?;
+    fs::rename(&partial, &path)?;
+    evict(&cache.dir, cache.max_bytes)?;
+    Ok(path)
+}
+
+/// Identifies a cover by everything drawn on it.
+fn cover_key(cover_title: &str, author: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(cover_title.as_bytes());
+    hasher.update([0]);
+    hasher.update(author.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+async fn generate_cover(
+    calibre_debug: &str,
+    out_path: &str,
+    cover_title: &str,
+    author: &str,
+) -> Result<()> {
+    let output = Command::new(calibre_debug)
+        .arg("-c")
+        .arg(CREATE_COVER)
+        .env("COVER_PATH", out_path)
+        .env("COVER_TITLE", cover_title)
+        .env("COVER_AUTHOR", author)
+        .output()
+        .await
+        .with_context(|| "Failed to spawn calibre-debug. Perhaps calibre is not installed?")?;
+    info!(
+        stdout = ?String::from_utf8_lossy(&output.stdout),
+        stderr = ?String::from_utf8_lossy(&output.stderr),
+        status_code = ?output.status
+    );
+    if !output.status.success() {
+        bail!("Cover generation failed with status {:?}", output.status);
+    }
+    Ok(())
+}
+
+/// Removes the least recently used covers until `dir` holds at most
+/// `max_bytes` of them.
+fn evict(dir: &Path, max_bytes: u64) -> Result<()> {
+    let mut covers = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "jpg") {
+            let metadata = entry.metadata()?;
+            covers.push((metadata.modified()?, metadata.len(), path));
+        }
+    }
+    let mut total: u64 = covers.iter().map(|(_, len, _)| len).sum();
+    covers.sort();
+    for (_, len, path) in covers {
+        if total <= max_bytes {
+            break;
+        }
+        fs::remove_file(&path)?;
+        metrics::counter!("cover_cache_evictions_total").increment(1);
+        total -= len;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn cache(max_bytes: u64) -> CoverCacheConfig {
+        CoverCacheConfig {
+            dir: std::env::temp_dir().join(format!("cereal-covers-{}", Uuid::new_v4().simple())),
+            max_bytes,
+            calibre_debug: concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/bin/calibre-debug"
+            )
+            .to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn reuses_cached_covers() {
+        let mut cache = cache(1024);
+        let first = cover(&cache, "Book: Chapter 1", "Author").await.unwrap();
+        assert_eq!(
+            fs::read_to_string(&first).unwrap(),
+            "Book: Chapter 1 by Author"
+        );
+
+        // A hit never runs calibre.
+        cache.calibre_debug = "/nonexistent/calibre-debug".to_owned();
+        assert_eq!(
+            cover(&cache, "Book: Chapter 1", "Author").await,
+            Some(first)
+        );
+        assert_eq!(cover(&cache, "Book: Chapter 2", "Author").await, None);
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used_covers() {
+        // Room for two of the 20 byte covers below.
+        let cache = cache(40);
+        let a = cover(&cache, "Book: Chapter A", "x").await.unwrap();
+        let b = cover(&cache, "Book: Chapter B", "x").await.unwrap();
+        let old = SystemTime::now() - Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&b)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&a)
+            .unwrap()
+            .set_modified(old - Duration::from_secs(60))
+            .unwrap();
+        // Using `a` makes `b` the least recently used.
+        cover(&cache, "Book: Chapter A", "x").await.unwrap();
+        let c = cover(&cache, "Book: Chapter C", "x").await.unwrap();
+        assert!(a.exists());
+        assert!(!b.exists());
+        assert!(c.exists());
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
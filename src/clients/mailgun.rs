@@ -1,6 +1,19 @@
-use anyhow::{bail, Error};
+use anyhow::{bail, Context, Error};
 use reqwest::multipart::Part;
-use std::env;
+use tracing::info;
+use url::Url;
+
+use crate::clients::http;
+use crate::config::{self, MailgunConfig};
+
+/// The largest message Mailgun sends, attachments included.
+pub const MAX_MESSAGE_BYTES: usize = 25 * 1024 * 1024;
+
+/// The largest email Amazon converts personal documents from.
+pub const AMAZON_MAX_MESSAGE_BYTES: usize = 50 * 1024 * 1024;
+
+/// The subject which has Amazon convert a personal document's attachment.
+pub const AMAZON_CONVERT_SUBJECT: &str = "Convert";
 
 #[derive(Debug, Clone)]
 pub struct Attachment {
@@ -15,6 +28,7 @@ pub struct Message {
     text: Option<String>,
     html: Option<String>,
     attachment: Option<Attachment>,
+    headers: Vec<(String, String)>,
 }
 
 impl Message {
@@ -31,8 +45,39 @@ impl Message {
             text: text.map(std::convert::Into::into),
             html: html.map(std::convert::Into::into),
             attachment,
+            headers: Vec::new(),
         }
     }
+
+    /// Adds a link to the end of the message which unsubscribes from
+    /// `book_name`, and the `List-Unsubscribe` headers mail clients show
+    /// their own unsubscribe button for.
+    pub fn with_unsubscribe(mut self, book_name: &str, link: &Url) -> Self {
+        if let Some(text) = &mut self.text {
+            text.push_str(&format!("\n\nUnsubscribe from {}: {}", book_name, link));
+        }
+        if let Some(html) = &mut self.html {
+            html.push_str(&format!(
+                "<p><a href=\"{}\">Unsubscribe from {}</a></p>",
+                escape(link.as_str()),
+                escape(book_name)
+            ));
+        }
+        self.headers
+            .push(("List-Unsubscribe".into(), format!("<{}>", link)));
+        self.headers.push((
+            "List-Unsubscribe-Post".into(),
+            "List-Unsubscribe=One-Click".into(),
+        ));
+        self
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
 #[tracing::instrument(
@@ -42,17 +87,42 @@ level = "info"
 skip(message)
 )]
 pub async fn send_message(message: Message) -> Result<(), Error> {
-    let client = reqwest::Client::new();
+    let config = config::get();
+    if config.dry_run {
+        info!(
+            to = %message.to,
+            subject = %message.subject,
+            text_len = message.text.as_ref().map(String::len),
+            html_len = message.html.as_ref().map(String::len),
+            attachment = message.attachment.as_ref().map(|x| x.file_name.as_str()),
+            attachment_size = message.attachment.as_ref().map(|x| x.bytes.len()),
+            headers = ?message.headers,
+            "Dry run, not sending email."
+        );
+        return Ok(());
+    }
+    let mailgun = config
+        .mailgun
+        .as_ref()
+        .context("Mailgun is not configured.")?;
+    send(mailgun, message).await
+}
+
+/// Sends `message` through the Mailgun API at `mailgun.api_endpoint`.
+pub async fn send(mailgun: &MailgunConfig, message: Message) -> Result<(), Error> {
     let mut form = reqwest::multipart::Form::new()
         .text("to", message.to)
         .text("subject", message.subject)
-        .text("from", env::var("CEREAL_FROM_EMAIL_ADDRESS").unwrap());
+        .text("from", mailgun.from_address.clone());
     if let Some(text) = message.text {
         form = form.text("text", text);
     }
     if let Some(html) = message.html {
         form = form.text("html", html);
     }
+    for (name, value) in message.headers {
+        form = form.text(format!("h:{}", name), value);
+    }
     if let Some(attachment) = message.attachment {
         form = form.part(
             "attachment",
@@ -61,14 +131,13 @@ pub async fn send_message(message: Message) -> Result<(), Error> {
                 .mime_str(&attachment.content_type)?,
         );
     }
-    let mailgun_api_key =
-        env::var("CEREAL_MAILGUN_API_KEY").expect("Mailgun API key not provided.");
-    let send_email_response = client
-        .post(env::var("CEREAL_MAILGUN_API_ENDPOINT").unwrap())
-        .basic_auth("api", Some(mailgun_api_key))
-        .multipart(form)
-        .send()
-        .await?;
+    let send_email_response = http::send(
+        http::client()
+            .post(&mailgun.api_endpoint)
+            .basic_auth("api", Some(&mailgun.api_key))
+            .multipart(form),
+    )
+    .await?;
     if !send_email_response.status().is_success() {
         bail!(
             "Received unsuccessful status code from mailgun: {}",
@@ -90,17 +159,171 @@ pub async fn send_epub_file(
     title: &str,
     subject: &str,
 ) -> Result<(), Error> {
+    send_message(epub_message(bytes, email, title, subject)).await
+}
+
+/// An email with the epub attached, the subject doubling as its body.
+pub fn epub_message(bytes: &[u8], email: &str, title: &str, subject: &str) -> Message {
     let attachment = Attachment {
         content_type: "application/epub+zip".into(),
         file_name: format!("{}.epub", &title),
         bytes: Vec::from(bytes),
     };
-    let message = Message::new(
+    Message::new(
         email,
         subject,
         Some(subject),
         Some(subject),
         Some(attachment),
-    );
-    send_message(message).await
+    )
+}
+
+/// An email with `html` attached for Amazon to convert. Amazon names the
+/// library entry after the file, so it is named with `title`.
+pub fn conversion_message(html: &str, email: &str, title: &str) -> Message {
+    let attachment = Attachment {
+        content_type: "text/html; charset=utf-8".into(),
+        file_name: format!("{}.html", title),
+        bytes: html.as_bytes().to_vec(),
+    };
+    Message::new(
+        email,
+        AMAZON_CONVERT_SUBJECT,
+        Some(title),
+        None,
+        Some(attachment),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::matchers::{basic_auth, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    fn mailgun(server: &MockServer) -> MailgunConfig {
+        MailgunConfig {
+            api_key: "key-test".into(),
+            api_endpoint: format!("{}/v3/mg.example.com/messages", server.uri()),
+            from_address: "cereal@example.com".into(),
+        }
+    }
+
+    fn message() -> Message {
+        let attachment = Attachment {
+            content_type: "application/epub+zip".into(),
+            file_name: "Chapter 1.epub".into(),
+            bytes: b"epub bytes".to_vec(),
+        };
+        Message::new(
+            "reader@kindle.com",
+            "New chapter",
+            Some("A new chapter is out."),
+            None,
+            Some(attachment),
+        )
+    }
+
+    #[tokio::test]
+    async fn sends_form_with_attachment() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v3/mg.example.com/messages"))
+            .and(basic_auth("api", "key-test"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        send(&mailgun(&server), message()).await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let content_type = requests[0].headers["content-type"].to_str().unwrap();
+        assert!(content_type.starts_with("multipart/form-data; boundary="));
+        let body = String::from_utf8_lossy(&requests[0].body);
+        for (name, value) in [
+            ("to", "reader@kindle.com"),
+            ("subject", "New chapter"),
+            ("from", "cereal@example.com"),
+            ("text", "A new chapter is out."),
+        ] {
+            let field = format!("name=\"{}\"\r\n\r\n{}\r\n", name, value);
+            assert!(body.contains(&field), "Missing field {} in {}", name, body);
+        }
+        assert!(!body.contains("name=\"html\""));
+        assert!(body.contains(concat!(
+            "name=\"attachment\"; filename=\"Chapter 1.epub\"\r\n",
+            "Content-Type: application/epub+zip\r\n\r\n",
+            "epub bytes\r\n",
+        )));
+    }
+
+    #[tokio::test]
+    async fn sends_unsubscribe_headers() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        let link = Url::parse("https://cereal.example.com/unsubscribe?token=a.b").unwrap();
+
+        send(&mailgun(&server), message().with_unsubscribe("Pale", &link))
+            .await
+            .unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let body = String::from_utf8_lossy(&requests[0].body);
+        for (name, value) in [
+            (
+                "text",
+                "A new chapter is out.\n\nUnsubscribe from Pale: \
+                 https://cereal.example.com/unsubscribe?token=a.b",
+            ),
+            (
+                "h:List-Unsubscribe",
+                "<https://cereal.example.com/unsubscribe?token=a.b>",
+            ),
+            ("h:List-Unsubscribe-Post", "List-Unsubscribe=One-Click"),
+        ] {
+            let field = format!("name=\"{}\"\r\n\r\n{}\r\n", name, value);
+            assert!(body.contains(&field), "Missing field {} in {}", name, body);
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_html_for_amazon_to_convert() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        let message = conversion_message("<h1>1.1</h1>", "reader@kindle.com", "Pale - 1.1");
+
+        send(&mailgun(&server), message).await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let body = String::from_utf8_lossy(&requests[0].body);
+        assert!(body.contains("name=\"subject\"\r\n\r\nConvert\r\n"));
+        assert!(body.contains(concat!(
+            "name=\"attachment\"; filename=\"Pale - 1.1.html\"\r\n",
+            "Content-Type: text/html; charset=utf-8\r\n\r\n",
+            "<h1>1.1</h1>\r\n",
+        )));
+    }
+
+    #[tokio::test]
+    async fn fails_on_error_status() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+
+        let err = send(&mailgun(&server), message()).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Received unsuccessful status code from mailgun: 401 Unauthorized"
+        );
+    }
 }
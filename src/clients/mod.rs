@@ -1,4 +1,7 @@
 pub mod calibre;
-pub mod honeycomb;
+pub mod covers;
+pub mod http;
 pub mod mailgun;
+pub mod otel;
 pub mod pushover;
+pub mod sentry;
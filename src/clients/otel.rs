@@ -0,0 +1,111 @@
+use anyhow::{Context, Result};
+use opentelemetry::sdk::trace::{self, Sampler, SamplingDecision, SamplingResult, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::{SpanBuilder, StatusCode, TraceContextExt};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use tracing::{span, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::{Context as LayerContext, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::TelemetryConfig;
+
+/// Samples root spans with the configured ratio.
+///
+/// Sampling decisions are made in this order, first match wins:
+/// 1. A span which logged an error is always kept, see [`SampleErrors`].
+/// 2. A span with a parent follows its parent's decision.
+/// 3. A root span is kept with the configured ratio, by default all of them.
+fn sampler(ratio: f64) -> Sampler {
+    Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(ratio)))
+}
+
+/// Keeps spans which logged an error even when sampling would drop them, so
+/// failures are never lost to the sampling ratio. Must be added to the
+/// subscriber before the OpenTelemetry layer so it sees spans closing first.
+pub struct SampleErrors;
+
+impl<S> Layer<S> for SampleErrors
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_close(&self, id: span::Id, ctx: LayerContext<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(builder) = extensions.get_mut::<SpanBuilder>() {
+            if builder.status_code == Some(StatusCode::Error) {
+                builder.sampling_result = Some(SamplingResult {
+                    decision: SamplingDecision::RecordAndSample,
+                    attributes: Vec::new(),
+                    trace_state: Default::default(),
+                });
+            }
+        }
+    }
+}
+
+/// Builds a tracer which batches spans to an OTLP collector, or `None` when
+/// no collector is configured. The batch queue honours the standard
+/// `OTEL_BSP_*` variables, such as `OTEL_BSP_MAX_QUEUE_SIZE`.
+pub fn get_tracer(config: &TelemetryConfig) -> Result<Option<Tracer>> {
+    let target = match &config.export {
+        Some(target) => target.clone(),
+        None => return Ok(None),
+    };
+    let trace_config = trace::config()
+        .with_sampler(sampler(config.sampler_ratio))
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            config.service_name.clone(),
+        )]));
+    let pipeline = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_trace_config(trace_config);
+    let pipeline = if target.http {
+        pipeline.with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(format!(
+                    "{}/v1/traces",
+                    target.endpoint.trim_end_matches('/')
+                ))
+                .with_headers(target.headers),
+        )
+    } else {
+        let mut metadata = tonic::metadata::MetadataMap::with_capacity(target.headers.len());
+        for (key, value) in target.headers {
+            let key: tonic::metadata::MetadataKey<_> = key
+                .parse()
+                .with_context(|| format!("{} is not a valid header name.", key))?;
+            let value = value
+                .parse()
+                .with_context(|| format!("The {} header is not a valid header value.", key))?;
+            metadata.insert(key, value);
+        }
+        pipeline.with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(target.endpoint)
+                .with_metadata(metadata),
+        )
+    };
+    let tracer = pipeline
+        .install_batch(opentelemetry::runtime::Tokio)
+        .context("Failed to install the OTLP exporter.")?;
+    Ok(Some(tracer))
+}
+
+/// The trace id of the current span, for error logs to be looked up in the
+/// collector. `None` outside of a span or when export is disabled.
+pub fn current_trace_id() -> Option<String> {
+    let context = Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_hex())
+}
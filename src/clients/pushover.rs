@@ -1,5 +1,71 @@
-use anyhow::Result;
-use std::{collections::HashMap, env};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::info;
+use url::Url;
+
+use crate::clients::http;
+use crate::config;
+
+/// The longest an emergency message may repeat for, in seconds.
+pub const MAX_EXPIRE_SECS: i32 = 10800;
+/// The shortest gap Pushover allows between an emergency message's repeats,
+/// in seconds.
+pub const MIN_RETRY_SECS: i32 = 30;
+
+/// How a message alerts the user, as Pushover's `priority` parameter.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Delivered without a sound or vibration, or at -2 without a notification.
+    Quiet(i16),
+    #[default]
+    Normal,
+    /// Bypasses the user's quiet hours.
+    High,
+    /// Repeats every `retry` seconds until the user acknowledges it or
+    /// `expire` seconds have passed.
+    Emergency { retry: i32, expire: i32 },
+}
+
+impl Priority {
+    fn value(&self) -> i16 {
+        match self {
+            Self::Quiet(priority) => *priority,
+            Self::Normal => 0,
+            Self::High => 1,
+            Self::Emergency { .. } => 2,
+        }
+    }
+}
+
+/// Whether an emergency message has been acknowledged, from its receipt.
+#[derive(Debug, Deserialize)]
+pub struct Receipt {
+    #[serde(deserialize_with = "flag")]
+    pub acknowledged: bool,
+    #[serde(deserialize_with = "timestamp")]
+    pub acknowledged_at: Option<DateTime<Utc>>,
+}
+
+fn flag<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    Ok(i64::deserialize(deserializer)? == 1)
+}
+
+/// Pushover reports times in seconds since the epoch, or 0 for never.
+fn timestamp<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<DateTime<Utc>>, D::Error> {
+    Ok(match i64::deserialize(deserializer)? {
+        0 => None,
+        secs => DateTime::from_timestamp(secs, 0),
+    })
+}
+
+#[derive(Deserialize)]
+struct MessageResponse {
+    receipt: Option<String>,
+}
 
 pub async fn send_verification_token(user_code: &str, code: &str) -> Result<()> {
     let message = format!("Thank you for using cereal. Please use the following code to validate your pushover token: {}", code);
@@ -7,18 +73,237 @@ pub async fn send_verification_token(user_code: &str, code: &str) -> Result<()>
 }
 
 pub async fn send_message(user_code: &str, message: &str) -> Result<()> {
-    let application_key =
-        env::var("CEREAL_PUSHOVER_TOKEN").expect("Pushover app token not provided.");
-    let client = reqwest::Client::default();
-    let mut map = HashMap::new();
-    map.insert("token", application_key);
-    map.insert("user", user_code.into());
-    map.insert("message", message.into());
-    let _response = client
-        .post("https://api.pushover.net/1/messages.json")
-        .json(&map)
-        .send()
+    send_with_priority(user_code, message, Priority::Normal).await?;
+    Ok(())
+}
+
+/// Sends `message` at `priority`, returning the receipt of an emergency
+/// message. Dry runs have no receipt.
+pub async fn send_with_priority(
+    user_code: &str,
+    message: &str,
+    priority: Priority,
+) -> Result<Option<String>> {
+    let config = config::get();
+    if config.dry_run {
+        // The user key is left out as it grants access to the user's devices.
+        info!(
+            text = message,
+            ?priority,
+            "Dry run, not sending pushover notification."
+        );
+        return Ok(None);
+    }
+    send(
+        &config.endpoints.pushover,
+        application_key()?,
+        user_code,
+        message,
+        priority,
+    )
+    .await
+}
+
+/// Checks whether the emergency message with `receipt` was acknowledged.
+pub async fn check_receipt(receipt: &str) -> Result<Receipt> {
+    let config = config::get();
+    fetch_receipt(&config.endpoints.pushover, application_key()?, receipt).await
+}
+
+/// Stops the emergency message with `receipt` repeating.
+pub async fn cancel_receipt(receipt: &str) -> Result<()> {
+    let config = config::get();
+    if config.dry_run {
+        info!(receipt, "Dry run, not cancelling pushover notification.");
+        return Ok(());
+    }
+    cancel(&config.endpoints.pushover, application_key()?, receipt).await
+}
+
+fn application_key() -> Result<&'static str> {
+    config::get()
+        .pushover_token
+        .as_deref()
+        .context("Pushover is not configured.")
+}
+
+/// Sends `message` to the user with `user_code` through the Pushover API at
+/// `api_url`, returning the receipt of an emergency message.
+pub async fn send(
+    api_url: &Url,
+    application_key: &str,
+    user_code: &str,
+    message: &str,
+    priority: Priority,
+) -> Result<Option<String>> {
+    let mut body = json!({
+        "token": application_key,
+        "user": user_code,
+        "message": message,
+    });
+    // Normal messages are sent as they always were, without a priority.
+    match priority {
+        Priority::Normal => {}
+        Priority::Emergency { retry, expire } => {
+            body["priority"] = json!(priority.value());
+            body["retry"] = json!(retry);
+            body["expire"] = json!(expire);
+        }
+        _ => body["priority"] = json!(priority.value()),
+    }
+    let response: MessageResponse = http::send(
+        http::client()
+            .post(api_url.join("1/messages.json")?)
+            .json(&body),
+    )
+    .await?
+    .error_for_status()?
+    .json()
+    .await
+    // Only emergency messages have a receipt, which is all that's read.
+    .unwrap_or(MessageResponse { receipt: None });
+    Ok(response.receipt)
+}
+
+/// Fetches the emergency message with `receipt` from the Pushover API at
+/// `api_url`.
+pub async fn fetch_receipt(api_url: &Url, application_key: &str, receipt: &str) -> Result<Receipt> {
+    let mut url = api_url.join(&format!("1/receipts/{}.json", receipt))?;
+    url.query_pairs_mut().append_pair("token", application_key);
+    Ok(http::send(http::client().get(url))
         .await?
-        .error_for_status()?;
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+/// Cancels the emergency message with `receipt` through the Pushover API at
+/// `api_url`.
+pub async fn cancel(api_url: &Url, application_key: &str, receipt: &str) -> Result<()> {
+    let _response = http::send(
+        http::client()
+            .post(api_url.join(&format!("1/receipts/{}/cancel.json", receipt))?)
+            .json(&json!({ "token": application_key })),
+    )
+    .await?
+    .error_for_status()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+    use wiremock::matchers::{body_json, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sends_json_message() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/1/messages.json"))
+            .and(body_json(json!({
+                "token": "app-token",
+                "user": "user-key",
+                "message": "A new chapter is out.",
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let api_url = Url::parse(&server.uri()).unwrap();
+        let receipt = send(
+            &api_url,
+            "app-token",
+            "user-key",
+            "A new chapter is out.",
+            Priority::Normal,
+        )
+        .await
+        .unwrap();
+        assert_eq!(receipt, None);
+    }
+
+    #[tokio::test]
+    async fn fails_with_error_status() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400))
+            .mount(&server)
+            .await;
+
+        let api_url = Url::parse(&server.uri()).unwrap();
+        let err = send(&api_url, "app-token", "bad-key", "Hello", Priority::Normal)
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<reqwest::Error>().unwrap();
+        assert_eq!(err.status(), Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[tokio::test]
+    async fn sends_emergency_message_and_checks_its_receipt() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/1/messages.json"))
+            .and(body_json(json!({
+                "token": "app-token",
+                "user": "user-key",
+                "message": "A new chapter is out.",
+                "priority": 2,
+                "retry": 60,
+                "expire": 3600,
+            })))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({"status": 1, "receipt": "r1"})),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/1/receipts/r1.json"))
+            .and(query_param("token", "app-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "status": 1,
+                "acknowledged": 1,
+                "acknowledged_at": 1_700_000_000,
+                "expired": 0,
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/1/receipts/r1/cancel.json"))
+            .and(body_json(json!({"token": "app-token"})))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let api_url = Url::parse(&server.uri()).unwrap();
+        let emergency = Priority::Emergency {
+            retry: 60,
+            expire: 3600,
+        };
+        let receipt = send(
+            &api_url,
+            "app-token",
+            "user-key",
+            "A new chapter is out.",
+            emergency,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(receipt, "r1");
+        let status = fetch_receipt(&api_url, "app-token", &receipt)
+            .await
+            .unwrap();
+        assert!(status.acknowledged);
+        assert_eq!(
+            status.acknowledged_at,
+            DateTime::from_timestamp(1_700_000_000, 0)
+        );
+        cancel(&api_url, "app-token", &receipt).await.unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use ::sentry::integrations::tracing::{EventFilter, SentryLayer};
+use ::sentry::protocol::{Breadcrumb, Event};
+use ::sentry::{ClientInitGuard, ClientOptions};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::TelemetryConfig;
+
+/// Field names whose values are never sent to Sentry, matched as a suffix so
+/// `pushover_verification_code` is caught by `verification_code`.
+const SECRET_FIELDS: [&str; 3] = ["verification_code", "pushover_key", "password"];
+const FILTERED: &str = "[Filtered]";
+
+/// Starts the Sentry client when a DSN is configured. Events are sent until
+/// the returned guard is dropped, which flushes any still queued.
+pub fn init(config: &TelemetryConfig) -> Option<ClientInitGuard> {
+    let dsn = config.sentry_dsn.clone()?;
+    let options = ClientOptions::new()
+        .release(::sentry::release_name!().unwrap_or_default())
+        .before_send(|event: Event<'static>| Some(scrub(event)))
+        .before_breadcrumb(|breadcrumb: Breadcrumb| Some(scrub(breadcrumb)));
+    Some(::sentry::init((dsn, options)))
+}
+
+/// Records log lines as breadcrumbs on captured errors, or `None` when Sentry
+/// is not configured. Errors are captured explicitly with [`capture`] so they
+/// carry tags and their full context chain, rather than once per log line.
+pub fn layer<S>() -> Option<SentryLayer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    ::sentry::Hub::current().client()?;
+    Some(::sentry::integrations::tracing::layer().event_filter(
+        |metadata| match *metadata.level() {
+            tracing::Level::ERROR | tracing::Level::WARN | tracing::Level::INFO => {
+                EventFilter::Breadcrumb
+            }
+            _ => EventFilter::Ignore,
+        },
+    ))
+}
+
+/// Reports an error to Sentry with the given tags. Does nothing when Sentry is
+/// not configured.
+pub fn capture(err: &anyhow::Error, tags: &[(&str, &str)]) {
+    ::sentry::with_scope(
+        |scope| {
+            for (key, value) in tags {
+                scope.set_tag(key, value);
+            }
+        },
+        || ::sentry::integrations::anyhow::capture_anyhow(err),
+    );
+}
+
+/// Replaces secret values anywhere in an event or breadcrumb, including in
+/// the debug output of request structs recorded on spans.
+fn scrub<T: Serialize + DeserializeOwned>(item: T) -> T {
+    let mut value = match serde_json::to_value(&item) {
+        Ok(value) => value,
+        Err(_) => return item,
+    };
+    scrub_value(&mut value);
+    serde_json::from_value(value).unwrap_or(item)
+}
+
+fn scrub_value(value: &mut Value) {
+    match value {
+        Value::String(text) => *text = scrub_text(text),
+        Value::Array(items) => items.iter_mut().for_each(scrub_value),
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if is_secret(key) && !value.is_null() {
+                    *value = Value::String(FILTERED.into());
+                } else {
+                    scrub_value(value);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn is_secret(name: &str) -> bool {
+    SECRET_FIELDS.iter().any(|field| name.ends_with(field))
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Filters the values of secret fields written as `name: "value"`,
+/// `name: Some("value")`, `name=value` or `"name":"value"`.
+fn scrub_text(text: &str) -> String {
+    let mut scrubbed = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(is_name_char) {
+        let end = rest[start..]
+            .find(|c| !is_name_char(c))
+            .map_or(rest.len(), |len| start + len);
+        let name = &rest[start..end];
+        scrubbed.push_str(&rest[..end]);
+        rest = &rest[end..];
+
+        // Skips the closing quote of a JSON key, then the separator.
+        let value = rest.strip_prefix('"').unwrap_or(rest);
+        let value = value.trim_start_matches([' ', ':', '=']);
+        if !is_secret(name) || !rest[..rest.len() - value.len()].contains([':', '=']) {
+            continue;
+        }
+        let value = value.strip_prefix("Some(").unwrap_or(value);
+        let quoted = value.starts_with('"');
+        let value = value.strip_prefix('"').unwrap_or(value);
+        if !quoted && (value.starts_with("None") || value.starts_with("null")) {
+            continue;
+        }
+        // Quoted values end at the closing quote so spaces in them are filtered too.
+        let mut escaped = false;
+        let len = value
+            .find(|c: char| {
+                let ends = if quoted {
+                    c == '"' && !escaped
+                } else {
+                    c.is_whitespace() || matches!(c, ',' | '}' | ')')
+                };
+                escaped = c == '\\' && !escaped;
+                ends
+            })
+            .unwrap_or(value.len());
+        scrubbed.push_str(&rest[..rest.len() - value.len()]);
+        scrubbed.push_str(FILTERED);
+        rest = &value[len..];
+    }
+    scrubbed.push_str(rest);
+    scrubbed
+}
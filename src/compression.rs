@@ -0,0 +1,174 @@
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder};
+use futures::TryStreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+use warp::http::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY};
+use warp::hyper::body::HttpBody;
+use warp::hyper::Body;
+use warp::{Filter, Rejection, Reply};
+
+/// Replies smaller than this gain too little to be worth compressing.
+const MIN_COMPRESSED_BYTES: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+}
+
+/// Compresses the replies of `filter` with brotli or gzip, whichever the
+/// request's `Accept-Encoding` prefers. Small replies, replies which are
+/// already encoded and replies which aren't text, such as ebooks, are left
+/// as they are, as are event streams, which would otherwise be buffered.
+pub fn compress<R: Reply>(
+    filter: impl Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("accept-encoding")
+        .and(filter)
+        .map(compress_reply)
+}
+
+fn compress_reply(accept_encoding: Option<String>, reply: impl Reply) -> warp::reply::Response {
+    let mut response = reply.into_response();
+    if !is_compressible(&response) {
+        return response;
+    }
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    let Some(encoding) = accept_encoding.as_deref().and_then(negotiate) else {
+        return response;
+    };
+    let (mut parts, body) = response.into_parts();
+    let reader = StreamReader::new(TryStreamExt::map_err(body, std::io::Error::other));
+    let body = match encoding {
+        Encoding::Brotli => Body::wrap_stream(ReaderStream::new(BrotliEncoder::new(reader))),
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader))),
+    };
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    warp::reply::Response::from_parts(parts, body)
+}
+
+fn is_compressible(response: &warp::reply::Response) -> bool {
+    let headers = response.headers();
+    if headers.contains_key(CONTENT_ENCODING) {
+        return false;
+    }
+    let small = HttpBody::size_hint(response.body())
+        .exact()
+        .is_some_and(|length| length < MIN_COMPRESSED_BYTES);
+    if small {
+        return false;
+    }
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default();
+    let text = content_type.starts_with("text/")
+        || content_type.contains("json")
+        || content_type.contains("xml");
+    text && !content_type.starts_with("text/event-stream")
+}
+
+/// The preferred encoding among those the client accepts, brotli winning
+/// ties. Codings weighted `q=0` are refused.
+fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut brotli = None;
+    let mut gzip = None;
+    let mut any = None;
+    for coding in accept_encoding.split(',') {
+        let mut params = coding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default().to_ascii_lowercase();
+        let quality = params
+            .find_map(|param| param.strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+            .unwrap_or(0.0);
+        match name.as_str() {
+            "br" => brotli = Some(quality),
+            "gzip" | "x-gzip" => gzip = Some(quality),
+            "*" => any = Some(quality),
+            _ => {}
+        }
+    }
+    let brotli = brotli.or(any).unwrap_or(0.0);
+    let gzip = gzip.or(any).unwrap_or(0.0);
+    match (brotli, gzip) {
+        (brotli, gzip) if brotli > 0.0 && brotli >= gzip => Some(Encoding::Brotli),
+        (_, gzip) if gzip > 0.0 => Some(Encoding::Gzip),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::bufread::GzipDecoder;
+    use tokio::io::AsyncReadExt;
+
+    fn routes() -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+        let large = warp::path("large").map(|| {
+            let chapters = (0..500)
+                .map(|i| serde_json::json!({ "id": i, "name": format!("Chapter {}", i) }))
+                .collect::<Vec<_>>();
+            warp::reply::json(&chapters)
+        });
+        let small = warp::path("small").map(|| warp::reply::json(&"ok"));
+        compress(large.or(small))
+    }
+
+    #[test]
+    fn negotiates_encodings() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0.5, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0, gzip;q=0"), None);
+        assert_eq!(negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("identity"), None);
+    }
+
+    #[tokio::test]
+    async fn gzips_large_replies_when_accepted() {
+        let identity = warp::test::request().path("/large").reply(&routes()).await;
+        assert_eq!(identity.status(), 200);
+        assert!(!identity.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(identity.headers()[VARY], "accept-encoding");
+
+        let gzipped = warp::test::request()
+            .path("/large")
+            .header("accept-encoding", "gzip")
+            .reply(&routes())
+            .await;
+        assert_eq!(gzipped.status(), 200);
+        assert_eq!(gzipped.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(gzipped.headers()[CONTENT_TYPE], "application/json");
+        assert!(gzipped.body().len() < identity.body().len());
+        let mut body = Vec::new();
+        GzipDecoder::new(&gzipped.body()[..])
+            .read_to_end(&mut body)
+            .await
+            .unwrap();
+        assert_eq!(body, identity.body().as_ref());
+    }
+
+    #[tokio::test]
+    async fn leaves_small_replies_alone() {
+        let response = warp::test::request()
+            .path("/small")
+            .header("accept-encoding", "gzip")
+            .reply(&routes())
+            .await;
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(response.body(), "\"ok\"");
+    }
+}
@@ -0,0 +1,1072 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use ::sentry::types::Dsn;
+use anyhow::{bail, Result};
+use base64::Engine;
+use ipnet::IpNet;
+use url::Url;
+
+use crate::templates::{Template, Templates};
+
+/// Every setting read from the environment, parsed and validated once at
+/// startup so misconfiguration is reported before anything runs.
+#[derive(Clone)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    /// The address the API listens on, set by `CEREAL_BIND_ADDRESS`.
+    pub bind_address: SocketAddr,
+    /// The certificate the API is served over https with, `None` to serve
+    /// plain http behind a proxy terminating TLS.
+    pub tls: Option<TlsConfig>,
+    /// The unix socket the API listens on in place of `bind_address`, for a
+    /// proxy on the same host.
+    pub listen_socket: Option<ListenSocket>,
+    /// How long the API keeps serving after shutdown is requested while
+    /// reporting it isn't ready, so load balancers stop routing to it first.
+    pub shutdown_delay: Duration,
+    /// Logs emails and notifications instead of sending them, for staging
+    /// instances running against a copy of production data.
+    pub dry_run: bool,
+    /// The bearer token for admin endpoints, which are unusable without it.
+    pub admin_token: Option<String>,
+    /// Email delivery settings, `None` when Mailgun is not configured.
+    pub mailgun: Option<MailgunConfig>,
+    /// The key Mailgun signs inbound email webhooks with. Inbound email is
+    /// rejected while it is unset.
+    pub mailgun_webhook_key: Option<String>,
+    /// Signs the unsubscribe links in delivery emails, which are left out
+    /// when it isn't set.
+    pub unsubscribe: Option<UnsubscribeConfig>,
+    /// The Pushover application token, `None` when Pushover is not configured.
+    pub pushover_token: Option<String>,
+    /// The calibre executable ebooks are converted with, set by
+    /// `CEREAL_EBOOK_CONVERT_PATH` when it isn't on the `PATH`.
+    pub ebook_convert: String,
+    /// Where generated covers are kept for reuse, `None` when calibre draws a
+    /// new cover for every conversion.
+    pub cover_cache: Option<CoverCacheConfig>,
+    pub storage: StorageConfig,
+    /// The bucket receiving forwarded patreon emails, `None` when patreon
+    /// books are not checked.
+    pub email_bucket: Option<EmailBucketConfig>,
+    pub telemetry: TelemetryConfig,
+    pub intervals: Intervals,
+    pub rate_limits: RateLimits,
+    pub ip_access: IpAccess,
+    pub endpoints: Endpoints,
+    pub proxies: ProxyConfig,
+    pub body_limits: BodyLimits,
+    pub delivery_backlog: DeliveryBacklog,
+    /// Chapters published longer ago than this, and sent to every
+    /// subscriber, are moved to the archive. `None` keeps every chapter.
+    pub archive_chapters_after: Option<chrono::Duration>,
+    /// Notification templates for users who haven't set their own, set by
+    /// `CEREAL_KINDLE_SUBJECT_TEMPLATE`, `CEREAL_PUSHOVER_TEMPLATE` and
+    /// `CEREAL_PUSHOVER_COMBINED_TEMPLATE`.
+    pub templates: Templates,
+}
+
+/// PEM files the API's certificate is read from.
+#[derive(Clone)]
+pub struct TlsConfig {
+    /// The certificate chain, set by `CEREAL_TLS_CERT_PATH`.
+    pub cert_path: PathBuf,
+    /// The certificate's private key, set by `CEREAL_TLS_KEY_PATH`.
+    pub key_path: PathBuf,
+}
+
+#[derive(Clone)]
+pub struct ListenSocket {
+    /// Set by `CEREAL_LISTEN_SOCKET`.
+    pub path: PathBuf,
+    /// The socket file's permissions, set in octal by
+    /// `CEREAL_LISTEN_SOCKET_MODE`.
+    pub mode: u32,
+}
+
+#[derive(Clone)]
+pub struct DatabaseConfig {
+    pub url: String,
+    /// Leaves pending migrations for operators who manage the schema
+    /// separately, set by `CEREAL_SKIP_MIGRATIONS`.
+    pub skip_migrations: bool,
+    /// How long a caller waits for a free connection before giving up.
+    pub acquire_timeout: Duration,
+    /// Upper bound on open connections.
+    pub max_connections: u64,
+}
+
+#[derive(Clone)]
+pub struct MailgunConfig {
+    pub api_key: String,
+    pub api_endpoint: String,
+    pub from_address: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct UnsubscribeConfig {
+    /// Where the API is reachable from the internet, set by
+    /// `CEREAL_PUBLIC_URL`.
+    pub public_url: Url,
+    /// The key links are signed with, set by `CEREAL_UNSUBSCRIBE_KEY`.
+    pub key: String,
+}
+
+#[derive(Clone)]
+pub struct StorageConfig {
+    pub key: String,
+    pub secret: String,
+    pub endpoint: String,
+    pub bucket: String,
+    /// Converted ebooks older than this are removed from storage.
+    pub artifact_retention: chrono::Duration,
+    /// The AES-256 key chapter bodies are encrypted with, if any.
+    pub encryption_key: Option<[u8; 32]>,
+    /// How many responses providers failed to parse are kept each day under
+    /// `debug/`, `None` when none are kept.
+    pub debug_snapshots_per_day: Option<usize>,
+    /// Objects of at least this many bytes are uploaded in parts of this size,
+    /// set in megabytes by `CEREAL_STORAGE_MULTIPART_THRESHOLD_MB`.
+    pub multipart_threshold: u64,
+}
+
+/// A directory of generated covers, enabled by `CEREAL_COVER_CACHE_DIR`.
+#[derive(Clone)]
+pub struct CoverCacheConfig {
+    pub dir: PathBuf,
+    /// The least recently used covers are removed once the directory holds
+    /// more than this, set by `CEREAL_COVER_CACHE_MAX_MB`.
+    pub max_bytes: u64,
+    /// The calibre executable covers are drawn with, set by
+    /// `CEREAL_CALIBRE_DEBUG_PATH` when it isn't on the `PATH`.
+    pub calibre_debug: String,
+}
+
+#[derive(Clone)]
+pub struct EmailBucketConfig {
+    pub bucket: String,
+    pub access_key: String,
+    pub secret_key: String,
+    pub region: String,
+}
+
+#[derive(Clone)]
+pub struct TelemetryConfig {
+    /// Where spans are exported, `None` when no collector is configured.
+    pub export: Option<ExportTarget>,
+    /// The ratio of root spans to keep.
+    pub sampler_ratio: f64,
+    pub service_name: String,
+    /// Where errors are reported, `None` when Sentry is not configured.
+    pub sentry_dsn: Option<Dsn>,
+    pub log_format: LogFormat,
+}
+
+/// Where and how spans are exported.
+#[derive(Clone)]
+pub struct ExportTarget {
+    pub endpoint: String,
+    pub headers: HashMap<String, String>,
+    pub http: bool,
+}
+
+/// How log lines are written to stdout, set by `CEREAL_LOG_FORMAT`.
+#[derive(Clone, Copy)]
+pub enum LogFormat {
+    /// Human readable lines, the default.
+    Text,
+    /// One JSON object per line with the event's fields and its spans.
+    Json,
+}
+
+/// How often each background loop runs.
+#[derive(Clone)]
+pub struct Intervals {
+    pub check: Duration,
+    pub notification: Duration,
+    pub summary: Duration,
+}
+
+/// Requests allowed per second from each client and to each route.
+#[derive(Clone)]
+pub struct RateLimits {
+    pub per_ip: NonZeroU32,
+    pub per_route: NonZeroU32,
+    /// Conversions allowed per minute from each client, as each runs calibre.
+    pub convert_per_minute: NonZeroU32,
+}
+
+/// Networks let past or kept out of the API by client address, as single
+/// addresses or CIDR networks separated by commas.
+#[derive(Clone, Default)]
+pub struct IpAccess {
+    /// Exempt from the IP rate limits, set by `CEREAL_IP_ALLOWLIST`.
+    pub allow: Vec<IpNet>,
+    /// Refused before reaching any route, even if also allowed, set by
+    /// `CEREAL_IP_DENYLIST`.
+    pub deny: Vec<IpNet>,
+    /// The only networks admin routes answer, set by
+    /// `CEREAL_ADMIN_IP_ALLOWLIST`. `None` leaves them open to any address.
+    pub admin: Option<Vec<IpNet>>,
+}
+
+/// The base urls of the services chapters are fetched from and pushover, so
+/// tests and staging can point them at a mock server. Each ends in a slash,
+/// as paths are joined onto them.
+#[derive(Clone)]
+pub struct Endpoints {
+    pub pushover: Url,
+    pub royalroad: Url,
+    pub pale: Url,
+    pub practical_guide: Url,
+    pub wandering_inn: Url,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        let url = |url: &str| Url::parse(url).unwrap();
+        Self {
+            pushover: url("https://api.pushover.net/"),
+            royalroad: url("https://www.royalroad.com/"),
+            pale: url("https://palewebserial.wordpress.com/"),
+            practical_guide: url("https://practicalguidetoevil.wordpress.com/"),
+            wandering_inn: url("https://wanderinginn.com/"),
+        }
+    }
+}
+
+/// Size limits on scraped chapter bodies, in bytes, as a body which is far
+/// too large is more often a scraper selecting the whole page than a chapter.
+#[derive(Clone)]
+pub struct BodyLimits {
+    /// Bodies over this are stored with a warning, set by
+    /// `CEREAL_BODY_WARN_BYTES`.
+    pub warn: usize,
+    /// Bodies over this aren't stored and their chapter fails to fetch, set
+    /// by `CEREAL_BODY_MAX_BYTES`.
+    pub max: usize,
+}
+
+/// Limits on the deliveries made by each notification cycle, so a cycle after
+/// an outage doesn't try to send everything at once.
+#[derive(Clone)]
+pub struct DeliveryBacklog {
+    /// Delivery groups sent per cycle, oldest first, set by
+    /// `CEREAL_DELIVERY_GROUPS_PER_CYCLE`.
+    pub groups_per_cycle: usize,
+    /// A warning is logged while more groups than this are waiting, set by
+    /// `CEREAL_DELIVERY_BACKLOG_WARNING`.
+    pub warn: usize,
+}
+
+/// Proxies outbound requests are sent through. Without any, requests use the
+/// proxies in the standard `HTTP_PROXY` variables, if any.
+#[derive(Clone, Default)]
+pub struct ProxyConfig {
+    /// The proxy for plain http requests, set by `CEREAL_HTTP_PROXY`.
+    pub http: Option<Url>,
+    /// The proxy for https requests, set by `CEREAL_HTTPS_PROXY`.
+    pub https: Option<Url>,
+    /// The proxy every scraping request goes through in place of the two
+    /// above, set by `CEREAL_SCRAPE_PROXY`.
+    pub scrape: Option<Url>,
+    /// Sends API client requests, such as to Mailgun and Pushover, without a
+    /// proxy, set by `CEREAL_API_BYPASS_PROXY`.
+    pub api_bypass: bool,
+    /// Comma separated hosts and domains requested without a proxy, set by
+    /// `CEREAL_NO_PROXY` in the format of `NO_PROXY`.
+    pub no_proxy: Option<String>,
+}
+
+const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0:3000";
+const DEFAULT_EBOOK_CONVERT: &str = "ebook-convert";
+const DEFAULT_CALIBRE_DEBUG: &str = "calibre-debug";
+const DEFAULT_COVER_CACHE_MAX_MB: u64 = 100;
+const DEFAULT_LISTEN_SOCKET_MODE: u32 = 0o660;
+const DEFAULT_SHUTDOWN_DELAY: Duration = Duration::from_secs(5);
+const DEFAULT_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_CONNECTIONS: u64 = 30;
+const DEFAULT_ARTIFACT_RETENTION_DAYS: i64 = 30;
+const DEFAULT_DEBUG_SNAPSHOTS_PER_DAY: usize = 20;
+const DEFAULT_MULTIPART_THRESHOLD_MB: u64 = 64;
+/// The smallest part S3 accepts in a multipart upload, other than the last.
+const MIN_MULTIPART_THRESHOLD_MB: u64 = 5;
+const DEFAULT_REGION: &str = "us-east-1";
+const DEFAULT_SERVICE_NAME: &str = "cereal-convert";
+const HONEYCOMB_ENDPOINT: &str = "https://api.honeycomb.io";
+const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_NOTIFICATION_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_SUMMARY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_BODY_WARN_BYTES: usize = 1024 * 1024;
+const DEFAULT_BODY_MAX_BYTES: usize = 8 * 1024 * 1024;
+const DEFAULT_DELIVERY_GROUPS_PER_CYCLE: usize = 100;
+const DEFAULT_DELIVERY_BACKLOG_WARNING: usize = 500;
+const DEFAULT_RATE_LIMIT: NonZeroU32 = match NonZeroU32::new(5) {
+    Some(limit) => limit,
+    None => unreachable!(),
+};
+const DEFAULT_CONVERT_RATE_LIMIT: NonZeroU32 = match NonZeroU32::new(2) {
+    Some(limit) => limit,
+    None => unreachable!(),
+};
+
+/// Variables holding credentials. Each may instead be read from the file
+/// named by the same variable suffixed with `_FILE`, as secret mounts
+/// provide, with the variable itself taking precedence.
+const SECRETS: &[&str] = &[
+    "DATABASE_URL",
+    "CEREAL_ADMIN_TOKEN",
+    "CEREAL_MAILGUN_API_KEY",
+    "CEREAL_MAILGUN_WEBHOOK_SIGNING_KEY",
+    "CEREAL_UNSUBSCRIBE_KEY",
+    "CEREAL_PUSHOVER_TOKEN",
+    "CEREAL_SPACES_KEY",
+    "CEREAL_SPACES_SECRET",
+    "CEREAL_STORAGE_ENCRYPTION_KEY",
+    "AWS_ACCESS_KEY",
+    "AWS_SECRET_ACCESS_KEY",
+    "SENTRY_DSN",
+    "HONEYCOMB_API_KEY",
+    "OTEL_EXPORTER_OTLP_HEADERS",
+];
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Makes `config` available to [`get`] for the rest of the process.
+pub fn init(config: Config) -> &'static Config {
+    CONFIG.get_or_init(|| config)
+}
+
+/// The configuration loaded at startup, for code too deep in call stacks to
+/// have it passed in, such as the delivery clients.
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("Configuration is not loaded.")
+}
+
+impl Config {
+    /// Reads the configuration from the environment, reporting every missing
+    /// or invalid variable at once.
+    pub fn from_env() -> Result<Self> {
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Reads the configuration from `var`, which returns the value of a
+    /// variable if it is set.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut vars = Vars {
+            var: &var,
+            errors: Vec::new(),
+        };
+
+        let database = DatabaseConfig {
+            url: vars.required("DATABASE_URL"),
+            skip_migrations: vars.parse("CEREAL_SKIP_MIGRATIONS", false, "true or false"),
+            acquire_timeout: vars.secs("CEREAL_DB_ACQUIRE_TIMEOUT_SECS", DEFAULT_ACQUIRE_TIMEOUT),
+            max_connections: vars.parse(
+                "CEREAL_DB_MAX_CONNECTIONS",
+                DEFAULT_MAX_CONNECTIONS,
+                "a whole number",
+            ),
+        };
+        let bind_address = vars.parse(
+            "CEREAL_BIND_ADDRESS",
+            DEFAULT_BIND_ADDRESS.parse().unwrap(),
+            "an address such as 0.0.0.0:3000",
+        );
+        let tls = match (
+            vars.optional("CEREAL_TLS_CERT_PATH"),
+            vars.optional("CEREAL_TLS_KEY_PATH"),
+        ) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                cert_path: cert_path.into(),
+                key_path: key_path.into(),
+            }),
+            (None, None) => None,
+            _ => {
+                vars.errors.push(
+                    "CEREAL_TLS_CERT_PATH and CEREAL_TLS_KEY_PATH must be set together.".into(),
+                );
+                None
+            }
+        };
+        let listen_socket = vars.optional("CEREAL_LISTEN_SOCKET").map(|path| {
+            let mode = match vars.optional("CEREAL_LISTEN_SOCKET_MODE") {
+                Some(mode) => vars
+                    .check(
+                        "CEREAL_LISTEN_SOCKET_MODE",
+                        &mode,
+                        "an octal mode such as 660",
+                        u32::from_str_radix(mode.trim(), 8),
+                    )
+                    .unwrap_or(DEFAULT_LISTEN_SOCKET_MODE),
+                None => DEFAULT_LISTEN_SOCKET_MODE,
+            };
+            ListenSocket {
+                path: path.into(),
+                mode,
+            }
+        });
+        if listen_socket.is_some() && tls.is_some() {
+            vars.errors.push(
+                "CEREAL_LISTEN_SOCKET can't be used with CEREAL_TLS_CERT_PATH, TLS is left to the proxy."
+                    .into(),
+            );
+        }
+        let shutdown_delay = vars.secs("CEREAL_SHUTDOWN_DELAY_SECS", DEFAULT_SHUTDOWN_DELAY);
+        let dry_run = vars.parse("CEREAL_DRY_RUN", false, "true or false");
+        let admin_token = vars.optional("CEREAL_ADMIN_TOKEN");
+        let mailgun = vars
+            .all_or_none([
+                "CEREAL_MAILGUN_API_KEY",
+                "CEREAL_MAILGUN_API_ENDPOINT",
+                "CEREAL_FROM_EMAIL_ADDRESS",
+            ])
+            .map(|[api_key, api_endpoint, from_address]| MailgunConfig {
+                api_key,
+                api_endpoint,
+                from_address,
+            });
+        if let Some(mailgun) = &mailgun {
+            vars.url("CEREAL_MAILGUN_API_ENDPOINT", &mailgun.api_endpoint);
+        }
+        let mailgun_webhook_key = vars.optional("CEREAL_MAILGUN_WEBHOOK_SIGNING_KEY");
+        let unsubscribe = vars
+            .all_or_none(["CEREAL_PUBLIC_URL", "CEREAL_UNSUBSCRIBE_KEY"])
+            .and_then(|[public_url, key]| {
+                Some(UnsubscribeConfig {
+                    public_url: vars.parse_base_url("CEREAL_PUBLIC_URL", &public_url)?,
+                    key,
+                })
+            });
+        let pushover_token = vars.optional("CEREAL_PUSHOVER_TOKEN");
+        let ebook_convert = vars
+            .optional("CEREAL_EBOOK_CONVERT_PATH")
+            .unwrap_or_else(|| DEFAULT_EBOOK_CONVERT.to_owned());
+        let cover_cache = vars
+            .optional("CEREAL_COVER_CACHE_DIR")
+            .map(|dir| CoverCacheConfig {
+                dir: PathBuf::from(dir),
+                max_bytes: vars.parse(
+                    "CEREAL_COVER_CACHE_MAX_MB",
+                    DEFAULT_COVER_CACHE_MAX_MB,
+                    "a whole number of megabytes",
+                ) * 1024
+                    * 1024,
+                calibre_debug: vars
+                    .optional("CEREAL_CALIBRE_DEBUG_PATH")
+                    .unwrap_or_else(|| DEFAULT_CALIBRE_DEBUG.to_owned()),
+            });
+
+        let storage = StorageConfig {
+            key: vars.required("CEREAL_SPACES_KEY"),
+            secret: vars.required("CEREAL_SPACES_SECRET"),
+            endpoint: vars.required("CEREAL_SPACES_ENDPOINT"),
+            bucket: vars.required("CEREAL_SPACES_NAME"),
+            artifact_retention: chrono::Duration::days(vars.parse(
+                "CEREAL_ARTIFACT_RETENTION_DAYS",
+                DEFAULT_ARTIFACT_RETENTION_DAYS,
+                "a whole number of days",
+            )),
+            encryption_key: vars.encryption_key("CEREAL_STORAGE_ENCRYPTION_KEY"),
+            debug_snapshots_per_day: vars
+                .parse("CEREAL_DEBUG_SNAPSHOTS", false, "true or false")
+                .then(|| {
+                    vars.parse(
+                        "CEREAL_DEBUG_SNAPSHOTS_PER_DAY",
+                        DEFAULT_DEBUG_SNAPSHOTS_PER_DAY,
+                        "a whole number",
+                    )
+                }),
+            multipart_threshold: vars.multipart_threshold("CEREAL_STORAGE_MULTIPART_THRESHOLD_MB"),
+        };
+        if !storage.endpoint.is_empty() {
+            vars.url("CEREAL_SPACES_ENDPOINT", &storage.endpoint);
+        }
+        let email_bucket = vars
+            .all_or_none([
+                "AWS_EMAIL_BUCKET",
+                "AWS_ACCESS_KEY",
+                "AWS_SECRET_ACCESS_KEY",
+            ])
+            .map(|[bucket, access_key, secret_key]| EmailBucketConfig {
+                bucket,
+                access_key,
+                secret_key,
+                region: vars
+                    .optional("AWS_DEFAULT_REGION")
+                    .or_else(|| vars.optional("AWS_REGION"))
+                    .unwrap_or_else(|| DEFAULT_REGION.to_owned()),
+            });
+
+        let telemetry = TelemetryConfig {
+            export: vars.export_target(),
+            sampler_ratio: vars.ratio("OTEL_TRACES_SAMPLER_ARG", 1.0),
+            service_name: vars
+                .optional("OTEL_SERVICE_NAME")
+                .unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_owned()),
+            sentry_dsn: vars
+                .optional("SENTRY_DSN")
+                .and_then(|dsn| vars.check("SENTRY_DSN", &dsn, "a valid DSN", dsn.parse())),
+            log_format: match vars.optional("CEREAL_LOG_FORMAT").as_deref() {
+                None | Some("text") => LogFormat::Text,
+                Some("json") => LogFormat::Json,
+                Some(other) => {
+                    vars.invalid("CEREAL_LOG_FORMAT", other, "json or text");
+                    LogFormat::Text
+                }
+            },
+        };
+
+        let intervals = Intervals {
+            check: vars.secs("CEREAL_CHECK_INTERVAL_SECS", DEFAULT_CHECK_INTERVAL),
+            notification: vars.secs(
+                "CEREAL_NOTIFICATION_INTERVAL_SECS",
+                DEFAULT_NOTIFICATION_INTERVAL,
+            ),
+            summary: vars.secs("CEREAL_SUMMARY_INTERVAL_SECS", DEFAULT_SUMMARY_INTERVAL),
+        };
+        let rate_limits = RateLimits {
+            per_ip: vars.parse(
+                "CEREAL_IP_RATE_LIMIT_PER_SECOND",
+                DEFAULT_RATE_LIMIT,
+                "a positive whole number",
+            ),
+            per_route: vars.parse(
+                "CEREAL_ROUTE_RATE_LIMIT_PER_SECOND",
+                DEFAULT_RATE_LIMIT,
+                "a positive whole number",
+            ),
+            convert_per_minute: vars.parse(
+                "CEREAL_CONVERT_RATE_LIMIT_PER_MINUTE",
+                DEFAULT_CONVERT_RATE_LIMIT,
+                "a positive whole number",
+            ),
+        };
+        let ip_access = IpAccess {
+            allow: vars.networks("CEREAL_IP_ALLOWLIST").unwrap_or_default(),
+            deny: vars.networks("CEREAL_IP_DENYLIST").unwrap_or_default(),
+            admin: vars.networks("CEREAL_ADMIN_IP_ALLOWLIST"),
+        };
+        let defaults = Endpoints::default();
+        let endpoints = Endpoints {
+            pushover: vars.base_url("CEREAL_PUSHOVER_URL", defaults.pushover),
+            royalroad: vars.base_url("CEREAL_ROYALROAD_URL", defaults.royalroad),
+            pale: vars.base_url("CEREAL_PALE_URL", defaults.pale),
+            practical_guide: vars.base_url("CEREAL_PRACTICAL_GUIDE_URL", defaults.practical_guide),
+            wandering_inn: vars.base_url("CEREAL_WANDERING_INN_URL", defaults.wandering_inn),
+        };
+        let proxies = ProxyConfig {
+            http: vars.proxy("CEREAL_HTTP_PROXY"),
+            https: vars.proxy("CEREAL_HTTPS_PROXY"),
+            scrape: vars.proxy("CEREAL_SCRAPE_PROXY"),
+            api_bypass: vars.parse("CEREAL_API_BYPASS_PROXY", false, "true or false"),
+            no_proxy: vars.optional("CEREAL_NO_PROXY"),
+        };
+        let body_limits = BodyLimits {
+            warn: vars.parse(
+                "CEREAL_BODY_WARN_BYTES",
+                DEFAULT_BODY_WARN_BYTES,
+                "a whole number of bytes",
+            ),
+            max: vars.parse(
+                "CEREAL_BODY_MAX_BYTES",
+                DEFAULT_BODY_MAX_BYTES,
+                "a whole number of bytes",
+            ),
+        };
+        let delivery_backlog = DeliveryBacklog {
+            groups_per_cycle: vars
+                .parse(
+                    "CEREAL_DELIVERY_GROUPS_PER_CYCLE",
+                    DEFAULT_DELIVERY_GROUPS_PER_CYCLE,
+                    "a whole number",
+                )
+                .max(1),
+            warn: vars.parse(
+                "CEREAL_DELIVERY_BACKLOG_WARNING",
+                DEFAULT_DELIVERY_BACKLOG_WARNING,
+                "a whole number",
+            ),
+        };
+
+        let archive_chapters_after = vars
+            .optional_days("CEREAL_ARCHIVE_CHAPTERS_AFTER_DAYS")
+            .map(chrono::Duration::days);
+
+        let templates = Templates {
+            kindle_subject: vars.template("CEREAL_KINDLE_SUBJECT_TEMPLATE"),
+            pushover: vars.template("CEREAL_PUSHOVER_TEMPLATE"),
+            pushover_combined: vars.template("CEREAL_PUSHOVER_COMBINED_TEMPLATE"),
+        };
+
+        if !vars.errors.is_empty() {
+            bail!("Invalid configuration. {}", vars.errors.join(" "));
+        }
+        Ok(Self {
+            database,
+            bind_address,
+            tls,
+            listen_socket,
+            shutdown_delay,
+            dry_run,
+            admin_token,
+            mailgun,
+            mailgun_webhook_key,
+            unsubscribe,
+            pushover_token,
+            ebook_convert,
+            cover_cache,
+            storage,
+            email_bucket,
+            telemetry,
+            intervals,
+            rate_limits,
+            ip_access,
+            endpoints,
+            proxies,
+            body_limits,
+            delivery_backlog,
+            archive_chapters_after,
+            templates,
+        })
+    }
+}
+
+/// Names `name` in errors, along with its `_FILE` variant for secrets.
+fn describe(name: &str) -> String {
+    if SECRETS.contains(&name) {
+        format!("{} or {}_FILE", name, name)
+    } else {
+        name.to_owned()
+    }
+}
+
+/// Reads variables, collecting an error for each missing or invalid one.
+struct Vars<'a> {
+    var: &'a dyn Fn(&str) -> Option<String>,
+    errors: Vec<String>,
+}
+
+impl Vars<'_> {
+    /// The value of `name`, treating a blank value as unset. Secrets which
+    /// aren't set are read from their `_FILE` variant, trimmed.
+    fn optional(&mut self, name: &str) -> Option<String> {
+        let set = |value: &String| !value.trim().is_empty();
+        if let Some(value) = (self.var)(name).filter(set) {
+            return Some(value);
+        }
+        if !SECRETS.contains(&name) {
+            return None;
+        }
+        let file_var = format!("{}_FILE", name);
+        let path = (self.var)(&file_var).filter(set)?;
+        match std::fs::read_to_string(path.trim()) {
+            Ok(contents) if !contents.trim().is_empty() => Some(contents.trim().to_owned()),
+            Ok(_) => {
+                self.errors
+                    .push(format!("{} names an empty file, {}.", file_var, path));
+                None
+            }
+            Err(err) => {
+                self.errors
+                    .push(format!("{} {} could not be read: {}", file_var, path, err));
+                None
+            }
+        }
+    }
+
+    fn required(&mut self, name: &str) -> String {
+        let errors = self.errors.len();
+        self.optional(name).unwrap_or_else(|| {
+            // An unreadable file was already reported.
+            if self.errors.len() == errors {
+                self.errors.push(format!("{} must be set.", describe(name)));
+            }
+            String::new()
+        })
+    }
+
+    /// The values of a group of variables which are only usable together,
+    /// `None` when none of them are set.
+    fn all_or_none<const N: usize>(&mut self, names: [&str; N]) -> Option<[String; N]> {
+        let values = names.map(|name| self.optional(name));
+        if values.iter().all(Option::is_none) {
+            return None;
+        }
+        let (set, missing): (Vec<_>, Vec<_>) = names
+            .iter()
+            .zip(&values)
+            .partition(|(_, value)| value.is_some());
+        if !missing.is_empty() {
+            let names = |vars: Vec<(&&str, _)>| {
+                vars.into_iter()
+                    .map(|(name, _)| describe(name))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            self.errors.push(format!(
+                "{} must be set when {} is.",
+                names(missing),
+                names(set)
+            ));
+            return None;
+        }
+        Some(values.map(Option::unwrap_or_default))
+    }
+
+    fn invalid(&mut self, name: &str, value: &str, expected: &str) {
+        self.errors
+            .push(format!("{} {} is not {}.", name, value, expected));
+    }
+
+    /// Records an error for `name` if `result` is one.
+    fn check<T, E: Display>(
+        &mut self,
+        name: &str,
+        value: &str,
+        expected: &str,
+        result: Result<T, E>,
+    ) -> Option<T> {
+        match result {
+            Ok(parsed) => Some(parsed),
+            Err(err) => {
+                self.errors
+                    .push(format!("{} {} is not {}: {}", name, value, expected, err));
+                None
+            }
+        }
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &str, default: T, expected: &str) -> T {
+        match self.optional(name) {
+            Some(value) => value.trim().parse().unwrap_or_else(|_| {
+                self.invalid(name, &value, expected);
+                default
+            }),
+            None => default,
+        }
+    }
+
+    /// A size in megabytes no smaller than S3's minimum part size, in bytes.
+    fn multipart_threshold(&mut self, name: &str) -> u64 {
+        let Some(value) = self.optional(name) else {
+            return DEFAULT_MULTIPART_THRESHOLD_MB * 1024 * 1024;
+        };
+        let megabytes = value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|megabytes| *megabytes >= MIN_MULTIPART_THRESHOLD_MB);
+        if megabytes.is_none() {
+            self.invalid(name, &value, "a whole number of megabytes of at least 5");
+        }
+        megabytes.unwrap_or(DEFAULT_MULTIPART_THRESHOLD_MB) * 1024 * 1024
+    }
+
+    /// A positive number of days, if `name` is set.
+    fn optional_days(&mut self, name: &str) -> Option<i64> {
+        let value = self.optional(name)?;
+        let days = value.trim().parse().ok().filter(|days| *days > 0);
+        if days.is_none() {
+            self.invalid(name, &value, "a positive whole number of days");
+        }
+        days
+    }
+
+    fn template(&mut self, name: &str) -> Option<Template> {
+        let value = self.optional(name)?;
+        let template = value.parse::<Template>();
+        self.check(name, &value, "a notification template", template)
+    }
+
+    fn secs(&mut self, name: &str, default: Duration) -> Duration {
+        Duration::from_secs(self.parse(name, default.as_secs(), "a whole number of seconds"))
+    }
+
+    fn ratio(&mut self, name: &str, default: f64) -> f64 {
+        let ratio = self.parse(name, default, "a ratio between 0 and 1");
+        if (0.0..=1.0).contains(&ratio) {
+            return ratio;
+        }
+        self.invalid(name, &ratio.to_string(), "a ratio between 0 and 1");
+        default
+    }
+
+    fn url(&mut self, name: &str, value: &str) {
+        self.check(name, value, "a valid url", Url::parse(value));
+    }
+
+    /// A url paths are joined onto, given a trailing slash if it lacks one so
+    /// joining doesn't replace its last segment.
+    fn base_url(&mut self, name: &str, default: Url) -> Url {
+        let Some(value) = self.optional(name) else {
+            return default;
+        };
+        self.parse_base_url(name, &value).unwrap_or(default)
+    }
+
+    fn parse_base_url(&mut self, name: &str, value: &str) -> Option<Url> {
+        let mut url = self.check(name, value, "a valid url", Url::parse(value))?;
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        Some(url)
+    }
+
+    /// Comma separated addresses and CIDR networks, if `name` is set. A bare
+    /// address is a network of just itself.
+    fn networks(&mut self, name: &str) -> Option<Vec<IpNet>> {
+        let value = self.optional(name)?;
+        let mut networks = Vec::new();
+        for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<std::net::IpAddr>().map(IpNet::from))
+            {
+                Ok(network) => networks.push(network),
+                Err(_) => self.errors.push(format!(
+                    "{} entry {} is not an IP address or CIDR network.",
+                    name, entry
+                )),
+            }
+        }
+        Some(networks)
+    }
+
+    /// A proxy url, which may carry credentials. They are kept out of the
+    /// error, as it is logged.
+    fn proxy(&mut self, name: &str) -> Option<Url> {
+        let value = self.optional(name)?;
+        match Url::parse(&value) {
+            Ok(url) => Some(url),
+            Err(err) => {
+                self.errors
+                    .push(format!("{} is not a valid proxy url: {}", name, err));
+                None
+            }
+        }
+    }
+
+    fn encryption_key(&mut self, name: &str) -> Option<[u8; 32]> {
+        let key = self.optional(name)?;
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(key.trim())
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| {
+                let len = bytes.len();
+                bytes
+                    .try_into()
+                    .map_err(|_| format!("expected 32 bytes, found {}", len))
+            });
+        match decoded {
+            Ok(key) => Some(key),
+            Err(err) => {
+                self.errors.push(format!(
+                    "{} must be a base64 encoded 32 byte key: {}",
+                    name, err
+                ));
+                None
+            }
+        }
+    }
+
+    /// Reads the export target from the standard `OTEL_EXPORTER_OTLP_*`
+    /// variables, falling back to Honeycomb when only `HONEYCOMB_API_KEY` and
+    /// `HONEYCOMB_DATASET` are set.
+    fn export_target(&mut self) -> Option<ExportTarget> {
+        if let Some(endpoint) = self.optional("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            let headers = match self.optional("OTEL_EXPORTER_OTLP_HEADERS") {
+                Some(headers) => self.headers(&headers),
+                None => HashMap::new(),
+            };
+            let http = match self.optional("OTEL_EXPORTER_OTLP_PROTOCOL").as_deref() {
+                None | Some("grpc") => false,
+                Some("http/protobuf") => true,
+                Some(other) => {
+                    self.invalid(
+                        "OTEL_EXPORTER_OTLP_PROTOCOL",
+                        other,
+                        "grpc or http/protobuf",
+                    );
+                    false
+                }
+            };
+            return Some(ExportTarget {
+                endpoint,
+                headers,
+                http,
+            });
+        }
+        let [api_key, dataset] = self.all_or_none(["HONEYCOMB_API_KEY", "HONEYCOMB_DATASET"])?;
+        Some(ExportTarget {
+            endpoint: HONEYCOMB_ENDPOINT.into(),
+            headers: HashMap::from([
+                ("x-honeycomb-team".into(), api_key),
+                ("x-honeycomb-dataset".into(), dataset),
+            ]),
+            http: false,
+        })
+    }
+
+    /// Parses `OTEL_EXPORTER_OTLP_HEADERS`, a comma separated list of `key=value`.
+    fn headers(&mut self, headers: &str) -> HashMap<String, String> {
+        let mut parsed = HashMap::new();
+        for header in headers
+            .split(',')
+            .filter(|header| !header.trim().is_empty())
+        {
+            match header.split_once('=') {
+                Some((key, value)) => {
+                    parsed.insert(key.trim().to_owned(), value.trim().to_owned());
+                }
+                None => self.errors.push(format!(
+                    "OTEL_EXPORTER_OTLP_HEADERS entry {} is not key=value.",
+                    header
+                )),
+            }
+        }
+        parsed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(vars: &[(&str, &str)]) -> Result<Config> {
+        let vars: HashMap<String, String> = [
+            ("CEREAL_SPACES_KEY", "key"),
+            ("CEREAL_SPACES_ENDPOINT", "http://127.0.0.1:9"),
+            ("CEREAL_SPACES_NAME", "cereal-test"),
+        ]
+        .iter()
+        .chain(vars)
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        Config::from_vars(|name| vars.get(name).cloned())
+    }
+
+    fn error(vars: &[(&str, &str)]) -> String {
+        match load(vars) {
+            Ok(_) => panic!("Loaded an invalid configuration."),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn reads_secrets_from_files() {
+        let dir = std::env::temp_dir().join(format!("cereal-secrets-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let secret = dir.join("spaces-secret");
+        std::fs::write(&secret, "from-file\n").unwrap();
+        let secret = secret.to_str().unwrap();
+        let database = ("DATABASE_URL", "postgres://localhost/cereal");
+
+        let config = load(&[database, ("CEREAL_SPACES_SECRET_FILE", secret)]).unwrap();
+        assert_eq!(config.storage.secret, "from-file");
+        let config = load(&[
+            database,
+            ("CEREAL_SPACES_SECRET", "from-env"),
+            ("CEREAL_SPACES_SECRET_FILE", secret),
+        ])
+        .unwrap();
+        assert_eq!(config.storage.secret, "from-env");
+
+        let err = error(&[database]);
+        assert!(err.contains("CEREAL_SPACES_SECRET or CEREAL_SPACES_SECRET_FILE must be set."));
+        let missing = dir.join("missing");
+        let err = error(&[
+            database,
+            ("CEREAL_SPACES_SECRET_FILE", missing.to_str().unwrap()),
+        ]);
+        assert!(err.contains("CEREAL_SPACES_SECRET_FILE"));
+        assert!(!err.contains("must be set"));
+        let err = error(&[
+            database,
+            ("CEREAL_SPACES_SECRET", "secret"),
+            (
+                "CEREAL_MAILGUN_API_ENDPOINT",
+                "https://api.mailgun.net/v3/mg/messages",
+            ),
+            ("CEREAL_FROM_EMAIL_ADDRESS", "cereal@example.com"),
+        ]);
+        assert!(err.contains("CEREAL_MAILGUN_API_KEY or CEREAL_MAILGUN_API_KEY_FILE must be set"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_ip_access_lists() {
+        let database = ("DATABASE_URL", "postgres://localhost/cereal");
+        let secret = ("CEREAL_SPACES_SECRET", "secret");
+
+        let config = load(&[database, secret]).unwrap();
+        assert!(config.ip_access.allow.is_empty());
+        assert!(config.ip_access.deny.is_empty());
+        assert!(config.ip_access.admin.is_none());
+        let config = load(&[
+            database,
+            secret,
+            ("CEREAL_IP_ALLOWLIST", "10.0.0.0/8, 2001:db8::/32"),
+            ("CEREAL_IP_DENYLIST", "203.0.113.7"),
+            ("CEREAL_ADMIN_IP_ALLOWLIST", "192.168.1.0/24"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.ip_access.allow,
+            [
+                "10.0.0.0/8".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            config.ip_access.deny,
+            ["203.0.113.7/32".parse::<IpNet>().unwrap()]
+        );
+        assert_eq!(
+            config.ip_access.admin,
+            Some(vec!["192.168.1.0/24".parse().unwrap()])
+        );
+
+        let err = error(&[
+            database,
+            secret,
+            ("CEREAL_IP_DENYLIST", "203.0.113.7,10.0.0/8"),
+        ]);
+        assert!(
+            err.contains("CEREAL_IP_DENYLIST entry 10.0.0/8 is not an IP address or CIDR network.")
+        );
+    }
+
+    #[test]
+    fn reads_multipart_threshold() {
+        let database = ("DATABASE_URL", "postgres://localhost/cereal");
+        let secret = ("CEREAL_SPACES_SECRET", "secret");
+
+        let config = load(&[database, secret]).unwrap();
+        assert_eq!(config.storage.multipart_threshold, 64 * 1024 * 1024);
+        let config = load(&[
+            database,
+            secret,
+            ("CEREAL_STORAGE_MULTIPART_THRESHOLD_MB", "5"),
+        ])
+        .unwrap();
+        assert_eq!(config.storage.multipart_threshold, 5 * 1024 * 1024);
+
+        let err = error(&[
+            database,
+            secret,
+            ("CEREAL_STORAGE_MULTIPART_THRESHOLD_MB", "4"),
+        ]);
+        assert!(err.contains(
+            "CEREAL_STORAGE_MULTIPART_THRESHOLD_MB 4 is not a whole number of megabytes of at least 5."
+        ));
+    }
+}
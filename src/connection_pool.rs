@@ -1,32 +1,66 @@
-use std::env;
-
+use diesel::connection::{Instrumentation, InstrumentationEvent};
 use diesel::sql_types::Integer;
-use diesel::{Connection, ConnectionError, RunQueryDsl};
-use diesel_tracing::pg::InstrumentedPgConnection;
+use diesel::ConnectionError;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
 use mobc::{async_trait, Manager, Pool};
+use tracing::Span;
 
+use crate::config::DatabaseConfig;
 use crate::util::InstrumentedPgConnectionPool;
 
 #[derive(QueryableByName)]
 struct TestResult {
-    #[sql_type = "Integer"]
+    #[diesel(sql_type = Integer)]
     _a: i32,
 }
 
-pub struct PgConnectionManager;
+/// Emits a span for every query run on a connection, covering the time from
+/// the query being sent to its result being read.
+#[derive(Default)]
+struct QueryTracing {
+    query_span: Option<Span>,
+}
+
+impl Instrumentation for QueryTracing {
+    fn on_connection_event(&mut self, event: InstrumentationEvent<'_>) {
+        match event {
+            InstrumentationEvent::StartQuery { query, .. } => {
+                self.query_span = Some(tracing::info_span!(
+                    "Database Query",
+                    db.system = "postgresql",
+                    db.statement = %query,
+                ));
+            }
+            InstrumentationEvent::FinishQuery { error, .. } => {
+                if let (Some(span), Some(error)) = (self.query_span.take(), error) {
+                    span.in_scope(|| tracing::warn!(%error, "Database query failed."));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+pub struct PgConnectionManager {
+    database_url: String,
+}
 
 #[async_trait]
 impl Manager for PgConnectionManager {
-    type Connection = InstrumentedPgConnection;
+    type Connection = AsyncPgConnection;
     type Error = ConnectionError;
 
     async fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        InstrumentedPgConnection::establish(&database_url)
+        let mut conn = AsyncPgConnection::establish(&self.database_url).await?;
+        conn.set_instrumentation(QueryTracing::default());
+        Ok(conn)
     }
 
-    async fn check(&self, conn: Self::Connection) -> Result<Self::Connection, Self::Error> {
-        match diesel::sql_query("SELECT 1 as _a").load::<TestResult>(&conn) {
+    async fn check(&self, mut conn: Self::Connection) -> Result<Self::Connection, Self::Error> {
+        match diesel::sql_query("SELECT 1 as _a")
+            .load::<TestResult>(&mut conn)
+            .await
+        {
             Ok(_) => Ok(conn),
             Err(_) => Err(ConnectionError::BadConnection(String::from(
                 "Failed to select 1.",
@@ -35,6 +69,13 @@ impl Manager for PgConnectionManager {
     }
 }
 
-pub fn establish() -> InstrumentedPgConnectionPool {
-    InstrumentedPgConnectionPool(Pool::builder().max_open(30).build(PgConnectionManager))
+pub fn establish(config: &DatabaseConfig) -> InstrumentedPgConnectionPool {
+    InstrumentedPgConnectionPool(
+        Pool::builder()
+            .max_open(config.max_connections)
+            .get_timeout(Some(config.acquire_timeout))
+            .build(PgConnectionManager {
+                database_url: config.url.clone(),
+            }),
+    )
 }
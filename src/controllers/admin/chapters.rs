@@ -0,0 +1,364 @@
+use crate::controllers::admin;
+use crate::models::{chapter_not_deleted, chapter_order, Chapter, ChapterBody};
+use crate::schema::{chapter_bodies, chapters, deliveries, subscriptions, unsent_chapters};
+use crate::storage::{Storage, StorageLocation};
+use crate::util::{error_chain, ApiError, InstrumentedPgConnectionPool};
+
+use anyhow::Result;
+use diesel::sql_types::Uuid as SqlUuid;
+use diesel::{sql_query, ExpressionMethods, OptionalExtension, PgArrayExpressionMethods, QueryDsl};
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteChapterRequest {
+    /// Delete the chapter even though it has been delivered.
+    #[serde(default)]
+    force: bool,
+}
+
+/// What deleting a chapter cleaned up.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ChapterDeletion {
+    pub chapter_id: Uuid,
+    pub book_id: Uuid,
+    /// Whether the chapter had a stored body.
+    pub body_deleted: bool,
+    /// Keys of the stored objects removed, its body and converted ebook.
+    pub objects_deleted: Vec<String>,
+    pub unsent_chapters_deleted: usize,
+    /// Subscriptions whose last sent chapter was moved back to the chapter
+    /// before it.
+    pub subscriptions_repaired: usize,
+    /// Subscriptions whose last notified chapter was moved back likewise.
+    pub notifications_repaired: usize,
+    /// Past deliveries which included the chapter. They are kept as sent.
+    pub deliveries: usize,
+}
+
+#[tracing::instrument(
+name = "Deleting a chapter.",
+err,
+level = "info"
+skip(authorization, db_pool, storage),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn delete_chapter(
+    chapter_id: Uuid,
+    request: DeleteChapterRequest,
+    authorization: Option<String>,
+    db_pool: InstrumentedPgConnectionPool,
+    storage: Storage,
+) -> Result<ChapterDeletion> {
+    admin::authorize(authorization)?;
+    delete(chapter_id, request.force, &db_pool, &storage).await
+}
+
+/// Removes a chapter along with its body, stored objects and queue entries.
+/// Subscriptions which had been sent up to it are moved back to the chapter
+/// before it, so the chapters after it are still queued for them. A chapter
+/// which is still in its book's feed is discovered again on the next check.
+pub(crate) async fn delete(
+    chapter_id: Uuid,
+    force: bool,
+    db_pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+) -> Result<ChapterDeletion> {
+    let mut conn = db_pool.get().await?;
+    let chapter: Chapter = chapters::table
+        .find(chapter_id)
+        .first(&mut *conn)
+        .await
+        .optional()?
+        .ok_or_else(|| ApiError::NotFound(format!("Chapter {} does not exist.", chapter_id)))?;
+    let delivered: i64 = deliveries::table
+        .filter(deliveries::chapter_ids.contains(vec![chapter_id]))
+        .count()
+        .get_result(&mut *conn)
+        .await?;
+    if delivered > 0 && !force {
+        return Err(ApiError::Conflict(format!(
+            "Chapter {} has been delivered {} times, pass force=true to delete it anyway.",
+            chapter_id, delivered
+        ))
+        .into());
+    }
+    let previous = chapters::table
+        .filter(chapters::book_id.eq(chapter.book_id))
+        .filter(chapter_not_deleted())
+        .filter(chapters::id.ne(chapter_id))
+        .order(chapter_order())
+        .load::<Chapter>(&mut *conn)
+        .await?
+        .into_iter()
+        .take_while(|chap| chap.sort_key() < chapter.sort_key())
+        .last()
+        .map(|chap| chap.id);
+
+    let (body, unsent_chapters_deleted, subscriptions_repaired, notifications_repaired) = conn
+        .transaction::<_, anyhow::Error, _>(async |conn| {
+            let subscriptions_repaired = diesel::update(
+                subscriptions::table.filter(subscriptions::last_chapter_id.eq(chapter_id)),
+            )
+            .set(subscriptions::last_chapter_id.eq(previous))
+            .execute(&mut *conn)
+            .await?;
+            let notifications_repaired = diesel::update(
+                subscriptions::table
+                    .filter(subscriptions::pushover_notified_chapter_id.eq(chapter_id)),
+            )
+            .set(subscriptions::pushover_notified_chapter_id.eq(previous))
+            .execute(&mut *conn)
+            .await?;
+            let unsent_chapters_deleted = diesel::delete(
+                unsent_chapters::table.filter(unsent_chapters::chapter_id.eq(chapter_id)),
+            )
+            .execute(&mut *conn)
+            .await?;
+            let body: Option<ChapterBody> = diesel::delete(chapter_bodies::table.find(chapter_id))
+                .get_result(&mut *conn)
+                .await
+                .optional()?;
+            diesel::delete(chapters::table.find(chapter_id))
+                .execute(&mut *conn)
+                .await?;
+            sql_query(
+                "update books set
+                    chapter_count = (select count(*) from chapters
+                        where chapters.book_id = books.id and chapters.deleted_at is null)
+                        + (select count(*) from chapters_archive
+                        where chapters_archive.book_id = books.id),
+                    latest_chapter_published_at = greatest(
+                        (select max(published_at) from chapters
+                        where chapters.book_id = books.id and chapters.deleted_at is null),
+                        (select max(published_at) from chapters_archive
+                        where chapters_archive.book_id = books.id))
+                where books.id = $1",
+            )
+            .bind::<SqlUuid, _>(chapter.book_id)
+            .execute(&mut *conn)
+            .await?;
+            Ok((
+                body,
+                unsent_chapters_deleted,
+                subscriptions_repaired,
+                notifications_repaired,
+            ))
+        })
+        .await?;
+    drop(conn);
+
+    // Objects are removed once the rows are gone, as an object left behind
+    // is only wasted space while a row left without its object breaks
+    // deliveries.
+    let mut objects: Vec<StorageLocation> = Vec::new();
+    let body_deleted = body.is_some();
+    objects.extend(body.map(StorageLocation::from));
+    objects.extend(
+        storage
+            .chapter_artifact(&chapter.book_id, &chapter_id)
+            .await?,
+    );
+    objects.extend(
+        storage
+            .chapter_extras(&chapter.book_id, &chapter_id)
+            .await?,
+    );
+    let mut objects_deleted = Vec::new();
+    for object in objects {
+        match storage.delete(&object).await {
+            Ok(()) => objects_deleted.push(object.key),
+            Err(err) => error!(
+                error = %error_chain(&err),
+                "Failed to delete an object of a deleted chapter."
+            ),
+        }
+    }
+
+    Ok(ChapterDeletion {
+        chapter_id,
+        book_id: chapter.book_id,
+        body_deleted,
+        objects_deleted,
+        unsent_chapters_deleted,
+        subscriptions_repaired,
+        notifications_repaired,
+        deliveries: delivered as usize,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Book, BookKind, ChapterKind, ChapterStatus, NewBook, NewChapter};
+    use crate::schema::books;
+    use crate::storage;
+    use crate::test_support::{self, TestDatabase};
+    use aws_sdk_s3::primitives::ByteStream;
+    use chrono::{DateTime, Utc};
+
+    async fn insert_chapter(db: &TestDatabase, book: &Book, published_at: DateTime<Utc>) -> Uuid {
+        let mut conn = db.pool.get().await.unwrap();
+        let id = Uuid::new_v4();
+        diesel::insert_into(chapters::table)
+            .values((
+                chapters::id.eq(id),
+                NewChapter {
+                    name: id.to_string(),
+                    author: book.author.clone(),
+                    book_id: book.id,
+                    published_at,
+                    metadata: ChapterKind::Pale {
+                        url: format!("https://palewebserial.wordpress.com/{}/", id),
+                    },
+                },
+                chapters::status.eq(ChapterStatus::Fetched),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn deletes_a_chapter_and_repairs_subscriptions() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        test_support::config();
+        let storage = test_support::storage();
+        let t0 = Utc::now() - chrono::Duration::days(1);
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books::table)
+            .values(NewBook {
+                name: "Pale".into(),
+                author: "Wildbow".into(),
+                metadata: BookKind::Pale,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let first = insert_chapter(&db, &book, t0).await;
+        let garbage = insert_chapter(&db, &book, t0 + chrono::Duration::hours(1)).await;
+        let last = insert_chapter(&db, &book, t0 + chrono::Duration::hours(2)).await;
+        let body = storage
+            .store_book(
+                &book.id,
+                &garbage,
+                ByteStream::from_static(b"<p>Log in</p>"),
+            )
+            .await
+            .unwrap();
+        let artifact = storage
+            .store_chapter_artifact(&book.id, &garbage, ByteStream::from_static(b"epub"))
+            .await
+            .unwrap();
+        let mut conn = db.pool.get().await.unwrap();
+        diesel::insert_into(chapter_bodies::table)
+            .values(ChapterBody {
+                key: body.location.key.clone(),
+                bucket: body.location.bucket,
+                chapter_id: garbage,
+                size: Some(body.size),
+            })
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        for (user_id, last_chapter_id) in [("sent-garbage", garbage), ("sent-last", last)] {
+            diesel::insert_into(subscriptions::table)
+                .values((
+                    subscriptions::user_id.eq(user_id),
+                    subscriptions::book_id.eq(book.id),
+                    subscriptions::last_chapter_id.eq(last_chapter_id),
+                    subscriptions::pushover_notified_chapter_id.eq(last_chapter_id),
+                ))
+                .execute(&mut *conn)
+                .await
+                .unwrap();
+        }
+        diesel::insert_into(unsent_chapters::table)
+            .values((
+                unsent_chapters::id.eq(Uuid::new_v4()),
+                unsent_chapters::user_id.eq("sent-last"),
+                unsent_chapters::chapter_id.eq(garbage),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        diesel::insert_into(deliveries::table)
+            .values((
+                deliveries::user_id.eq("sent-garbage"),
+                deliveries::book_id.eq(book.id),
+                deliveries::chapter_ids.eq(vec![first, garbage]),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+
+        let err = delete(garbage, false, &db.pool, &storage)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::Conflict(_))
+        ));
+
+        let deletion = delete(garbage, true, &db.pool, &storage).await.unwrap();
+        assert_eq!(
+            deletion,
+            ChapterDeletion {
+                chapter_id: garbage,
+                book_id: book.id,
+                body_deleted: true,
+                objects_deleted: vec![body.location.key, artifact.key],
+                unsent_chapters_deleted: 1,
+                subscriptions_repaired: 1,
+                notifications_repaired: 1,
+                deliveries: 1,
+            }
+        );
+        let prefix = format!("{}/{}/", storage::BODIES_PREFIX, book.id);
+        assert!(storage.list(&prefix).await.unwrap().is_empty());
+        assert!(storage
+            .chapter_artifact(&book.id, &garbage)
+            .await
+            .unwrap()
+            .is_none());
+
+        let mut conn = db.pool.get().await.unwrap();
+        let mut subs: Vec<(String, Option<Uuid>, Option<Uuid>)> = subscriptions::table
+            .select((
+                subscriptions::user_id,
+                subscriptions::last_chapter_id,
+                subscriptions::pushover_notified_chapter_id,
+            ))
+            .load(&mut *conn)
+            .await
+            .unwrap();
+        subs.sort();
+        assert_eq!(
+            subs,
+            [
+                ("sent-garbage".into(), Some(first), Some(first)),
+                ("sent-last".into(), Some(last), Some(last)),
+            ]
+        );
+        let book: Book = books::table.find(book.id).first(&mut *conn).await.unwrap();
+        assert_eq!(book.chapter_count, 2);
+        drop(conn);
+
+        let err = delete(garbage, true, &db.pool, &storage).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::NotFound(_))
+        ));
+    }
+}
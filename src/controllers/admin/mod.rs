@@ -0,0 +1,165 @@
+mod chapters;
+
+use crate::config;
+use crate::providers::health::{self, ProviderHealth};
+use crate::storage::{self, Storage, StoredObject};
+use crate::tasks::{self, Redelivery};
+use crate::util::{map_result, ApiError, InstrumentedPgConnectionPool};
+
+use anyhow::Result;
+use serde::Deserialize;
+use uuid::Uuid;
+use warp::{Filter, Reply};
+
+/// Checks a request's bearer token against `CEREAL_ADMIN_TOKEN`. Admin
+/// endpoints are unusable while the token is unset.
+pub fn authorize(authorization: Option<String>) -> Result<()> {
+    let token = config::get().admin_token.as_deref();
+    let provided = authorization
+        .as_deref()
+        .and_then(|header| header.strip_prefix("Bearer "));
+    match (provided, token) {
+        (Some(provided), Some(token)) if provided == token => Ok(()),
+        _ => Err(ApiError::Unauthorized("A valid admin token is required.".into()).into()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListObjectsRequest {
+    book_id: Uuid,
+}
+
+#[tracing::instrument(
+name = "Listing stored objects for a book.",
+err,
+level = "info"
+skip(authorization, storage),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn list_objects(
+    request: ListObjectsRequest,
+    authorization: Option<String>,
+    storage: Storage,
+) -> Result<Vec<StoredObject>> {
+    authorize(authorization)?;
+    let mut objects = Vec::new();
+    for prefix in [
+        storage::BODIES_PREFIX,
+        storage::SOURCES_PREFIX,
+        storage::COVERS_PREFIX,
+        storage::ARTIFACTS_PREFIX,
+    ] {
+        let prefix = format!("{}/{}/", prefix, request.book_id);
+        objects.extend(storage.list(&prefix).await?);
+    }
+    Ok(objects)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RedeliverRequest {
+    /// Scrape the chapters from their source again before converting them.
+    #[serde(default)]
+    refetch: bool,
+    /// Report what would be sent without sending it.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[tracing::instrument(
+name = "Redelivering a past delivery.",
+err,
+level = "info"
+skip(authorization, db_pool, storage),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn redeliver(
+    delivery_id: Uuid,
+    request: RedeliverRequest,
+    authorization: Option<String>,
+    db_pool: InstrumentedPgConnectionPool,
+    storage: Storage,
+) -> Result<Redelivery> {
+    authorize(authorization)?;
+    tasks::redeliver(
+        &db_pool,
+        &storage,
+        delivery_id,
+        request.refetch,
+        request.dry_run,
+    )
+    .await
+}
+
+#[tracing::instrument(
+name = "Reporting provider health.",
+err,
+level = "info"
+skip(authorization),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn provider_health(authorization: Option<String>) -> Result<Vec<ProviderHealth>> {
+    authorize(authorization)?;
+    Ok(health::provider_health())
+}
+
+pub fn get_filters(
+    db_pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let list_storage = storage.clone();
+    let list_objects_filter = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("storage"))
+        .and(warp::path("objects"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::header::optional("authorization"))
+        .and(warp::any().map(move || list_storage.clone()))
+        .then(list_objects)
+        .map(map_result);
+    let redeliver_db = db_pool.clone();
+    let redeliver_storage = storage.clone();
+    let redeliver_filter = warp::post()
+        .and(warp::path("admin"))
+        .and(warp::path("deliveries"))
+        .and(warp::path::param())
+        .and(warp::path("redeliver"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::header::optional("authorization"))
+        .and(warp::any().map(move || redeliver_db.clone()))
+        .and(warp::any().map(move || redeliver_storage.clone()))
+        .then(redeliver)
+        .map(map_result);
+    let delete_chapter_db = db_pool.clone();
+    let delete_chapter_storage = storage.clone();
+    let delete_chapter_filter = warp::delete()
+        .and(warp::path("admin"))
+        .and(warp::path("chapters"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::header::optional("authorization"))
+        .and(warp::any().map(move || delete_chapter_db.clone()))
+        .and(warp::any().map(move || delete_chapter_storage.clone()))
+        .then(chapters::delete_chapter)
+        .map(map_result);
+    let provider_health_filter = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("providers"))
+        .and(warp::path("health"))
+        .and(warp::path::end())
+        .and(warp::header::optional("authorization"))
+        .then(provider_health)
+        .map(map_result);
+    list_objects_filter
+        .or(redeliver_filter)
+        .or(delete_chapter_filter)
+        .or(provider_health_filter)
+}
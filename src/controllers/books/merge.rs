@@ -0,0 +1,635 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::controllers::admin;
+use crate::models::{chapter_not_deleted, Book, Chapter, ChapterBody, ChapterStatus, Subscription};
+use crate::schema::{book_aliases, books, chapter_bodies, chapters, deliveries, subscriptions};
+use crate::storage::{self, Storage, StorageLocation};
+use crate::tasks;
+use crate::util::{ApiError, InstrumentedPgConnectionPool};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use diesel::sql_types::{Array, Uuid as SqlUuid};
+use diesel::{sql_query, ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Chapters of the two books with the same name published within this many
+/// hours of each other are taken to be the same chapter.
+const DUPLICATE_WINDOW_HOURS: i64 = 24;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MergeBooksRequest {
+    source_book_id: Uuid,
+    target_book_id: Uuid,
+}
+
+/// What a merge moved from the source book to the target.
+#[derive(Debug, Serialize, Default, PartialEq, Eq)]
+pub struct MergeSummary {
+    /// Subscriptions moved to the target, whose users weren't subscribed to it.
+    pub subscriptions_moved: usize,
+    /// Subscriptions dropped as their users were already subscribed to the
+    /// target.
+    pub subscriptions_merged: usize,
+    /// Chapters moved to the target.
+    pub chapters_moved: usize,
+    /// Chapters deleted as the target already had them.
+    pub chapters_deduplicated: usize,
+    /// Chapter bodies moved to the target's chapters.
+    pub bodies_moved: usize,
+    pub deliveries_moved: usize,
+}
+
+#[tracing::instrument(
+name = "Merging duplicate books.",
+err,
+level = "info"
+skip(authorization, db_pool, storage),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn merge_books(
+    request: MergeBooksRequest,
+    authorization: Option<String>,
+    db_pool: InstrumentedPgConnectionPool,
+    storage: Storage,
+) -> Result<MergeSummary> {
+    admin::authorize(authorization)?;
+    merge(
+        request.source_book_id,
+        request.target_book_id,
+        &db_pool,
+        &storage,
+    )
+    .await
+}
+
+/// A source chapter's body, copied under the target's prefix.
+struct MovedBody {
+    chapter_id: Uuid,
+    location: StorageLocation,
+    size: i64,
+    word_count: Option<i64>,
+    /// Whether the body fills in a target chapter which duplicates the source
+    /// chapter but lacked a body.
+    fills_duplicate: bool,
+}
+
+fn normalized_name(chapter: &Chapter) -> String {
+    chapter.name.trim().to_lowercase()
+}
+
+/// The target chapter each of the source's chapters duplicates, if any. Each
+/// target chapter is matched at most once, to the closest source chapter.
+fn find_duplicates(source: &[Chapter], target: &[Chapter]) -> HashMap<Uuid, Uuid> {
+    let window = chrono::Duration::hours(DUPLICATE_WINDOW_HOURS);
+    let mut candidates = source
+        .iter()
+        .flat_map(|source| {
+            target
+                .iter()
+                .filter(move |target| {
+                    normalized_name(source) == normalized_name(target)
+                        && (source.published_at - target.published_at).abs() <= window
+                })
+                .map(move |target| {
+                    let distance = (source.published_at - target.published_at).abs();
+                    (distance, source.id, target.id)
+                })
+        })
+        .collect::<Vec<_>>();
+    candidates.sort();
+    let mut matched_targets = HashSet::new();
+    let mut duplicates = HashMap::new();
+    for (_distance, source_id, target_id) in candidates {
+        if duplicates.contains_key(&source_id) || matched_targets.contains(&target_id) {
+            continue;
+        }
+        matched_targets.insert(target_id);
+        duplicates.insert(source_id, target_id);
+    }
+    duplicates
+}
+
+/// Merges the source book into the target: its subscriptions, chapters,
+/// bodies and deliveries move to the target, chapters the target already has
+/// are deleted, and the source is deleted with its metadata kept as an alias
+/// of the target.
+pub(crate) async fn merge(
+    source_book_id: Uuid,
+    target_book_id: Uuid,
+    db_pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+) -> Result<MergeSummary> {
+    if source_book_id == target_book_id {
+        return Err(ApiError::BadRequest("A book can't be merged into itself.".into()).into());
+    }
+    // Archived chapters are merged and deduplicated along with the rest.
+    tasks::unarchive_chapters(db_pool, &[source_book_id, target_book_id]).await?;
+    let (source, source_chapters, target_chapters, bodies) = {
+        let mut conn = db_pool.get().await?;
+        let mut live_book = async |book_id: Uuid| -> Result<Book> {
+            books::table
+                .find(book_id)
+                .filter(books::deleted_at.is_null())
+                .first(&mut *conn)
+                .await
+                .optional()?
+                .ok_or_else(|| ApiError::NotFound(format!("Book {} does not exist.", book_id)))
+                .map_err(Into::into)
+        };
+        let source = live_book(source_book_id).await?;
+        live_book(target_book_id).await?;
+        let chapters_of = |book_id: Uuid| {
+            chapters::table
+                .filter(chapters::book_id.eq(book_id))
+                .filter(chapter_not_deleted())
+        };
+        let source_chapters: Vec<Chapter> = chapters_of(source_book_id).load(&mut *conn).await?;
+        let target_chapters: Vec<Chapter> = chapters_of(target_book_id).load(&mut *conn).await?;
+        let bodies: HashMap<Uuid, ChapterBody> = chapter_bodies::table
+            .filter(
+                chapter_bodies::chapter_id.eq_any(
+                    source_chapters
+                        .iter()
+                        .chain(&target_chapters)
+                        .map(|chap| chap.id),
+                ),
+            )
+            .load::<ChapterBody>(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|body| (body.chapter_id, body))
+            .collect();
+        (source, source_chapters, target_chapters, bodies)
+    };
+    let duplicates = find_duplicates(&source_chapters, &target_chapters);
+
+    // Bodies are copied under the target's prefix before anything else, as
+    // purging the deleted source removes every object under its prefix.
+    let mut moved_bodies: Vec<MovedBody> = Vec::new();
+    for chapter in &source_chapters {
+        let Some(body) = bodies.get(&chapter.id) else {
+            continue;
+        };
+        let chapter_id = match duplicates.get(&chapter.id) {
+            None => chapter.id,
+            // Only bodies the target's copy of the chapter is missing are kept.
+            Some(target_id) if !bodies.contains_key(target_id) => *target_id,
+            Some(_) => continue,
+        };
+        let location = storage
+            .copy(
+                &body.clone().into(),
+                storage::chapter_body_key(&target_book_id, &chapter_id),
+            )
+            .await?;
+        let size = match body.size {
+            Some(size) => size,
+            None => storage.size(&location).await?,
+        };
+        moved_bodies.push(MovedBody {
+            chapter_id,
+            location,
+            size,
+            word_count: chapter.word_count,
+            fills_duplicate: chapter_id != chapter.id,
+        });
+    }
+
+    // The two books' ordinals don't order against each other, so chapters of
+    // both are compared by when they were published.
+    let sort_keys: HashMap<Uuid, (DateTime<Utc>, Option<i32>, Uuid)> = source_chapters
+        .iter()
+        .chain(&target_chapters)
+        .map(|chap| (chap.id, (chap.published_at, chap.ordinal, chap.id)))
+        .collect();
+    let remap =
+        |chapter_id: Option<Uuid>| chapter_id.map(|id| duplicates.get(&id).copied().unwrap_or(id));
+
+    let mut summary = MergeSummary::default();
+    let mut conn = db_pool.get().await?;
+    conn.transaction::<_, anyhow::Error, _>(async |conn| {
+        let now = Utc::now();
+        for chapter in &source_chapters {
+            if duplicates.contains_key(&chapter.id) {
+                diesel::update(chapters::table.find(chapter.id))
+                    .set(chapters::deleted_at.eq(now))
+                    .execute(&mut *conn)
+                    .await?;
+                summary.chapters_deduplicated += 1;
+            } else {
+                diesel::update(chapters::table.find(chapter.id))
+                    .set(chapters::book_id.eq(target_book_id))
+                    .execute(&mut *conn)
+                    .await?;
+                summary.chapters_moved += 1;
+            }
+        }
+        for moved in &moved_bodies {
+            let body = ChapterBody {
+                key: moved.location.key.clone(),
+                bucket: moved.location.bucket.clone(),
+                chapter_id: moved.chapter_id,
+                size: Some(moved.size),
+            };
+            diesel::insert_into(chapter_bodies::table)
+                .values(&body)
+                .on_conflict(chapter_bodies::chapter_id)
+                .do_update()
+                .set((
+                    chapter_bodies::key.eq(&body.key),
+                    chapter_bodies::bucket.eq(&body.bucket),
+                    chapter_bodies::size.eq(body.size),
+                ))
+                .execute(&mut *conn)
+                .await?;
+            if !moved.fills_duplicate {
+                continue;
+            }
+            diesel::update(chapters::table.find(moved.chapter_id))
+                .set((
+                    chapters::status.eq(ChapterStatus::Fetched),
+                    chapters::word_count.eq(moved.word_count),
+                ))
+                .execute(&mut *conn)
+                .await?;
+        }
+        summary.bodies_moved = moved_bodies.len();
+
+        let source_subscriptions: Vec<Subscription> = subscriptions::table
+            .filter(subscriptions::book_id.eq(source_book_id))
+            .load(&mut *conn)
+            .await?;
+        let target_subscriptions: HashMap<String, Subscription> = subscriptions::table
+            .filter(subscriptions::book_id.eq(target_book_id))
+            .load::<Subscription>(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|sub| (sub.user_id.clone(), sub))
+            .collect();
+        for sub in source_subscriptions {
+            let source_last = remap(sub.last_chapter_id);
+            let source_row = subscriptions::table
+                .filter(subscriptions::user_id.eq(&sub.user_id))
+                .filter(subscriptions::book_id.eq(source_book_id));
+            let Some(target_sub) = target_subscriptions.get(&sub.user_id) else {
+                diesel::update(source_row)
+                    .set((
+                        subscriptions::book_id.eq(target_book_id),
+                        subscriptions::last_chapter_id.eq(source_last),
+                        subscriptions::pushover_notified_chapter_id
+                            .eq(remap(sub.pushover_notified_chapter_id)),
+                    ))
+                    .execute(&mut *conn)
+                    .await?;
+                summary.subscriptions_moved += 1;
+                continue;
+            };
+            // The earlier of the two, so no chapter is skipped. Having been
+            // sent nothing is earliest of all.
+            let earlier = [source_last, target_sub.last_chapter_id]
+                .into_iter()
+                .min_by_key(|last| last.map(|id| sort_keys.get(&id).copied()))
+                .flatten();
+            diesel::update(
+                subscriptions::table
+                    .filter(subscriptions::user_id.eq(&sub.user_id))
+                    .filter(subscriptions::book_id.eq(target_book_id)),
+            )
+            .set(subscriptions::last_chapter_id.eq(earlier))
+            .execute(&mut *conn)
+            .await?;
+            diesel::delete(source_row).execute(&mut *conn).await?;
+            summary.subscriptions_merged += 1;
+        }
+
+        summary.deliveries_moved =
+            diesel::update(deliveries::table.filter(deliveries::book_id.eq(source_book_id)))
+                .set(deliveries::book_id.eq(target_book_id))
+                .execute(&mut *conn)
+                .await?;
+
+        diesel::update(book_aliases::table.filter(book_aliases::book_id.eq(source_book_id)))
+            .set(book_aliases::book_id.eq(target_book_id))
+            .execute(&mut *conn)
+            .await?;
+        diesel::insert_into(book_aliases::table)
+            .values((
+                book_aliases::metadata.eq(source.metadata.without_credentials()),
+                book_aliases::book_id.eq(target_book_id),
+            ))
+            .on_conflict(book_aliases::metadata)
+            .do_update()
+            .set(book_aliases::book_id.eq(target_book_id))
+            .execute(&mut *conn)
+            .await?;
+        diesel::update(books::table.find(source_book_id))
+            .set(books::deleted_at.eq(now))
+            .execute(&mut *conn)
+            .await?;
+        sql_query(
+            "update books set
+                chapter_count = (select count(*) from chapters
+                    where chapters.book_id = books.id and chapters.deleted_at is null)
+                    + (select count(*) from chapters_archive
+                    where chapters_archive.book_id = books.id),
+                latest_chapter_published_at = greatest(
+                    (select max(published_at) from chapters
+                    where chapters.book_id = books.id and chapters.deleted_at is null),
+                    (select max(published_at) from chapters_archive
+                    where chapters_archive.book_id = books.id))
+            where books.id = any($1)",
+        )
+        .bind::<Array<SqlUuid>, _>(vec![source_book_id, target_book_id])
+        .execute(&mut *conn)
+        .await?;
+        // Numbers the merged chapters by when they were published, keeping
+        // each book's order among chapters published together.
+        sql_query(
+            "with numbered as (
+                select id, (row_number() over (
+                    order by published_at, ordinal nulls first, id) - 1)::integer as ordinal
+                from chapters where book_id = $1 and deleted_at is null
+            )
+            update chapters set ordinal = numbered.ordinal
+            from numbered where chapters.id = numbered.id",
+        )
+        .bind::<SqlUuid, _>(target_book_id)
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    })
+    .await?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controllers::books::{create_book, find_book, CreateBookRequest};
+    use crate::models::{BookKind, ChapterKind, NewBook, NewChapter};
+    use crate::providers::royalroad::RoyalRoadBookKind;
+    use crate::test_support::{self, TestDatabase};
+    use aws_sdk_s3::primitives::ByteStream;
+
+    async fn insert_book(db: &TestDatabase, name: &str, metadata: BookKind) -> Book {
+        let mut conn = db.pool.get().await.unwrap();
+        diesel::insert_into(books::table)
+            .values(NewBook {
+                name: name.into(),
+                author: "Wildbow".into(),
+                metadata,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap()
+    }
+
+    /// Inserts a chapter, storing `body` for it if there is one.
+    async fn insert_chapter(
+        db: &TestDatabase,
+        storage: &Storage,
+        book: &Book,
+        name: &str,
+        published_at: DateTime<Utc>,
+        body: Option<&'static str>,
+    ) -> Uuid {
+        let id = Uuid::new_v4();
+        let status = match body {
+            Some(_) => ChapterStatus::Fetched,
+            None => ChapterStatus::FetchFailed,
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        diesel::insert_into(chapters::table)
+            .values((
+                chapters::id.eq(id),
+                NewChapter {
+                    name: name.into(),
+                    author: book.author.clone(),
+                    book_id: book.id,
+                    published_at,
+                    metadata: ChapterKind::Pale {
+                        url: format!("https://palewebserial.wordpress.com/{}/", id),
+                    },
+                },
+                chapters::status.eq(status),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        if let Some(body) = body {
+            let location = storage
+                .store_book(&book.id, &id, ByteStream::from_static(body.as_bytes()))
+                .await
+                .unwrap()
+                .location;
+            // Sizes are left unknown, as for bodies stored before they were
+            // recorded, so moved bodies have theirs probed.
+            diesel::insert_into(chapter_bodies::table)
+                .values(ChapterBody {
+                    key: location.key,
+                    bucket: location.bucket,
+                    chapter_id: id,
+                    size: None,
+                })
+                .execute(&mut *conn)
+                .await
+                .unwrap();
+        }
+        id
+    }
+
+    async fn subscribe(db: &TestDatabase, user_id: &str, book: &Book, last: Option<Uuid>) {
+        let mut conn = db.pool.get().await.unwrap();
+        diesel::insert_into(subscriptions::table)
+            .values((
+                subscriptions::user_id.eq(user_id),
+                subscriptions::book_id.eq(book.id),
+                subscriptions::last_chapter_id.eq(last),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+    }
+
+    fn chapter(name: &str, published_at: DateTime<Utc>) -> Chapter {
+        Chapter {
+            id: Uuid::new_v4(),
+            name: name.into(),
+            author: "Wildbow".into(),
+            created_at: published_at,
+            updated_at: published_at,
+            book_id: Uuid::new_v4(),
+            published_at,
+            metadata: ChapterKind::Pale { url: "".into() },
+            status: ChapterStatus::Fetched,
+            deleted_at: None,
+            word_count: None,
+            ordinal: None,
+        }
+    }
+
+    #[test]
+    fn matches_each_duplicate_to_the_closest_chapter() {
+        let now = Utc::now();
+        let hours = chrono::Duration::hours;
+        let source = [
+            chapter("1.1 Blood Run Cold", now),
+            chapter(" 1.1 blood run cold", now + hours(3)),
+            chapter("1.2", now + hours(DUPLICATE_WINDOW_HOURS + 1)),
+        ];
+        let target = [
+            chapter("1.1 Blood Run Cold", now + hours(1)),
+            chapter("1.2", now),
+        ];
+
+        let duplicates = find_duplicates(&source, &target);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[&source[0].id], target[0].id);
+    }
+
+    #[tokio::test]
+    async fn merges_subscriptions_chapters_and_bodies() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        test_support::config();
+        let storage = test_support::storage();
+        let hours = chrono::Duration::hours;
+        let t0 = Utc::now() - chrono::Duration::days(7);
+        let target = insert_book(&db, "Pale", BookKind::Pale).await;
+        let source = insert_book(
+            &db,
+            "Pale (Royal Road)",
+            BookKind::RoyalRoad(RoyalRoadBookKind::new(777)),
+        )
+        .await;
+        let t1 = insert_chapter(&db, &storage, &target, "1.1", t0, Some("<p>t1</p>")).await;
+        let t2 = insert_chapter(&db, &storage, &target, "1.2", t0 + hours(24), None).await;
+        let t3 = insert_chapter(&db, &storage, &target, "1.3", t0 + hours(48), Some("t3")).await;
+        let s1 = insert_chapter(&db, &storage, &source, "1.1 ", t0 + hours(1), Some("s1")).await;
+        let s2 = insert_chapter(&db, &storage, &source, "1.2", t0 + hours(26), Some("s2")).await;
+        let s4 = insert_chapter(&db, &storage, &source, "1.4", t0 + hours(72), Some("s4")).await;
+        subscribe(&db, "both-source-earlier", &source, Some(s1)).await;
+        subscribe(&db, "both-source-earlier", &target, Some(t3)).await;
+        subscribe(&db, "both-target-unsent", &source, Some(s4)).await;
+        subscribe(&db, "both-target-unsent", &target, None).await;
+        subscribe(&db, "source-only", &source, Some(s2)).await;
+        subscribe(&db, "target-only", &target, Some(t1)).await;
+        let mut conn = db.pool.get().await.unwrap();
+        diesel::insert_into(deliveries::table)
+            .values((
+                deliveries::user_id.eq("source-only"),
+                deliveries::book_id.eq(source.id),
+                deliveries::chapter_ids.eq(vec![s1]),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+
+        let summary = merge(source.id, target.id, &db.pool, &storage)
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            MergeSummary {
+                subscriptions_moved: 1,
+                subscriptions_merged: 2,
+                chapters_moved: 1,
+                chapters_deduplicated: 2,
+                bodies_moved: 2,
+                deliveries_moved: 1,
+            }
+        );
+
+        let mut conn = db.pool.get().await.unwrap();
+        let mut subs: Vec<(String, Uuid, Option<Uuid>)> = subscriptions::table
+            .select((
+                subscriptions::user_id,
+                subscriptions::book_id,
+                subscriptions::last_chapter_id,
+            ))
+            .load(&mut *conn)
+            .await
+            .unwrap();
+        subs.sort();
+        assert_eq!(
+            subs,
+            [
+                ("both-source-earlier".into(), target.id, Some(t1)),
+                ("both-target-unsent".into(), target.id, None),
+                ("source-only".into(), target.id, Some(t2)),
+                ("target-only".into(), target.id, Some(t1)),
+            ]
+        );
+
+        let chapter_map: HashMap<Uuid, Chapter> = chapters::table
+            .load::<Chapter>(&mut *conn)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|chap| (chap.id, chap))
+            .collect();
+        assert_eq!(chapter_map[&s4].book_id, target.id);
+        assert!(chapter_map[&s4].deleted_at.is_none());
+        for duplicate in [s1, s2] {
+            assert!(chapter_map[&duplicate].deleted_at.is_some());
+        }
+        assert_eq!(chapter_map[&t2].status, ChapterStatus::Fetched);
+        for (id, text) in [(s4, "s4"), (t2, "s2"), (t1, "<p>t1</p>")] {
+            let body: ChapterBody = chapter_bodies::table
+                .find(id)
+                .first(&mut *conn)
+                .await
+                .unwrap();
+            assert_eq!(body.key, storage::chapter_body_key(&target.id, &id));
+            let moved = id != t1;
+            assert_eq!(body.size, moved.then_some(text.len() as i64));
+            assert_eq!(storage.fetch(body.into()).await.unwrap(), text.as_bytes());
+        }
+
+        let books: Vec<Book> = books::table.load(&mut *conn).await.unwrap();
+        let merged_source = books.iter().find(|book| book.id == source.id).unwrap();
+        assert!(merged_source.deleted_at.is_some());
+        assert_eq!(merged_source.chapter_count, 0);
+        let merged_target = books.iter().find(|book| book.id == target.id).unwrap();
+        assert_eq!(merged_target.chapter_count, 4);
+        let delivery_book: Option<Uuid> = deliveries::table
+            .select(deliveries::book_id)
+            .first(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(delivery_book, Some(target.id));
+        drop(conn);
+
+        let url = "https://www.royalroad.com/fiction/777";
+        let found = find_book(url, &db.pool).await.unwrap().unwrap();
+        assert_eq!(found.id, target.id);
+        let request = CreateBookRequest::new(url.into());
+        let created = create_book(db.pool.clone(), request).await.unwrap();
+        assert_eq!(created.id, target.id);
+
+        let err = merge(source.id, target.id, &db.pool, &storage)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::NotFound(_))
+        ));
+        let err = merge(target.id, target.id, &db.pool, &storage)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::BadRequest(_))
+        ));
+    }
+}
@@ -1,20 +1,37 @@
+mod merge;
+
+use crate::config;
+use crate::controllers::admin;
 use crate::diesel::ExpressionMethods;
-use crate::models::{Book, BookKind, NewBook};
-use crate::util::{map_result, InstrumentedPgConnectionPool};
+use crate::models::{
+    book_not_deleted, chapter_not_deleted, ArchivedChapter, Book, BookKind, Chapter, NewBook,
+};
+use crate::storage::Storage;
+use crate::util::{map_result, retry_read, with_etag, ApiError, InstrumentedPgConnectionPool};
 
+use crate::providers::royalroad::RoyalRoadBookKind;
 use crate::providers::{
     apparatus_of_change_patreon, pale, practical_guide, royalroad, the_daily_grind_patreon,
     wandering_inn, wandering_inn_patreon,
 };
 use anyhow::{bail, Result};
-use diesel::{QueryDsl, RunQueryDsl};
-use serde::Deserialize;
+use chrono::{DateTime, Utc};
+use derive_more::DebugCustom;
+use diesel::dsl::exists;
+use diesel::sql_types::{Jsonb, Text};
+use diesel::{
+    define_sql_function, BoolExpressionMethods, OptionalExtension, PgArrayExpressionMethods,
+    PgSortExpressionMethods, QueryDsl,
+};
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use warp::{Filter, Reply};
 
-use crate::schema::books::dsl::{books, metadata};
+use crate::schema::books::dsl::{books, deleted_at, metadata};
+use crate::schema::{book_aliases, chapters, chapters_archive};
 
-fn get_book_metadata(url: &str) -> Result<BookKind> {
+pub fn get_book_metadata(url: &str) -> Result<BookKind> {
     if let Ok(x) = royalroad::try_parse_url(url) {
         return Ok(BookKind::RoyalRoad(x));
     }
@@ -40,8 +57,126 @@ fn get_book_metadata(url: &str) -> Result<BookKind> {
 }
 
 #[derive(Debug, Deserialize)]
+pub struct ListBooksRequest {
+    /// Only list books with this tag, such as `LitRPG`.
+    tag: Option<String>,
+}
+
+const DEFAULT_CHAPTER_LIMIT: usize = 50;
+const MAX_CHAPTER_LIMIT: usize = 200;
+
+/// A page of a book's chapters, read with increasing offsets.
+#[derive(Debug, Deserialize)]
+pub struct ListChaptersRequest {
+    /// Also list chapters moved to the archive.
+    #[serde(default)]
+    include_archived: bool,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// A chapter of a book, as listed.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ChapterListing {
+    pub id: Uuid,
+    pub name: String,
+    pub author: String,
+    pub published_at: DateTime<Utc>,
+    pub ordinal: Option<i32>,
+    pub word_count: Option<i64>,
+    pub archived: bool,
+}
+
+impl From<Chapter> for ChapterListing {
+    fn from(chapter: Chapter) -> Self {
+        Self {
+            id: chapter.id,
+            name: chapter.name,
+            author: chapter.author,
+            published_at: chapter.published_at,
+            ordinal: chapter.ordinal,
+            word_count: chapter.word_count,
+            archived: false,
+        }
+    }
+}
+
+impl From<ArchivedChapter> for ChapterListing {
+    fn from(chapter: ArchivedChapter) -> Self {
+        Self {
+            id: chapter.id,
+            name: chapter.name,
+            author: chapter.author,
+            published_at: chapter.published_at,
+            ordinal: chapter.ordinal,
+            word_count: chapter.word_count,
+            archived: true,
+        }
+    }
+}
+
+/// Books not successfully checked within this many hours are stale, unless
+/// the request says otherwise.
+const DEFAULT_STALE_HOURS: i64 = 24;
+
+#[derive(DebugCustom, Deserialize)]
+#[debug(fmt = "CreateBookRequest {{ url: {} }}", url)]
 pub struct CreateBookRequest {
-    url: String,
+    pub url: String,
+    /// The cookies of a logged in RoyalRoad session, for books whose advance
+    /// chapters only the author's patrons can read. An empty value removes
+    /// the cookies stored with a book.
+    #[serde(default)]
+    pub session_cookie: Option<String>,
+}
+
+impl CreateBookRequest {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            session_cookie: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreviewBookRequest {
+    pub url: String,
+}
+
+/// A book as added, with others which are probably the same serial.
+#[derive(Debug, Serialize)]
+pub struct AddedBook {
+    #[serde(flatten)]
+    pub book: Book,
+    pub possible_duplicates: Vec<Book>,
+}
+
+/// What adding the book at a url would do, without adding it.
+#[derive(Debug, Serialize)]
+pub struct BookPreview {
+    pub name: String,
+    pub author: String,
+    /// The book the url was already added as.
+    pub existing: Option<Book>,
+    pub possible_duplicates: Vec<Book>,
+}
+
+/// How alike the "name author" of two books must be, by trigram similarity
+/// from 0 to 1, to be reported as possible duplicates.
+const DUPLICATE_SIMILARITY: f32 = 0.5;
+
+const MAX_POSSIBLE_DUPLICATES: i64 = 5;
+
+define_sql_function!(fn similarity(a: Text, b: Text) -> Float4);
+define_sql_function!(fn lower(x: Text) -> Text);
+// A book's metadata without its credentials, which the books_identity index covers.
+define_sql_function!(fn book_identity(kind: Jsonb) -> Jsonb);
+
+#[derive(Debug, Deserialize)]
+pub struct StaleBooksRequest {
+    hours: Option<i64>,
 }
 
 #[tracing::instrument(
@@ -54,12 +189,249 @@ fields(
 )
 )]
 pub async fn get_book(book_id: Uuid, db_pool: InstrumentedPgConnectionPool) -> Result<Book> {
-    let conn = db_pool.get().await?;
+    // Read only, so a dropped connection is retried.
+    let book: Option<Book> = retry_read(|| async {
+        let mut conn = db_pool.get().await?;
+        Ok(books
+            .find(book_id)
+            .filter(book_not_deleted())
+            .first(&mut *conn)
+            .await
+            .optional()?)
+    })
+    .await?;
+    let book =
+        book.ok_or_else(|| ApiError::NotFound(format!("Book {} does not exist.", book_id)))?;
+    Ok(book)
+}
 
-    let book: Book = books.find(book_id).first(&*conn)?;
+/// Lists the books which aren't deleted by name.
+#[tracing::instrument(
+name = "List books.",
+err,
+level = "info"
+skip(db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn list_books(
+    request: ListBooksRequest,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<Vec<Book>> {
+    use crate::schema::books;
+    // Read only, so a dropped connection is retried.
+    retry_read(|| async {
+        let mut conn = db_pool.get().await?;
+        let mut query = books::table
+            .filter(book_not_deleted())
+            .order((books::name.asc(), books::id.asc()))
+            .into_boxed();
+        if let Some(tag) = &request.tag {
+            query = query.filter(books::tags.contains(vec![tag.clone()]));
+        }
+        Ok(query.load(&mut *conn).await?)
+    })
+    .await
+}
+
+/// Lists a page of a book's chapters newest first. Archived chapters are only
+/// read when asked for, as they are kept out of the chapters table to keep it
+/// small.
+#[tracing::instrument(
+name = "List a book's chapters.",
+err,
+level = "info"
+skip(db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn list_chapters(
+    book_id: Uuid,
+    request: ListChaptersRequest,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<Vec<ChapterListing>> {
+    let book = get_book(book_id, db_pool.clone()).await?;
+    let limit = request
+        .limit
+        .unwrap_or(DEFAULT_CHAPTER_LIMIT)
+        .min(MAX_CHAPTER_LIMIT);
+    let offset = i64::try_from(request.offset)
+        .ok()
+        .filter(|offset| offset.checked_add(limit as i64).is_some())
+        .ok_or_else(|| {
+            ApiError::BadRequest(format!("offset {} is out of range.", request.offset))
+        })?;
+    let chapters_query = chapters::table
+        .filter(chapters::book_id.eq(book.id))
+        .filter(chapter_not_deleted())
+        .order((chapters::published_at.desc(), chapters::id.desc()));
+    let mut conn = db_pool.get().await?;
+    if !request.include_archived {
+        return Ok(chapters_query
+            .offset(offset)
+            .limit(limit as i64)
+            .load::<Chapter>(&mut *conn)
+            .await?
+            .into_iter()
+            .map(ChapterListing::from)
+            .collect());
+    }
+    // The page is drawn from the newest chapters of each table up to its end.
+    let end = offset + limit as i64;
+    let mut listings = chapters_query
+        .limit(end)
+        .load::<Chapter>(&mut *conn)
+        .await?
+        .into_iter()
+        .map(ChapterListing::from)
+        .collect::<Vec<_>>();
+    listings.extend(
+        chapters_archive::table
+            .filter(chapters_archive::book_id.eq(book.id))
+            .order((
+                chapters_archive::published_at.desc(),
+                chapters_archive::id.desc(),
+            ))
+            .limit(end)
+            .load::<ArchivedChapter>(&mut *conn)
+            .await?
+            .into_iter()
+            .map(ChapterListing::from),
+    );
+    listings.sort_by_key(|listing| std::cmp::Reverse((listing.published_at, listing.id)));
+    Ok(listings
+        .into_iter()
+        .skip(request.offset)
+        .take(limit)
+        .collect())
+}
+
+/// The book a merged book's metadata now refers to, unless it is deleted.
+async fn aliased_book(
+    book_kind: &BookKind,
+    db_pool: &InstrumentedPgConnectionPool,
+) -> Result<Option<Book>> {
+    let mut conn = db_pool.get().await?;
+    let book = book_aliases::table
+        .inner_join(books)
+        .filter(book_aliases::metadata.eq(book_kind))
+        .filter(book_not_deleted())
+        .select(crate::schema::books::all_columns)
+        .first(&mut *conn)
+        .await
+        .optional()?;
     Ok(book)
 }
 
+/// The book at `url`, if it has been added and isn't deleted.
+pub async fn find_book(url: &str, db_pool: &InstrumentedPgConnectionPool) -> Result<Option<Book>> {
+    let book_kind = get_book_metadata(url)?;
+    if let Some(book) = aliased_book(&book_kind, db_pool).await? {
+        return Ok(Some(book));
+    }
+    let mut conn = db_pool.get().await?;
+    let book = books
+        .filter(book_identity(metadata).eq(book_kind.without_credentials()))
+        .filter(book_not_deleted())
+        .first(&mut *conn)
+        .await
+        .optional()?;
+    Ok(book)
+}
+
+/// Books other than `except` whose name and author are close to the given
+/// ones, case-insensitively, most alike first. Books added from another
+/// provider or a different url for the same serial are only matched here.
+pub async fn possible_duplicates(
+    db_pool: &InstrumentedPgConnectionPool,
+    book_name: &str,
+    book_author: &str,
+    except: Option<Uuid>,
+) -> Result<Vec<Book>> {
+    use crate::schema::books::dsl::{author, id, name};
+    use diesel::TextExpressionMethods;
+    let similar = similarity(
+        lower(name.concat(" ").concat(author)),
+        lower(format!("{book_name} {book_author}")),
+    );
+    let mut conn = db_pool.get().await?;
+    Ok(books
+        .filter(book_not_deleted())
+        .filter(id.ne(except.unwrap_or_else(Uuid::nil)))
+        .filter(similar.clone().ge(DUPLICATE_SIMILARITY))
+        .order(similar.desc())
+        .limit(MAX_POSSIBLE_DUPLICATES)
+        .load(&mut *conn)
+        .await?)
+}
+
+#[tracing::instrument(
+name = "Adding a book.",
+err,
+level = "info"
+skip(db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn add_book(
+    db_pool: InstrumentedPgConnectionPool,
+    body: CreateBookRequest,
+) -> Result<AddedBook> {
+    let book = create_book(db_pool.clone(), body).await?;
+    let possible_duplicates =
+        possible_duplicates(&db_pool, &book.name, &book.author, Some(book.id)).await?;
+    Ok(AddedBook {
+        book,
+        possible_duplicates,
+    })
+}
+
+#[tracing::instrument(
+name = "Previewing a book.",
+err,
+level = "info"
+skip(db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn preview_book(
+    request: PreviewBookRequest,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<BookPreview> {
+    let Ok(book_kind) = get_book_metadata(&request.url) else {
+        return Err(ApiError::BadRequest(format!(
+            "{} is not the url of a supported book.",
+            request.url
+        ))
+        .into());
+    };
+    let existing = find_book(&request.url, &db_pool).await?;
+    let (book_name, book_author) = match &existing {
+        Some(book) => (book.name.clone(), book.author.clone()),
+        None => {
+            let book = book_kind.to_new_book(&config::get().endpoints).await?;
+            (book.name, book.author)
+        }
+    };
+    let possible_duplicates = possible_duplicates(
+        &db_pool,
+        &book_name,
+        &book_author,
+        existing.as_ref().map(|book| book.id),
+    )
+    .await?;
+    Ok(BookPreview {
+        name: book_name,
+        author: book_author,
+        existing,
+        possible_duplicates,
+    })
+}
+
 #[tracing::instrument(
 name = "Creating a new book.",
 err,
@@ -74,20 +446,184 @@ pub async fn create_book(
     body: CreateBookRequest,
 ) -> Result<Book> {
     let book_kind = get_book_metadata(&body.url)?;
-    let conn = db_pool.get().await?;
-    let existing_book: Result<Book, _> = books.filter(metadata.eq(&book_kind)).first(&*conn);
+    if body.session_cookie.is_some() && !matches!(book_kind, BookKind::RoyalRoad(_)) {
+        return Err(ApiError::BadRequest(format!(
+            "{} is not a RoyalRoad book, so takes no session cookie.",
+            body.url
+        ))
+        .into());
+    }
+    let book = find_or_add_book(&db_pool, &book_kind).await?;
+    match body.session_cookie {
+        Some(session_cookie) => set_session_cookie(&db_pool, book, &session_cookie).await,
+        None => Ok(book),
+    }
+}
+
+/// Stores the session cookie a RoyalRoad book's chapters are fetched with.
+async fn set_session_cookie(
+    db_pool: &InstrumentedPgConnectionPool,
+    book: Book,
+    session_cookie: &str,
+) -> Result<Book> {
+    let BookKind::RoyalRoad(kind) = &book.metadata else {
+        return Err(ApiError::BadRequest(format!(
+            "{} is not a RoyalRoad book, so takes no session cookie.",
+            book.name
+        ))
+        .into());
+    };
+    let session_cookie = Some(session_cookie.trim().to_owned()).filter(|cookie| !cookie.is_empty());
+    if kind.session_cookie == session_cookie {
+        return Ok(book);
+    }
+    let kind = BookKind::RoyalRoad(RoyalRoadBookKind {
+        session_cookie,
+        ..kind.clone()
+    });
+    let mut conn = db_pool.get().await?;
+    let book = diesel::update(books.find(book.id))
+        .set(metadata.eq(kind))
+        .get_result(&mut *conn)
+        .await?;
+    Ok(book)
+}
+
+async fn find_or_add_book(
+    db_pool: &InstrumentedPgConnectionPool,
+    book_kind: &BookKind,
+) -> Result<Book> {
+    if let Some(book) = aliased_book(book_kind, db_pool).await? {
+        return Ok(book);
+    }
+    let mut conn = db_pool.get().await?;
+    // Matched without credentials, as the stored kind may also hold them.
+    let existing_book: Result<Book, _> = books
+        .filter(book_identity(metadata).eq(book_kind.without_credentials()))
+        .first(&mut *conn)
+        .await;
     if let Ok(existing_book) = existing_book {
-        return Ok(existing_book);
+        if existing_book.deleted_at.is_none() {
+            return Ok(existing_book);
+        }
+        // Adding a deleted book again restores it.
+        let restored: Book = diesel::update(books.find(existing_book.id))
+            .set(deleted_at.eq(None::<DateTime<Utc>>))
+            .get_result(&mut *conn)
+            .await?;
+        return Ok(restored);
     }
-    let book = book_kind.to_new_book().await?;
+    let book = book_kind.to_new_book(&config::get().endpoints).await?;
     let db_result: Book = diesel::insert_into(books)
         .values::<NewBook>(book)
-        .get_result(&*conn)?;
+        .get_result(&mut *conn)
+        .await?;
     Ok(db_result)
 }
 
+#[tracing::instrument(
+name = "Deleting a book.",
+err,
+level = "info"
+skip(authorization, db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn delete_book(
+    book_id: Uuid,
+    authorization: Option<String>,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<Book> {
+    admin::authorize(authorization)?;
+    set_deleted_at(book_id, Some(Utc::now()), &db_pool).await
+}
+
+#[tracing::instrument(
+name = "Restoring a deleted book.",
+err,
+level = "info"
+skip(authorization, db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn undelete_book(
+    book_id: Uuid,
+    authorization: Option<String>,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<Book> {
+    admin::authorize(authorization)?;
+    set_deleted_at(book_id, None, &db_pool).await
+}
+
+#[tracing::instrument(
+name = "Listing stale books.",
+err,
+level = "info"
+skip(authorization, db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn stale_books(
+    request: StaleBooksRequest,
+    authorization: Option<String>,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<Vec<Book>> {
+    admin::authorize(authorization)?;
+    let hours = request.hours.unwrap_or(DEFAULT_STALE_HOURS);
+    if hours <= 0 {
+        return Err(ApiError::BadRequest("hours must be a positive number.".into()).into());
+    }
+    find_stale_books(Utc::now() - chrono::Duration::hours(hours), &db_pool).await
+}
+
+/// The books being checked for new chapters, which are those with
+/// subscribers, that haven't been checked successfully since `cutoff`. Books
+/// never checked successfully come first.
+pub(crate) async fn find_stale_books(
+    cutoff: DateTime<Utc>,
+    db_pool: &InstrumentedPgConnectionPool,
+) -> Result<Vec<Book>> {
+    use crate::schema::{books, subscriptions};
+    let mut conn = db_pool.get().await?;
+    let stale = books::table
+        .filter(book_not_deleted())
+        .filter(exists(
+            subscriptions::table.filter(subscriptions::book_id.eq(books::id)),
+        ))
+        .filter(
+            books::last_success_at
+                .is_null()
+                .or(books::last_success_at.lt(cutoff)),
+        )
+        .order((
+            books::last_success_at.asc().nulls_first(),
+            books::name.asc(),
+        ))
+        .load(&mut *conn)
+        .await?;
+    Ok(stale)
+}
+
+async fn set_deleted_at(
+    book_id: Uuid,
+    deleted: Option<DateTime<Utc>>,
+    db_pool: &InstrumentedPgConnectionPool,
+) -> Result<Book> {
+    let mut conn = db_pool.get().await?;
+    diesel::update(books.find(book_id))
+        .set(deleted_at.eq(deleted))
+        .get_result(&mut *conn)
+        .await
+        .optional()?
+        .ok_or_else(|| ApiError::NotFound(format!("Book {} does not exist.", book_id)).into())
+}
+
 pub fn get_filters(
     db_pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
 ) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
     let create_book_db = db_pool.clone();
     let create_book_filter = warp::post()
@@ -96,15 +632,468 @@ pub fn get_filters(
         .and(warp::body::content_length_limit(1024))
         .and(warp::any().map(move || create_book_db.clone()))
         .and(warp::body::json())
-        .then(create_book)
+        .then(add_book)
+        .map(map_result);
+    let list_books_db = db_pool.clone();
+    let list_books_filter = warp::get()
+        .and(warp::path("books"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::any().map(move || list_books_db.clone()))
+        .then(list_books)
+        .map(map_result);
+    let preview_db = db_pool.clone();
+    let preview_book_filter = warp::get()
+        .and(warp::path("books"))
+        .and(warp::path("preview"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::any().map(move || preview_db.clone()))
+        .then(preview_book)
         .map(map_result);
     let get_book_db = db_pool.clone();
-    let get_book_filter = warp::get()
+    let get_book_filter = with_etag(
+        warp::get()
+            .and(warp::path("books"))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::any().map(move || get_book_db.clone()))
+            .then(get_book)
+            .map(map_result),
+    );
+    let list_chapters_db = db_pool.clone();
+    let list_chapters_filter = warp::get()
+        .and(warp::path("books"))
+        .and(warp::path::param())
+        .and(warp::path("chapters"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::any().map(move || list_chapters_db.clone()))
+        .then(list_chapters)
+        .map(map_result);
+    let delete_book_db = db_pool.clone();
+    let delete_book_filter = warp::delete()
+        .and(warp::path("books"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::header::optional("authorization"))
+        .and(warp::any().map(move || delete_book_db.clone()))
+        .then(delete_book)
+        .map(map_result);
+    let undelete_book_db = db_pool.clone();
+    let undelete_book_filter = warp::post()
         .and(warp::path("books"))
         .and(warp::path::param())
+        .and(warp::path("undelete"))
+        .and(warp::path::end())
+        .and(warp::header::optional("authorization"))
+        .and(warp::any().map(move || undelete_book_db.clone()))
+        .then(undelete_book)
+        .map(map_result);
+    let merge_db = db_pool.clone();
+    let merge_storage = storage.clone();
+    let merge_books_filter = warp::post()
+        .and(warp::path("admin"))
+        .and(warp::path("books"))
+        .and(warp::path("merge"))
         .and(warp::path::end())
-        .and(warp::any().map(move || get_book_db.clone()))
-        .then(get_book)
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(warp::header::optional("authorization"))
+        .and(warp::any().map(move || merge_db.clone()))
+        .and(warp::any().map(move || merge_storage.clone()))
+        .then(merge::merge_books)
+        .map(map_result);
+    let stale_db = db_pool.clone();
+    let stale_books_filter = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("books"))
+        .and(warp::path("stale"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::header::optional("authorization"))
+        .and(warp::any().map(move || stale_db.clone()))
+        .then(stale_books)
         .map(map_result);
-    create_book_filter.or(get_book_filter)
+    create_book_filter
+        .or(list_books_filter)
+        .or(preview_book_filter)
+        .or(get_book_filter)
+        .or(list_chapters_filter)
+        .or(delete_book_filter)
+        .or(undelete_book_filter)
+        .or(merge_books_filter)
+        .or(stale_books_filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, TestDatabase};
+
+    #[tokio::test]
+    async fn tags_books_with_etags() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books)
+            .values(NewBook {
+                name: "Pale".into(),
+                author: "Wildbow".into(),
+                metadata: BookKind::Pale,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let routes = get_filters(&db.pool, &test_support::storage());
+
+        test_support::assert_etags(&routes, &format!("/books/{}", book.id)).await;
+    }
+
+    #[tokio::test]
+    async fn filters_books_by_tag() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        let tagged: Book = diesel::insert_into(books)
+            .values(NewBook {
+                name: "Mother of Learning".into(),
+                author: "nobody103".into(),
+                metadata: BookKind::RoyalRoad(royalroad::RoyalRoadBookKind::new(21220)),
+                tags: vec!["LitRPG".into(), "Fantasy".into()],
+                content_warnings: vec!["Gore".into()],
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        let untagged: Book = diesel::insert_into(books)
+            .values(NewBook {
+                name: "Pale".into(),
+                author: "Wildbow".into(),
+                metadata: BookKind::Pale,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let routes = get_filters(&db.pool, &test_support::storage());
+
+        let list = |query: &'static str| {
+            warp::test::request()
+                .method("GET")
+                .path(&format!("/books{}", query))
+                .reply(&routes)
+        };
+        let ids = |body: &[u8]| -> Vec<String> {
+            serde_json::from_slice::<Vec<serde_json::Value>>(body)
+                .unwrap()
+                .iter()
+                .map(|book| book["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let res = list("").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            ids(res.body()),
+            [tagged.id.to_string(), untagged.id.to_string()]
+        );
+        let res = list("?tag=LitRPG").await;
+        assert_eq!(ids(res.body()), [tagged.id.to_string()]);
+        let body: Vec<serde_json::Value> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body[0]["tags"], serde_json::json!(["LitRPG", "Fantasy"]));
+        assert_eq!(body[0]["content_warnings"], serde_json::json!(["Gore"]));
+        let res = list("?tag=Horror").await;
+        assert!(ids(res.body()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn lists_archived_chapters_on_request() {
+        use crate::models::{ChapterKind, ChapterStatus, NewChapter};
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books)
+            .values(NewBook {
+                name: "Pale".into(),
+                author: "Wildbow".into(),
+                metadata: BookKind::Pale,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        let chapter = |name: &str, days_ago| NewChapter {
+            name: name.into(),
+            author: book.author.clone(),
+            book_id: book.id,
+            published_at: Utc::now() - chrono::Duration::days(days_ago),
+            metadata: ChapterKind::Pale {
+                url: format!("https://palewebserial.wordpress.com/{}/", name),
+            },
+        };
+        diesel::insert_into(chapters::table)
+            .values(chapter("1.2", 1))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        let archived = chapter("1.1", 400);
+        diesel::insert_into(chapters_archive::table)
+            .values((
+                chapters_archive::id.eq(Uuid::new_v4()),
+                chapters_archive::name.eq(&archived.name),
+                chapters_archive::author.eq(&archived.author),
+                chapters_archive::created_at.eq(archived.published_at),
+                chapters_archive::updated_at.eq(archived.published_at),
+                chapters_archive::book_id.eq(book.id),
+                chapters_archive::published_at.eq(archived.published_at),
+                chapters_archive::metadata.eq(&archived.metadata),
+                chapters_archive::status.eq(ChapterStatus::Fetched),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let routes = get_filters(&db.pool, &test_support::storage());
+        let list = |query: &'static str| {
+            let path = format!("/books/{}/chapters{}", book.id, query);
+            let routes = routes.clone();
+            async move {
+                let response = warp::test::request().path(&path).reply(&routes).await;
+                assert_eq!(response.status(), 200);
+                serde_json::from_slice::<Vec<serde_json::Value>>(response.body())
+                    .unwrap()
+                    .into_iter()
+                    .map(|chapter| (chapter["name"].clone(), chapter["archived"].clone()))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(list("").await, [("1.2".into(), false.into())]);
+        assert_eq!(
+            list("?include_archived=true").await,
+            [("1.2".into(), false.into()), ("1.1".into(), true.into())]
+        );
+        assert_eq!(
+            list("?include_archived=true&offset=1&limit=1").await,
+            [("1.1".into(), true.into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn stores_session_cookies_on_royalroad_books() {
+        test_support::config();
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books)
+            .values(NewBook {
+                name: "Advance Serial".into(),
+                author: "Test Author".into(),
+                metadata: BookKind::RoyalRoad(RoyalRoadBookKind::new(31337)),
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let url = "https://www.royalroad.com/fiction/31337/advance-serial";
+        let add = |session_cookie: Option<&str>| {
+            let db_pool = db.pool.clone();
+            let request = CreateBookRequest {
+                url: url.into(),
+                session_cookie: session_cookie.map(Into::into),
+            };
+            async move { create_book(db_pool, request).await }
+        };
+        let session_cookie = |book: &Book| match &book.metadata {
+            BookKind::RoyalRoad(kind) => kind.session_cookie.clone(),
+            _ => panic!("Unexpected book kind {:?}", book.metadata),
+        };
+
+        let gated = add(Some(" session=abc ")).await.unwrap();
+        assert_eq!(gated.id, book.id);
+        assert_eq!(session_cookie(&gated), Some("session=abc".into()));
+        let found = find_book(url, &db.pool).await.unwrap().unwrap();
+        assert_eq!(found.id, book.id);
+        let again = add(None).await.unwrap();
+        assert_eq!(session_cookie(&again), Some("session=abc".into()));
+        let cleared = add(Some("")).await.unwrap();
+        assert_eq!(session_cookie(&cleared), None);
+
+        let err = create_book(
+            db.pool.clone(),
+            CreateBookRequest {
+                url: "https://wanderinginn.com/".into(),
+                session_cookie: Some("session=abc".into()),
+            },
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn pages_chapters_newest_first() {
+        use crate::models::{ChapterKind, NewChapter};
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books)
+            .values(NewBook {
+                name: "The Wandering Inn".into(),
+                author: "pirateaba".into(),
+                metadata: BookKind::TheWanderingInn,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        let chapters = (0..250)
+            .map(|number| NewChapter {
+                name: format!("1.{:03}", number),
+                author: book.author.clone(),
+                book_id: book.id,
+                published_at: Utc::now() - chrono::Duration::hours(250 - number),
+                metadata: ChapterKind::TheWanderingInn {
+                    url: format!("https://wanderinginn.com/1-{}/", number),
+                },
+            })
+            .collect::<Vec<_>>();
+        diesel::insert_into(chapters::table)
+            .values(&chapters)
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let routes = get_filters(&db.pool, &test_support::storage());
+        let list = |path: String| {
+            let routes = routes.clone();
+            async move { warp::test::request().path(&path).reply(&routes).await }
+        };
+        let names = |body: &[u8]| {
+            serde_json::from_slice::<Vec<serde_json::Value>>(body)
+                .unwrap()
+                .into_iter()
+                .map(|chapter| chapter["name"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let response = list(format!("/books/{}/chapters", book.id)).await;
+        assert_eq!(response.status(), 200);
+        let page = names(response.body());
+        assert_eq!(page.len(), 50);
+        assert_eq!(page[0], "1.249");
+        let body: Vec<serde_json::Value> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body[0]["author"], "pirateaba");
+        let response = list(format!("/books/{}/chapters?offset=240&limit=1000", book.id)).await;
+        assert_eq!(
+            names(response.body()),
+            (0..10)
+                .rev()
+                .map(|n| format!("1.{:03}", n))
+                .collect::<Vec<_>>()
+        );
+        let response = list(format!("/books/{}/chapters?offset=0&limit=1000", book.id)).await;
+        assert_eq!(names(response.body()).len(), 200);
+        let response = list(format!(
+            "/books/{}/chapters?offset={}",
+            book.id,
+            usize::MAX - 10
+        ))
+        .await;
+        assert_eq!(response.status(), 400);
+        let response = list(format!("/books/{}/chapters?offset=1000", book.id)).await;
+        assert!(names(response.body()).is_empty());
+
+        let response = list(format!("/books/{}/chapters", Uuid::new_v4())).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn reports_possible_duplicates() {
+        use crate::providers::royalroad::RoyalRoadBookKind;
+        test_support::config();
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        let mirror: Book = diesel::insert_into(books)
+            .values(NewBook {
+                name: "PALE".into(),
+                author: "wildbow".into(),
+                metadata: BookKind::RoyalRoad(RoyalRoadBookKind::new(1)),
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        diesel::insert_into(books)
+            .values(NewBook {
+                name: "Mother of Learning".into(),
+                author: "nobody103".into(),
+                metadata: BookKind::RoyalRoad(RoyalRoadBookKind::new(2)),
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let routes = get_filters(&db.pool, &test_support::storage());
+        let url = "https://practicalguidetoevil.wordpress.com/";
+        let duplicate_ids = |reply: &serde_json::Value| {
+            reply["possible_duplicates"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|book| book["id"].clone())
+                .collect::<Vec<_>>()
+        };
+
+        let preview = warp::test::request()
+            .path(&format!("/books/preview?url={url}"))
+            .reply(&routes)
+            .await;
+        assert_eq!(preview.status(), 200);
+        let preview: serde_json::Value = serde_json::from_slice(preview.body()).unwrap();
+        assert_eq!(preview["name"], "Pale");
+        assert_eq!(preview["existing"], serde_json::Value::Null);
+        assert_eq!(duplicate_ids(&preview), [serde_json::json!(mirror.id)]);
+
+        let added = warp::test::request()
+            .method("POST")
+            .path("/books")
+            .json(&serde_json::json!({ "url": url }))
+            .reply(&routes)
+            .await;
+        assert_eq!(added.status(), 200);
+        let added: serde_json::Value = serde_json::from_slice(added.body()).unwrap();
+        assert_eq!(added["name"], "Pale");
+        assert_eq!(duplicate_ids(&added), [serde_json::json!(mirror.id)]);
+
+        let preview = warp::test::request()
+            .path(&format!("/books/preview?url={url}"))
+            .reply(&routes)
+            .await;
+        let preview: serde_json::Value = serde_json::from_slice(preview.body()).unwrap();
+        assert_eq!(preview["existing"]["id"], added["id"]);
+        assert_eq!(duplicate_ids(&preview), [serde_json::json!(mirror.id)]);
+    }
 }
@@ -0,0 +1,516 @@
+use crate::clients::calibre;
+use crate::config;
+use crate::controllers::{admin, feeds};
+use crate::models::{
+    book_not_deleted, chapter_not_deleted, Book, Chapter, ChapterBody, ExtrasPreference,
+};
+use crate::schema::{books, chapter_bodies, chapters, subscriptions};
+use crate::storage::{Storage, StorageLocation};
+use crate::tasks::{self, RefetchedBody};
+use crate::util::{error_chain, map_result, ApiError, InstrumentedPgConnectionPool};
+
+use anyhow::Result;
+use aws_sdk_s3::primitives::ByteStream;
+use diesel::{OptionalExtension, QueryDsl};
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use tokio_util::io::ReaderStream;
+use tracing::{error, Instrument};
+use uuid::Uuid;
+use warp::hyper::Body;
+use warp::{Filter, Reply};
+
+/// How much of a body is buffered ahead of the client while it is streamed.
+const STREAM_BUFFER_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct ChapterBodyRequest {
+    user_id: String,
+    #[serde(default)]
+    format: BodyFormat,
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyFormat {
+    #[default]
+    Html,
+    Epub,
+}
+
+/// Admins refetch with their bearer token, subscribers with their user id and
+/// feed token.
+#[derive(Debug, Deserialize)]
+pub struct RefetchRequest {
+    user_id: Option<String>,
+    token: Option<String>,
+}
+
+pub enum ChapterBodyReply {
+    /// The stored html, streamed from storage as it is read.
+    Html(Body),
+    Epub(Vec<u8>),
+}
+
+#[tracing::instrument(
+name = "Downloading a chapter body.",
+err,
+level = "info"
+skip(db_pool, storage),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn get_chapter_body(
+    chapter_id: Uuid,
+    request: ChapterBodyRequest,
+    db_pool: InstrumentedPgConnectionPool,
+    storage: Storage,
+) -> Result<ChapterBodyReply> {
+    let (chapter, book, body) = {
+        let mut conn = db_pool.get().await?;
+        let chapter: Chapter = chapters::table
+            .find(chapter_id)
+            .filter(chapter_not_deleted())
+            .first(&mut *conn)
+            .await
+            .optional()?
+            .ok_or_else(|| ApiError::NotFound(format!("Chapter {} does not exist.", chapter_id)))?;
+        let book: Book = books::table
+            .find(chapter.book_id)
+            .filter(book_not_deleted())
+            .first(&mut *conn)
+            .await
+            .optional()?
+            .ok_or_else(|| {
+                ApiError::NotFound(format!("Book {} does not exist.", chapter.book_id))
+            })?;
+        subscriptions::table
+            .find((&request.user_id, book.id))
+            .select(subscriptions::book_id)
+            .first::<Uuid>(&mut *conn)
+            .await
+            .optional()?
+            .ok_or_else(|| {
+                ApiError::Unauthorized(format!(
+                    "User {} is not subscribed to {}.",
+                    request.user_id, book.name
+                ))
+            })?;
+        let body: ChapterBody = chapter_bodies::table
+            .find(chapter_id)
+            .first(&mut *conn)
+            .await
+            .optional()?
+            .ok_or_else(|| {
+                ApiError::NotFound(format!("Chapter {} has no stored body.", chapter_id))
+            })?;
+        (chapter, book, body)
+    };
+
+    match request.format {
+        BodyFormat::Html => Ok(ChapterBodyReply::Html(stream(storage, body.into()))),
+        BodyFormat::Epub => {
+            if let Some(location) = storage.chapter_artifact(&book.id, &chapter.id).await? {
+                return Ok(ChapterBodyReply::Epub(storage.fetch(location).await?));
+            }
+            let bytes = tasks::generate_ebook(
+                &book,
+                &[(&chapter, &body)],
+                ExtrasPreference::default(),
+                calibre::DEFAULT_OUTPUT_PROFILE,
+                &storage,
+            )
+            .await?;
+            storage
+                .store_chapter_artifact(&book.id, &chapter.id, ByteStream::from(bytes.clone()))
+                .await?;
+            Ok(ChapterBodyReply::Epub(bytes))
+        }
+    }
+}
+
+#[tracing::instrument(
+name = "Refetching a chapter body.",
+err,
+level = "info"
+skip(request, authorization, db_pool, storage),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn refetch_chapter_body(
+    chapter_id: Uuid,
+    request: RefetchRequest,
+    authorization: Option<String>,
+    db_pool: InstrumentedPgConnectionPool,
+    storage: Storage,
+) -> Result<RefetchedBody> {
+    let (chapter, book) = {
+        let mut conn = db_pool.get().await?;
+        let chapter: Chapter = chapters::table
+            .find(chapter_id)
+            .filter(chapter_not_deleted())
+            .first(&mut *conn)
+            .await
+            .optional()?
+            .ok_or_else(|| ApiError::NotFound(format!("Chapter {} does not exist.", chapter_id)))?;
+        let book: Book = books::table
+            .find(chapter.book_id)
+            .filter(book_not_deleted())
+            .first(&mut *conn)
+            .await
+            .optional()?
+            .ok_or_else(|| {
+                ApiError::NotFound(format!("Book {} does not exist.", chapter.book_id))
+            })?;
+        (chapter, book)
+    };
+    if admin::authorize(authorization).is_err() {
+        let (Some(user_id), Some(token)) = (&request.user_id, &request.token) else {
+            return Err(ApiError::Unauthorized(
+                "An admin token, or a subscriber's user id and feed token, is required.".into(),
+            )
+            .into());
+        };
+        feeds::authorize(user_id, token, &db_pool).await?;
+        let mut conn = db_pool.get().await?;
+        subscriptions::table
+            .find((user_id, book.id))
+            .select(subscriptions::book_id)
+            .first::<Uuid>(&mut *conn)
+            .await
+            .optional()?
+            .ok_or_else(|| {
+                ApiError::Unauthorized(format!(
+                    "User {} is not subscribed to {}.",
+                    user_id, book.name
+                ))
+            })?;
+    }
+    tasks::refetch_chapter_body(
+        &db_pool,
+        &storage,
+        &config::get().endpoints,
+        &book,
+        &chapter,
+    )
+    .await
+}
+
+/// Streams a stored object to the client, so large bodies are never held in
+/// memory whole.
+fn stream(storage: Storage, location: StorageLocation) -> Body {
+    let (mut writer, reader) = tokio::io::duplex(STREAM_BUFFER_BYTES);
+    tokio::spawn(
+        async move {
+            // The client sees a truncated body, as the status was already sent.
+            if let Err(err) = storage.fetch_into(location, &mut writer).await {
+                error!(error = %error_chain(&err), "Failed to stream chapter body.");
+            }
+        }
+        .in_current_span(),
+    );
+    Body::wrap_stream(ReaderStream::new(reader))
+}
+
+fn map_body(result: Result<ChapterBodyReply>) -> warp::reply::Response {
+    match result {
+        Ok(ChapterBodyReply::Html(body)) => warp::reply::with_header(
+            warp::reply::Response::new(body),
+            "content-type",
+            "text/html; charset=utf-8",
+        )
+        .into_response(),
+        Ok(ChapterBodyReply::Epub(bytes)) => {
+            warp::reply::with_header(bytes, "content-type", "application/epub+zip").into_response()
+        }
+        Err(err) => map_result(Err::<(), _>(err)).into_response(),
+    }
+}
+
+pub fn get_filters(
+    db_pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let body_db = db_pool.clone();
+    let body_storage = storage.clone();
+    let body_filter = warp::get()
+        .and(warp::path("chapters"))
+        .and(warp::path::param())
+        .and(warp::path("body"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::any().map(move || body_db.clone()))
+        .and(warp::any().map(move || body_storage.clone()))
+        .then(get_chapter_body)
+        .map(map_body);
+    let refetch_db = db_pool.clone();
+    let refetch_storage = storage.clone();
+    let refetch_filter = warp::post()
+        .and(warp::path("chapters"))
+        .and(warp::path::param())
+        .and(warp::path("refetch"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::header::optional("authorization"))
+        .and(warp::any().map(move || refetch_db.clone()))
+        .and(warp::any().map(move || refetch_storage.clone()))
+        .then(refetch_chapter_body)
+        .map(map_result);
+    body_filter.or(refetch_filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{BookKind, ChapterKind, ChapterStatus, NewBook, NewChapter};
+    use crate::test_support::{self, TestDatabase};
+    use diesel::ExpressionMethods;
+
+    const USER_ID: &str = "reader";
+    const BODY: &str = "<p>Chapter text.</p>";
+
+    async fn insert_chapter(db: &TestDatabase, storage: &Storage, with_body: bool) -> Uuid {
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books::table)
+            .values(NewBook {
+                name: "Pale".into(),
+                author: "Wildbow".into(),
+                metadata: BookKind::Pale,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        let chapter_id: Uuid = diesel::insert_into(chapters::table)
+            .values((
+                NewChapter {
+                    name: "1.1".into(),
+                    author: book.author.clone(),
+                    book_id: book.id,
+                    published_at: chrono::Utc::now(),
+                    metadata: ChapterKind::Pale {
+                        url: "https://palewebserial.wordpress.com/1-1/".into(),
+                    },
+                },
+                chapters::status.eq(ChapterStatus::Fetched),
+            ))
+            .returning(chapters::id)
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        diesel::insert_into(subscriptions::table)
+            .values((
+                subscriptions::user_id.eq(USER_ID),
+                subscriptions::book_id.eq(book.id),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        if with_body {
+            let uploaded = storage
+                .store_book(
+                    &book.id,
+                    &chapter_id,
+                    ByteStream::from_static(BODY.as_bytes()),
+                )
+                .await
+                .unwrap();
+            diesel::insert_into(chapter_bodies::table)
+                .values(ChapterBody {
+                    key: uploaded.location.key,
+                    bucket: uploaded.location.bucket,
+                    chapter_id,
+                    size: Some(uploaded.size),
+                })
+                .execute(&mut *conn)
+                .await
+                .unwrap();
+        }
+        chapter_id
+    }
+
+    fn path(chapter_id: Uuid, user_id: &str, format: &str) -> String {
+        format!(
+            "/chapters/{}/body?user_id={}&format={}",
+            chapter_id, user_id, format
+        )
+    }
+
+    #[tokio::test]
+    async fn streams_html_to_subscribers() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let storage = test_support::storage();
+        let chapter_id = insert_chapter(&db, &storage, true).await;
+        let routes = get_filters(&db.pool, &storage);
+
+        let response = warp::test::request()
+            .path(&path(chapter_id, USER_ID, "html"))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(response.body(), BODY);
+
+        let response = warp::test::request()
+            .path(&path(chapter_id, "stranger", "html"))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn converts_epub_once() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        test_support::config();
+        let storage = test_support::storage();
+        let chapter_id = insert_chapter(&db, &storage, true).await;
+        let routes = get_filters(&db.pool, &storage);
+
+        for _ in 0..2 {
+            let response = warp::test::request()
+                .path(&path(chapter_id, USER_ID, "epub"))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.headers()["content-type"], "application/epub+zip");
+        }
+        let artifacts = storage
+            .list(crate::storage::ARTIFACTS_PREFIX)
+            .await
+            .unwrap();
+        assert_eq!(artifacts.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reports_missing_bodies() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let storage = test_support::storage();
+        let chapter_id = insert_chapter(&db, &storage, false).await;
+        let request = ChapterBodyRequest {
+            user_id: USER_ID.into(),
+            format: BodyFormat::Html,
+        };
+
+        let err = get_chapter_body(chapter_id, request, db.pool.clone(), storage)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!("Chapter {} has no stored body.", chapter_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn refetches_bodies_for_subscribers() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        test_support::config();
+        let storage = test_support::storage();
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books::table)
+            .values(NewBook {
+                name: "Apparatus of Change".into(),
+                author: "Argusthecat".into(),
+                metadata: BookKind::ApparatusOfChangePatreon,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        let chapter_id: Uuid = diesel::insert_into(chapters::table)
+            .values((
+                NewChapter {
+                    name: "Chapter 1".into(),
+                    author: book.author.clone(),
+                    book_id: book.id,
+                    published_at: chrono::Utc::now(),
+                    metadata: ChapterKind::ApparatusOfChangePatreon {
+                        html: "<p>The whole chapter.</p>".into(),
+                    },
+                },
+                chapters::status.eq(ChapterStatus::Fetched),
+            ))
+            .returning(chapters::id)
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        diesel::insert_into(subscriptions::table)
+            .values((
+                subscriptions::user_id.eq(USER_ID),
+                subscriptions::book_id.eq(book.id),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        let truncated = storage
+            .store_book(&book.id, &chapter_id, ByteStream::from_static(b"<p>The"))
+            .await
+            .unwrap();
+        diesel::insert_into(chapter_bodies::table)
+            .values(ChapterBody {
+                key: truncated.location.key.clone(),
+                bucket: truncated.location.bucket.clone(),
+                chapter_id,
+                size: Some(truncated.size),
+            })
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        storage
+            .store_chapter_artifact(&book.id, &chapter_id, ByteStream::from_static(b"epub"))
+            .await
+            .unwrap();
+        let token = feeds::create_feed_token(USER_ID.into(), db.pool.clone())
+            .await
+            .unwrap()
+            .token;
+        let routes = get_filters(&db.pool, &storage);
+        let refetch = |user_id: &str| {
+            warp::test::request().method("POST").path(&format!(
+                "/chapters/{}/refetch?user_id={}&token={}",
+                chapter_id, user_id, token
+            ))
+        };
+
+        let response = refetch("stranger").reply(&routes).await;
+        assert_eq!(response.status(), 401);
+
+        let response = refetch(USER_ID).reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        let refetched: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(refetched["old_bytes"], 6);
+        assert_eq!(refetched["changed"], true);
+        assert_eq!(refetched["word_count"], 8);
+        let body = String::from_utf8(storage.fetch(truncated.location).await.unwrap()).unwrap();
+        assert_eq!(
+            body,
+            "<h1>Apparatus of Change: Chapter 1</h1><p>The whole chapter.</p>"
+        );
+        assert_eq!(refetched["new_bytes"], body.len());
+        assert!(storage
+            .chapter_artifact(&book.id, &chapter_id)
+            .await
+            .unwrap()
+            .is_none());
+
+        let response = refetch(USER_ID).reply(&routes).await;
+        let refetched: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(refetched["changed"], false);
+    }
+}
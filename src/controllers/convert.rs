@@ -0,0 +1,492 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::cache::TtlCache;
+use crate::clients::calibre;
+use crate::clock::SharedClock;
+use crate::config;
+use crate::controllers::{deliveries, feeds};
+use crate::models::{
+    book_not_deleted, chapter_not_deleted, chapter_order, Book, Chapter, ChapterBody, ChapterKind,
+    NewChapter,
+};
+use crate::providers::royalroad;
+use crate::schema::{books, chapter_bodies, chapters};
+use crate::storage::{Storage, StorageLocation};
+use crate::tasks;
+use crate::util::{map_result, ApiError, InstrumentedPgConnectionPool};
+
+use anyhow::Result;
+use aws_sdk_s3::primitives::ByteStream;
+use chrono::{DateTime, Utc};
+use diesel::{ExpressionMethods, JoinOnDsl, OptionalExtension, QueryDsl};
+use diesel_async::RunQueryDsl;
+use futures::future::try_join_all;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use uuid::Uuid;
+use warp::{Filter, Reply};
+
+/// The most chapters converted by a single request.
+const MAX_CHAPTERS: usize = 50;
+
+/// How long identical requests are answered with the earlier conversion.
+const CACHE_TTL: chrono::Duration = chrono::Duration::hours(1);
+
+/// The author of royalroad chapters whose fiction isn't followed, as chapter
+/// pages don't name it.
+const UNKNOWN_AUTHOR: &str = "Unknown";
+
+/// Conversions by a hash of the chapters requested.
+pub type ConversionCache = Arc<TtlCache<String, StorageLocation>>;
+
+#[derive(Debug, Deserialize)]
+pub struct ConvertRequest {
+    user_id: String,
+    token: String,
+    #[serde(flatten)]
+    chapters: ConvertChapters,
+}
+
+/// Royalroad chapters by id, whether or not their fiction is followed, or a
+/// range of a book's chapters in publication order numbered from one.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ConvertChapters {
+    RoyalRoad {
+        royalroad_chapter_ids: Vec<u64>,
+    },
+    Book {
+        book_id: Uuid,
+        first: usize,
+        last: usize,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConvertResponse {
+    url: String,
+    expires_at: DateTime<Utc>,
+    /// Whether an identical earlier request's conversion was reused.
+    cached: bool,
+}
+
+/// A chapter of a conversion.
+struct Part {
+    book_name: String,
+    author: String,
+    chapter_name: String,
+    body: PartBody,
+}
+
+enum PartBody {
+    Stored(StorageLocation),
+    Fetched(String),
+}
+
+#[tracing::instrument(
+name = "Converting chapters on request.",
+err,
+level = "info"
+skip(request, db_pool, storage, cache),
+fields(
+    request_id = %Uuid::new_v4(),
+    user_id = %request.user_id,
+)
+)]
+pub async fn convert(
+    request: ConvertRequest,
+    db_pool: InstrumentedPgConnectionPool,
+    storage: Storage,
+    cache: ConversionCache,
+) -> Result<ConvertResponse> {
+    feeds::authorize(&request.user_id, &request.token, &db_pool).await?;
+    validate(&request.chapters)?;
+    let hash = format!(
+        "{:x}",
+        Sha256::digest(serde_json::to_vec(&request.chapters)?)
+    );
+
+    let cached = cache.get(&hash);
+    let is_cached = cached.is_some();
+    let location = match cached {
+        Some(location) => location,
+        None => {
+            let parts = match request.chapters {
+                ConvertChapters::RoyalRoad {
+                    royalroad_chapter_ids,
+                } => royalroad_parts(&royalroad_chapter_ids, &db_pool).await?,
+                ConvertChapters::Book {
+                    book_id,
+                    first,
+                    last,
+                } => book_parts(book_id, first, last, &db_pool, &storage).await?,
+            };
+            let bytes = convert_parts(&parts, &storage).await?;
+            let location = storage
+                .store_conversion(&hash, ByteStream::from(bytes))
+                .await?;
+            cache.insert(hash, location.clone());
+            location
+        }
+    };
+    let url = storage
+        .presign_get(&location, deliveries::DOWNLOAD_URL_EXPIRY)
+        .await?;
+    Ok(ConvertResponse {
+        url,
+        expires_at: Utc::now() + chrono::Duration::from_std(deliveries::DOWNLOAD_URL_EXPIRY)?,
+        cached: is_cached,
+    })
+}
+
+fn validate(chapters: &ConvertChapters) -> Result<()> {
+    let count = match chapters {
+        ConvertChapters::RoyalRoad {
+            royalroad_chapter_ids,
+        } => royalroad_chapter_ids.len(),
+        ConvertChapters::Book { first, last, .. } if *first >= 1 && last >= first => {
+            last - first + 1
+        }
+        ConvertChapters::Book { .. } => {
+            return Err(ApiError::BadRequest(
+                "The chapter range must start at 1 or later and end at or after its start.".into(),
+            )
+            .into())
+        }
+    };
+    if !(1..=MAX_CHAPTERS).contains(&count) {
+        return Err(ApiError::BadRequest(format!(
+            "Between 1 and {} chapters can be converted at once.",
+            MAX_CHAPTERS
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// The royalroad chapters, reusing the bodies of those already stored and
+/// fetching the rest.
+async fn royalroad_parts(ids: &[u64], db_pool: &InstrumentedPgConnectionPool) -> Result<Vec<Part>> {
+    let kinds = ids
+        .iter()
+        .map(|id| ChapterKind::RoyalRoad { id: *id })
+        .collect_vec();
+    let stored: HashMap<u64, (Chapter, ChapterBody, Book)> = {
+        let mut conn = db_pool.get().await?;
+        chapters::table
+            .inner_join(chapter_bodies::table)
+            .inner_join(books::table.on(books::id.eq(chapters::book_id)))
+            .filter(chapters::metadata.eq_any(&kinds))
+            .load::<(Chapter, ChapterBody, Book)>(&mut *conn)
+            .await?
+            .into_iter()
+            .filter_map(|(chap, body, book)| match chap.metadata {
+                ChapterKind::RoyalRoad { id } => Some((id, (chap, body, book))),
+                _ => None,
+            })
+            .collect()
+    };
+    let base_url = &config::get().endpoints.royalroad;
+    try_join_all(ids.iter().map(|id| async {
+        if let Some((chap, body, book)) = stored.get(id) {
+            return Ok(Part {
+                book_name: book.name.clone(),
+                author: book.author.clone(),
+                chapter_name: chap.name.clone(),
+                body: PartBody::Stored(body.clone().into()),
+            });
+        }
+        let page = royalroad::get_chapter_page(base_url, *id).await?;
+        Ok(Part {
+            body: PartBody::Fetched(format!(
+                "<h1>{}: {}</h1>{}",
+                page.fiction, page.title, page.body
+            )),
+            book_name: page.fiction,
+            author: UNKNOWN_AUTHOR.into(),
+            chapter_name: page.title,
+        })
+    }))
+    .await
+}
+
+/// A range of a book's chapters, fetching the bodies of any which weren't
+/// stored.
+async fn book_parts(
+    book_id: Uuid,
+    first: usize,
+    last: usize,
+    db_pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+) -> Result<Vec<Part>> {
+    let (book, chapters, mut bodies) = {
+        let mut conn = db_pool.get().await?;
+        let book: Book = books::table
+            .find(book_id)
+            .filter(book_not_deleted())
+            .first(&mut *conn)
+            .await
+            .optional()?
+            .ok_or_else(|| ApiError::NotFound(format!("Book {} does not exist.", book_id)))?;
+        let chapters: Vec<Chapter> = chapters::table
+            .filter(chapters::book_id.eq(book_id))
+            .filter(chapter_not_deleted())
+            .order(chapter_order())
+            .offset(first as i64 - 1)
+            .limit((last - first + 1) as i64)
+            .load(&mut *conn)
+            .await?;
+        let bodies: HashMap<Uuid, ChapterBody> = chapter_bodies::table
+            .filter(chapter_bodies::chapter_id.eq_any(chapters.iter().map(|chap| chap.id)))
+            .load::<ChapterBody>(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|body| (body.chapter_id, body))
+            .collect();
+        (book, chapters, bodies)
+    };
+    if chapters.is_empty() {
+        return Err(ApiError::NotFound(format!(
+            "{} has no chapters {} through {}.",
+            book.name, first, last
+        ))
+        .into());
+    }
+    let endpoints = &config::get().endpoints;
+    try_join_all(chapters.iter().map(|chap| {
+        let stored = bodies.remove(&chap.id);
+        let book = &book;
+        async move {
+            let body = match stored {
+                Some(body) => PartBody::Stored(body.into()),
+                None => PartBody::Fetched(
+                    tasks::fetch_chapter_body(&NewChapter::from(chap), book, storage, endpoints)
+                        .await?
+                        .body,
+                ),
+            };
+            anyhow::Ok(Part {
+                book_name: book.name.clone(),
+                author: book.author.clone(),
+                chapter_name: chap.name.clone(),
+                body,
+            })
+        }
+    }))
+    .await
+}
+
+async fn convert_parts(parts: &[Part], storage: &Storage) -> Result<Vec<u8>> {
+    let in_path = calibre::temp_path("html");
+    if let Err(err) = write_parts(&in_path, parts, storage).await {
+        let _ = tokio::fs::remove_file(&in_path).await;
+        return Err(err);
+    }
+    let names = parts
+        .iter()
+        .map(|part| part.chapter_name.as_str())
+        .collect_vec();
+    let first = &parts[0];
+    let cover_title = tasks::cover_title(&first.book_name, &names);
+    calibre::convert_file(
+        &in_path,
+        &cover_title,
+        &first.book_name,
+        &first.author,
+        calibre::DEFAULT_OUTPUT_PROFILE,
+    )
+    .await
+}
+
+async fn write_parts(path: &str, parts: &[Part], storage: &Storage) -> Result<()> {
+    let mut input = BufWriter::new(File::create(path).await?);
+    for part in parts {
+        match &part.body {
+            PartBody::Stored(location) => {
+                storage.fetch_into(location.clone(), &mut input).await?;
+            }
+            PartBody::Fetched(html) => input.write_all(html.as_bytes()).await?,
+        }
+    }
+    input.flush().await?;
+    Ok(())
+}
+
+pub fn get_filters(
+    db_pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+    clock: &SharedClock,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let convert_db = db_pool.clone();
+    let convert_storage = storage.clone();
+    let cache: ConversionCache = Arc::new(TtlCache::new(CACHE_TTL, clock.clone()));
+    warp::post()
+        .and(warp::path("convert"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(warp::any().map(move || convert_db.clone()))
+        .and(warp::any().map(move || convert_storage.clone()))
+        .and(warp::any().map(move || cache.clone()))
+        .then(convert)
+        .map(map_result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::models::{BookKind, ChapterStatus, NewBook};
+    use crate::schema::delivery_methods;
+    use crate::storage::CONVERSIONS_PREFIX;
+    use crate::test_support::{self, TestDatabase};
+
+    const USER_ID: &str = "reader";
+    const TOKEN: &str = "feed-token";
+
+    /// Adds a book with stored chapters of the given kinds, returning its id.
+    async fn insert_book(db: &TestDatabase, storage: &Storage, kinds: Vec<ChapterKind>) -> Uuid {
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books::table)
+            .values(NewBook {
+                name: "The Test Serial".into(),
+                author: "Test Author".into(),
+                metadata: BookKind::Pale,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        for (number, kind) in kinds.into_iter().enumerate() {
+            let chapter_id: Uuid = diesel::insert_into(chapters::table)
+                .values((
+                    NewChapter {
+                        name: format!("Chapter {}", number + 1),
+                        author: book.author.clone(),
+                        book_id: book.id,
+                        published_at: Utc::now() + chrono::Duration::minutes(number as i64),
+                        metadata: kind,
+                    },
+                    chapters::status.eq(ChapterStatus::Fetched),
+                ))
+                .returning(chapters::id)
+                .get_result(&mut *conn)
+                .await
+                .unwrap();
+            let uploaded = storage
+                .store_book(
+                    &book.id,
+                    &chapter_id,
+                    ByteStream::from(format!("<p>Chapter {}</p>", number + 1).into_bytes()),
+                )
+                .await
+                .unwrap();
+            diesel::insert_into(chapter_bodies::table)
+                .values(ChapterBody {
+                    key: uploaded.location.key,
+                    bucket: uploaded.location.bucket,
+                    chapter_id,
+                    size: Some(uploaded.size),
+                })
+                .execute(&mut *conn)
+                .await
+                .unwrap();
+        }
+        diesel::insert_into(delivery_methods::table)
+            .values((
+                delivery_methods::user_id.eq(USER_ID),
+                delivery_methods::feed_token.eq(TOKEN),
+            ))
+            .on_conflict_do_nothing()
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        book.id
+    }
+
+    async fn post(
+        routes: &(impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone + 'static),
+        body: serde_json::Value,
+    ) -> (u16, serde_json::Value) {
+        let response = warp::test::request()
+            .method("POST")
+            .path("/convert")
+            .json(&body)
+            .reply(routes)
+            .await;
+        let json = serde_json::from_slice(response.body()).unwrap();
+        (response.status().as_u16(), json)
+    }
+
+    #[tokio::test]
+    async fn converts_book_ranges_once() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        test_support::config();
+        let storage = test_support::storage();
+        let pale = |n: usize| ChapterKind::Pale {
+            url: format!("https://palewebserial.wordpress.com/{}/", n),
+        };
+        let book_id = insert_book(&db, &storage, (1..=3).map(pale).collect()).await;
+        let routes = get_filters(&db.pool, &storage, &(ManualClock::new(Utc::now()) as _));
+        let request = serde_json::json!({
+            "user_id": USER_ID,
+            "token": TOKEN,
+            "book_id": book_id,
+            "first": 2,
+            "last": 3,
+        });
+
+        let (status, first) = post(&routes, request.clone()).await;
+        assert_eq!(status, 200, "{}", first);
+        assert_eq!(first["cached"], false);
+        let (status, second) = post(&routes, request.clone()).await;
+        assert_eq!(status, 200);
+        assert_eq!(second["cached"], true);
+        assert_eq!(storage.list(CONVERSIONS_PREFIX).await.unwrap().len(), 1);
+
+        let mut forged = request.clone();
+        forged["token"] = "guess".into();
+        assert_eq!(post(&routes, forged).await.0, 401);
+        let mut backwards = request;
+        backwards["first"] = 3.into();
+        backwards["last"] = 2.into();
+        assert_eq!(post(&routes, backwards).await.0, 400);
+    }
+
+    #[tokio::test]
+    async fn reuses_stored_royalroad_chapters() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        test_support::config();
+        let storage = test_support::storage();
+        insert_book(
+            &db,
+            &storage,
+            vec![
+                ChapterKind::RoyalRoad { id: 101 },
+                ChapterKind::RoyalRoad { id: 102 },
+            ],
+        )
+        .await;
+        let parts = royalroad_parts(&[102, 101], &db.pool).await.unwrap();
+        let names = parts
+            .iter()
+            .map(|part| part.chapter_name.as_str())
+            .collect_vec();
+        assert_eq!(names, ["Chapter 2", "Chapter 1"]);
+        assert!(parts
+            .iter()
+            .all(|part| matches!(part.body, PartBody::Stored(_))));
+
+        let bytes = convert_parts(&parts, &storage).await.unwrap();
+        assert_eq!(bytes, b"<p>Chapter 2</p><p>Chapter 1</p>");
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::clients::calibre;
+use crate::models::{chapter_order, Book, Chapter, ChapterBody, Delivery, ExtrasPreference};
+use crate::schema::{books, chapter_bodies, chapters, deliveries};
+use crate::storage::{Storage, StorageLocation, Uploaded};
+use crate::tasks;
+use crate::util::{map_result, ApiError, InstrumentedPgConnectionPool};
+
+use anyhow::Result;
+use aws_sdk_s3::primitives::ByteStream;
+use chrono::{DateTime, Utc};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::RunQueryDsl;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::{Filter, Reply};
+
+/// How long a presigned download url remains valid.
+pub const DOWNLOAD_URL_EXPIRY: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Deserialize)]
+pub struct DownloadDeliveryRequest {
+    user_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DownloadDeliveryResponse {
+    url: String,
+    expires_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(
+name = "Generating a delivery download url.",
+err,
+level = "info"
+skip(db_pool, storage),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn download_delivery(
+    delivery_id: Uuid,
+    request: DownloadDeliveryRequest,
+    db_pool: InstrumentedPgConnectionPool,
+    storage: Storage,
+) -> Result<DownloadDeliveryResponse> {
+    let url = presign_delivery(delivery_id, &request.user_id, &db_pool, &storage).await?;
+    Ok(DownloadDeliveryResponse {
+        url,
+        expires_at: Utc::now() + chrono::Duration::from_std(DOWNLOAD_URL_EXPIRY)?,
+    })
+}
+
+/// Generates a download url for a user's delivery, converting its chapters
+/// again if the ebook was not kept.
+pub async fn presign_delivery(
+    delivery_id: Uuid,
+    user_id: &str,
+    db_pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+) -> Result<String> {
+    let delivery: Delivery = {
+        let mut conn = db_pool.get().await?;
+        deliveries::table
+            .find(delivery_id)
+            .filter(deliveries::user_id.eq(user_id))
+            .first(&mut *conn)
+            .await
+            .optional()?
+            .ok_or_else(|| {
+                ApiError::NotFound(format!("Delivery {} does not exist.", delivery_id))
+            })?
+    };
+    let location = match delivery.artifact_location() {
+        Some(location) => location,
+        // Boxed so the handler future stays within the compiler's layout depth limit.
+        None => Box::pin(convert_delivery(&delivery, db_pool, storage)).await?,
+    };
+    storage.presign_get(&location, DOWNLOAD_URL_EXPIRY).await
+}
+
+/// Converts a delivery whose ebook was not kept, storing the result on the delivery.
+async fn convert_delivery(
+    delivery: &Delivery,
+    db_pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+) -> Result<StorageLocation> {
+    let book_id = delivery.book_id.ok_or_else(|| {
+        ApiError::NotFound(format!(
+            "Delivery {} was a test delivery, which has no ebook.",
+            delivery.id
+        ))
+    })?;
+    let (book, chapters, bodies) = {
+        let mut conn = db_pool.get().await?;
+        let book: Book = books::table.find(book_id).first(&mut *conn).await?;
+        let chapters: Vec<Chapter> = chapters::table
+            .filter(chapters::id.eq_any(&delivery.chapter_ids))
+            .order(chapter_order())
+            .load(&mut *conn)
+            .await?;
+        let bodies: HashMap<Uuid, ChapterBody> = chapter_bodies::table
+            .filter(chapter_bodies::chapter_id.eq_any(&delivery.chapter_ids))
+            .load::<ChapterBody>(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|body| (body.chapter_id, body))
+            .collect();
+        (book, chapters, bodies)
+    };
+    let chapters_with_body = chapters
+        .iter()
+        .filter_map(|chap| bodies.get(&chap.id).map(|body| (chap, body)))
+        .collect_vec();
+    if chapters_with_body.is_empty() {
+        return Err(ApiError::NotFound(format!(
+            "Delivery {} has no stored chapter bodies.",
+            delivery.id
+        ))
+        .into());
+    }
+
+    let bytes = tasks::generate_ebook(
+        &book,
+        &chapters_with_body,
+        ExtrasPreference::default(),
+        calibre::DEFAULT_OUTPUT_PROFILE,
+        storage,
+    )
+    .await?;
+    let Uploaded { location, size } = storage
+        .store_artifact(&book.id, ByteStream::from(bytes))
+        .await?;
+    let mut conn = db_pool.get().await?;
+    diesel::update(deliveries::table.find(delivery.id))
+        .set((
+            deliveries::artifact_bucket.eq(&location.bucket),
+            deliveries::artifact_key.eq(&location.key),
+            deliveries::artifact_size.eq(size),
+            deliveries::artifact_format.eq("epub"),
+        ))
+        .execute(&mut *conn)
+        .await?;
+    Ok(location)
+}
+
+pub fn get_filters(
+    db_pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let download_db = db_pool.clone();
+    let download_storage = storage.clone();
+    warp::get()
+        .and(warp::path("deliveries"))
+        .and(warp::path::param())
+        .and(warp::path("download"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::any().map(move || download_db.clone()))
+        .and(warp::any().map(move || download_storage.clone()))
+        .then(download_delivery)
+        .map(map_result)
+}
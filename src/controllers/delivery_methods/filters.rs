@@ -1,16 +1,20 @@
 use warp::{Filter, Reply};
 
-use crate::util::{map_result, InstrumentedPgConnectionPool};
+use crate::clock::SharedClock;
+use crate::util::{map_result, with_etag, InstrumentedPgConnectionPool};
 
 use super::{
-    get_delivery_methods, register_kindle_email, register_pushover_key, validate_kindle_email,
-    validate_pushover_key,
+    get_delivery_methods, register_kindle_email, register_pushover_key, send_kindle_test,
+    send_pushover_test, update_kindle_preferences, update_pushover_preferences,
+    validate_kindle_email, validate_pushover_key,
 };
 
 pub fn get(
     db_pool: &InstrumentedPgConnectionPool,
+    clock: &SharedClock,
 ) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
     let add_db = db_pool.clone();
+    let add_clock = clock.clone();
     let register_email_filter = warp::post()
         .and(warp::path("delivery_methods"))
         .and(warp::path("kindle"))
@@ -18,9 +22,11 @@ pub fn get(
         .and(warp::body::content_length_limit(1024))
         .and(warp::body::json())
         .and(warp::any().map(move || add_db.clone()))
+        .and(warp::any().map(move || add_clock.clone()))
         .then(register_kindle_email)
         .map(map_result);
     let validate_db = db_pool.clone();
+    let validate_clock = clock.clone();
     let validate_email_filter = warp::post()
         .and(warp::path("delivery_methods"))
         .and(warp::path("kindle"))
@@ -29,9 +35,35 @@ pub fn get(
         .and(warp::body::content_length_limit(1024))
         .and(warp::body::json())
         .and(warp::any().map(move || validate_db.clone()))
+        .and(warp::any().map(move || validate_clock.clone()))
         .then(validate_kindle_email)
         .map(map_result);
+    let kindle_preferences_db = db_pool.clone();
+    let kindle_preferences_filter = warp::post()
+        .and(warp::path("delivery_methods"))
+        .and(warp::path("kindle"))
+        .and(warp::path("preferences"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(warp::any().map(move || kindle_preferences_db.clone()))
+        .then(update_kindle_preferences)
+        .map(map_result);
+    let kindle_test_db = db_pool.clone();
+    let kindle_test_clock = clock.clone();
+    let kindle_test_filter = warp::post()
+        .and(warp::path("delivery_methods"))
+        .and(warp::path("kindle"))
+        .and(warp::path("test"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(warp::any().map(move || kindle_test_db.clone()))
+        .and(warp::any().map(move || kindle_test_clock.clone()))
+        .then(send_kindle_test)
+        .map(map_result);
     let add_pool_db = db_pool.clone();
+    let add_pool_clock = clock.clone();
     let register_pushover_filter = warp::post()
         .and(warp::path("delivery_methods"))
         .and(warp::path("pushover"))
@@ -39,9 +71,11 @@ pub fn get(
         .and(warp::body::content_length_limit(1024))
         .and(warp::body::json())
         .and(warp::any().map(move || add_pool_db.clone()))
+        .and(warp::any().map(move || add_pool_clock.clone()))
         .then(register_pushover_key)
         .map(map_result);
     let validate_db_pool = db_pool.clone();
+    let validate_clock = clock.clone();
     let validate_pushover_filter = warp::post()
         .and(warp::path("delivery_methods"))
         .and(warp::path("pushover"))
@@ -50,20 +84,50 @@ pub fn get(
         .and(warp::body::content_length_limit(1024))
         .and(warp::body::json())
         .and(warp::any().map(move || validate_db_pool.clone()))
+        .and(warp::any().map(move || validate_clock.clone()))
         .then(validate_pushover_key)
         .map(map_result);
-    let get_methods_db_pool = db_pool.clone();
-    let get_methods_filter = warp::get()
+    let preferences_db_pool = db_pool.clone();
+    let pushover_preferences_filter = warp::post()
         .and(warp::path("delivery_methods"))
+        .and(warp::path("pushover"))
+        .and(warp::path("preferences"))
         .and(warp::path::end())
         .and(warp::body::content_length_limit(1024))
-        .and(warp::query())
-        .and(warp::any().map(move || get_methods_db_pool.clone()))
-        .then(get_delivery_methods)
+        .and(warp::body::json())
+        .and(warp::any().map(move || preferences_db_pool.clone()))
+        .then(update_pushover_preferences)
         .map(map_result);
+    let pushover_test_db = db_pool.clone();
+    let pushover_test_clock = clock.clone();
+    let pushover_test_filter = warp::post()
+        .and(warp::path("delivery_methods"))
+        .and(warp::path("pushover"))
+        .and(warp::path("test"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(warp::any().map(move || pushover_test_db.clone()))
+        .and(warp::any().map(move || pushover_test_clock.clone()))
+        .then(send_pushover_test)
+        .map(map_result);
+    let get_methods_db_pool = db_pool.clone();
+    let get_methods_filter = with_etag(
+        warp::get()
+            .and(warp::path("delivery_methods"))
+            .and(warp::path::end())
+            .and(warp::query())
+            .and(warp::any().map(move || get_methods_db_pool.clone()))
+            .then(get_delivery_methods)
+            .map(map_result),
+    );
     register_email_filter
         .or(validate_email_filter)
+        .or(kindle_preferences_filter)
+        .or(kindle_test_filter)
         .or(register_pushover_filter)
         .or(validate_pushover_filter)
+        .or(pushover_preferences_filter)
+        .or(pushover_test_filter)
         .or(get_methods_filter)
 }
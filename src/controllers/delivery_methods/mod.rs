@@ -1,15 +1,18 @@
 mod filters;
 use crate::clients::{calibre, mailgun, pushover};
-use crate::models::DeliveryMethod;
-use crate::schema::delivery_methods;
-use crate::util::InstrumentedPgConnectionPool;
+use crate::clock::SharedClock;
+use crate::config;
+use crate::models::{DeliveryMethod, KindleConversionMode};
+use crate::schema::{deliveries, delivery_methods, verification_sends};
+use crate::util::{error_chain, retry_read, ApiError, InstrumentedPgConnectionPool};
 
 use crate::schema::delivery_methods::dsl::*;
 
 use anyhow::Result;
 use anyhow::{anyhow, bail};
 use chrono::{DateTime, Utc};
-use diesel::{QueryDsl, RunQueryDsl};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::RunQueryDsl;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -17,6 +20,52 @@ use uuid::Uuid;
 
 pub use filters::get;
 
+/// How many test deliveries a user can send in an hour, across methods.
+const TEST_DELIVERIES_PER_HOUR: i64 = 2;
+
+/// How many verification codes one kindle email or pushover key can be sent
+/// in a day, across every user who registers it.
+const VERIFICATIONS_PER_TARGET_PER_DAY: i64 = 3;
+
+/// Records a verification code sent to `target`, a kindle email or pushover
+/// key, unless it has been sent too many in the last day by any user.
+async fn record_verification_send(
+    db_pool: &InstrumentedPgConnectionPool,
+    kind: &str,
+    target: &str,
+    clock: &SharedClock,
+) -> Result<()> {
+    // Case and spacing are ignored, so variants of one target count together.
+    let target = target.trim().to_lowercase();
+    let day_ago = clock.now() - chrono::Duration::days(1);
+    let mut conn = db_pool.get().await?;
+    diesel::delete(verification_sends::table.filter(verification_sends::sent_at.le(day_ago)))
+        .execute(&mut *conn)
+        .await?;
+    let recent: i64 = verification_sends::table
+        .filter(verification_sends::target.eq(&target))
+        .filter(verification_sends::sent_at.gt(day_ago))
+        .count()
+        .get_result(&mut *conn)
+        .await?;
+    if recent >= VERIFICATIONS_PER_TARGET_PER_DAY {
+        return Err(ApiError::TooManyRequests(format!(
+            "This {} has been sent {} verification codes in the last day, the most \
+            one can be sent whichever user asks. Try again tomorrow.",
+            kind, VERIFICATIONS_PER_TARGET_PER_DAY
+        ))
+        .into());
+    }
+    diesel::insert_into(verification_sends::table)
+        .values((
+            verification_sends::target.eq(&target),
+            verification_sends::sent_at.eq(clock.now()),
+        ))
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct ValidateKindleEmailRequest {
@@ -37,8 +86,8 @@ pub struct GetDeliveryMethodsRequest {
 }
 
 #[derive(Debug, AsChangeset, Insertable)]
-#[table_name = "delivery_methods"]
-#[changeset_options(treat_none_as_null = "true")]
+#[diesel(table_name = delivery_methods)]
+#[diesel(treat_none_as_null = true)]
 struct KindleEmailChangeset {
     user_id: String,
     kindle_email: String,
@@ -52,6 +101,9 @@ struct KindleEmailChangeset {
 pub struct GetDeliveryMethodsResponse {
     kindle_email: Option<String>,
     pushover_key: Option<String>,
+    pushover_combine_books: bool,
+    kindle_inline_fallback: bool,
+    kindle_conversion_mode: KindleConversionMode,
 }
 
 #[tracing::instrument(
@@ -67,10 +119,15 @@ pub async fn get_delivery_methods(
     request: GetDeliveryMethodsRequest,
     db_pool: InstrumentedPgConnectionPool,
 ) -> Result<GetDeliveryMethodsResponse> {
-    let delivery_method: DeliveryMethod = {
-        let conn = db_pool.get().await?;
-        delivery_methods.find(&request.user_id).first(&*conn)?
-    };
+    // Read only, so a dropped connection is retried.
+    let delivery_method: DeliveryMethod = retry_read(|| async {
+        let mut conn = db_pool.get().await?;
+        Ok(delivery_methods
+            .find(&request.user_id)
+            .first(&mut *conn)
+            .await?)
+    })
+    .await?;
     let kindle = if delivery_method.kindle_email_enabled && delivery_method.kindle_email_verified {
         delivery_method.get_kindle_email().clone()
     } else {
@@ -84,6 +141,9 @@ pub async fn get_delivery_methods(
     Ok(GetDeliveryMethodsResponse {
         kindle_email: kindle,
         pushover_key: pushover,
+        pushover_combine_books: delivery_method.pushover_combine_books,
+        kindle_inline_fallback: delivery_method.kindle_inline_fallback,
+        kindle_conversion_mode: delivery_method.kindle_conversion_mode,
     })
 }
 
@@ -91,7 +151,7 @@ pub async fn get_delivery_methods(
 name = "Validate kindle email.",
 err,
 level = "info"
-skip(db_pool),
+skip(db_pool, clock),
 fields(
     request_id = %Uuid::new_v4(),
 )
@@ -99,10 +159,14 @@ fields(
 pub async fn validate_kindle_email(
     request: ValidateKindleEmailRequest,
     db_pool: InstrumentedPgConnectionPool,
+    clock: SharedClock,
 ) -> Result<serde_json::Map<String, Value>> {
     let delivery_method: DeliveryMethod = {
-        let conn = db_pool.get().await?;
-        delivery_methods.find(&request.user_id).first(&*conn)?
+        let mut conn = db_pool.get().await?;
+        delivery_methods
+            .find(&request.user_id)
+            .first(&mut *conn)
+            .await?
     };
     match (
         delivery_method.kindle_email_verification_code,
@@ -110,7 +174,7 @@ pub async fn validate_kindle_email(
     ) {
         (Some(code), Some(time)) => {
             if request.verification_code == code
-                && (chrono::Utc::now() - time < chrono::Duration::hours(1))
+                && (clock.now() - time < chrono::Duration::hours(1))
             {
                 let changeset = KindleEmailChangeset {
                     user_id: request.user_id.clone(),
@@ -122,13 +186,14 @@ pub async fn validate_kindle_email(
                     kindle_email_verification_code_time: None,
                     kindle_email_verification_code: None,
                 };
-                let conn = db_pool.get().await?;
+                let mut conn = db_pool.get().await?;
                 let _result = diesel::insert_into(delivery_methods)
                     .values(&changeset)
                     .on_conflict(user_id)
                     .do_update()
                     .set(&changeset)
-                    .execute(&*conn)?;
+                    .execute(&mut *conn)
+                    .await?;
             } else {
                 bail!("User provided the incorrect validation code.");
             }
@@ -144,7 +209,7 @@ pub async fn validate_kindle_email(
 name = "Add kindle email as a delivery option.",
 err,
 level = "info"
-skip(db_pool),
+skip(db_pool, clock),
 fields(
     request_id = %Uuid::new_v4(),
 )
@@ -152,6 +217,7 @@ fields(
 pub async fn register_kindle_email(
     request: AddKindleEmailRequest,
     db_pool: InstrumentedPgConnectionPool,
+    clock: SharedClock,
 ) -> Result<serde_json::Map<String, Value>> {
     // Assert email domain is "kindle.com". Emails aren't free.
     let email = addr::parse_email_address(&request.kindle_email)
@@ -165,6 +231,7 @@ pub async fn register_kindle_email(
             bail!("Provided email hostname {:?} is not kindle.com", hostname)
         }
     }
+    record_verification_send(&db_pool, "kindle email", &request.kindle_email, &clock).await?;
 
     let code = rand::thread_rng()
         .sample_iter(&rand::distributions::Alphanumeric)
@@ -177,16 +244,17 @@ pub async fn register_kindle_email(
         kindle_email: request.kindle_email.clone(),
         kindle_email_enabled: false,
         kindle_email_verified: false,
-        kindle_email_verification_code_time: Some(chrono::Utc::now()),
+        kindle_email_verification_code_time: Some(clock.now()),
         kindle_email_verification_code: Some(code.clone()),
     };
-    let conn = db_pool.get().await?;
+    let mut conn = db_pool.get().await?;
     let _result = diesel::insert_into(delivery_methods)
         .values(&changeset)
         .on_conflict(user_id)
         .do_update()
         .set(&changeset)
-        .execute(&*conn)?;
+        .execute(&mut *conn)
+        .await?;
     let mobi_bytes = calibre::generate_kindle_email_validation_epub(&code).await?;
     mailgun::send_epub_file(
         mobi_bytes.as_slice(),
@@ -198,6 +266,56 @@ pub async fn register_kindle_email(
     Ok(serde_json::Map::new())
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KindlePreferencesRequest {
+    user_id: String,
+    /// Send chapters inline in the email when they fail to convert.
+    inline_fallback: Option<bool>,
+    /// Whether cereal or Amazon converts the chapters.
+    conversion_mode: Option<KindleConversionMode>,
+}
+
+#[derive(Debug, AsChangeset)]
+#[diesel(table_name = delivery_methods)]
+struct KindlePreferencesChangeset {
+    kindle_inline_fallback: Option<bool>,
+    kindle_conversion_mode: Option<KindleConversionMode>,
+}
+
+#[tracing::instrument(
+name = "Update kindle preferences.",
+err,
+level = "info"
+skip(db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn update_kindle_preferences(
+    request: KindlePreferencesRequest,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<serde_json::Map<String, Value>> {
+    if request.inline_fallback.is_none() && request.conversion_mode.is_none() {
+        return Err(ApiError::BadRequest(
+            "Either inline_fallback or conversion_mode is required.".into(),
+        )
+        .into());
+    }
+    let mut conn = db_pool.get().await?;
+    let updated = diesel::update(delivery_methods.find(&request.user_id))
+        .set(KindlePreferencesChangeset {
+            kindle_inline_fallback: request.inline_fallback,
+            kindle_conversion_mode: request.conversion_mode,
+        })
+        .execute(&mut *conn)
+        .await?;
+    if updated == 0 {
+        bail!("User has no delivery methods.");
+    }
+    Ok(serde_json::Map::new())
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct ValidatePushoverRequest {
@@ -213,8 +331,8 @@ pub struct AddPushoverRequest {
 }
 
 #[derive(Debug, AsChangeset, Insertable)]
-#[table_name = "delivery_methods"]
-#[changeset_options(treat_none_as_null = "true")]
+#[diesel(table_name = delivery_methods)]
+#[diesel(treat_none_as_null = true)]
 struct PushoverChangeset {
     user_id: String,
     pushover_key: String,
@@ -228,7 +346,7 @@ struct PushoverChangeset {
 name = "Validate pushover token.",
 err,
 level = "info"
-skip(db_pool),
+skip(db_pool, clock),
 fields(
     request_id = %Uuid::new_v4(),
 )
@@ -236,10 +354,14 @@ fields(
 pub async fn validate_pushover_key(
     request: ValidatePushoverRequest,
     db_pool: InstrumentedPgConnectionPool,
+    clock: SharedClock,
 ) -> Result<serde_json::Map<String, Value>> {
     let delivery_method: DeliveryMethod = {
-        let conn = db_pool.get().await?;
-        delivery_methods.find(&request.user_id).first(&*conn)?
+        let mut conn = db_pool.get().await?;
+        delivery_methods
+            .find(&request.user_id)
+            .first(&mut *conn)
+            .await?
     };
     match (
         delivery_method.pushover_verification_code,
@@ -247,7 +369,7 @@ pub async fn validate_pushover_key(
     ) {
         (Some(code), Some(time)) => {
             if request.verification_code == code
-                && (chrono::Utc::now() - time < chrono::Duration::minutes(5))
+                && (clock.now() - time < chrono::Duration::minutes(5))
             {
                 let changeset = PushoverChangeset {
                     user_id: request.user_id.clone(),
@@ -259,13 +381,14 @@ pub async fn validate_pushover_key(
                     pushover_verification_code_time: None,
                     pushover_verification_code: None,
                 };
-                let conn = db_pool.get().await?;
+                let mut conn = db_pool.get().await?;
                 let _result = diesel::insert_into(delivery_methods)
                     .values(&changeset)
                     .on_conflict(user_id)
                     .do_update()
                     .set(&changeset)
-                    .execute(&*conn)?;
+                    .execute(&mut *conn)
+                    .await?;
             } else {
                 bail!("User provided the incorrect validation code.");
             }
@@ -281,7 +404,7 @@ pub async fn validate_pushover_key(
 name = "Add pushover key.",
 err,
 level = "info"
-skip(db_pool),
+skip(db_pool, clock),
 fields(
     request_id = %Uuid::new_v4(),
 )
@@ -289,7 +412,9 @@ fields(
 pub async fn register_pushover_key(
     request: AddPushoverRequest,
     db_pool: InstrumentedPgConnectionPool,
+    clock: SharedClock,
 ) -> Result<serde_json::Map<String, Value>> {
+    record_verification_send(&db_pool, "pushover key", &request.pushover_key, &clock).await?;
     let code = rand::thread_rng()
         .sample_iter(&rand::distributions::Alphanumeric)
         .take(10)
@@ -301,16 +426,438 @@ pub async fn register_pushover_key(
         pushover_key: request.pushover_key.clone(),
         pushover_enabled: false,
         pushover_key_verified: false,
-        pushover_verification_code_time: Some(chrono::Utc::now()),
+        pushover_verification_code_time: Some(clock.now()),
         pushover_verification_code: Some(code.clone()),
     };
-    let conn = db_pool.get().await?;
+    let mut conn = db_pool.get().await?;
     let _result = diesel::insert_into(delivery_methods)
         .values(&changeset)
         .on_conflict(user_id)
         .do_update()
         .set(&changeset)
-        .execute(&*conn)?;
+        .execute(&mut *conn)
+        .await?;
     pushover::send_verification_token(&request.pushover_key, &code.clone()).await?;
     Ok(serde_json::Map::new())
 }
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PushoverPreferencesRequest {
+    user_id: String,
+    /// Announce all of a notification cycle's books in one message.
+    combine_books: bool,
+}
+
+#[tracing::instrument(
+name = "Update pushover preferences.",
+err,
+level = "info"
+skip(db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn update_pushover_preferences(
+    request: PushoverPreferencesRequest,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<serde_json::Map<String, Value>> {
+    let mut conn = db_pool.get().await?;
+    let updated = diesel::update(delivery_methods.find(&request.user_id))
+        .set(pushover_combine_books.eq(request.combine_books))
+        .execute(&mut *conn)
+        .await?;
+    if updated == 0 {
+        bail!("User has no delivery methods.");
+    }
+    Ok(serde_json::Map::new())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestDeliveryRequest {
+    user_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TestDeliveryResponse {
+    delivery_id: Uuid,
+}
+
+/// The user's delivery methods, whether or not they are verified.
+async fn find_delivery_method(
+    db_pool: &InstrumentedPgConnectionPool,
+    user: &str,
+) -> Result<DeliveryMethod> {
+    let mut conn = db_pool.get().await?;
+    Ok(delivery_methods
+        .find(user)
+        .first(&mut *conn)
+        .await
+        .optional()?
+        .ok_or_else(|| ApiError::NotFound(format!("User {} has no delivery methods.", user)))?)
+}
+
+/// Records a test delivery to `user`, unless they have sent too many in the
+/// last hour. Attempts are recorded before sending, so failures count too.
+async fn record_test_delivery(
+    db_pool: &InstrumentedPgConnectionPool,
+    user: &str,
+    clock: &SharedClock,
+) -> Result<Uuid> {
+    let now = clock.now();
+    let mut conn = db_pool.get().await?;
+    let recent: i64 = deliveries::table
+        .filter(deliveries::user_id.eq(user))
+        .filter(deliveries::test.eq(true))
+        .filter(deliveries::created_at.gt(now - chrono::Duration::hours(1)))
+        .count()
+        .get_result(&mut *conn)
+        .await?;
+    if recent >= TEST_DELIVERIES_PER_HOUR {
+        return Err(ApiError::TooManyRequests(format!(
+            "Only {} test deliveries can be sent an hour, try again later.",
+            TEST_DELIVERIES_PER_HOUR
+        ))
+        .into());
+    }
+    Ok(diesel::insert_into(deliveries::table)
+        .values((
+            deliveries::user_id.eq(user),
+            deliveries::chapter_ids.eq(Vec::<Uuid>::new()),
+            deliveries::created_at.eq(now),
+            deliveries::dry_run.eq(config::get().dry_run),
+            deliveries::test.eq(true),
+        ))
+        .returning(deliveries::id)
+        .get_result(&mut *conn)
+        .await?)
+}
+
+/// Reports a failure of the service a test was sent through to the caller,
+/// so a misconfigured method can be diagnosed from the response.
+fn upstream(service: &str, err: anyhow::Error) -> anyhow::Error {
+    ApiError::Upstream(format!(
+        "{} failed to send the test delivery. {}",
+        service,
+        error_chain(&err)
+    ))
+    .into()
+}
+
+#[tracing::instrument(
+name = "Send a test delivery to a kindle.",
+err,
+level = "info"
+skip(db_pool, clock),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn send_kindle_test(
+    request: TestDeliveryRequest,
+    db_pool: InstrumentedPgConnectionPool,
+    clock: SharedClock,
+) -> Result<TestDeliveryResponse> {
+    let delivery_method = find_delivery_method(&db_pool, &request.user_id).await?;
+    let Some(email) = delivery_method.get_kindle_email().clone() else {
+        return Err(ApiError::BadRequest("User has no verified kindle email.".into()).into());
+    };
+    let delivery_id = record_test_delivery(&db_pool, &request.user_id, &clock).await?;
+    let epub_bytes = calibre::generate_test_delivery_epub().await?;
+    mailgun::send_epub_file(
+        epub_bytes.as_slice(),
+        &email,
+        "CerealTestDelivery",
+        "Cereal Test Delivery",
+    )
+    .await
+    .map_err(|err| upstream("Mailgun", err))?;
+    Ok(TestDeliveryResponse { delivery_id })
+}
+
+#[tracing::instrument(
+name = "Send a test delivery through pushover.",
+err,
+level = "info"
+skip(db_pool, clock),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn send_pushover_test(
+    request: TestDeliveryRequest,
+    db_pool: InstrumentedPgConnectionPool,
+    clock: SharedClock,
+) -> Result<TestDeliveryResponse> {
+    let delivery_method = find_delivery_method(&db_pool, &request.user_id).await?;
+    let Some(key) = delivery_method.get_pushover_key().clone() else {
+        return Err(ApiError::BadRequest("User has no verified pushover key.".into()).into());
+    };
+    let delivery_id = record_test_delivery(&db_pool, &request.user_id, &clock).await?;
+    pushover::send_message(
+        &key,
+        "This is a test delivery from cereal. New chapters will be announced here.",
+    )
+    .await
+    .map_err(|err| upstream("Pushover", err))?;
+    Ok(TestDeliveryResponse { delivery_id })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use crate::test_support::{self, TestDatabase};
+
+    const USER_ID: &str = "reader";
+    const CODE: &str = "ABCDEFGHIJ";
+
+    async fn start_kindle_validation(pool: &InstrumentedPgConnectionPool, sent_at: DateTime<Utc>) {
+        let mut conn = pool.get().await.unwrap();
+        diesel::insert_into(delivery_methods)
+            .values(KindleEmailChangeset {
+                user_id: USER_ID.into(),
+                kindle_email: "reader@kindle.com".into(),
+                kindle_email_verified: false,
+                kindle_email_enabled: false,
+                kindle_email_verification_code_time: Some(sent_at),
+                kindle_email_verification_code: Some(CODE.into()),
+            })
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+    }
+
+    async fn start_pushover_validation(
+        pool: &InstrumentedPgConnectionPool,
+        sent_at: DateTime<Utc>,
+    ) {
+        let mut conn = pool.get().await.unwrap();
+        diesel::insert_into(delivery_methods)
+            .values(PushoverChangeset {
+                user_id: USER_ID.into(),
+                pushover_key: "pushover-key".into(),
+                pushover_key_verified: false,
+                pushover_enabled: false,
+                pushover_verification_code_time: Some(sent_at),
+                pushover_verification_code: Some(CODE.into()),
+            })
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+    }
+
+    async fn delivery_method(pool: &InstrumentedPgConnectionPool) -> DeliveryMethod {
+        let mut conn = pool.get().await.unwrap();
+        delivery_methods
+            .find(USER_ID)
+            .first(&mut *conn)
+            .await
+            .unwrap()
+    }
+
+    async fn validate_kindle(
+        pool: &InstrumentedPgConnectionPool,
+        clock: SharedClock,
+    ) -> Result<()> {
+        let request = ValidateKindleEmailRequest {
+            user_id: USER_ID.into(),
+            verification_code: CODE.into(),
+        };
+        validate_kindle_email(request, pool.clone(), clock)
+            .await
+            .map(drop)
+    }
+
+    async fn validate_pushover(
+        pool: &InstrumentedPgConnectionPool,
+        clock: SharedClock,
+    ) -> Result<()> {
+        let request = ValidatePushoverRequest {
+            user_id: USER_ID.into(),
+            verification_code: CODE.into(),
+        };
+        validate_pushover_key(request, pool.clone(), clock)
+            .await
+            .map(drop)
+    }
+
+    #[tokio::test]
+    async fn accepts_kindle_code_just_before_expiry() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let clock = ManualClock::new(Utc::now());
+        start_kindle_validation(&db.pool, clock.now()).await;
+
+        clock.advance(chrono::Duration::hours(1) - chrono::Duration::seconds(1));
+        validate_kindle(&db.pool, clock).await.unwrap();
+
+        let method = delivery_method(&db.pool).await;
+        assert!(method.kindle_email_verified && method.kindle_email_enabled);
+    }
+
+    #[tokio::test]
+    async fn rejects_kindle_code_at_expiry() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let clock = ManualClock::new(Utc::now());
+        start_kindle_validation(&db.pool, clock.now()).await;
+
+        clock.advance(chrono::Duration::hours(1));
+        assert!(validate_kindle(&db.pool, clock).await.is_err());
+
+        assert!(!delivery_method(&db.pool).await.kindle_email_verified);
+    }
+
+    #[tokio::test]
+    async fn accepts_pushover_code_just_before_expiry() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let clock = ManualClock::new(Utc::now());
+        start_pushover_validation(&db.pool, clock.now()).await;
+
+        clock.advance(chrono::Duration::minutes(5) - chrono::Duration::seconds(1));
+        validate_pushover(&db.pool, clock).await.unwrap();
+
+        let method = delivery_method(&db.pool).await;
+        assert!(method.pushover_key_verified && method.pushover_enabled);
+    }
+
+    #[tokio::test]
+    async fn rejects_pushover_code_at_expiry() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let clock = ManualClock::new(Utc::now());
+        start_pushover_validation(&db.pool, clock.now()).await;
+
+        clock.advance(chrono::Duration::minutes(5));
+        assert!(validate_pushover(&db.pool, clock).await.is_err());
+
+        assert!(!delivery_method(&db.pool).await.pushover_key_verified);
+    }
+
+    #[tokio::test]
+    async fn limits_verification_sends_to_each_kindle_email() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let clock = ManualClock::new(Utc::now());
+        let register = |user: usize, email: &str| {
+            let request = AddKindleEmailRequest {
+                user_id: format!("user-{user}"),
+                kindle_email: email.into(),
+            };
+            register_kindle_email(request, db.pool.clone(), clock.clone())
+        };
+
+        // Different users, and casings, of the one address share its limit.
+        for (user, email) in [
+            "stranger@kindle.com",
+            "Stranger@kindle.com",
+            "STRANGER@kindle.com",
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            register(user, email).await.unwrap();
+        }
+        let err = register(3, "stranger@kindle.com").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::TooManyRequests(_))
+        ));
+        register(3, "reader@kindle.com").await.unwrap();
+
+        clock.advance(chrono::Duration::days(1) - chrono::Duration::seconds(1));
+        assert!(register(4, "stranger@kindle.com").await.is_err());
+        clock.advance(chrono::Duration::seconds(1));
+        register(4, "stranger@kindle.com").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn limits_verification_sends_to_each_pushover_key() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let clock = ManualClock::new(Utc::now());
+        let register = |user: i64| {
+            let request = AddPushoverRequest {
+                user_id: format!("user-{user}"),
+                pushover_key: "pushover-key".into(),
+            };
+            register_pushover_key(request, db.pool.clone(), clock.clone())
+        };
+
+        for user in 0..VERIFICATIONS_PER_TARGET_PER_DAY {
+            register(user).await.unwrap();
+        }
+        let err = register(VERIFICATIONS_PER_TARGET_PER_DAY)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("This pushover key has been sent 3"));
+    }
+
+    #[tokio::test]
+    async fn limits_test_deliveries_to_verified_methods() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let clock = ManualClock::new(Utc::now());
+        start_kindle_validation(&db.pool, clock.now()).await;
+        validate_kindle(&db.pool, clock.clone()).await.unwrap();
+        let request = || TestDeliveryRequest {
+            user_id: USER_ID.into(),
+        };
+        let status = |result: Result<TestDeliveryResponse>| match result {
+            Ok(_) => None,
+            Err(err) => Some(err.downcast::<ApiError>().unwrap().to_string()),
+        };
+
+        let unverified = send_pushover_test(request(), db.pool.clone(), clock.clone()).await;
+        assert_eq!(
+            status(unverified).as_deref(),
+            Some("User has no verified pushover key.")
+        );
+        for _ in 0..TEST_DELIVERIES_PER_HOUR {
+            send_kindle_test(request(), db.pool.clone(), clock.clone())
+                .await
+                .unwrap();
+        }
+        let limited = send_kindle_test(request(), db.pool.clone(), clock.clone()).await;
+        assert!(status(limited)
+            .unwrap()
+            .starts_with("Only 2 test deliveries"));
+        clock.advance(chrono::Duration::hours(1));
+        send_kindle_test(request(), db.pool.clone(), clock.clone())
+            .await
+            .unwrap();
+
+        let mut conn = db.pool.get().await.unwrap();
+        let recorded: Vec<(Option<Uuid>, bool)> = deliveries::table
+            .filter(deliveries::user_id.eq(USER_ID))
+            .select((deliveries::book_id, deliveries::test))
+            .load(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(recorded, vec![(None, true); 3]);
+    }
+
+    #[tokio::test]
+    async fn tags_delivery_methods_with_etags() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        start_pushover_validation(&db.pool, Utc::now()).await;
+        let routes = get(&db.pool, &crate::clock::system());
+
+        test_support::assert_etags(&routes, &format!("/delivery_methods?user_id={}", USER_ID))
+            .await;
+    }
+}
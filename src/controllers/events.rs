@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use crate::controllers::feeds;
+use crate::events::{self, UserEvent};
+use crate::util::{map_result, InstrumentedPgConnectionPool};
+
+use anyhow::Result;
+use futures::{stream, Stream};
+use serde::Deserialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+use warp::sse;
+use warp::{Filter, Reply};
+
+/// Comments are sent on idle streams this often, so proxies don't close them.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Deserialize)]
+pub struct EventsRequest {
+    user_id: String,
+    token: String,
+}
+
+/// Streams the user's events as they happen. Nothing is replayed, so a client
+/// which reconnects, or is sent a `resync` event after falling behind,
+/// should read the pending endpoint again for what it missed.
+#[tracing::instrument(
+name = "Streaming a user's events.",
+err,
+level = "info"
+skip(request, db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+    user_id = %request.user_id,
+)
+)]
+pub async fn get_events(
+    request: EventsRequest,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<warp::reply::Response> {
+    feeds::authorize(&request.user_id, &request.token, &db_pool).await?;
+    let events = user_events(request.user_id, events::subscribe());
+    Ok(sse::reply(sse::keep_alive().interval(KEEP_ALIVE).stream(events)).into_response())
+}
+
+fn user_events(
+    user_id: String,
+    receiver: broadcast::Receiver<UserEvent>,
+) -> impl Stream<Item = Result<sse::Event, serde_json::Error>> {
+    stream::unfold(receiver, move |mut receiver| {
+        let user_id = user_id.clone();
+        async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(UserEvent { user_id: to, event }) if to == user_id => {
+                        sse::Event::default().event(event.name()).json_data(&event)
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(_)) => Ok(sse::Event::default().event("resync").data("")),
+                    Err(RecvError::Closed) => return None,
+                };
+                return Some((event, receiver));
+            }
+        }
+    })
+}
+
+fn map_events(result: Result<warp::reply::Response>) -> warp::reply::Response {
+    match result {
+        Ok(response) => response,
+        Err(err) => map_result(Err::<(), _>(err)).into_response(),
+    }
+}
+
+pub fn get_filters(
+    db_pool: &InstrumentedPgConnectionPool,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let events_db = db_pool.clone();
+    warp::get()
+        .and(warp::path("events"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::any().map(move || events_db.clone()))
+        .then(get_events)
+        .map(map_events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Event;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn streams_only_the_users_events() {
+        let (sender, receiver) = broadcast::channel(8);
+        let events = user_events("streamer".into(), receiver);
+        futures::pin_mut!(events);
+        let book_id = Uuid::new_v4();
+        for user_id in ["someone-else", "streamer"] {
+            sender
+                .send(UserEvent {
+                    user_id: user_id.into(),
+                    event: Event::DeliveryFailed {
+                        book_id,
+                        book_name: user_id.into(),
+                        error: "Failed to send.".into(),
+                    },
+                })
+                .unwrap();
+        }
+        drop(sender);
+
+        let event = events.next().await.unwrap().unwrap().to_string();
+        assert!(event.starts_with("event:delivery_failed\n"));
+        assert!(event.contains(r#""book_name":"streamer""#));
+        assert!(event.contains(r#""type":"delivery_failed""#));
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn asks_lagging_streams_to_resync() {
+        let (sender, receiver) = broadcast::channel(1);
+        let events = user_events("streamer".into(), receiver);
+        futures::pin_mut!(events);
+        for _ in 0..2 {
+            sender
+                .send(UserEvent {
+                    user_id: "streamer".into(),
+                    event: Event::DeliveryCompleted {
+                        delivery_id: Uuid::new_v4(),
+                        book_id: Uuid::new_v4(),
+                        chapter_ids: Vec::new(),
+                    },
+                })
+                .unwrap();
+        }
+
+        let event = events.next().await.unwrap().unwrap().to_string();
+        assert!(event.starts_with("event:resync\n"));
+        let event = events.next().await.unwrap().unwrap().to_string();
+        assert!(event.starts_with("event:delivery_completed\n"));
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+
+use crate::config::{self, Endpoints};
+use crate::models::{chapter_not_deleted, Book, Chapter};
+use crate::schema::{books, chapters, deliveries, delivery_methods};
+use crate::tasks;
+use crate::util::{map_result, map_xml, ApiError, InstrumentedPgConnectionPool};
+
+use anyhow::Result;
+use diesel::{ExpressionMethods, JoinOnDsl, OptionalExtension, PgSortExpressionMethods, QueryDsl};
+use diesel_async::RunQueryDsl;
+use itertools::Itertools;
+use rand::Rng;
+use rss::{Category, ChannelBuilder, Guid, Item, ItemBuilder};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::{Filter, Reply};
+
+/// The most entries a feed holds, newest first.
+const MAX_ENTRIES: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct FeedRequest {
+    token: String,
+    /// Also list chapters which are waiting to be delivered.
+    #[serde(default)]
+    pending: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeedTokenResponse {
+    pub token: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum EntryKind {
+    Delivered,
+    Pending,
+}
+
+struct FeedEntry {
+    book: Book,
+    chapter: Chapter,
+    kind: EntryKind,
+}
+
+#[tracing::instrument(
+name = "Rendering a user's chapter feed.",
+err,
+level = "info"
+skip(request, db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn get_feed(
+    user_id: String,
+    request: FeedRequest,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<String> {
+    authorize(&user_id, &request.token, &db_pool).await?;
+    let mut conn = db_pool.get().await?;
+    let delivered_ids: Vec<Vec<Uuid>> = deliveries::table
+        .filter(deliveries::user_id.eq(&user_id))
+        .order(deliveries::created_at.desc())
+        .limit(MAX_ENTRIES as i64)
+        .select(deliveries::chapter_ids)
+        .load(&mut *conn)
+        .await?;
+    let delivered_ids = delivered_ids.into_iter().flatten().collect_vec();
+    let delivered: Vec<(Chapter, Book)> = chapters::table
+        .inner_join(books::table.on(books::id.eq(chapters::book_id)))
+        .filter(chapters::id.eq_any(&delivered_ids))
+        .filter(chapter_not_deleted())
+        .order((
+            chapters::published_at.desc(),
+            chapters::ordinal.desc().nulls_last(),
+            chapters::id.desc(),
+        ))
+        .limit(MAX_ENTRIES as i64)
+        .load(&mut *conn)
+        .await?;
+    let mut entries = delivered
+        .into_iter()
+        .map(|(chapter, book)| FeedEntry {
+            book,
+            chapter,
+            kind: EntryKind::Delivered,
+        })
+        .collect_vec();
+
+    drop(conn);
+
+    if request.pending {
+        // Selected as deliveries select them, so the feed lists what will be sent.
+        let queued = tasks::queued_chapters(&db_pool, Some(&user_id)).await?;
+        let book_id_to_book: HashMap<Uuid, Book> = {
+            let mut conn = db_pool.get().await?;
+            books::table
+                .filter(books::id.eq_any(queued.iter().map(|queued| queued.subscription.book_id)))
+                .load::<Book>(&mut *conn)
+                .await?
+                .into_iter()
+                .map(|book| (book.id, book))
+                .collect()
+        };
+        for queued in queued {
+            let Some(book) = book_id_to_book.get(&queued.subscription.book_id) else {
+                continue;
+            };
+            entries.extend(queued.chapters.into_iter().map(|chapter| FeedEntry {
+                book: book.clone(),
+                chapter,
+                kind: EntryKind::Pending,
+            }));
+        }
+    }
+
+    // Newest first across books, as ordinals only order chapters within a book.
+    entries.sort_by_key(|entry| {
+        let chapter = &entry.chapter;
+        std::cmp::Reverse((chapter.published_at, chapter.ordinal, chapter.id))
+    });
+    entries.truncate(MAX_ENTRIES);
+    Ok(render_feed(&user_id, &entries, &config::get().endpoints))
+}
+
+/// Checks a user's feed token, which also grants access to their OPDS
+/// catalog.
+pub async fn authorize(
+    user_id: &str,
+    token: &str,
+    db_pool: &InstrumentedPgConnectionPool,
+) -> Result<()> {
+    let mut conn = db_pool.get().await?;
+    delivery_methods::table
+        .find(user_id)
+        .filter(delivery_methods::feed_token.eq(token))
+        .select(delivery_methods::user_id)
+        .first::<String>(&mut *conn)
+        .await
+        .optional()?
+        .ok_or_else(|| ApiError::Unauthorized("A valid feed token is required.".into()))?;
+    Ok(())
+}
+
+fn render_feed(user_id: &str, entries: &[FeedEntry], endpoints: &Endpoints) -> String {
+    let items = entries
+        .iter()
+        .map(|entry| feed_item(entry, endpoints))
+        .collect_vec();
+    ChannelBuilder::default()
+        .title("Cereal")
+        .description(format!("Chapters delivered to {}.", user_id))
+        .items(items)
+        .build()
+        .to_string()
+}
+
+fn feed_item(entry: &FeedEntry, endpoints: &Endpoints) -> Item {
+    let FeedEntry {
+        book,
+        chapter,
+        kind,
+    } = entry;
+    let status = match kind {
+        EntryKind::Delivered => "delivered",
+        EntryKind::Pending => "pending",
+    };
+    ItemBuilder::default()
+        .title(format!("{}: {}", book.name, chapter.name))
+        .link(chapter.metadata.source_url(endpoints))
+        .author(chapter.author.clone())
+        .pub_date(chapter.published_at.to_rfc2822())
+        .guid(Guid {
+            value: chapter.id.to_string(),
+            permalink: false,
+        })
+        .category(Category {
+            name: book.name.clone(),
+            domain: None,
+        })
+        .category(Category {
+            name: status.into(),
+            domain: None,
+        })
+        .build()
+}
+
+#[tracing::instrument(
+name = "Creating a feed token.",
+err,
+level = "info"
+skip(db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn create_feed_token(
+    user_id: String,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<FeedTokenResponse> {
+    let token = rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect::<String>();
+    let mut conn = db_pool.get().await?;
+    diesel::insert_into(delivery_methods::table)
+        .values((
+            delivery_methods::user_id.eq(&user_id),
+            delivery_methods::feed_token.eq(&token),
+        ))
+        .on_conflict(delivery_methods::user_id)
+        .do_update()
+        .set(delivery_methods::feed_token.eq(&token))
+        .execute(&mut *conn)
+        .await?;
+    Ok(FeedTokenResponse { token })
+}
+
+#[tracing::instrument(
+name = "Revoking a feed token.",
+err,
+level = "info"
+skip(db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn revoke_feed_token(
+    user_id: String,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let mut conn = db_pool.get().await?;
+    diesel::update(delivery_methods::table.find(&user_id))
+        .set(delivery_methods::feed_token.eq(None::<String>))
+        .execute(&mut *conn)
+        .await?;
+    Ok(serde_json::Map::new())
+}
+
+pub fn get_filters(
+    db_pool: &InstrumentedPgConnectionPool,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let feed_db = db_pool.clone();
+    let feed_filter = warp::get()
+        .and(warp::path("users"))
+        .and(warp::path::param())
+        .and(warp::path("feed.xml"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::any().map(move || feed_db.clone()))
+        .then(get_feed)
+        .map(|result| map_xml(result, "application/rss+xml; charset=utf-8"));
+    let create_db = db_pool.clone();
+    let create_token_filter = warp::post()
+        .and(warp::path("users"))
+        .and(warp::path::param())
+        .and(warp::path("feed_token"))
+        .and(warp::path::end())
+        .and(warp::any().map(move || create_db.clone()))
+        .then(create_feed_token)
+        .map(map_result);
+    let revoke_db = db_pool.clone();
+    let revoke_token_filter = warp::delete()
+        .and(warp::path("users"))
+        .and(warp::path::param())
+        .and(warp::path("feed_token"))
+        .and(warp::path::end())
+        .and(warp::any().map(move || revoke_db.clone()))
+        .then(revoke_feed_token)
+        .map(map_result);
+    feed_filter.or(create_token_filter).or(revoke_token_filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{BookKind, ChapterKind, ChapterStatus, NewBook, NewChapter};
+    use crate::providers::royalroad::RoyalRoadBookKind;
+    use crate::schema::subscriptions;
+    use crate::test_support::{self, TestDatabase};
+    use chrono::{DateTime, Utc};
+
+    const USER_ID: &str = "reader";
+
+    async fn insert_chapter(
+        pool: &InstrumentedPgConnectionPool,
+        book: &Book,
+        name: &str,
+        published_at: DateTime<Utc>,
+    ) -> Uuid {
+        let mut conn = pool.get().await.unwrap();
+        diesel::insert_into(chapters::table)
+            .values((
+                NewChapter {
+                    name: name.into(),
+                    author: book.author.clone(),
+                    book_id: book.id,
+                    published_at,
+                    metadata: ChapterKind::RoyalRoad { id: 42 },
+                },
+                chapters::status.eq(ChapterStatus::Fetched),
+            ))
+            .returning(chapters::id)
+            .get_result(&mut *conn)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn renders_delivered_and_pending_chapters() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        test_support::config();
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books::table)
+            .values(NewBook {
+                name: "Mother of Learning".into(),
+                author: "nobody103".into(),
+                metadata: BookKind::RoyalRoad(RoyalRoadBookKind::new(21220)),
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let now = Utc::now();
+        let sent = insert_chapter(&db.pool, &book, "Good Morning", now).await;
+        insert_chapter(
+            &db.pool,
+            &book,
+            "Life's Little Problems",
+            now + chrono::Duration::hours(1),
+        )
+        .await;
+        let mut conn = db.pool.get().await.unwrap();
+        diesel::insert_into(subscriptions::table)
+            .values((
+                subscriptions::user_id.eq(USER_ID),
+                subscriptions::book_id.eq(book.id),
+                subscriptions::last_chapter_id.eq(sent),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        diesel::insert_into(deliveries::table)
+            .values((
+                deliveries::user_id.eq(USER_ID),
+                deliveries::book_id.eq(book.id),
+                deliveries::chapter_ids.eq(vec![sent]),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let token = create_feed_token(USER_ID.into(), db.pool.clone())
+            .await
+            .unwrap()
+            .token;
+
+        let request = |pending| FeedRequest {
+            token: token.clone(),
+            pending,
+        };
+        let feed = get_feed(USER_ID.into(), request(false), db.pool.clone())
+            .await
+            .unwrap();
+        let channel = rss::Channel::read_from(feed.as_bytes()).unwrap();
+        assert_eq!(channel.items().len(), 1);
+        let item = &channel.items()[0];
+        assert_eq!(item.title(), Some("Mother of Learning: Good Morning"));
+        assert_eq!(
+            item.link(),
+            Some("https://www.royalroad.com/fiction/chapter/42")
+        );
+        assert_eq!(item.guid().unwrap().value(), sent.to_string());
+
+        let feed = get_feed(USER_ID.into(), request(true), db.pool.clone())
+            .await
+            .unwrap();
+        let channel = rss::Channel::read_from(feed.as_bytes()).unwrap();
+        let titles = channel
+            .items()
+            .iter()
+            .map(|item| item.title().unwrap())
+            .collect_vec();
+        assert_eq!(
+            titles,
+            [
+                "Mother of Learning: Life's Little Problems",
+                "Mother of Learning: Good Morning"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_revoked_tokens() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let token = create_feed_token(USER_ID.into(), db.pool.clone())
+            .await
+            .unwrap()
+            .token;
+        revoke_feed_token(USER_ID.into(), db.pool.clone())
+            .await
+            .unwrap();
+
+        let request = FeedRequest {
+            token,
+            pending: false,
+        };
+        let err = get_feed(USER_ID.into(), request, db.pool.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::Unauthorized(_))
+        ));
+    }
+}
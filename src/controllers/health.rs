@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use diesel_async::RunQueryDsl;
+use reqwest::StatusCode;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::warn;
+use warp::{reply, Filter, Reply};
+
+use crate::config;
+use crate::preflight;
+use crate::shutdown::Shutdown;
+use crate::storage::Storage;
+use crate::supervisor::{TaskStatus, TaskStatuses};
+use crate::util::{self, error_chain, InstrumentedPgConnectionPool};
+
+/// How long a storage check result is reused, so frequent probes don't turn
+/// into a request to the bucket each.
+const BUCKET_CHECK_TTL: Duration = Duration::from_secs(60);
+
+/// How long a check may take before it counts as failed, so a hung database
+/// or bucket can't hang the probe.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    /// Whether emails and notifications are only logged, so a staging
+    /// instance can't be mistaken for a real one.
+    dry_run: bool,
+    /// Each check's outcome, `ok` or the reason it failed.
+    checks: BTreeMap<&'static str, String>,
+    /// Each background task's restarts and why it last failed, which don't
+    /// affect readiness as failed tasks are restarted.
+    tasks: BTreeMap<&'static str, TaskStatus>,
+}
+
+/// Whether each dependency answered, for load balancers which only need to
+/// know whether to send traffic here.
+#[derive(Serialize)]
+struct Health {
+    database: &'static str,
+    /// `skipped` when storage is not checked.
+    storage: &'static str,
+}
+
+impl Health {
+    fn healthy(&self) -> bool {
+        self.database != "error" && self.storage != "error"
+    }
+}
+
+struct ReadinessChecks {
+    pool: InstrumentedPgConnectionPool,
+    /// `None` when storage is not checked.
+    storage: Option<Storage>,
+    shutdown: Shutdown,
+    tasks: TaskStatuses,
+    last_bucket_check: Mutex<Option<(Instant, Result<(), String>)>>,
+}
+
+impl ReadinessChecks {
+    async fn run(&self) -> Readiness {
+        let shutdown = if self.shutdown.is_requested() {
+            Err("Shutdown has been requested.".into())
+        } else {
+            Ok(())
+        };
+        let mut checks = vec![
+            ("shutdown", shutdown),
+            (
+                "database",
+                errors_to_string(preflight::check_database(&self.pool).await),
+            ),
+            (
+                "migrations",
+                errors_to_string(util::check_db_migrations(&self.pool).await),
+            ),
+        ];
+        if let Some(storage) = &self.storage {
+            checks.push(("storage", self.check_bucket(storage).await));
+        }
+        Readiness {
+            ready: checks.iter().all(|(_, result)| result.is_ok()),
+            dry_run: config::get().dry_run,
+            checks: checks
+                .into_iter()
+                .map(|(name, result)| (name, result.err().unwrap_or_else(|| "ok".into())))
+                .collect(),
+            tasks: self.tasks.lock().unwrap().clone(),
+        }
+    }
+
+    async fn health(&self) -> Health {
+        let database = timed(async {
+            let mut conn = self.pool.get().await?;
+            diesel::sql_query("SELECT 1").execute(&mut *conn).await?;
+            Ok(())
+        });
+        let storage = async {
+            match &self.storage {
+                Some(storage) => Some(self.check_bucket(storage).await),
+                None => None,
+            }
+        };
+        let (database, storage) = tokio::join!(database, storage);
+        Health {
+            database: component("database", errors_to_string(database)),
+            storage: storage.map_or("skipped", |result| component("storage", result)),
+        }
+    }
+
+    async fn check_bucket(&self, storage: &Storage) -> Result<(), String> {
+        let mut last = self.last_bucket_check.lock().await;
+        match &*last {
+            Some((checked_at, result)) if checked_at.elapsed() < BUCKET_CHECK_TTL => result.clone(),
+            _ => {
+                let result = errors_to_string(timed(storage.check_bucket()).await);
+                *last = Some((Instant::now(), result.clone()));
+                result
+            }
+        }
+    }
+}
+
+fn errors_to_string(result: Result<()>) -> Result<(), String> {
+    result.map_err(|err| error_chain(&err))
+}
+
+async fn timed(check: impl std::future::Future<Output = Result<()>>) -> Result<()> {
+    tokio::time::timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Timed out after {:?}.", CHECK_TIMEOUT)))
+}
+
+/// `ok`, or `error` with the reason logged, as the body of `/health` is
+/// public.
+fn component(name: &'static str, result: Result<(), String>) -> &'static str {
+    match result {
+        Ok(()) => "ok",
+        Err(err) => {
+            warn!(check = name, error = %err, "Health check failed.");
+            "error"
+        }
+    }
+}
+
+/// `GET /livez`, which succeeds whenever the server is up, `GET /readyz`,
+/// which fails with the reason for each failing check while the service can't
+/// do useful work, including once shutdown is requested, and `GET /health`,
+/// which fails while the database or the bucket can't be reached.
+pub fn get_filters(
+    db_pool: &InstrumentedPgConnectionPool,
+    storage: Option<&Storage>,
+    shutdown: Shutdown,
+    tasks: TaskStatuses,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let readiness = Arc::new(ReadinessChecks {
+        pool: db_pool.clone(),
+        storage: storage.cloned(),
+        shutdown,
+        tasks,
+        last_bucket_check: Mutex::new(None),
+    });
+
+    let livez = warp::get()
+        .and(warp::path("livez"))
+        .and(warp::path::end())
+        .map(|| "ok");
+    let readyz = warp::get()
+        .and(warp::path("readyz"))
+        .and(warp::path::end())
+        .and(with_checks(readiness.clone()))
+        .then(|readiness: Arc<ReadinessChecks>| async move {
+            let readiness = readiness.run().await;
+            let status = if readiness.ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            reply::with_status(reply::json(&readiness), status)
+        });
+    let health = warp::get()
+        .and(warp::path("health"))
+        .and(warp::path::end())
+        .and(with_checks(readiness))
+        .then(|checks: Arc<ReadinessChecks>| async move {
+            let health = checks.health().await;
+            let status = if health.healthy() {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            reply::with_status(reply::json(&health), status)
+        });
+    livez.or(readyz).or(health)
+}
+
+fn with_checks(
+    checks: Arc<ReadinessChecks>,
+) -> impl Filter<Extract = (Arc<ReadinessChecks>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || checks.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::test_support::{self, TestDatabase};
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn reports_each_dependency() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let (_sender, shutdown) = Shutdown::channel();
+        let health = |storage: Option<Storage>| {
+            let routes = get_filters(
+                &db.pool,
+                storage.as_ref(),
+                shutdown.clone(),
+                TaskStatuses::default(),
+            );
+            async move {
+                let response = warp::test::request().path("/health").reply(&routes).await;
+                let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+                (response.status(), body)
+            }
+        };
+
+        let (status, body) = health(Some(test_support::storage())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({"database": "ok", "storage": "ok"}));
+        let (status, body) = health(None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["storage"], "skipped");
+
+        let bucket = MockServer::start().await;
+        Mock::given(method("HEAD"))
+            .respond_with(ResponseTemplate::new(403))
+            .mount(&bucket)
+            .await;
+        let config = StorageConfig {
+            endpoint: bucket.uri(),
+            ..test_support::config().storage.clone()
+        };
+        let storage = Storage::new(&config, None);
+        let (status, body) = health(Some(storage)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body,
+            serde_json::json!({"database": "ok", "storage": "error"})
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use metrics_exporter_prometheus::PrometheusHandle;
+use warp::log::Info;
+use warp::{Filter, Reply};
+
+/// The paths each controller's `get_filters` serves, with `:id` standing for
+/// a path parameter. Requests are labeled by these rather than by their raw
+/// paths, which would make a label for every id.
+const ROUTES: &[&str] = &[
+    "/admin/books/merge",
+    "/admin/books/stale",
+    "/admin/chapters/:id",
+    "/admin/deliveries/:id/redeliver",
+    "/admin/providers/health",
+    "/admin/storage/objects",
+    "/books",
+    "/books/:id",
+    "/books/:id/chapters",
+    "/books/:id/undelete",
+    "/books/preview",
+    "/chapters/:id/body",
+    "/chapters/:id/refetch",
+    "/convert",
+    "/deliveries/:id/download",
+    "/delivery_methods",
+    "/delivery_methods/kindle",
+    "/delivery_methods/kindle/preferences",
+    "/delivery_methods/kindle/test",
+    "/delivery_methods/kindle/validate",
+    "/delivery_methods/pushover",
+    "/delivery_methods/pushover/preferences",
+    "/delivery_methods/pushover/test",
+    "/delivery_methods/pushover/validate",
+    "/events",
+    "/livez",
+    "/metrics",
+    "/opds",
+    "/opds/books/:id",
+    "/opds/deliveries/:id/download",
+    "/preferences",
+    "/readyz",
+    "/subscriptions",
+    "/subscriptions/by_url",
+    "/unsubscribe",
+    "/users/:id",
+    "/users/:id/export",
+    "/users/:id/feed.xml",
+    "/users/:id/feed_token",
+    "/users/:id/pending",
+    "/webhooks/mailgun/inbound",
+];
+
+/// The route serving `path`, or "unmatched" for paths no route serves. Routes
+/// with fewer ids win, so /books/preview isn't taken for /books/:id.
+pub fn route_template(path: &str) -> &'static str {
+    let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+    ROUTES
+        .iter()
+        .copied()
+        .filter(|route| {
+            let route_segments = route.split('/');
+            route_segments.clone().count() == segments.len()
+                && route_segments
+                    .zip(&segments)
+                    .all(|(expected, actual)| expected == ":id" || expected == *actual)
+        })
+        .min_by_key(|route| route.matches(":id").count())
+        .unwrap_or("unmatched")
+}
+
+/// Records how long a request took, labeled by its route, method and status
+/// class.
+pub fn record_request(info: Info<'_>) {
+    let status = format!("{}xx", info.status().as_u16() / 100);
+    metrics::histogram!(
+        "http_request_duration_seconds",
+        "route" => route_template(info.path()),
+        "method" => info.method().to_string(),
+        "status" => status,
+    )
+    .record(info.elapsed().as_secs_f64());
+}
+
+pub fn get_filters(
+    handle: PrometheusHandle,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .map(move || handle.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_requests_by_route() {
+        assert_eq!(route_template("/books"), "/books");
+        assert_eq!(
+            route_template("/books/7f0c1a4e-5d47-4c53-9a53-5f1f3c6c3c1b"),
+            "/books/:id"
+        );
+        assert_eq!(
+            route_template("/users/reader/feed.xml"),
+            "/users/:id/feed.xml"
+        );
+        assert_eq!(route_template("/users/reader"), "/users/:id");
+        assert_eq!(route_template("/books/preview"), "/books/preview");
+        assert_eq!(route_template("/admin/books/stale"), "/admin/books/stale");
+        assert_eq!(route_template("/subscriptions/"), "/subscriptions");
+        assert_eq!(route_template("/wp-login.php"), "unmatched");
+        assert_eq!(route_template("/books/1/2/3"), "unmatched");
+    }
+}
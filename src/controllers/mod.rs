@@ -1,36 +1,310 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
 
-use futures::Future;
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use futures::{Future, FutureExt};
 use governor::{Quota, RateLimiter};
-use nonzero_ext::nonzero;
-use warp::Filter;
+use metrics_exporter_prometheus::PrometheusHandle;
+use tracing::{info, warn};
+use warp::{Filter, Rejection, Reply};
 
 use crate::{
-    rate_limit::ip_rate_limit_filter, rate_limit::path_method_limit_filter,
+    clock,
+    compression::compress,
+    config::{Config, ListenSocket, TlsConfig},
+    rate_limit::{
+        admin_ip_filter, ip_deny_filter, ip_rate_limit_filter, path_method_limit_filter,
+        prune_limiters,
+    },
+    shutdown::Shutdown,
+    storage::Storage,
+    supervisor::TaskStatuses,
     util::InstrumentedPgConnectionPool,
 };
 
+pub mod admin;
 pub mod books;
+pub mod chapters;
+pub mod convert;
+pub mod deliveries;
 pub mod delivery_methods;
+pub mod events;
+pub mod feeds;
+pub mod health;
+pub mod metrics;
+pub mod opds;
+pub mod pending;
+pub mod preferences;
 pub mod subscriptions;
+pub mod unsubscribe;
+pub mod users;
+pub mod webhooks;
 
-pub fn get_server_future(pool: &InstrumentedPgConnectionPool) -> impl Future<Output = ()> {
-    let ip_limiter = Arc::new(RateLimiter::keyed(Quota::per_second(nonzero!(5u32))));
-    let ip_rate_limiter = ip_rate_limit_filter(ip_limiter);
-    let api_limiter = Arc::new(RateLimiter::keyed(Quota::per_second(nonzero!(5u32))));
-    let api_rate_limiter = path_method_limit_filter(api_limiter);
+pub fn get_server_future(
+    config: &Config,
+    pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+    metrics_handle: &PrometheusHandle,
+    check_bucket: bool,
+    tasks: TaskStatuses,
+    mut shutdown: Shutdown,
+) -> Result<BoxFuture<'static, ()>> {
+    let ip_limiter = Arc::new(RateLimiter::keyed(Quota::per_second(
+        config.rate_limits.per_ip,
+    )));
+    let ip_access = Arc::new(config.ip_access.clone());
+    let ip_rate_limiter = ip_rate_limit_filter(ip_limiter.clone(), ip_access.clone());
+    let api_limiter = Arc::new(RateLimiter::keyed(Quota::per_second(
+        config.rate_limits.per_route,
+    )));
+    let api_rate_limiter = path_method_limit_filter(api_limiter.clone());
+    // Conversions run calibre, so they are limited separately and far more strictly.
+    let convert_limiter = Arc::new(RateLimiter::keyed(Quota::per_minute(
+        config.rate_limits.convert_per_minute,
+    )));
+    let convert_rate_limiter = warp::post()
+        .and(warp::path("convert"))
+        .and(ip_rate_limit_filter(
+            convert_limiter.clone(),
+            ip_access.clone(),
+        ));
+    let pruning = prune_limiters(ip_limiter, api_limiter, convert_limiter);
 
-    let book_routes = books::get_filters(pool);
-    let delivery_methods_routes = delivery_methods::get(pool);
+    let admin_routes = admin::get_filters(pool, storage);
+    let book_routes = books::get_filters(pool, storage);
+    let chapter_routes = chapters::get_filters(pool, storage);
+    let convert_routes = convert::get_filters(pool, storage, &clock::system());
+    let deliveries_routes = deliveries::get_filters(pool, storage);
+    let delivery_methods_routes = delivery_methods::get(pool, &clock::system());
+    let event_routes = events::get_filters(pool);
+    let feed_routes = feeds::get_filters(pool);
+    let health_routes = health::get_filters(
+        pool,
+        check_bucket.then_some(storage),
+        shutdown.clone(),
+        tasks,
+    );
+    let opds_routes = opds::get_filters(pool, storage);
+    let pending_routes = pending::get_filters(pool);
+    let preferences_routes = preferences::get_filters(pool);
+    let metrics_routes = metrics::get_filters(metrics_handle.clone());
     let subscription_routes = subscriptions::get_filters(pool.clone());
+    let unsubscribe_routes = unsubscribe::get_filters(pool, &clock::system());
+    let user_routes = users::get_filters(pool, storage);
+    let webhook_routes = webhooks::get_filters(pool, &clock::system());
 
-    warp::serve(
-        ip_rate_limiter
+    let shutdown_delay = config.shutdown_delay;
+    // Denied addresses are refused ahead of everything. Probes are answered
+    // before the rate limits so they can't be throttled.
+    let routes = compress(
+        ip_deny_filter(ip_access.clone())
+            .or(health_routes)
+            .or(admin_ip_filter(ip_access))
+            .or(ip_rate_limiter)
             .or(api_rate_limiter)
+            .or(convert_rate_limiter)
+            .or(admin_routes)
             .or(book_routes)
+            .or(chapter_routes)
+            .or(convert_routes)
+            .or(deliveries_routes)
             .or(delivery_methods_routes)
+            .or(event_routes)
+            .or(feed_routes)
+            .or(metrics_routes)
+            .or(opds_routes)
+            .or(pending_routes)
+            .or(preferences_routes)
             .or(subscription_routes)
-            .with(warp::trace::request()),
+            .or(unsubscribe_routes)
+            .or(user_routes)
+            .or(webhook_routes),
     )
-    .run(([0, 0, 0, 0], 3000))
+    .with(warp::log::custom(metrics::record_request))
+    .with(warp::trace::request());
+
+    // Keeps serving while readiness fails on shutdown, then stops accepting
+    // connections and waits for in-flight requests.
+    let signal = async move {
+        shutdown.requested().await;
+        info!(
+            ?shutdown_delay,
+            "Reporting not ready before closing the listener."
+        );
+        tokio::time::sleep(shutdown_delay).await;
+    };
+    #[cfg(unix)]
+    if let Some(socket) = &config.listen_socket {
+        let server = bind_socket(routes, socket, signal)?;
+        return Ok(until_done(server, pruning));
+    }
+    let server = bind(routes, config.bind_address, config.tls.as_ref(), signal).1;
+    Ok(until_done(server, pruning))
+}
+
+/// Runs `maintenance` alongside `server`, stopping it once the server is done.
+fn until_done(
+    server: BoxFuture<'static, ()>,
+    maintenance: impl Future<Output = ()> + Send + 'static,
+) -> BoxFuture<'static, ()> {
+    futures::future::select(server, maintenance.boxed())
+        .map(|_| ())
+        .boxed()
+}
+
+/// Serves `routes` on `address` until `signal` resolves, over https when a
+/// certificate is configured.
+fn bind<F>(
+    routes: F,
+    address: SocketAddr,
+    tls: Option<&TlsConfig>,
+    signal: impl Future<Output = ()> + Send + 'static,
+) -> (SocketAddr, BoxFuture<'static, ()>)
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let server = warp::serve(routes);
+    match tls {
+        Some(tls) => {
+            let (address, server) = server
+                .tls()
+                .cert_path(&tls.cert_path)
+                .key_path(&tls.key_path)
+                .bind_with_graceful_shutdown(address, signal);
+            (address, server.boxed())
+        }
+        None => {
+            let (address, server) = server.bind_with_graceful_shutdown(address, signal);
+            (address, server.boxed())
+        }
+    }
+}
+
+/// Serves `routes` on a unix socket until `signal` resolves. A socket left
+/// by a previous run is replaced, and the socket is removed once done.
+#[cfg(unix)]
+fn bind_socket<F>(
+    routes: F,
+    socket: &ListenSocket,
+    signal: impl Future<Output = ()> + Send + 'static,
+) -> Result<BoxFuture<'static, ()>>
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::UnixListener;
+
+    let path = socket.path.clone();
+    match std::fs::remove_file(&path) {
+        Ok(()) => info!(path = %path.display(), "Removed a stale socket."),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to remove the stale socket {}.", path.display()))
+        }
+    }
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to listen on {}.", path.display()))?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(socket.mode))
+        .with_context(|| format!("Failed to set the permissions of {}.", path.display()))?;
+    info!(path = %path.display(), "Listening on a unix socket.");
+
+    let incoming = futures::stream::unfold(listener, |listener| async move {
+        let accepted = listener.accept().await.map(|(stream, _)| stream);
+        Some((accepted, listener))
+    });
+    let server = warp::serve(routes).serve_incoming_with_graceful_shutdown(incoming, signal);
+    Ok(async move {
+        server.await;
+        if let Err(err) = std::fs::remove_file(&path) {
+            warn!(path = %path.display(), error = %err, "Failed to remove the socket.");
+        }
+    }
+    .boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn serves_https_with_a_certificate() {
+        let tls = TlsConfig {
+            cert_path: concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls/cert.pem").into(),
+            key_path: concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls/key.pem").into(),
+        };
+        let (stop, stopped) = oneshot::channel::<()>();
+        let (address, server) = bind(
+            warp::path::end().map(|| "ok"),
+            ([127, 0, 0, 1], 0).into(),
+            Some(&tls),
+            async move {
+                let _ = stopped.await;
+            },
+        );
+        let server = tokio::spawn(server);
+
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let response = client
+            .get(format!("https://{}/", address))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+        assert!(client
+            .get(format!("http://{}/", address))
+            .send()
+            .await
+            .is_err());
+
+        stop.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_a_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::UnixStream;
+
+        let socket = ListenSocket {
+            path: std::env::temp_dir().join(format!("cereal-{}.sock", uuid::Uuid::new_v4())),
+            mode: 0o600,
+        };
+        // Left behind by a process which didn't shut down cleanly.
+        std::fs::write(&socket.path, "").unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = bind_socket(warp::path::end().map(|| "ok"), &socket, async move {
+            let _ = stopped.await;
+        })
+        .unwrap();
+        let server = tokio::spawn(server);
+        let mode = std::fs::metadata(&socket.path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut stream = UnixStream::connect(&socket.path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("ok"));
+
+        stop.send(()).unwrap();
+        server.await.unwrap();
+        assert!(!socket.path.exists());
+    }
 }
@@ -0,0 +1,450 @@
+use std::fmt::Write;
+
+use crate::controllers::{deliveries, feeds};
+use crate::models::{
+    book_not_deleted, chapter_not_deleted, chapter_order, Book, Chapter, Delivery,
+};
+use crate::schema::{books, chapters, deliveries as deliveries_table, subscriptions};
+use crate::storage::Storage;
+use crate::tasks;
+use crate::util::{map_result, map_xml, ApiError, InstrumentedPgConnectionPool};
+
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use diesel::{ExpressionMethods, JoinOnDsl, OptionalExtension, QueryDsl};
+use diesel_async::RunQueryDsl;
+use itertools::Itertools;
+use serde::Deserialize;
+use uuid::Uuid;
+use warp::http::Uri;
+use warp::{Filter, Reply};
+
+const NAVIGATION_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ACQUISITION_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+const ACQUISITION_REL: &str = "http://opds-spec.org/acquisition";
+const EPUB_TYPE: &str = "application/epub+zip";
+
+/// The most deliveries listed in a book's feed, newest first.
+const MAX_ENTRIES: i64 = 100;
+
+/// OPDS clients are given a url once and follow links from it, so the user
+/// and feed token travel in every link's query.
+#[derive(Debug, Deserialize)]
+pub struct CatalogRequest {
+    user_id: String,
+    token: String,
+}
+
+impl CatalogRequest {
+    fn link(&self, path: &str) -> String {
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("user_id", &self.user_id)
+            .append_pair("token", &self.token)
+            .finish();
+        format!("/opds{}?{}", path, query)
+    }
+}
+
+#[tracing::instrument(
+name = "Rendering a user's OPDS catalog.",
+err,
+level = "info"
+skip(request, db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+    user_id = %request.user_id,
+)
+)]
+pub async fn get_catalog(
+    request: CatalogRequest,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<String> {
+    feeds::authorize(&request.user_id, &request.token, &db_pool).await?;
+    let books: Vec<Book> = {
+        let mut conn = db_pool.get().await?;
+        subscriptions::table
+            .inner_join(books::table.on(books::id.eq(subscriptions::book_id)))
+            .filter(subscriptions::user_id.eq(&request.user_id))
+            .filter(book_not_deleted())
+            .order(books::name.asc())
+            .select(books::all_columns)
+            .load(&mut *conn)
+            .await?
+    };
+
+    let mut catalog = Catalog::new(
+        &format!("urn:cereal:opds:{}", request.user_id),
+        "Cereal",
+        Utc::now(),
+    );
+    catalog.link("self", &request.link(""), NAVIGATION_TYPE);
+    catalog.link("start", &request.link(""), NAVIGATION_TYPE);
+    for book in books {
+        let updated = book.latest_chapter_published_at.unwrap_or(book.updated_at);
+        catalog.entry(&book.id, &book.name, &book.author, updated, |entry| {
+            entry.content(&format!("{} chapters", book.chapter_count));
+            entry.link(
+                "subsection",
+                &request.link(&format!("/books/{}", book.id)),
+                ACQUISITION_TYPE,
+            );
+        });
+    }
+    Ok(catalog.finish())
+}
+
+#[tracing::instrument(
+name = "Rendering an OPDS book feed.",
+err,
+level = "info"
+skip(request, db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+    user_id = %request.user_id,
+)
+)]
+pub async fn get_book_feed(
+    book_id: Uuid,
+    request: CatalogRequest,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<String> {
+    feeds::authorize(&request.user_id, &request.token, &db_pool).await?;
+    let mut conn = db_pool.get().await?;
+    let book: Book = subscriptions::table
+        .inner_join(books::table.on(books::id.eq(subscriptions::book_id)))
+        .filter(subscriptions::user_id.eq(&request.user_id))
+        .filter(subscriptions::book_id.eq(book_id))
+        .filter(book_not_deleted())
+        .select(books::all_columns)
+        .first(&mut *conn)
+        .await
+        .optional()?
+        .ok_or_else(|| {
+            ApiError::NotFound(format!("No subscription to book {} exists.", book_id))
+        })?;
+    let deliveries: Vec<Delivery> = deliveries_table::table
+        .filter(deliveries_table::user_id.eq(&request.user_id))
+        .filter(deliveries_table::book_id.eq(book_id))
+        .order(deliveries_table::created_at.desc())
+        .limit(MAX_ENTRIES)
+        .load(&mut *conn)
+        .await?;
+    let chapter_ids = deliveries
+        .iter()
+        .flat_map(|delivery| delivery.chapter_ids.iter())
+        .collect_vec();
+    let chapters: Vec<Chapter> = chapters::table
+        .filter(chapters::id.eq_any(chapter_ids))
+        .filter(chapter_not_deleted())
+        .order(chapter_order())
+        .load(&mut *conn)
+        .await?;
+    drop(conn);
+
+    let updated = deliveries
+        .iter()
+        .map(|delivery| delivery.created_at)
+        .max()
+        .unwrap_or(book.updated_at);
+    let mut catalog = Catalog::new(&format!("urn:uuid:{}", book.id), &book.name, updated);
+    catalog.link(
+        "self",
+        &request.link(&format!("/books/{}", book.id)),
+        ACQUISITION_TYPE,
+    );
+    catalog.link("start", &request.link(""), NAVIGATION_TYPE);
+    catalog.link("up", &request.link(""), NAVIGATION_TYPE);
+    for delivery in deliveries {
+        let delivered = chapters
+            .iter()
+            .filter(|chapter| delivery.chapter_ids.contains(&chapter.id))
+            .collect_vec();
+        if delivered.is_empty() {
+            continue;
+        }
+        let title = tasks::ebook_title(&book, &delivered);
+        catalog.entry(
+            &delivery.id,
+            &title,
+            &book.author,
+            delivery.created_at,
+            |entry| {
+                entry.link(
+                    ACQUISITION_REL,
+                    &request.link(&format!("/deliveries/{}/download", delivery.id)),
+                    EPUB_TYPE,
+                );
+            },
+        );
+    }
+    Ok(catalog.finish())
+}
+
+#[tracing::instrument(
+name = "Downloading a delivery from the OPDS catalog.",
+err,
+level = "info"
+skip(request, db_pool, storage),
+fields(
+    request_id = %Uuid::new_v4(),
+    user_id = %request.user_id,
+)
+)]
+pub async fn download(
+    delivery_id: Uuid,
+    request: CatalogRequest,
+    db_pool: InstrumentedPgConnectionPool,
+    storage: Storage,
+) -> Result<String> {
+    feeds::authorize(&request.user_id, &request.token, &db_pool).await?;
+    deliveries::presign_delivery(delivery_id, &request.user_id, &db_pool, &storage).await
+}
+
+/// Redirects to the presigned url of a download.
+fn map_redirect(result: Result<String>) -> warp::reply::Response {
+    match result.and_then(|url| Ok(url.parse::<Uri>()?)) {
+        Ok(uri) => warp::redirect::found(uri).into_response(),
+        Err(err) => map_result(Err::<(), _>(err)).into_response(),
+    }
+}
+
+/// An Atom feed with the OPDS namespace, written one element at a time.
+struct Catalog(String);
+
+impl Catalog {
+    fn new(id: &str, title: &str, updated: DateTime<Utc>) -> Self {
+        let mut xml = String::from(concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            "\n",
+            r#"<feed xmlns="http://www.w3.org/2005/Atom" xmlns:opds="http://opds-spec.org/2010/catalog">"#,
+            "\n",
+        ));
+        let _ = writeln!(xml, "<id>{}</id>", escape(id));
+        let _ = writeln!(xml, "<title>{}</title>", escape(title));
+        let _ = writeln!(xml, "<updated>{}</updated>", timestamp(updated));
+        xml.push_str("<author><name>Cereal</name></author>\n");
+        Self(xml)
+    }
+
+    fn link(&mut self, rel: &str, href: &str, media_type: &str) {
+        let _ = writeln!(
+            self.0,
+            r#"<link rel="{}" href="{}" type="{}"/>"#,
+            escape(rel),
+            escape(href),
+            escape(media_type)
+        );
+    }
+
+    fn content(&mut self, text: &str) {
+        let _ = writeln!(self.0, r#"<content type="text">{}</content>"#, escape(text));
+    }
+
+    fn entry(
+        &mut self,
+        id: &Uuid,
+        title: &str,
+        author: &str,
+        updated: DateTime<Utc>,
+        body: impl FnOnce(&mut Self),
+    ) {
+        self.0.push_str("<entry>\n");
+        let _ = writeln!(self.0, "<id>urn:uuid:{}</id>", id);
+        let _ = writeln!(self.0, "<title>{}</title>", escape(title));
+        let _ = writeln!(self.0, "<updated>{}</updated>", timestamp(updated));
+        let _ = writeln!(self.0, "<author><name>{}</name></author>", escape(author));
+        body(self);
+        self.0.push_str("</entry>\n");
+    }
+
+    fn finish(mut self) -> String {
+        self.0.push_str("</feed>\n");
+        self.0
+    }
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn get_filters(
+    db_pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let catalog_db = db_pool.clone();
+    let catalog_filter = warp::get()
+        .and(warp::path("opds"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::any().map(move || catalog_db.clone()))
+        .then(get_catalog)
+        .map(|result| map_xml(result, NAVIGATION_TYPE));
+    let book_db = db_pool.clone();
+    let book_filter = warp::get()
+        .and(warp::path("opds"))
+        .and(warp::path("books"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::any().map(move || book_db.clone()))
+        .then(get_book_feed)
+        .map(|result| map_xml(result, ACQUISITION_TYPE));
+    let download_db = db_pool.clone();
+    let download_storage = storage.clone();
+    let download_filter = warp::get()
+        .and(warp::path("opds"))
+        .and(warp::path("deliveries"))
+        .and(warp::path::param())
+        .and(warp::path("download"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::any().map(move || download_db.clone()))
+        .and(warp::any().map(move || download_storage.clone()))
+        .then(download)
+        .map(map_redirect);
+    catalog_filter.or(book_filter).or(download_filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{BookKind, ChapterKind, ChapterStatus, NewBook, NewChapter};
+    use crate::test_support::{self, TestDatabase};
+
+    const USER_ID: &str = "reader";
+
+    #[test]
+    fn escapes_text_and_attributes() {
+        assert_eq!(
+            escape(r#"<Tom & "Jerry's">"#),
+            "&lt;Tom &amp; &quot;Jerry&apos;s&quot;&gt;"
+        );
+    }
+
+    #[tokio::test]
+    async fn links_books_to_their_deliveries() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books::table)
+            .values(NewBook {
+                name: "Pale & Wan".into(),
+                author: "Wildbow".into(),
+                metadata: BookKind::Pale,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        let mut chapter_ids = Vec::new();
+        for (hours, name) in [(0, "1.1"), (1, "1.2")] {
+            let id: Uuid = diesel::insert_into(chapters::table)
+                .values((
+                    NewChapter {
+                        name: name.into(),
+                        author: book.author.clone(),
+                        book_id: book.id,
+                        published_at: Utc::now() + chrono::Duration::hours(hours),
+                        metadata: ChapterKind::Pale {
+                            url: format!("https://palewebserial.wordpress.com/{}/", name),
+                        },
+                    },
+                    chapters::status.eq(ChapterStatus::Fetched),
+                ))
+                .returning(chapters::id)
+                .get_result(&mut *conn)
+                .await
+                .unwrap();
+            chapter_ids.push(id);
+        }
+        diesel::insert_into(subscriptions::table)
+            .values((
+                subscriptions::user_id.eq(USER_ID),
+                subscriptions::book_id.eq(book.id),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        let delivery_id: Uuid = diesel::insert_into(deliveries_table::table)
+            .values((
+                deliveries_table::user_id.eq(USER_ID),
+                deliveries_table::book_id.eq(book.id),
+                deliveries_table::chapter_ids.eq(&chapter_ids),
+                deliveries_table::artifact_bucket.eq(test_support::BUCKET),
+                deliveries_table::artifact_key.eq("artifacts/pale.epub"),
+            ))
+            .returning(deliveries_table::id)
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let token = feeds::create_feed_token(USER_ID.into(), db.pool.clone())
+            .await
+            .unwrap()
+            .token;
+        let request = || CatalogRequest {
+            user_id: USER_ID.into(),
+            token: token.clone(),
+        };
+
+        let catalog = get_catalog(request(), db.pool.clone()).await.unwrap();
+        assert!(catalog.contains("<title>Pale &amp; Wan</title>"));
+        assert!(catalog.contains(&format!(
+            r#"<link rel="subsection" href="{}" type="{}"/>"#,
+            escape(&request().link(&format!("/books/{}", book.id))),
+            ACQUISITION_TYPE
+        )));
+
+        let feed = get_book_feed(book.id, request(), db.pool.clone())
+            .await
+            .unwrap();
+        assert!(feed.contains("<title>Pale &amp; Wan: 1.1 through 1.2</title>"));
+        assert!(feed.contains(&format!(
+            r#"<link rel="{}" href="{}" type="{}"/>"#,
+            ACQUISITION_REL,
+            escape(&request().link(&format!("/deliveries/{}/download", delivery_id))),
+            EPUB_TYPE
+        )));
+
+        let routes = get_filters(&db.pool, &test_support::storage());
+        let response = warp::test::request()
+            .path(&request().link(""))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.headers()["content-type"], NAVIGATION_TYPE);
+        let response = warp::test::request()
+            .path(&request().link(&format!("/deliveries/{}/download", delivery_id)))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 302);
+        assert_eq!(
+            response.headers()["location"],
+            "memory://cereal-test/artifacts/pale.epub"
+        );
+
+        let err = get_book_feed(Uuid::new_v4(), request(), db.pool.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+
+use crate::controllers::feeds;
+use crate::models::Book;
+use crate::schema::books;
+use crate::tasks::{self, QueuedChapters};
+use crate::util::{map_result, InstrumentedPgConnectionPool};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::{Filter, Reply};
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 100;
+
+/// Chapters are paged within each book, so a book with a long queue is read
+/// by passing its id with increasing offsets.
+#[derive(Debug, Deserialize)]
+pub struct PendingRequest {
+    token: String,
+    book_id: Option<Uuid>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PendingBook {
+    book: Book,
+    grouping_quantity: i64,
+    /// Every queued chapter, of which `chapters` is a page.
+    total_chapters: usize,
+    chapters: Vec<PendingChapter>,
+    next_delivery: NextDelivery,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PendingChapter {
+    id: Uuid,
+    name: String,
+    published_at: DateTime<Utc>,
+    word_count: Option<i64>,
+}
+
+/// What must happen before a book's queued chapters are delivered.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "condition", rename_all = "snake_case")]
+pub enum NextDelivery {
+    /// Enough chapters are queued for the next notification cycle to deliver
+    /// them.
+    NextCycle,
+    /// More chapters must be published first.
+    MoreChapters { needed: i64 },
+    /// The subscription is snoozed until then.
+    Snoozed { until: DateTime<Utc> },
+}
+
+impl From<&QueuedChapters> for NextDelivery {
+    fn from(queued: &QueuedChapters) -> Self {
+        match queued.subscription.snoozed_until {
+            Some(until) if until > Utc::now() => Self::Snoozed { until },
+            _ if queued.is_due() => Self::NextCycle,
+            // Subscriptions grouping zero chapters still wait for one.
+            _ => Self::MoreChapters {
+                needed: queued.chapters_needed().max(1),
+            },
+        }
+    }
+}
+
+#[tracing::instrument(
+name = "Listing a user's pending deliveries.",
+err,
+level = "info"
+skip(request, db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn get_pending(
+    user_id: String,
+    request: PendingRequest,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<Vec<PendingBook>> {
+    feeds::authorize(&user_id, &request.token, &db_pool).await?;
+    let mut queued = tasks::queued_chapters(&db_pool, Some(&user_id)).await?;
+    if let Some(book_id) = request.book_id {
+        queued.retain(|queued| queued.subscription.book_id == book_id);
+    }
+    let book_id_to_book: HashMap<Uuid, Book> = {
+        let mut conn = db_pool.get().await?;
+        books::table
+            .filter(books::id.eq_any(queued.iter().map(|queued| queued.subscription.book_id)))
+            .load::<Book>(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|book| (book.id, book))
+            .collect()
+    };
+
+    let limit = request.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let mut pending = queued
+        .into_iter()
+        .filter_map(|queued| {
+            let book = book_id_to_book.get(&queued.subscription.book_id)?.clone();
+            let next_delivery = NextDelivery::from(&queued);
+            Some(PendingBook {
+                book,
+                grouping_quantity: queued.subscription.grouping_quantity,
+                total_chapters: queued.chapters.len(),
+                chapters: queued
+                    .chapters
+                    .into_iter()
+                    .skip(request.offset)
+                    .take(limit)
+                    .map(|chap| PendingChapter {
+                        id: chap.id,
+                        name: chap.name,
+                        published_at: chap.published_at,
+                        word_count: chap.word_count,
+                    })
+                    .collect(),
+                next_delivery,
+            })
+        })
+        .collect::<Vec<_>>();
+    pending.sort_by(|a, b| a.book.name.cmp(&b.book.name));
+    Ok(pending)
+}
+
+pub fn get_filters(
+    db_pool: &InstrumentedPgConnectionPool,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let pending_db = db_pool.clone();
+    warp::get()
+        .and(warp::path("users"))
+        .and(warp::path::param())
+        .and(warp::path("pending"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::any().map(move || pending_db.clone()))
+        .then(get_pending)
+        .map(map_result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{BookKind, ChapterKind, ChapterStatus, NewBook, NewChapter};
+    use crate::providers::royalroad::RoyalRoadBookKind;
+    use crate::schema::{chapters, subscriptions};
+    use crate::test_support::TestDatabase;
+    use crate::util::ApiError;
+    use itertools::Itertools;
+
+    const USER_ID: &str = "pending-reader";
+
+    #[tokio::test]
+    async fn lists_queued_chapters_with_delivery_conditions() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books::table)
+            .values(NewBook {
+                name: "Mother of Learning".into(),
+                author: "nobody103".into(),
+                metadata: BookKind::RoyalRoad(RoyalRoadBookKind::new(21220)),
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        let now = Utc::now();
+        let mut chapter_ids = vec![];
+        for (i, name) in ["Good Morning", "Life's Little Problems", "Lessons"]
+            .into_iter()
+            .enumerate()
+        {
+            let id: Uuid = diesel::insert_into(chapters::table)
+                .values((
+                    NewChapter {
+                        name: name.into(),
+                        author: book.author.clone(),
+                        book_id: book.id,
+                        published_at: now + chrono::Duration::hours(i as i64),
+                        metadata: ChapterKind::RoyalRoad { id: 100 + i as u64 },
+                    },
+                    chapters::status.eq(ChapterStatus::Fetched),
+                ))
+                .returning(chapters::id)
+                .get_result(&mut *conn)
+                .await
+                .unwrap();
+            chapter_ids.push(id);
+        }
+        diesel::insert_into(subscriptions::table)
+            .values((
+                subscriptions::user_id.eq(USER_ID),
+                subscriptions::book_id.eq(book.id),
+                subscriptions::last_chapter_id.eq(chapter_ids[0]),
+                subscriptions::grouping_quantity.eq(2),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let token = feeds::create_feed_token(USER_ID.into(), db.pool.clone())
+            .await
+            .unwrap()
+            .token;
+        let request = |offset, limit| PendingRequest {
+            token: token.clone(),
+            book_id: Some(book.id),
+            offset,
+            limit,
+        };
+
+        let pending = get_pending(USER_ID.into(), request(0, None), db.pool.clone())
+            .await
+            .unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].total_chapters, 2);
+        assert_eq!(pending[0].next_delivery, NextDelivery::NextCycle);
+        let names = pending[0]
+            .chapters
+            .iter()
+            .map(|chap| &chap.name)
+            .collect_vec();
+        assert_eq!(names, ["Life's Little Problems", "Lessons"]);
+
+        let pending = get_pending(USER_ID.into(), request(1, Some(1)), db.pool.clone())
+            .await
+            .unwrap();
+        assert_eq!(pending[0].chapters.len(), 1);
+        assert_eq!(pending[0].chapters[0].id, chapter_ids[2]);
+
+        let mut conn = db.pool.get().await.unwrap();
+        diesel::update(subscriptions::table)
+            .filter(subscriptions::user_id.eq(USER_ID))
+            .set(subscriptions::grouping_quantity.eq(5))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let pending = get_pending(USER_ID.into(), request(0, None), db.pool.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            pending[0].next_delivery,
+            NextDelivery::MoreChapters { needed: 3 }
+        );
+
+        let forged = PendingRequest {
+            token: "forged".into(),
+            book_id: None,
+            offset: 0,
+            limit: None,
+        };
+        let err = get_pending(USER_ID.into(), forged, db.pool.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::Unauthorized(_))
+        ));
+    }
+}
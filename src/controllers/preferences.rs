@@ -0,0 +1,337 @@
+use crate::clients::calibre;
+use crate::models::{DeliveryFormat, UserPreferences};
+use crate::schema::user_preferences;
+use crate::templates::Template;
+use crate::util::{map_result, retry_read, ApiError, InstrumentedPgConnectionPool};
+
+use anyhow::Result;
+use chrono::FixedOffset;
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Deserializer, Serialize};
+use uuid::Uuid;
+use warp::{Filter, Reply};
+
+#[derive(Debug, Deserialize)]
+pub struct GetPreferencesRequest {
+    user_id: String,
+}
+
+/// Changes to a user's preferences. Omitted fields are left as they are and
+/// null clears a preference back to the default.
+#[derive(Debug, Deserialize, AsChangeset)]
+#[diesel(table_name = user_preferences)]
+#[serde(deny_unknown_fields)]
+pub struct UpdatePreferencesRequest {
+    #[diesel(skip_update)]
+    user_id: String,
+    #[serde(default, deserialize_with = "nullable")]
+    default_format: Option<Option<DeliveryFormat>>,
+    #[serde(default, deserialize_with = "nullable")]
+    default_grouping_quantity: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    output_profile: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    combine_deliveries: Option<Option<bool>>,
+    #[serde(default, deserialize_with = "nullable")]
+    quiet_hours_start: Option<Option<i16>>,
+    #[serde(default, deserialize_with = "nullable")]
+    quiet_hours_end: Option<Option<i16>>,
+    #[serde(default, deserialize_with = "nullable")]
+    timezone: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    kindle_subject_template: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pushover_template: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pushover_combined_template: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    skip_snoozed_chapters: Option<Option<bool>>,
+}
+
+/// Tells a null apart from an omitted field, which `default` leaves as None.
+pub(crate) fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl UpdatePreferencesRequest {
+    const fn is_empty(&self) -> bool {
+        self.default_format.is_none()
+            && self.default_grouping_quantity.is_none()
+            && self.output_profile.is_none()
+            && self.combine_deliveries.is_none()
+            && self.quiet_hours_start.is_none()
+            && self.quiet_hours_end.is_none()
+            && self.timezone.is_none()
+            && self.kindle_subject_template.is_none()
+            && self.pushover_template.is_none()
+            && self.pushover_combined_template.is_none()
+            && self.skip_snoozed_chapters.is_none()
+    }
+
+    fn validate(&self) -> Result<(), ApiError> {
+        if let Some(Some(quantity)) = self.default_grouping_quantity {
+            if quantity < 1 {
+                return Err(ApiError::BadRequest(
+                    "default_grouping_quantity must be at least 1.".into(),
+                ));
+            }
+        }
+        if let Some(Some(profile)) = &self.output_profile {
+            if !calibre::OUTPUT_PROFILES.contains(&profile.as_str()) {
+                return Err(ApiError::BadRequest(format!(
+                    "{profile} is not a calibre output profile."
+                )));
+            }
+        }
+        for hour in [self.quiet_hours_start, self.quiet_hours_end]
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            if !(0..24).contains(&hour) {
+                return Err(ApiError::BadRequest(format!(
+                    "{hour} is not an hour of the day."
+                )));
+            }
+        }
+        if self.quiet_hours_start.is_some() != self.quiet_hours_end.is_some()
+            || self.quiet_hours_start.flatten().is_some()
+                != self.quiet_hours_end.flatten().is_some()
+        {
+            return Err(ApiError::BadRequest(
+                "quiet_hours_start and quiet_hours_end are set together.".into(),
+            ));
+        }
+        if let Some(Some(timezone)) = &self.timezone {
+            if timezone.parse::<FixedOffset>().is_err() {
+                return Err(ApiError::BadRequest(format!(
+                    "{timezone} is not a UTC offset such as +02:00."
+                )));
+            }
+        }
+        for (name, template) in [
+            ("kindle_subject_template", &self.kindle_subject_template),
+            ("pushover_template", &self.pushover_template),
+            (
+                "pushover_combined_template",
+                &self.pushover_combined_template,
+            ),
+        ] {
+            if let Some(Some(template)) = template {
+                if let Err(err) = template.parse::<Template>() {
+                    return Err(ApiError::BadRequest(format!("{name} is invalid. {err}")));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct PreferencesResponse {
+    default_format: Option<DeliveryFormat>,
+    default_grouping_quantity: Option<i64>,
+    output_profile: Option<String>,
+    combine_deliveries: Option<bool>,
+    quiet_hours_start: Option<i16>,
+    quiet_hours_end: Option<i16>,
+    timezone: Option<String>,
+    kindle_subject_template: Option<String>,
+    pushover_template: Option<String>,
+    pushover_combined_template: Option<String>,
+    skip_snoozed_chapters: Option<bool>,
+}
+
+impl From<UserPreferences> for PreferencesResponse {
+    fn from(preferences: UserPreferences) -> Self {
+        Self {
+            default_format: preferences.default_format,
+            default_grouping_quantity: preferences.default_grouping_quantity,
+            output_profile: preferences.output_profile,
+            combine_deliveries: preferences.combine_deliveries,
+            quiet_hours_start: preferences.quiet_hours_start,
+            quiet_hours_end: preferences.quiet_hours_end,
+            timezone: preferences.timezone,
+            kindle_subject_template: preferences.kindle_subject_template,
+            pushover_template: preferences.pushover_template,
+            pushover_combined_template: preferences.pushover_combined_template,
+            skip_snoozed_chapters: preferences.skip_snoozed_chapters,
+        }
+    }
+}
+
+/// The user's preferences, if they have set any.
+pub(crate) async fn find(
+    db_pool: &InstrumentedPgConnectionPool,
+    user_id: &str,
+) -> Result<Option<UserPreferences>> {
+    // Read only, so a dropped connection is retried.
+    retry_read(|| async {
+        let mut conn = db_pool.get().await?;
+        Ok(user_preferences::table
+            .find(user_id)
+            .first(&mut *conn)
+            .await
+            .optional()?)
+    })
+    .await
+}
+
+#[tracing::instrument(
+name = "Get preferences.",
+err,
+level = "info"
+skip(db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn get_preferences(
+    db_pool: InstrumentedPgConnectionPool,
+    request: GetPreferencesRequest,
+) -> Result<PreferencesResponse> {
+    Ok(find(&db_pool, &request.user_id)
+        .await?
+        .map(PreferencesResponse::from)
+        .unwrap_or_default())
+}
+
+#[tracing::instrument(
+name = "Update preferences.",
+err,
+level = "info"
+skip(db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn update_preferences(
+    db_pool: InstrumentedPgConnectionPool,
+    request: UpdatePreferencesRequest,
+) -> Result<PreferencesResponse> {
+    request.validate()?;
+    if request.is_empty() {
+        return get_preferences(
+            db_pool,
+            GetPreferencesRequest {
+                user_id: request.user_id,
+            },
+        )
+        .await;
+    }
+    let mut conn = db_pool.get().await?;
+    diesel::insert_into(user_preferences::table)
+        .values(user_preferences::user_id.eq(&request.user_id))
+        .on_conflict_do_nothing()
+        .execute(&mut *conn)
+        .await?;
+    let preferences: UserPreferences =
+        diesel::update(user_preferences::table.find(&request.user_id))
+            .set(&request)
+            .get_result(&mut *conn)
+            .await?;
+    Ok(preferences.into())
+}
+
+pub fn get_filters(
+    db_pool: &InstrumentedPgConnectionPool,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let get_db = db_pool.clone();
+    let get_filter = warp::get()
+        .and(warp::path("preferences"))
+        .and(warp::path::end())
+        .and(warp::any().map(move || get_db.clone()))
+        .and(warp::query())
+        .then(get_preferences)
+        .map(map_result);
+    let update_db = db_pool.clone();
+    let update_filter = warp::patch()
+        .and(warp::path("preferences"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::any().map(move || update_db.clone()))
+        .and(warp::body::json())
+        .then(update_preferences)
+        .map(map_result);
+    get_filter.or(update_filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestDatabase;
+
+    #[tokio::test]
+    async fn updates_only_the_given_preferences() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let routes = get_filters(&db.pool);
+        let patch = |body: serde_json::Value| {
+            let routes = routes.clone();
+            async move {
+                warp::test::request()
+                    .method("PATCH")
+                    .path("/preferences")
+                    .json(&body)
+                    .reply(&routes)
+                    .await
+            }
+        };
+
+        let response = patch(serde_json::json!({
+            "user_id": "reader",
+            "default_format": "html",
+            "output_profile": "kobo",
+            "quiet_hours_start": 22,
+            "quiet_hours_end": 7,
+            "timezone": "-05:00",
+        }))
+        .await;
+        assert_eq!(response.status(), 200);
+        let response = patch(serde_json::json!({
+            "user_id": "reader",
+            "output_profile": null,
+            "default_grouping_quantity": 3,
+        }))
+        .await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request()
+            .path("/preferences?user_id=reader")
+            .reply(&routes)
+            .await;
+        let preferences: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            preferences,
+            serde_json::json!({
+                "default_format": "html",
+                "default_grouping_quantity": 3,
+                "output_profile": null,
+                "combine_deliveries": null,
+                "quiet_hours_start": 22,
+                "quiet_hours_end": 7,
+                "timezone": "-05:00",
+                "kindle_subject_template": null,
+                "pushover_template": null,
+                "pushover_combined_template": null,
+                "skip_snoozed_chapters": null,
+            })
+        );
+
+        for invalid in [
+            serde_json::json!({"user_id": "reader", "output_profile": "kindle_paper"}),
+            serde_json::json!({"user_id": "reader", "quiet_hours_start": 1}),
+            serde_json::json!({"user_id": "reader", "timezone": "Europe/London"}),
+            serde_json::json!({"user_id": "reader", "default_grouping_quantity": 0}),
+            serde_json::json!({"user_id": "reader", "pushover_template": "{book} {chapter}"}),
+        ] {
+            assert_eq!(patch(invalid).await.status(), 400);
+        }
+    }
+}
@@ -1,23 +1,67 @@
+use crate::clients::pushover;
+use crate::controllers::books::{self, CreateBookRequest};
+use crate::controllers::preferences;
+use crate::models::book_not_deleted;
+use crate::models::chapter_not_deleted;
+use crate::models::AuthorNotes;
 use crate::models::Book;
 use crate::models::Subscription;
 use crate::schema::subscriptions;
 
-use crate::util::{map_result, InstrumentedPgConnectionPool};
-use anyhow::anyhow;
+use crate::util::{map_result, retry_read, with_etag, ApiError, InstrumentedPgConnectionPool};
 use anyhow::Result;
-use diesel::{OptionalExtension, QueryDsl, RunQueryDsl};
-use serde::Deserialize;
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel::{OptionalExtension, QueryDsl};
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use warp::{Filter, Reply};
 
+/// The most chapters a subscription may group into one delivery.
+const MAX_GROUPING_QUANTITY: i64 = 50;
+
+fn validate_grouping_quantity(grouping_quantity: Option<i64>) -> Result<(), ApiError> {
+    match grouping_quantity {
+        Some(quantity) if !(1..=MAX_GROUPING_QUANTITY).contains(&quantity) => {
+            Err(ApiError::BadRequest(format!(
+                "grouping_quantity must be from 1 to {}, not {}.",
+                MAX_GROUPING_QUANTITY, quantity
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
 #[derive(Debug, Deserialize, Insertable)]
-#[table_name = "subscriptions"]
+#[diesel(table_name = subscriptions)]
 pub struct SubscriptionRequest {
-    book_id: Uuid,
-    user_id: String,
-    grouping_quantity: Option<i64>,
+    pub book_id: Uuid,
+    pub user_id: String,
+    pub grouping_quantity: Option<i64>,
+    pub include_author_notes: Option<AuthorNotes>,
+    pub include_comment_count: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubscribeByUrlRequest {
+    pub user_id: String,
+    pub url: String,
+    pub grouping_quantity: Option<i64>,
 }
 
+#[derive(Debug, Serialize)]
+pub struct SubscribedBook {
+    pub book: Book,
+    pub subscription: Subscription,
+}
+
+/// A subscription's grouping quantity, book, when its snooze ends and whether
+/// it is active rather than paused.
+type ListedSubscription = (i64, Book, Option<DateTime<Utc>>, bool);
+
 #[derive(Debug, Deserialize)]
 pub struct ListSubscriptionsRequest {
     user_id: String,
@@ -34,15 +78,125 @@ fields(
 )]
 pub async fn create_subscription(
     db_pool: InstrumentedPgConnectionPool,
-    body: SubscriptionRequest,
+    mut body: SubscriptionRequest,
 ) -> Result<Subscription> {
-    let conn = db_pool.get().await?;
+    validate_grouping_quantity(body.grouping_quantity)?;
+    if body.grouping_quantity.is_none() {
+        body.grouping_quantity = preferences::find(&db_pool, &body.user_id)
+            .await?
+            .and_then(|preferences| preferences.default_grouping_quantity);
+    }
+    let mut conn = db_pool.get().await?;
+    {
+        use crate::schema::books;
+        books::table
+            .find(body.book_id)
+            .filter(book_not_deleted())
+            .select(books::id)
+            .first::<Uuid>(&mut *conn)
+            .await
+            .optional()?
+            .ok_or_else(|| ApiError::NotFound(format!("Book {} does not exist.", body.book_id)))?;
+    }
     let db_result: Subscription = diesel::insert_into(subscriptions::table)
         .values(body)
-        .get_result(&*conn)?;
+        .get_result(&mut *conn)
+        .await?;
     Ok(db_result)
 }
 
+/// Adds the book at the url, or finds it if it was already added, and
+/// subscribes the user to it. Subscribing again returns the existing
+/// subscription unchanged.
+#[tracing::instrument(
+name = "Subscribing to a book by url.",
+err,
+level = "info"
+skip(db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn subscribe_by_url(
+    db_pool: InstrumentedPgConnectionPool,
+    body: SubscribeByUrlRequest,
+) -> Result<SubscribedBook> {
+    validate_grouping_quantity(body.grouping_quantity)?;
+    if books::get_book_metadata(&body.url).is_err() {
+        return Err(ApiError::BadRequest(format!(
+            "{} is not the url of a supported book.",
+            body.url
+        ))
+        .into());
+    }
+    let book = books::create_book(db_pool.clone(), CreateBookRequest::new(body.url.clone()))
+        .await
+        .with_context(|| format!("Failed to add the book at {}", body.url))?;
+    let subscription = subscribe(&db_pool, &book, body.user_id, body.grouping_quantity)
+        .await
+        .map_err(|err| match err.downcast_ref::<ApiError>() {
+            Some(ApiError::NotFound(message)) => ApiError::NotFound(format!(
+                "Added the book {} but failed to subscribe to it: {message}",
+                book.name
+            ))
+            .into(),
+            _ => err.context(format!(
+                "Added the book {} but failed to subscribe to it",
+                book.name
+            )),
+        })?;
+    Ok(SubscribedBook { book, subscription })
+}
+
+/// The user's subscription to `book`, created as `create_subscription` does
+/// if there isn't one yet.
+async fn subscribe(
+    db_pool: &InstrumentedPgConnectionPool,
+    book: &Book,
+    user_id: String,
+    grouping_quantity: Option<i64>,
+) -> Result<Subscription> {
+    let existing = || async {
+        let mut conn = db_pool.get().await?;
+        Ok::<_, anyhow::Error>(
+            subscriptions::table
+                .find((&user_id, book.id))
+                .first::<Subscription>(&mut *conn)
+                .await
+                .optional()?,
+        )
+    };
+    if let Some(subscription) = existing().await? {
+        return Ok(subscription);
+    }
+    let created = create_subscription(
+        db_pool.clone(),
+        SubscriptionRequest {
+            book_id: book.id,
+            user_id: user_id.clone(),
+            grouping_quantity,
+            include_author_notes: None,
+            include_comment_count: None,
+        },
+    )
+    .await;
+    match created {
+        // Lost a race with another request subscribing the user.
+        Err(err)
+            if matches!(
+                err.downcast_ref::<DieselError>(),
+                Some(DieselError::DatabaseError(
+                    DatabaseErrorKind::UniqueViolation,
+                    _
+                ))
+            ) =>
+        {
+            existing().await?.ok_or(err)
+        }
+        created => created,
+    }
+}
+
 #[tracing::instrument(
 name = "Listing subscriptions.",
 err,
@@ -55,20 +209,24 @@ fields(
 pub async fn list_subscriptions(
     db_pool: InstrumentedPgConnectionPool,
     body: ListSubscriptionsRequest,
-) -> Result<Vec<(i64, Book)>> {
-    let conn = db_pool.get().await?;
-    let db_result = {
-        use crate::diesel::prelude::*;
-        use crate::schema::books;
-        use crate::schema::subscriptions::dsl::*;
-        subscriptions
+) -> Result<Vec<ListedSubscription>> {
+    use crate::schema::books;
+    use crate::schema::subscriptions::dsl::*;
+    use diesel::{ExpressionMethods, JoinOnDsl};
+    // Read only, so a dropped connection is retried.
+    let db_result = retry_read(|| async {
+        let mut conn = db_pool.get().await?;
+        Ok(subscriptions
             .filter(user_id.eq(&body.user_id))
             .inner_join(books::table.on(books::id.eq(book_id)))
-            .load::<(Subscription, Book)>(&*conn)?
-            .into_iter()
-            .map(|(sub, book)| (sub.grouping_quantity, book))
-            .collect()
-    };
+            .filter(book_not_deleted())
+            .load::<(Subscription, Book)>(&mut *conn)
+            .await?)
+    })
+    .await?
+    .into_iter()
+    .map(|(sub, book)| (sub.grouping_quantity, book, sub.snoozed_until, sub.active))
+    .collect();
     Ok(db_result)
 }
 
@@ -86,9 +244,10 @@ pub async fn delete_subscription(
     body: SubscriptionRequest,
 ) -> Result<Subscription> {
     use crate::schema::subscriptions::dsl::*;
-    let conn = db_pool.get().await?;
+    let mut conn = db_pool.get().await?;
     diesel::delete(subscriptions.find((&body.user_id, &body.book_id)))
-        .get_result(&*conn)
+        .get_result(&mut *conn)
+        .await
         .optional()?
         .ok_or_else(|| {
             anyhow!(
@@ -99,18 +258,237 @@ pub async fn delete_subscription(
         })
 }
 
+#[derive(Debug, Deserialize, AsChangeset)]
+#[diesel(table_name = subscriptions)]
+#[serde(deny_unknown_fields)]
+pub struct SubscriptionPreferencesRequest {
+    #[diesel(skip_update)]
+    user_id: String,
+    #[diesel(skip_update)]
+    book_id: Uuid,
+    /// How many chapters are grouped into each delivery.
+    grouping_quantity: Option<i64>,
+    /// Which of the author's notes are added to the end of each chapter.
+    include_author_notes: Option<AuthorNotes>,
+    /// Add how many comments each chapter had when it was fetched.
+    include_comment_count: Option<bool>,
+    /// The priority pushover announcements are sent at, from -2 to 2. At 2
+    /// they repeat until acknowledged.
+    pushover_priority: Option<i16>,
+    /// Seconds between the repeats of an emergency announcement.
+    pushover_retry: Option<i32>,
+    /// Seconds an unacknowledged emergency announcement repeats for.
+    pushover_expire: Option<i32>,
+    /// Holds deliveries until this time, or null to resume them now.
+    #[serde(default, deserialize_with = "preferences::nullable")]
+    snoozed_until: Option<Option<DateTime<Utc>>>,
+}
+
+impl SubscriptionPreferencesRequest {
+    fn is_empty(&self) -> bool {
+        self.grouping_quantity.is_none()
+            && self.include_author_notes.is_none()
+            && self.include_comment_count.is_none()
+            && self.pushover_priority.is_none()
+            && self.pushover_retry.is_none()
+            && self.pushover_expire.is_none()
+            && self.snoozed_until.is_none()
+    }
+
+    /// Checks the grouping is within bounds and the pushover settings are
+    /// within the bounds Pushover accepts.
+    fn validate(&self) -> Result<(), ApiError> {
+        validate_grouping_quantity(self.grouping_quantity)?;
+        if let Some(priority) = self.pushover_priority {
+            if !(-2..=2).contains(&priority) {
+                return Err(ApiError::BadRequest(format!(
+                    "pushover_priority must be from -2 to 2, not {}.",
+                    priority
+                )));
+            }
+        }
+        if let Some(retry) = self.pushover_retry {
+            if !(pushover::MIN_RETRY_SECS..=pushover::MAX_EXPIRE_SECS).contains(&retry) {
+                return Err(ApiError::BadRequest(format!(
+                    "pushover_retry must be from {} to {} seconds, not {}.",
+                    pushover::MIN_RETRY_SECS,
+                    pushover::MAX_EXPIRE_SECS,
+                    retry
+                )));
+            }
+        }
+        if let Some(expire) = self.pushover_expire {
+            if !(1..=pushover::MAX_EXPIRE_SECS).contains(&expire) {
+                return Err(ApiError::BadRequest(format!(
+                    "pushover_expire must be from 1 to {} seconds, not {}.",
+                    pushover::MAX_EXPIRE_SECS,
+                    expire
+                )));
+            }
+        }
+        if let Some(Some(until)) = self.snoozed_until {
+            if until <= Utc::now() {
+                return Err(ApiError::BadRequest(
+                    "snoozed_until must be in the future.".into(),
+                ));
+            }
+        }
+        if let (Some(retry), Some(expire)) = (self.pushover_retry, self.pushover_expire) {
+            if retry > expire {
+                return Err(ApiError::BadRequest(
+                    "pushover_retry must not be longer than pushover_expire.".into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tracing::instrument(
+name = "Update subscription preferences.",
+err,
+level = "info"
+skip(db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn update_subscription_preferences(
+    db_pool: InstrumentedPgConnectionPool,
+    body: SubscriptionPreferencesRequest,
+) -> Result<Subscription> {
+    body.validate()?;
+    let mut conn = db_pool.get().await?;
+    let found = subscriptions::table.find((&body.user_id, &body.book_id));
+    let subscription = if body.is_empty() {
+        found.first(&mut *conn).await.optional()?
+    } else {
+        diesel::update(found)
+            .set(&body)
+            .get_result(&mut *conn)
+            .await
+            .optional()?
+    };
+    subscription.ok_or_else(|| not_subscribed(&body.user_id, &body.book_id))
+}
+
+fn not_subscribed(user_id: &str, book_id: &Uuid) -> anyhow::Error {
+    ApiError::NotFound(format!(
+        "User {} is not subscribed to book {}.",
+        user_id, book_id
+    ))
+    .into()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PauseSubscriptionRequest {
+    user_id: String,
+    book_id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResumeSubscriptionRequest {
+    user_id: String,
+    book_id: Uuid,
+    /// Deliver the chapters found while paused, rather than resuming after
+    /// the book's latest chapter.
+    #[serde(default)]
+    deliver_missed: bool,
+}
+
+/// Stops deliveries for a subscription, keeping its place in the book.
+#[tracing::instrument(
+name = "Pausing a subscription.",
+err,
+level = "info"
+skip(db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn pause_subscription(
+    db_pool: InstrumentedPgConnectionPool,
+    body: PauseSubscriptionRequest,
+) -> Result<Subscription> {
+    use diesel::ExpressionMethods;
+    let mut conn = db_pool.get().await?;
+    diesel::update(subscriptions::table.find((&body.user_id, &body.book_id)))
+        .set(subscriptions::active.eq(false))
+        .get_result(&mut *conn)
+        .await
+        .optional()?
+        .ok_or_else(|| not_subscribed(&body.user_id, &body.book_id))
+}
+
+/// Starts deliveries for a paused subscription again. Unless the missed
+/// chapters are asked for, it resumes after the book's latest chapter so the
+/// backlog isn't delivered. Resuming an active subscription changes nothing.
+#[tracing::instrument(
+name = "Resuming a subscription.",
+err,
+level = "info"
+skip(db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn resume_subscription(
+    db_pool: InstrumentedPgConnectionPool,
+    body: ResumeSubscriptionRequest,
+) -> Result<Subscription> {
+    use crate::schema::chapters;
+    use diesel::{ExpressionMethods, PgSortExpressionMethods};
+    let mut conn = db_pool.get().await?;
+    let latest_chapter_id = match body.deliver_missed {
+        true => None,
+        false => chapters::table
+            .filter(chapters::book_id.eq(body.book_id))
+            .filter(chapter_not_deleted())
+            .order((
+                chapters::ordinal.desc().nulls_last(),
+                chapters::published_at.desc(),
+                chapters::id.desc(),
+            ))
+            .select(chapters::id)
+            .first::<Uuid>(&mut *conn)
+            .await
+            .optional()?,
+    };
+    let found = subscriptions::table.find((&body.user_id, &body.book_id));
+    let resumed = diesel::update(found.filter(subscriptions::active.eq(false)))
+        .set((
+            subscriptions::active.eq(true),
+            latest_chapter_id.map(|id| subscriptions::last_chapter_id.eq(id)),
+        ))
+        .get_result(&mut *conn)
+        .await
+        .optional()?;
+    match resumed {
+        Some(subscription) => Ok(subscription),
+        None => found
+            .first(&mut *conn)
+            .await
+            .optional()?
+            .ok_or_else(|| not_subscribed(&body.user_id, &body.book_id)),
+    }
+}
+
 pub fn get_filters(
     db_pool: InstrumentedPgConnectionPool,
 ) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
     let create_sub_db = db_pool.clone();
     let list_subs_db = db_pool.clone();
-    let list_subs_filter = warp::get()
-        .and(warp::path("subscriptions"))
-        .and(warp::path::end())
-        .and(warp::any().map(move || list_subs_db.clone()))
-        .and(warp::query())
-        .then(list_subscriptions)
-        .map(map_result);
+    let list_subs_filter = with_etag(
+        warp::get()
+            .and(warp::path("subscriptions"))
+            .and(warp::path::end())
+            .and(warp::any().map(move || list_subs_db.clone()))
+            .and(warp::query())
+            .then(list_subscriptions)
+            .map(map_result),
+    );
     let create_sub_filter = warp::post()
         .and(warp::path("subscriptions"))
         .and(warp::path::end())
@@ -119,6 +497,58 @@ pub fn get_filters(
         .and(warp::body::json())
         .then(create_subscription)
         .map(map_result);
+    let preferences_db = db_pool.clone();
+    let preferences_filter = warp::post()
+        .or(warp::patch())
+        .unify()
+        .and(warp::path("subscriptions"))
+        .and(warp::path("preferences"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::any().map(move || preferences_db.clone()))
+        .and(warp::body::json())
+        .then(update_subscription_preferences)
+        .map(map_result);
+    // Changes settings in place, keeping the subscription's place in the book.
+    let update_sub_db = db_pool.clone();
+    let update_sub_filter = warp::patch()
+        .and(warp::path("subscriptions"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::any().map(move || update_sub_db.clone()))
+        .and(warp::body::json())
+        .then(update_subscription_preferences)
+        .map(map_result);
+    let pause_db = db_pool.clone();
+    let pause_filter = warp::patch()
+        .and(warp::path("subscriptions"))
+        .and(warp::path("pause"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::any().map(move || pause_db.clone()))
+        .and(warp::body::json())
+        .then(pause_subscription)
+        .map(map_result);
+    let resume_db = db_pool.clone();
+    let resume_filter = warp::patch()
+        .and(warp::path("subscriptions"))
+        .and(warp::path("resume"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::any().map(move || resume_db.clone()))
+        .and(warp::body::json())
+        .then(resume_subscription)
+        .map(map_result);
+    let by_url_db = db_pool.clone();
+    let by_url_filter = warp::post()
+        .and(warp::path("subscriptions"))
+        .and(warp::path("by_url"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::any().map(move || by_url_db.clone()))
+        .and(warp::body::json())
+        .then(subscribe_by_url)
+        .map(map_result);
     let delete_sub_filter = warp::delete()
         .and(warp::path("subscriptions"))
         .and(warp::path::end())
@@ -127,5 +557,397 @@ pub fn get_filters(
         .and(warp::body::json())
         .then(delete_subscription)
         .map(map_result);
-    create_sub_filter.or(delete_sub_filter).or(list_subs_filter)
+    create_sub_filter
+        .or(by_url_filter)
+        .or(preferences_filter)
+        .or(update_sub_filter)
+        .or(pause_filter)
+        .or(resume_filter)
+        .or(delete_sub_filter)
+        .or(list_subs_filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{BookKind, ChapterKind, NewBook, NewChapter};
+    use crate::schema::{books, chapters};
+    use crate::test_support::{self, TestDatabase};
+    use diesel::ExpressionMethods;
+
+    #[tokio::test]
+    async fn tags_subscription_lists_with_etags() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books::table)
+            .values(NewBook {
+                name: "Pale".into(),
+                author: "Wildbow".into(),
+                metadata: BookKind::Pale,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        create_subscription(
+            db.pool.clone(),
+            SubscriptionRequest {
+                book_id: book.id,
+                user_id: "reader".into(),
+                grouping_quantity: None,
+                include_author_notes: None,
+                include_comment_count: None,
+            },
+        )
+        .await
+        .unwrap();
+        let routes = get_filters(db.pool.clone());
+
+        test_support::assert_etags(&routes, "/subscriptions?user_id=reader").await;
+    }
+
+    #[tokio::test]
+    async fn updates_extras_preferences() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books::table)
+            .values(NewBook {
+                name: "Pale".into(),
+                author: "Wildbow".into(),
+                metadata: BookKind::Pale,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let created = create_subscription(
+            db.pool.clone(),
+            SubscriptionRequest {
+                book_id: book.id,
+                user_id: "reader".into(),
+                grouping_quantity: None,
+                include_author_notes: None,
+                include_comment_count: Some(true),
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(created.include_author_notes, AuthorNotes::Omit);
+        assert!(created.include_comment_count);
+        let routes = get_filters(db.pool.clone());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/subscriptions/preferences")
+            .json(&serde_json::json!({
+                "user_id": "reader",
+                "book_id": book.id,
+                "include_author_notes": "after",
+            }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        let updated: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(updated["include_author_notes"], "after");
+        assert_eq!(updated["include_comment_count"], true);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/subscriptions/preferences")
+            .json(&serde_json::json!({
+                "user_id": "someone-else",
+                "book_id": book.id,
+                "include_comment_count": false,
+            }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn subscribes_by_url_idempotently() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let routes = get_filters(db.pool.clone());
+        let subscribe = |body: serde_json::Value| {
+            let routes = routes.clone();
+            async move {
+                warp::test::request()
+                    .method("POST")
+                    .path("/subscriptions/by_url")
+                    .json(&body)
+                    .reply(&routes)
+                    .await
+            }
+        };
+
+        let request = serde_json::json!({
+            "user_id": "reader",
+            "url": "https://practicalguidetoevil.wordpress.com/",
+            "grouping_quantity": 2,
+        });
+        let first = subscribe(request.clone()).await;
+        assert_eq!(first.status(), 200);
+        let first: serde_json::Value = serde_json::from_slice(first.body()).unwrap();
+        assert_eq!(first["subscription"]["grouping_quantity"], 2);
+        assert_eq!(first["subscription"]["book_id"], first["book"]["id"]);
+        let again = subscribe(request).await;
+        assert_eq!(again.status(), 200);
+        let again: serde_json::Value = serde_json::from_slice(again.body()).unwrap();
+        assert_eq!(again, first);
+
+        let unsupported = subscribe(serde_json::json!({
+            "user_id": "reader",
+            "url": "https://example.com/a-book",
+        }))
+        .await;
+        assert_eq!(unsupported.status(), 400);
+        let message: serde_json::Value = serde_json::from_slice(unsupported.body()).unwrap();
+        assert!(message
+            .to_string()
+            .contains("not the url of a supported book"));
+    }
+
+    #[tokio::test]
+    async fn regroups_subscriptions_in_place() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books::table)
+            .values(NewBook {
+                name: "Pale".into(),
+                author: "Wildbow".into(),
+                metadata: BookKind::Pale,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let routes = get_filters(db.pool.clone());
+        let send = |method: &'static str, body: serde_json::Value| {
+            let routes = routes.clone();
+            async move {
+                warp::test::request()
+                    .method(method)
+                    .path("/subscriptions")
+                    .json(&body)
+                    .reply(&routes)
+                    .await
+            }
+        };
+
+        let created = send(
+            "POST",
+            serde_json::json!({"user_id": "reader", "book_id": book.id, "grouping_quantity": 5}),
+        )
+        .await;
+        assert_eq!(created.status(), 200);
+        let created: serde_json::Value = serde_json::from_slice(created.body()).unwrap();
+        assert_eq!(created["grouping_quantity"], 5);
+        let mut conn = db.pool.get().await.unwrap();
+        let last_chapter_id: Uuid = diesel::insert_into(chapters::table)
+            .values(NewChapter {
+                name: "1.1".into(),
+                author: book.author.clone(),
+                book_id: book.id,
+                published_at: Utc::now(),
+                metadata: ChapterKind::Pale {
+                    url: "https://palewebserial.wordpress.com/1-1/".into(),
+                },
+            })
+            .returning(chapters::id)
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        diesel::update(subscriptions::table.find(("reader", book.id)))
+            .set(subscriptions::last_chapter_id.eq(last_chapter_id))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+
+        let updated = send(
+            "PATCH",
+            serde_json::json!({"user_id": "reader", "book_id": book.id, "grouping_quantity": 3}),
+        )
+        .await;
+        assert_eq!(updated.status(), 200);
+        let updated: serde_json::Value = serde_json::from_slice(updated.body()).unwrap();
+        assert_eq!(updated["grouping_quantity"], 3);
+        assert_eq!(updated["last_chapter_id"], last_chapter_id.to_string());
+        assert_eq!(updated["created_at"], created["created_at"]);
+
+        for quantity in [0, -1, 51] {
+            let body = serde_json::json!({"user_id": "reader", "book_id": book.id, "grouping_quantity": quantity});
+            assert_eq!(send("PATCH", body.clone()).await.status(), 400);
+            assert_eq!(send("POST", body).await.status(), 400);
+        }
+        let missing = send(
+            "PATCH",
+            serde_json::json!({"user_id": "someone-else", "book_id": book.id, "grouping_quantity": 2}),
+        )
+        .await;
+        assert_eq!(missing.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn pauses_and_resumes_in_place() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books::table)
+            .values(NewBook {
+                name: "The Wandering Inn".into(),
+                author: "pirateaba".into(),
+                metadata: BookKind::TheWanderingInn,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let add_chapter = |name: &'static str| {
+            let pool = db.pool.clone();
+            let book = book.clone();
+            async move {
+                let mut conn = pool.get().await.unwrap();
+                diesel::insert_into(chapters::table)
+                    .values(NewChapter {
+                        name: name.into(),
+                        author: book.author.clone(),
+                        book_id: book.id,
+                        published_at: Utc::now(),
+                        metadata: ChapterKind::TheWanderingInn {
+                            url: format!("https://wanderinginn.com/{}/", name),
+                        },
+                    })
+                    .returning(chapters::id)
+                    .get_result::<Uuid>(&mut *conn)
+                    .await
+                    .unwrap()
+            }
+        };
+        let routes = get_filters(db.pool.clone());
+        let send = |action: &'static str, body: serde_json::Value| {
+            let routes = routes.clone();
+            async move {
+                let response = warp::test::request()
+                    .method("PATCH")
+                    .path(&format!("/subscriptions/{}", action))
+                    .json(&body)
+                    .reply(&routes)
+                    .await;
+                let status = response.status();
+                let body: serde_json::Value =
+                    serde_json::from_slice(response.body()).unwrap_or_default();
+                (status, body)
+            }
+        };
+        let reader = serde_json::json!({"user_id": "reader", "book_id": book.id});
+
+        let first = add_chapter("10.01").await;
+        let created = create_subscription(
+            db.pool.clone(),
+            SubscriptionRequest {
+                book_id: book.id,
+                user_id: "reader".into(),
+                grouping_quantity: None,
+                include_author_notes: None,
+                include_comment_count: None,
+            },
+        )
+        .await
+        .unwrap();
+        assert!(created.active);
+        let mut conn = db.pool.get().await.unwrap();
+        diesel::update(subscriptions::table.find(("reader", book.id)))
+            .set(subscriptions::last_chapter_id.eq(first))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+
+        let (status, paused) = send("pause", reader.clone()).await;
+        assert_eq!(status, 200);
+        assert_eq!(paused["active"], false);
+        assert!(crate::tasks::books_to_check(&db.pool)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(crate::tasks::queued_chapters(&db.pool, Some("reader"))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // What was published while paused is skipped.
+        let missed = add_chapter("10.02").await;
+        let (status, resumed) = send("resume", reader.clone()).await;
+        assert_eq!(status, 200);
+        assert_eq!(resumed["active"], true);
+        assert_eq!(resumed["last_chapter_id"], missed.to_string());
+        assert_eq!(resumed["created_at"], paused["created_at"]);
+        assert_eq!(
+            crate::tasks::books_to_check(&db.pool).await.unwrap().len(),
+            1
+        );
+
+        // Unless it is asked for, and resuming again changes nothing.
+        send("pause", reader.clone()).await;
+        add_chapter("10.03").await;
+        let catch_up =
+            serde_json::json!({"user_id": "reader", "book_id": book.id, "deliver_missed": true});
+        let (_, resumed) = send("resume", catch_up).await;
+        assert_eq!(resumed["last_chapter_id"], missed.to_string());
+        let (_, again) = send("resume", reader).await;
+        assert_eq!(again["last_chapter_id"], missed.to_string());
+
+        let someone_else = serde_json::json!({"user_id": "someone-else", "book_id": book.id});
+        assert_eq!(send("pause", someone_else.clone()).await.0, 404);
+        assert_eq!(send("resume", someone_else).await.0, 404);
+    }
+
+    #[test]
+    fn validates_pushover_priority() {
+        let request = |body: serde_json::Value| {
+            let mut body = body;
+            body["user_id"] = "reader".into();
+            body["book_id"] = Uuid::nil().to_string().into();
+            serde_json::from_value::<SubscriptionPreferencesRequest>(body).unwrap()
+        };
+        let invalid = |body| request(body).validate().unwrap_err().to_string();
+
+        request(serde_json::json!({"pushover_priority": 2, "pushover_retry": 30}))
+            .validate()
+            .unwrap();
+        assert_eq!(
+            invalid(serde_json::json!({"pushover_priority": 3})),
+            "pushover_priority must be from -2 to 2, not 3."
+        );
+        assert_eq!(
+            invalid(serde_json::json!({"pushover_retry": 10})),
+            "pushover_retry must be from 30 to 10800 seconds, not 10."
+        );
+        assert_eq!(
+            invalid(serde_json::json!({"pushover_expire": 86400})),
+            "pushover_expire must be from 1 to 10800 seconds, not 86400."
+        );
+        assert!(
+            invalid(serde_json::json!({"pushover_retry": 600, "pushover_expire": 300}))
+                .contains("must not be longer")
+        );
+    }
 }
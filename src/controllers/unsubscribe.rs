@@ -0,0 +1,204 @@
+use crate::clock::SharedClock;
+use crate::config;
+use crate::controllers::opds::escape;
+use crate::schema::subscriptions;
+use crate::unsubscribe;
+use crate::util::{error_chain, InstrumentedPgConnectionPool};
+
+use anyhow::Result;
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use tracing::{error, info};
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+const UNSUBSCRIBED: &str = "You've been unsubscribed, no more chapters of this book will be sent.";
+
+const INVALID: &str = "This unsubscribe link is invalid or has expired. \
+    You can still unsubscribe by replying to a chapter email with \"unsubscribe <url>\".";
+
+const FAILED: &str = "Sorry, something went wrong unsubscribing you. Please try again later.";
+
+#[derive(Debug, Deserialize)]
+pub struct UnsubscribeRequest {
+    token: String,
+}
+
+/// Deletes the subscription a signed link was sent for. The same page is
+/// shown whether or not the subscription still existed, and a refused link
+/// says nothing of who or what it was for.
+#[tracing::instrument(
+name = "Unsubscribing with a signed link.",
+level = "info"
+skip(request, db_pool, clock),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn unsubscribe(
+    request: UnsubscribeRequest,
+    db_pool: InstrumentedPgConnectionPool,
+    clock: SharedClock,
+) -> warp::reply::Response {
+    let claims = config::get()
+        .unsubscribe
+        .as_ref()
+        .and_then(|config| unsubscribe::verify(&config.key, &request.token, clock.now()));
+    let Some(claims) = claims else {
+        info!("Refusing an invalid unsubscribe link.");
+        return page(INVALID, StatusCode::BAD_REQUEST);
+    };
+    match delete_subscription(&claims.user_id, claims.book_id, &db_pool).await {
+        Ok(()) => page(UNSUBSCRIBED, StatusCode::OK),
+        Err(err) => {
+            error!(error = %error_chain(&err), "Failed to unsubscribe with a signed link.");
+            page(FAILED, StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn delete_subscription(
+    user_id: &str,
+    book_id: Uuid,
+    db_pool: &InstrumentedPgConnectionPool,
+) -> Result<()> {
+    let mut conn = db_pool.get().await?;
+    let deleted = diesel::delete(
+        subscriptions::table
+            .filter(subscriptions::user_id.eq(user_id))
+            .filter(subscriptions::book_id.eq(book_id)),
+    )
+    .execute(&mut *conn)
+    .await?;
+    info!(%user_id, %book_id, deleted, "Unsubscribed with a signed link.");
+    Ok(())
+}
+
+fn page(message: &str, status: StatusCode) -> warp::reply::Response {
+    let html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Cereal</title></head>\
+         <body><p>{}</p></body></html>\n",
+        escape(message)
+    );
+    warp::reply::with_status(warp::reply::html(html), status).into_response()
+}
+
+/// Links are opened with a GET, and mail clients' own buttons POST to them
+/// as described by RFC 8058.
+pub fn get_filters(
+    db_pool: &InstrumentedPgConnectionPool,
+    clock: &SharedClock,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let unsubscribe_db = db_pool.clone();
+    let unsubscribe_clock = clock.clone();
+    warp::get()
+        .or(warp::post())
+        .unify()
+        .and(warp::path("unsubscribe"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::any().map(move || unsubscribe_db.clone()))
+        .and(warp::any().map(move || unsubscribe_clock.clone()))
+        .then(unsubscribe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use crate::models::{Book, BookKind, NewBook, Subscription};
+    use crate::providers::royalroad::RoyalRoadBookKind;
+    use crate::schema::books;
+    use crate::test_support::{self, TestDatabase};
+
+    /// A book with a subscriber for each of `user_ids`.
+    async fn subscribed_book(pool: &InstrumentedPgConnectionPool, user_ids: &[&str]) -> Book {
+        let mut conn = pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books::table)
+            .values(NewBook {
+                name: "Mother of Learning".into(),
+                author: "nobody103".into(),
+                metadata: BookKind::RoyalRoad(RoyalRoadBookKind::new(21220)),
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        for user_id in user_ids {
+            diesel::insert_into(subscriptions::table)
+                .values((
+                    subscriptions::user_id.eq(user_id),
+                    subscriptions::book_id.eq(book.id),
+                ))
+                .execute(&mut *conn)
+                .await
+                .unwrap();
+        }
+        book
+    }
+
+    async fn subscriptions(pool: &InstrumentedPgConnectionPool) -> Vec<Subscription> {
+        let mut conn = pool.get().await.unwrap();
+        subscriptions::table.load(&mut *conn).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn unsubscribes_with_a_signed_link() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let config = test_support::config().unsubscribe.as_ref().unwrap();
+        let book = subscribed_book(&db.pool, &["reader", "someone-else"]).await;
+        let clock = ManualClock::new(chrono::Utc::now());
+        let shared: SharedClock = clock.clone();
+        let routes = get_filters(&db.pool, &shared);
+        let link = unsubscribe::link(config, "reader", book.id, clock.now());
+        let path = format!("{}?{}", link.path(), link.query().unwrap());
+
+        let response = warp::test::request().path(&path).reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        assert!(String::from_utf8_lossy(response.body()).contains(&escape(UNSUBSCRIBED)));
+        let remaining = subscriptions(&db.pool).await;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].user_id, "someone-else");
+
+        // Clicking again, or the mail client's own button, looks the same.
+        let response = warp::test::request()
+            .method("POST")
+            .path(&path)
+            .body("List-Unsubscribe=One-Click")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert!(String::from_utf8_lossy(response.body()).contains(&escape(UNSUBSCRIBED)));
+    }
+
+    #[tokio::test]
+    async fn refuses_expired_and_forged_links() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let config = test_support::config().unsubscribe.as_ref().unwrap();
+        let book = subscribed_book(&db.pool, &["reader"]).await;
+        let clock = ManualClock::new(chrono::Utc::now());
+        let shared: SharedClock = clock.clone();
+        let routes = get_filters(&db.pool, &shared);
+        let expired = unsubscribe::link(config, "reader", book.id, clock.now());
+        clock.advance(chrono::Duration::days(365));
+        let forged = unsubscribe::token("another-key", "reader", book.id, clock.now());
+
+        for path in [
+            format!("{}?{}", expired.path(), expired.query().unwrap()),
+            format!("/unsubscribe?token={}", forged),
+            "/unsubscribe?token=garbage".into(),
+        ] {
+            let response = warp::test::request().path(&path).reply(&routes).await;
+            assert_eq!(response.status(), 400);
+            assert!(String::from_utf8_lossy(response.body()).contains("invalid or has expired"));
+        }
+        assert_eq!(subscriptions(&db.pool).await.len(), 1);
+    }
+}
@@ -0,0 +1,698 @@
+use crate::controllers::feeds;
+use crate::models::{Book, Delivery, DeliveryMethod, KindleConversionMode, Subscription};
+use crate::schema::{
+    books, deliveries, delivery_methods, subscriptions, unsent_chapters, user_preferences,
+};
+use crate::storage::{Storage, StorageLocation};
+use crate::util::{error_chain, map_result, ApiError, InstrumentedPgConnectionPool};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use diesel::{BoolExpressionMethods, ExpressionMethods, JoinOnDsl, OptionalExtension, QueryDsl};
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use tracing::{error, Instrument};
+use uuid::Uuid;
+use warp::hyper::Body;
+use warp::{Filter, Reply};
+
+/// The version of the export's format, increased whenever a field is removed
+/// or changes meaning.
+pub const EXPORT_SCHEMA_VERSION: u32 = 1;
+
+/// Deliveries are read and written out this many at a time.
+const DELIVERY_PAGE_SIZE: i64 = 500;
+
+/// How much of an export is buffered ahead of the client while it is streamed.
+const STREAM_BUFFER_BYTES: usize = 64 * 1024;
+
+/// How many trailing characters of a secret are left unmasked.
+const UNMASKED_CHARS: usize = 4;
+
+#[derive(Debug, Deserialize)]
+pub struct ExportRequest {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteUserRequest {
+    token: String,
+    /// The user's id again, confirming their account is to be deleted.
+    confirm: String,
+}
+
+/// What deleting a user removed. Books and chapters are shared, so they are
+/// kept.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct UserDeletion {
+    pub user_id: String,
+    pub subscriptions_deleted: usize,
+    pub delivery_methods_deleted: bool,
+    pub unsent_chapters_deleted: usize,
+    pub deliveries_deleted: usize,
+    /// Keys of the delivered ebooks removed from storage.
+    pub objects_deleted: Vec<String>,
+}
+
+/// Everything stored about a user but their deliveries, which are streamed
+/// after it.
+#[derive(Debug, Serialize)]
+struct ExportHead {
+    schema_version: u32,
+    user_id: String,
+    exported_at: DateTime<Utc>,
+    delivery_methods: Option<ExportedDeliveryMethods>,
+    pending_verifications: Vec<PendingVerification>,
+    subscriptions: Vec<ExportedSubscription>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedDeliveryMethods {
+    kindle_email: Option<String>,
+    kindle_email_verified: bool,
+    kindle_email_enabled: bool,
+    kindle_inline_fallback: bool,
+    kindle_conversion_mode: KindleConversionMode,
+    /// Masked but for its last few characters.
+    pushover_key: Option<String>,
+    pushover_key_verified: bool,
+    pushover_enabled: bool,
+    pushover_combine_books: bool,
+    has_feed_token: bool,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+/// A verification code which was sent and not yet entered.
+#[derive(Debug, Serialize)]
+struct PendingVerification {
+    method: VerificationMethod,
+    sent_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum VerificationMethod {
+    KindleEmail,
+    Pushover,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedSubscription {
+    book: Book,
+    grouping_quantity: i64,
+    last_chapter_id: Option<Uuid>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedDelivery {
+    id: Uuid,
+    book_id: Option<Uuid>,
+    chapter_ids: Vec<Uuid>,
+    created_at: DateTime<Utc>,
+    artifact_format: Option<String>,
+    artifact_size: Option<i64>,
+    dry_run: bool,
+    degraded: bool,
+    redelivery_of: Option<Uuid>,
+    test: bool,
+    pushover_acknowledged_at: Option<DateTime<Utc>>,
+    pushover_cancelled_at: Option<DateTime<Utc>>,
+}
+
+impl From<Delivery> for ExportedDelivery {
+    fn from(delivery: Delivery) -> Self {
+        Self {
+            id: delivery.id,
+            book_id: delivery.book_id,
+            chapter_ids: delivery.chapter_ids,
+            created_at: delivery.created_at,
+            artifact_format: delivery.artifact_format,
+            artifact_size: delivery.artifact_size,
+            dry_run: delivery.dry_run,
+            degraded: delivery.degraded,
+            redelivery_of: delivery.redelivery_of,
+            test: delivery.test,
+            pushover_acknowledged_at: delivery.pushover_acknowledged_at,
+            pushover_cancelled_at: delivery.pushover_cancelled_at,
+        }
+    }
+}
+
+fn mask(secret: &str) -> String {
+    let chars = secret.chars().count();
+    secret
+        .chars()
+        .enumerate()
+        .map(|(i, c)| if i + UNMASKED_CHARS < chars { '*' } else { c })
+        .collect()
+}
+
+fn pending_verifications(methods: &DeliveryMethod) -> Vec<PendingVerification> {
+    let mut pending = Vec::new();
+    if methods.kindle_email_verification_code.is_some() && !methods.kindle_email_verified {
+        pending.push(PendingVerification {
+            method: VerificationMethod::KindleEmail,
+            sent_at: methods.kindle_email_verification_code_time,
+        });
+    }
+    if methods.pushover_verification_code.is_some() && !methods.pushover_key_verified {
+        pending.push(PendingVerification {
+            method: VerificationMethod::Pushover,
+            sent_at: methods.pushover_verification_code_time,
+        });
+    }
+    pending
+}
+
+impl From<DeliveryMethod> for ExportedDeliveryMethods {
+    fn from(methods: DeliveryMethod) -> Self {
+        Self {
+            kindle_email: methods.kindle_email,
+            kindle_email_verified: methods.kindle_email_verified,
+            kindle_email_enabled: methods.kindle_email_enabled,
+            kindle_inline_fallback: methods.kindle_inline_fallback,
+            kindle_conversion_mode: methods.kindle_conversion_mode,
+            pushover_key: methods.pushover_key.as_deref().map(mask),
+            pushover_key_verified: methods.pushover_key_verified,
+            pushover_enabled: methods.pushover_enabled,
+            pushover_combine_books: methods.pushover_combine_books,
+            has_feed_token: methods.feed_token.is_some(),
+            created_at: methods.created_at,
+            updated_at: methods.updated_at,
+        }
+    }
+}
+
+#[tracing::instrument(
+name = "Exporting a user's data.",
+err,
+level = "info"
+skip(request, db_pool),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn export_user(
+    user_id: String,
+    request: ExportRequest,
+    db_pool: InstrumentedPgConnectionPool,
+) -> Result<Body> {
+    feeds::authorize(&user_id, &request.token, &db_pool).await?;
+    let head = {
+        let mut conn = db_pool.get().await?;
+        let methods: Option<DeliveryMethod> = delivery_methods::table
+            .find(&user_id)
+            .first(&mut *conn)
+            .await
+            .optional()?;
+        // Deleted books are included, the subscriptions to them are kept.
+        let subscriptions: Vec<(Subscription, Book)> = subscriptions::table
+            .inner_join(books::table.on(books::id.eq(subscriptions::book_id)))
+            .filter(subscriptions::user_id.eq(&user_id))
+            .order(books::name.asc())
+            .load(&mut *conn)
+            .await?;
+        ExportHead {
+            schema_version: EXPORT_SCHEMA_VERSION,
+            user_id: user_id.clone(),
+            exported_at: Utc::now(),
+            pending_verifications: methods
+                .as_ref()
+                .map(pending_verifications)
+                .unwrap_or_default(),
+            delivery_methods: methods.map(Into::into),
+            subscriptions: subscriptions
+                .into_iter()
+                .map(|(sub, book)| ExportedSubscription {
+                    book,
+                    grouping_quantity: sub.grouping_quantity,
+                    last_chapter_id: sub.last_chapter_id,
+                    created_at: sub.created_at,
+                })
+                .collect(),
+        }
+    };
+
+    let (mut writer, reader) = tokio::io::duplex(STREAM_BUFFER_BYTES);
+    tokio::spawn(
+        async move {
+            // The client sees truncated json, as the status was already sent.
+            if let Err(err) = write_export(head, &db_pool, &mut writer).await {
+                error!(error = %error_chain(&err), "Failed to stream a user export.");
+            }
+        }
+        .in_current_span(),
+    );
+    Ok(Body::wrap_stream(ReaderStream::new(reader)))
+}
+
+/// Writes the export as a single json object, its deliveries last as they are
+/// read a page at a time, so a long history is never held in memory whole.
+async fn write_export<W>(
+    head: ExportHead,
+    db_pool: &InstrumentedPgConnectionPool,
+    writer: &mut W,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut json = serde_json::to_vec(&head)?;
+    // Reopens the object to append the deliveries to it.
+    json.pop();
+    json.extend_from_slice(br#","deliveries":["#);
+    writer.write_all(&json).await?;
+
+    let mut after: Option<(DateTime<Utc>, Uuid)> = None;
+    loop {
+        let page: Vec<Delivery> = {
+            let mut conn = db_pool.get().await?;
+            let mut query = deliveries::table
+                .filter(deliveries::user_id.eq(&head.user_id))
+                .order((deliveries::created_at.asc(), deliveries::id.asc()))
+                .limit(DELIVERY_PAGE_SIZE)
+                .into_boxed();
+            if let Some((created_at, id)) = after {
+                query = query.filter(
+                    deliveries::created_at
+                        .gt(created_at)
+                        .or(deliveries::created_at
+                            .eq(created_at)
+                            .and(deliveries::id.gt(id))),
+                );
+            }
+            query.load(&mut *conn).await?
+        };
+        let Some(last) = page.last() else {
+            break;
+        };
+        let first_page = after.is_none();
+        after = Some((last.created_at, last.id));
+        for (i, delivery) in page.into_iter().enumerate() {
+            if !(first_page && i == 0) {
+                writer.write_all(b",").await?;
+            }
+            let json = serde_json::to_vec(&ExportedDelivery::from(delivery))?;
+            writer.write_all(&json).await?;
+        }
+    }
+    writer.write_all(b"]}").await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+#[tracing::instrument(
+name = "Deleting a user.",
+err,
+level = "info"
+skip(request, db_pool, storage),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn delete_user(
+    user_id: String,
+    request: DeleteUserRequest,
+    db_pool: InstrumentedPgConnectionPool,
+    storage: Storage,
+) -> Result<UserDeletion> {
+    feeds::authorize(&user_id, &request.token, &db_pool).await?;
+    if request.confirm != user_id {
+        return Err(ApiError::BadRequest(format!(
+            "Pass confirm={} to delete the account and everything stored for it.",
+            user_id
+        ))
+        .into());
+    }
+    delete(&user_id, &db_pool, &storage).await
+}
+
+/// Removes everything stored for a user. Books they were the only subscriber
+/// of are kept, and stop being checked as they have no subscribers.
+pub(crate) async fn delete(
+    user_id: &str,
+    db_pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+) -> Result<UserDeletion> {
+    let mut conn = db_pool.get().await?;
+    let (
+        deleted_deliveries,
+        subscriptions_deleted,
+        delivery_methods_deleted,
+        unsent_chapters_deleted,
+    ) = conn
+        .transaction::<_, anyhow::Error, _>(async |conn| {
+            let deleted_deliveries: Vec<Delivery> =
+                diesel::delete(deliveries::table.filter(deliveries::user_id.eq(user_id)))
+                    .get_results(&mut *conn)
+                    .await?;
+            let subscriptions_deleted =
+                diesel::delete(subscriptions::table.filter(subscriptions::user_id.eq(user_id)))
+                    .execute(&mut *conn)
+                    .await?;
+            let delivery_methods_deleted = diesel::delete(delivery_methods::table.find(user_id))
+                .execute(&mut *conn)
+                .await?;
+            let unsent_chapters_deleted =
+                diesel::delete(unsent_chapters::table.filter(unsent_chapters::user_id.eq(user_id)))
+                    .execute(&mut *conn)
+                    .await?;
+            diesel::delete(user_preferences::table.find(user_id))
+                .execute(&mut *conn)
+                .await?;
+            Ok((
+                deleted_deliveries,
+                subscriptions_deleted,
+                delivery_methods_deleted > 0,
+                unsent_chapters_deleted,
+            ))
+        })
+        .await?;
+    drop(conn);
+
+    // Ebooks are removed once the rows are gone, so a failure leaves only
+    // unreferenced objects, which expire with the artifact retention anyway.
+    let artifacts: Vec<StorageLocation> = deleted_deliveries
+        .iter()
+        .filter_map(Delivery::artifact_location)
+        .collect();
+    let mut objects_deleted = Vec::new();
+    for artifact in artifacts {
+        match storage.delete(&artifact).await {
+            Ok(()) => objects_deleted.push(artifact.key),
+            Err(err) => error!(
+                error = %error_chain(&err),
+                "Failed to delete a deleted user's delivery artifact."
+            ),
+        }
+    }
+
+    Ok(UserDeletion {
+        user_id: user_id.into(),
+        subscriptions_deleted,
+        delivery_methods_deleted,
+        unsent_chapters_deleted,
+        deliveries_deleted: deleted_deliveries.len(),
+        objects_deleted,
+    })
+}
+
+fn map_export(result: Result<Body>) -> warp::reply::Response {
+    match result {
+        Ok(body) => warp::reply::with_header(
+            warp::reply::Response::new(body),
+            "content-type",
+            "application/json",
+        )
+        .into_response(),
+        Err(err) => map_result(Err::<(), _>(err)).into_response(),
+    }
+}
+
+pub fn get_filters(
+    db_pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let export_db = db_pool.clone();
+    let export_filter = warp::get()
+        .and(warp::path("users"))
+        .and(warp::path::param())
+        .and(warp::path("export"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::any().map(move || export_db.clone()))
+        .then(export_user)
+        .map(map_export);
+    let delete_db = db_pool.clone();
+    let delete_storage = storage.clone();
+    let delete_filter = warp::delete()
+        .and(warp::path("users"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::any().map(move || delete_db.clone()))
+        .and(warp::any().map(move || delete_storage.clone()))
+        .then(delete_user)
+        .map(map_result);
+    export_filter.or(delete_filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{BookKind, ChapterKind, NewBook, NewChapter, NewUnsentChapter};
+    use crate::schema::chapters;
+    use crate::tasks;
+    use crate::test_support::{self, TestDatabase};
+    use aws_sdk_s3::primitives::ByteStream;
+    use diesel::CombineDsl;
+    use serde_json::Value;
+    use std::collections::HashSet;
+
+    const USER_ID: &str = "exporter";
+    const OTHER_USER_ID: &str = "bystander";
+
+    #[test]
+    fn masks_all_but_the_end_of_secrets() {
+        assert_eq!(mask("uQiRzpo4"), "****zpo4");
+        assert_eq!(mask("abc"), "abc");
+    }
+
+    #[tokio::test]
+    async fn exports_only_the_users_own_data() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books::table)
+            .values(NewBook {
+                name: "Pale".into(),
+                author: "Wildbow".into(),
+                metadata: BookKind::Pale,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        for user_id in [USER_ID, OTHER_USER_ID] {
+            diesel::insert_into(subscriptions::table)
+                .values((
+                    subscriptions::user_id.eq(user_id),
+                    subscriptions::book_id.eq(book.id),
+                ))
+                .execute(&mut *conn)
+                .await
+                .unwrap();
+            diesel::insert_into(delivery_methods::table)
+                .values((
+                    delivery_methods::user_id.eq(user_id),
+                    delivery_methods::pushover_key.eq(format!("{}-pushover-key", user_id)),
+                    delivery_methods::pushover_verification_code.eq("123456"),
+                    delivery_methods::pushover_verification_code_time.eq(Utc::now()),
+                ))
+                .execute(&mut *conn)
+                .await
+                .unwrap();
+        }
+        let deliveries_made = DELIVERY_PAGE_SIZE as usize + 1;
+        let delivery = |user_id: &str| {
+            (
+                deliveries::user_id.eq(user_id.to_owned()),
+                deliveries::book_id.eq(book.id),
+                deliveries::chapter_ids.eq(Vec::<Uuid>::new()),
+            )
+        };
+        diesel::insert_into(deliveries::table)
+            .values(
+                (0..deliveries_made)
+                    .map(|_| delivery(USER_ID))
+                    .chain([delivery(OTHER_USER_ID)])
+                    .collect::<Vec<_>>(),
+            )
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let token = feeds::create_feed_token(USER_ID.into(), db.pool.clone())
+            .await
+            .unwrap()
+            .token;
+        let routes = get_filters(&db.pool, &test_support::storage());
+
+        let response = warp::test::request()
+            .path(&format!("/users/{}/export?token={}", USER_ID, token))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        let export: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(export["schema_version"], EXPORT_SCHEMA_VERSION);
+        assert_eq!(export["user_id"], USER_ID);
+        assert_eq!(
+            export["delivery_methods"]["pushover_key"],
+            mask("exporter-pushover-key")
+        );
+        assert_eq!(export["pending_verifications"][0]["method"], "pushover");
+        assert_eq!(export["subscriptions"].as_array().unwrap().len(), 1);
+        assert_eq!(export["subscriptions"][0]["book"]["name"], "Pale");
+        let exported = export["deliveries"].as_array().unwrap();
+        assert_eq!(exported.len(), deliveries_made);
+        let ids = exported
+            .iter()
+            .map(|delivery| delivery["id"].as_str().unwrap())
+            .collect::<HashSet<_>>();
+        assert_eq!(ids.len(), deliveries_made);
+        let body = String::from_utf8_lossy(response.body());
+        assert!(!body.contains(OTHER_USER_ID));
+        assert!(!body.contains("123456"));
+
+        let response = warp::test::request()
+            .path(&format!("/users/{}/export?token={}", OTHER_USER_ID, token))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn deletes_the_user_and_keeps_shared_books() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        test_support::config();
+        let storage = test_support::storage();
+        let mut conn = db.pool.get().await.unwrap();
+        let mut insert_book = async |name: &str, metadata: BookKind| -> Book {
+            diesel::insert_into(books::table)
+                .values(NewBook {
+                    name: name.into(),
+                    author: "Wildbow".into(),
+                    metadata,
+                    tags: Vec::new(),
+                    content_warnings: Vec::new(),
+                })
+                .get_result(&mut *conn)
+                .await
+                .unwrap()
+        };
+        let only_theirs = insert_book("Pale", BookKind::Pale).await;
+        let shared =
+            insert_book("A Practical Guide to Evil", BookKind::APracticalGuideToEvil).await;
+        let chapter_id: Uuid = diesel::insert_into(chapters::table)
+            .values(NewChapter {
+                name: "1.1".into(),
+                author: only_theirs.author.clone(),
+                book_id: only_theirs.id,
+                published_at: Utc::now(),
+                metadata: ChapterKind::Pale {
+                    url: "https://palewebserial.wordpress.com/1-1/".into(),
+                },
+            })
+            .returning(chapters::id)
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        for (user_id, book) in [
+            (USER_ID, &only_theirs),
+            (USER_ID, &shared),
+            (OTHER_USER_ID, &shared),
+        ] {
+            diesel::insert_into(subscriptions::table)
+                .values((
+                    subscriptions::user_id.eq(user_id),
+                    subscriptions::book_id.eq(book.id),
+                ))
+                .execute(&mut *conn)
+                .await
+                .unwrap();
+        }
+        diesel::insert_into(unsent_chapters::table)
+            .values(NewUnsentChapter {
+                user_id: USER_ID.into(),
+                chapter_id,
+            })
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        let artifact = storage
+            .store_artifact(&only_theirs.id, ByteStream::from_static(b"epub"))
+            .await
+            .unwrap()
+            .location;
+        for user_id in [USER_ID, OTHER_USER_ID] {
+            diesel::insert_into(deliveries::table)
+                .values((
+                    deliveries::user_id.eq(user_id),
+                    deliveries::book_id.eq(only_theirs.id),
+                    deliveries::chapter_ids.eq(vec![chapter_id]),
+                    deliveries::artifact_bucket.eq(&artifact.bucket),
+                    deliveries::artifact_key.eq(&artifact.key),
+                ))
+                .execute(&mut *conn)
+                .await
+                .unwrap();
+        }
+        diesel::update(deliveries::table.filter(deliveries::user_id.eq(OTHER_USER_ID)))
+            .set(deliveries::artifact_key.eq(None::<String>))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let token = feeds::create_feed_token(USER_ID.into(), db.pool.clone())
+            .await
+            .unwrap()
+            .token;
+        feeds::create_feed_token(OTHER_USER_ID.into(), db.pool.clone())
+            .await
+            .unwrap();
+        let routes = get_filters(&db.pool, &storage);
+        let request = |confirm: &str| {
+            warp::test::request().method("DELETE").path(&format!(
+                "/users/{}?token={}&confirm={}",
+                USER_ID, token, confirm
+            ))
+        };
+
+        let response = request(OTHER_USER_ID).reply(&routes).await;
+        assert_eq!(response.status(), 400);
+
+        let response = request(USER_ID).reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        let deletion: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            deletion,
+            serde_json::json!({
+                "user_id": USER_ID,
+                "subscriptions_deleted": 2,
+                "delivery_methods_deleted": true,
+                "unsent_chapters_deleted": 1,
+                "deliveries_deleted": 1,
+                "objects_deleted": [artifact.key],
+            })
+        );
+        assert!(storage.fetch(artifact).await.is_err());
+
+        let mut conn = db.pool.get().await.unwrap();
+        let remaining: Vec<String> = subscriptions::table
+            .select(subscriptions::user_id)
+            .union(deliveries::table.select(deliveries::user_id))
+            .union(delivery_methods::table.select(delivery_methods::user_id))
+            .load(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(remaining, [OTHER_USER_ID]);
+        let book_count: i64 = books::table.count().get_result(&mut *conn).await.unwrap();
+        assert_eq!(book_count, 2);
+        drop(conn);
+        let checked = tasks::books_to_check(&db.pool).await.unwrap();
+        assert_eq!(
+            checked.iter().map(|book| book.id).collect::<Vec<_>>(),
+            [shared.id]
+        );
+
+        let response = request(USER_ID).reply(&routes).await;
+        assert_eq!(response.status(), 401);
+    }
+}
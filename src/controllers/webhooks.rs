@@ -0,0 +1,680 @@
+use std::collections::HashMap;
+
+use crate::clients::mailgun::{self, Message};
+use crate::clock::SharedClock;
+use crate::config;
+use crate::controllers::{books, subscriptions};
+use crate::models::Book;
+use crate::schema::{delivery_methods, subscriptions as subscriptions_table};
+use crate::tasks;
+use crate::util::{error_chain, map_result, ApiError, InstrumentedPgConnectionPool};
+
+use anyhow::{anyhow, Result};
+use diesel::sql_types::{Nullable, Text};
+use diesel::{define_sql_function, ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::RunQueryDsl;
+use futures::{TryFutureExt, TryStreamExt};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use tracing::{error, info};
+use uuid::Uuid;
+use warp::hyper::body::Buf;
+use warp::multipart::FormData;
+use warp::{Filter, Reply};
+
+/// The largest inbound email accepted, attachments included.
+const MAX_INBOUND_BYTES: u64 = 25 * 1024 * 1024;
+
+/// Webhooks signed longer ago than this are rejected, so a captured request
+/// can't be replayed later.
+const MAX_WEBHOOK_AGE: chrono::Duration = chrono::Duration::minutes(15);
+
+const HELP: &str = "To subscribe, send an email with a line reading \"subscribe <url>\" \
+    or just the book's url. To unsubscribe, send a line reading \"unsubscribe <url>\".";
+
+const FAILED: &str = "Sorry, something went wrong running your command. Please try again later.";
+
+define_sql_function!(fn lower(x: Nullable<Text>) -> Nullable<Text>);
+
+/// A command parsed from an inbound email.
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Subscribe(String),
+    Unsubscribe(String),
+}
+
+/// The fields Mailgun signs each webhook with.
+#[derive(Debug, Deserialize)]
+struct WebhookSignature {
+    timestamp: String,
+    token: String,
+    signature: String,
+}
+
+/// The fields of a Mailgun route's forwarded email which are used.
+#[derive(Debug)]
+struct InboundEmail {
+    sender: String,
+    subject: String,
+    /// The body without quoted replies or signatures, when Mailgun found them.
+    body: String,
+    signature: WebhookSignature,
+}
+
+/// A Mailgun event webhook, of which only failed deliveries are acted on.
+#[derive(Debug, Deserialize)]
+pub struct EventWebhook {
+    signature: WebhookSignature,
+    #[serde(rename = "event-data")]
+    event_data: EventData,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventData {
+    event: String,
+    /// Whether a failure was `permanent` or `temporary`, which Mailgun retries.
+    #[serde(default)]
+    severity: Option<String>,
+    #[serde(default)]
+    recipient: String,
+}
+
+impl InboundEmail {
+    fn from_fields(mut fields: HashMap<String, String>) -> Self {
+        let mut take = |name: &str| fields.remove(name).unwrap_or_default();
+        let stripped = take("stripped-text");
+        let plain = take("body-plain");
+        Self {
+            sender: take("sender").trim().to_owned(),
+            subject: take("subject"),
+            body: if stripped.trim().is_empty() {
+                plain
+            } else {
+                stripped
+            },
+            signature: WebhookSignature {
+                timestamp: take("timestamp"),
+                token: take("token"),
+                signature: take("signature"),
+            },
+        }
+    }
+}
+
+#[tracing::instrument(
+name = "Receiving an inbound email.",
+err,
+level = "info"
+skip(fields, db_pool, clock),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn receive_inbound_email(
+    fields: HashMap<String, String>,
+    db_pool: InstrumentedPgConnectionPool,
+    clock: SharedClock,
+) -> Result<()> {
+    let email = InboundEmail::from_fields(fields);
+    let key = config::get()
+        .mailgun_webhook_key
+        .as_deref()
+        .ok_or_else(|| ApiError::Unauthorized("Inbound email is not configured.".into()))?;
+    verify_signature(key, &email.signature, clock.now())?;
+
+    let reply = match find_user(&email.sender, &db_pool).await? {
+        None => {
+            info!(sender = %email.sender, "Rejecting inbound email from an unknown sender.");
+            format!(
+                "Sorry, {} isn't a verified email address on Cereal, so this email was \
+                 ignored. Commands are only accepted from the address you receive chapters at.",
+                email.sender
+            )
+        }
+        Some(user_id) => match parse_command(&email.subject, &email.body) {
+            None => format!("Sorry, no command was found in your email. {}", HELP),
+            Some(command) => {
+                info!(%user_id, ?command, "Running an inbound email command.");
+                run_command(&user_id, command, &db_pool)
+                    .await
+                    .unwrap_or_else(|err| {
+                        error!(
+                            error = %error_chain(&err),
+                            "Failed to run an inbound email command."
+                        );
+                        FAILED.into()
+                    })
+            }
+        },
+    };
+    send_reply(&email, &reply).await
+}
+
+/// Checks the webhook was signed by Mailgun recently, which signs the
+/// timestamp followed by the token with the account's webhook signing key.
+fn verify_signature(
+    key: &str,
+    signed: &WebhookSignature,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<()> {
+    let invalid = || ApiError::Unauthorized("A valid webhook signature is required.".into());
+    let signature = hex::decode(&signed.signature).map_err(|_| invalid())?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes())?;
+    mac.update(signed.timestamp.as_bytes());
+    mac.update(signed.token.as_bytes());
+    mac.verify_slice(&signature).map_err(|_| invalid())?;
+
+    let signed_at = signed
+        .timestamp
+        .parse()
+        .ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .ok_or_else(invalid)?;
+    if (now - signed_at).abs() > MAX_WEBHOOK_AGE {
+        return Err(ApiError::Unauthorized("The webhook signature has expired.".into()).into());
+    }
+    Ok(())
+}
+
+#[tracing::instrument(
+name = "Receiving a mailgun event.",
+err,
+level = "info"
+skip(webhook, db_pool, clock),
+fields(
+    request_id = %Uuid::new_v4(),
+)
+)]
+pub async fn receive_event(
+    webhook: EventWebhook,
+    db_pool: InstrumentedPgConnectionPool,
+    clock: SharedClock,
+) -> Result<()> {
+    let key = config::get()
+        .mailgun_webhook_key
+        .as_deref()
+        .ok_or_else(|| ApiError::Unauthorized("Mailgun webhooks are not configured.".into()))?;
+    verify_signature(key, &webhook.signature, clock.now())?;
+
+    let event = webhook.event_data;
+    if event.event != "failed" || event.severity.as_deref() != Some("permanent") {
+        return Ok(());
+    }
+    // The chapters didn't arrive, so their emergency announcements stop
+    // repeating. Every user the address is verified for is covered.
+    let users: Vec<String> = {
+        let mut conn = db_pool.get().await?;
+        delivery_methods::table
+            .filter(lower(delivery_methods::kindle_email).eq(event.recipient.to_lowercase()))
+            .filter(delivery_methods::kindle_email_verified.eq(true))
+            .select(delivery_methods::user_id)
+            .load(&mut *conn)
+            .await?
+    };
+    for user_id in users {
+        let cancelled = tasks::cancel_pushover_receipts(&db_pool, &user_id).await?;
+        if cancelled > 0 {
+            info!(%user_id, cancelled, "Withdrew pushover notifications for a bounced email.");
+        }
+    }
+    Ok(())
+}
+
+/// The user whose verified delivery email matches `sender`, ignoring case.
+async fn find_user(sender: &str, db_pool: &InstrumentedPgConnectionPool) -> Result<Option<String>> {
+    if sender.is_empty() {
+        return Ok(None);
+    }
+    let mut conn = db_pool.get().await?;
+    let users: Vec<String> = delivery_methods::table
+        .filter(lower(delivery_methods::kindle_email).eq(sender.to_lowercase()))
+        .filter(delivery_methods::kindle_email_verified.eq(true))
+        .select(delivery_methods::user_id)
+        .load(&mut *conn)
+        .await?;
+    // An address verified by several users can't say which of them sent it.
+    match users.as_slice() {
+        [user_id] => Ok(Some(user_id.clone())),
+        _ => Ok(None),
+    }
+}
+
+/// Finds the first line of the subject or body reading `subscribe <url>`,
+/// `unsubscribe <url>` or just a url, which subscribes.
+fn parse_command(subject: &str, body: &str) -> Option<Command> {
+    subject.lines().chain(body.lines()).find_map(|line| {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [url] => parse_url(url).map(Command::Subscribe),
+            [verb, url] if verb.eq_ignore_ascii_case("subscribe") => {
+                parse_url(url).map(Command::Subscribe)
+            }
+            [verb, url] if verb.eq_ignore_ascii_case("unsubscribe") => {
+                parse_url(url).map(Command::Unsubscribe)
+            }
+            _ => None,
+        }
+    })
+}
+
+/// A url as mail clients write it, which may be wrapped in angle brackets.
+fn parse_url(word: &str) -> Option<String> {
+    let url = word.trim_start_matches('<').trim_end_matches('>');
+    url::Url::parse(url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .map(|_| url.to_owned())
+}
+
+/// Runs `command` for `user_id`, returning the reply describing its outcome.
+async fn run_command(
+    user_id: &str,
+    command: Command,
+    db_pool: &InstrumentedPgConnectionPool,
+) -> Result<String> {
+    let url = match &command {
+        Command::Subscribe(url) | Command::Unsubscribe(url) => url,
+    };
+    if books::get_book_metadata(url).is_err() {
+        return Ok(format!(
+            "Sorry, {} isn't a book Cereal knows how to follow.",
+            url
+        ));
+    }
+    match command {
+        Command::Subscribe(url) => {
+            let book =
+                books::create_book(db_pool.clone(), books::CreateBookRequest::new(url)).await?;
+            if is_subscribed(user_id, &book, db_pool).await? {
+                return Ok(format!("You're already subscribed to {}.", book.name));
+            }
+            subscriptions::create_subscription(
+                db_pool.clone(),
+                subscriptions::SubscriptionRequest {
+                    book_id: book.id,
+                    user_id: user_id.to_owned(),
+                    grouping_quantity: None,
+                    include_author_notes: None,
+                    include_comment_count: None,
+                },
+            )
+            .await?;
+            Ok(format!(
+                "You're now subscribed to {} by {}.",
+                book.name, book.author
+            ))
+        }
+        Command::Unsubscribe(url) => {
+            let book = match books::find_book(&url, db_pool).await? {
+                Some(book) if is_subscribed(user_id, &book, db_pool).await? => book,
+                _ => return Ok(format!("You weren't subscribed to {}.", url)),
+            };
+            subscriptions::delete_subscription(
+                db_pool.clone(),
+                subscriptions::SubscriptionRequest {
+                    book_id: book.id,
+                    user_id: user_id.to_owned(),
+                    grouping_quantity: None,
+                    include_author_notes: None,
+                    include_comment_count: None,
+                },
+            )
+            .await?;
+            Ok(format!("You're no longer subscribed to {}.", book.name))
+        }
+    }
+}
+
+async fn is_subscribed(
+    user_id: &str,
+    book: &Book,
+    db_pool: &InstrumentedPgConnectionPool,
+) -> Result<bool> {
+    let mut conn = db_pool.get().await?;
+    Ok(subscriptions_table::table
+        .find((user_id, book.id))
+        .select(subscriptions_table::book_id)
+        .first::<Uuid>(&mut *conn)
+        .await
+        .optional()?
+        .is_some())
+}
+
+async fn send_reply(email: &InboundEmail, text: &str) -> Result<()> {
+    // Replying to ourselves would loop.
+    let from = config::get()
+        .mailgun
+        .as_ref()
+        .map(|x| x.from_address.as_str());
+    if email.sender.is_empty() || Some(email.sender.as_str()) == from {
+        return Ok(());
+    }
+    let subject = match email.subject.trim() {
+        "" => "Cereal".to_owned(),
+        subject => format!("Re: {}", subject),
+    };
+    mailgun::send_message(Message::new(
+        &email.sender,
+        &subject,
+        Some(text),
+        None,
+        None,
+    ))
+    .await
+}
+
+/// Reads the fields of a form, urlencoded or multipart, skipping attachments.
+fn form_fields(
+) -> impl Filter<Extract = (HashMap<String, String>,), Error = warp::Rejection> + Clone {
+    let urlencoded = warp::body::form::<HashMap<String, String>>();
+    let multipart = warp::multipart::form()
+        .max_length(MAX_INBOUND_BYTES)
+        .and_then(|form: FormData| {
+            read_multipart(form).map_err(|err| {
+                info!(error = %err, "Failed to read an inbound email form.");
+                warp::reject::reject()
+            })
+        });
+    warp::body::content_length_limit(MAX_INBOUND_BYTES).and(urlencoded.or(multipart).unify())
+}
+
+async fn read_multipart(form: FormData) -> Result<HashMap<String, String>> {
+    form.map_err(|err| anyhow!(err))
+        .try_filter(|part| futures::future::ready(part.filename().is_none()))
+        .and_then(|part| async move {
+            let name = part.name().to_owned();
+            let bytes = part
+                .stream()
+                .try_fold(Vec::new(), |mut bytes, chunk| async move {
+                    bytes.extend_from_slice(chunk.chunk());
+                    Ok::<_, warp::Error>(bytes)
+                })
+                .await?;
+            Ok((name, String::from_utf8_lossy(&bytes).into_owned()))
+        })
+        .try_collect()
+        .await
+}
+
+pub fn get_filters(
+    db_pool: &InstrumentedPgConnectionPool,
+    clock: &SharedClock,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let inbound_db = db_pool.clone();
+    let inbound_clock = clock.clone();
+    let inbound_filter = warp::post()
+        .and(warp::path("webhooks"))
+        .and(warp::path("mailgun"))
+        .and(warp::path("inbound"))
+        .and(warp::path::end())
+        .and(form_fields())
+        .and(warp::any().map(move || inbound_db.clone()))
+        .and(warp::any().map(move || inbound_clock.clone()))
+        .then(receive_inbound_email)
+        .map(map_result);
+    let events_db = db_pool.clone();
+    let events_clock = clock.clone();
+    let events_filter = warp::post()
+        .and(warp::path("webhooks"))
+        .and(warp::path("mailgun"))
+        .and(warp::path("events"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::json())
+        .and(warp::any().map(move || events_db.clone()))
+        .and(warp::any().map(move || events_clock.clone()))
+        .then(receive_event)
+        .map(map_result);
+    inbound_filter.or(events_filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use crate::models::{BookKind, NewBook};
+    use crate::schema::{books, deliveries};
+    use crate::test_support::{self, TestDatabase, WEBHOOK_KEY};
+
+    const SENDER: &str = "reader@example.com";
+    const BOOK_URL: &str = "https://wanderinginn.com/";
+
+    fn sign(timestamp: &str, token: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(WEBHOOK_KEY.as_bytes()).unwrap();
+        mac.update(timestamp.as_bytes());
+        mac.update(token.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn signature(now: chrono::DateTime<chrono::Utc>) -> WebhookSignature {
+        let timestamp = now.timestamp().to_string();
+        WebhookSignature {
+            signature: sign(&timestamp, "token"),
+            timestamp,
+            token: "token".into(),
+        }
+    }
+
+    fn email(now: chrono::DateTime<chrono::Utc>, sender: &str, body: &str) -> InboundEmail {
+        InboundEmail {
+            sender: sender.into(),
+            subject: String::new(),
+            body: body.into(),
+            signature: signature(now),
+        }
+    }
+
+    fn form(email: &InboundEmail) -> String {
+        url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("sender", &email.sender)
+            .append_pair("subject", &email.subject)
+            .append_pair("body-plain", &email.body)
+            .append_pair("timestamp", &email.signature.timestamp)
+            .append_pair("token", &email.signature.token)
+            .append_pair("signature", &email.signature.signature)
+            .finish()
+    }
+
+    #[test]
+    fn verifies_signatures() {
+        // Signatures carry whole seconds.
+        let now = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let signed = signature(now);
+        verify_signature(WEBHOOK_KEY, &signed, now).unwrap();
+        verify_signature(WEBHOOK_KEY, &signed, now + MAX_WEBHOOK_AGE).unwrap();
+
+        let err = verify_signature("another-key", &signed, now).unwrap_err();
+        assert_eq!(err.to_string(), "A valid webhook signature is required.");
+        let stale = now + MAX_WEBHOOK_AGE + chrono::Duration::seconds(1);
+        let err = verify_signature(WEBHOOK_KEY, &signed, stale).unwrap_err();
+        assert_eq!(err.to_string(), "The webhook signature has expired.");
+        let tampered = WebhookSignature {
+            token: "another-token".into(),
+            ..signature(now)
+        };
+        assert!(verify_signature(WEBHOOK_KEY, &tampered, now).is_err());
+    }
+
+    #[test]
+    fn parses_commands() {
+        let subscribe = |url: &str| Some(Command::Subscribe(url.into()));
+        assert_eq!(parse_command("", BOOK_URL), subscribe(BOOK_URL));
+        assert_eq!(
+            parse_command(&format!("Subscribe {}", BOOK_URL), ""),
+            subscribe(BOOK_URL)
+        );
+        assert_eq!(
+            parse_command("Fwd: a book", &format!("Hi!\n\n  <{}>\n", BOOK_URL)),
+            subscribe(BOOK_URL)
+        );
+        assert_eq!(
+            parse_command("", &format!("UNSUBSCRIBE {}", BOOK_URL)),
+            Some(Command::Unsubscribe(BOOK_URL.into()))
+        );
+        assert_eq!(parse_command("subscribe", "please subscribe me"), None);
+        assert_eq!(parse_command("", "subscribe ftp://example.com/"), None);
+    }
+
+    #[tokio::test]
+    async fn runs_commands_from_verified_senders() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        test_support::config();
+        let clock = ManualClock::new(chrono::Utc::now());
+        {
+            let mut conn = db.pool.get().await.unwrap();
+            diesel::insert_into(delivery_methods::table)
+                .values((
+                    delivery_methods::user_id.eq("reader"),
+                    delivery_methods::kindle_email.eq("Reader@Example.com"),
+                    delivery_methods::kindle_email_verified.eq(true),
+                ))
+                .execute(&mut *conn)
+                .await
+                .unwrap();
+        }
+        let routes = get_filters(&db.pool, &(clock.clone() as SharedClock));
+        let subscribed = || async {
+            let mut conn = db.pool.get().await.unwrap();
+            subscriptions_table::table
+                .filter(subscriptions_table::user_id.eq("reader"))
+                .count()
+                .get_result::<i64>(&mut *conn)
+                .await
+                .unwrap()
+        };
+        let post = |email: InboundEmail| {
+            warp::test::request()
+                .method("POST")
+                .path("/webhooks/mailgun/inbound")
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body(form(&email))
+                .reply(&routes)
+        };
+
+        let response = post(email(clock.now(), "stranger@example.com", BOOK_URL)).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(subscribed().await, 0);
+
+        // Emails with attachments are forwarded as multipart forms.
+        let signed = email(clock.now(), SENDER, BOOK_URL);
+        let mut body = String::new();
+        for (name, value) in [
+            ("sender", signed.sender.as_str()),
+            ("body-plain", &signed.body),
+            ("timestamp", &signed.signature.timestamp),
+            ("token", &signed.signature.token),
+            ("signature", &signed.signature.signature),
+        ] {
+            body += &format!(
+                "--boundary\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                name, value
+            );
+        }
+        body += "--boundary\r\nContent-Disposition: form-data; name=\"attachment-1\"; \
+                 filename=\"cover.jpg\"\r\nContent-Type: image/jpeg\r\n\r\njpeg\r\n--boundary--\r\n";
+        let response = warp::test::request()
+            .method("POST")
+            .path("/webhooks/mailgun/inbound")
+            .header("content-type", "multipart/form-data; boundary=boundary")
+            .body(body)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(subscribed().await, 1);
+
+        // Subscribing again leaves the subscription as it was.
+        let response = post(email(clock.now(), SENDER, BOOK_URL)).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(subscribed().await, 1);
+
+        let unsubscribe = format!("unsubscribe {}", BOOK_URL);
+        let mut forged = email(clock.now(), SENDER, &unsubscribe);
+        forged.signature.signature = sign("0", "token");
+        assert_eq!(post(forged).await.status(), 401);
+        assert_eq!(subscribed().await, 1);
+
+        let response = post(email(clock.now(), SENDER, &unsubscribe)).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(subscribed().await, 0);
+    }
+
+    #[tokio::test]
+    async fn withdraws_pushover_notifications_for_bounced_emails() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        test_support::config();
+        let clock = ManualClock::new(chrono::Utc::now());
+        {
+            let mut conn = db.pool.get().await.unwrap();
+            diesel::insert_into(delivery_methods::table)
+                .values((
+                    delivery_methods::user_id.eq("reader"),
+                    delivery_methods::kindle_email.eq("Reader@Example.com"),
+                    delivery_methods::kindle_email_verified.eq(true),
+                ))
+                .execute(&mut *conn)
+                .await
+                .unwrap();
+            let book: Book = diesel::insert_into(books::table)
+                .values(NewBook {
+                    name: "Pale".into(),
+                    author: "Wildbow".into(),
+                    metadata: BookKind::Pale,
+                    tags: Vec::new(),
+                    content_warnings: Vec::new(),
+                })
+                .get_result(&mut *conn)
+                .await
+                .unwrap();
+            diesel::insert_into(deliveries::table)
+                .values((
+                    deliveries::user_id.eq("reader"),
+                    deliveries::book_id.eq(book.id),
+                    deliveries::chapter_ids.eq(Vec::<Uuid>::new()),
+                    deliveries::pushover_receipt.eq("receipt"),
+                ))
+                .execute(&mut *conn)
+                .await
+                .unwrap();
+        }
+        let routes = get_filters(&db.pool, &(clock.clone() as SharedClock));
+        let cancelled = || async {
+            let mut conn = db.pool.get().await.unwrap();
+            deliveries::table
+                .filter(deliveries::pushover_cancelled_at.is_not_null())
+                .count()
+                .get_result::<i64>(&mut *conn)
+                .await
+                .unwrap()
+        };
+        let post = |severity: &str| {
+            let signed = signature(clock.now());
+            warp::test::request()
+                .method("POST")
+                .path("/webhooks/mailgun/events")
+                .json(&serde_json::json!({
+                    "signature": {
+                        "timestamp": signed.timestamp,
+                        "token": signed.token,
+                        "signature": signed.signature,
+                    },
+                    "event-data": {
+                        "event": "failed",
+                        "severity": severity,
+                        "recipient": "reader@example.com",
+                    },
+                }))
+                .reply(&routes)
+        };
+
+        // Mailgun retries temporary failures, which may still arrive.
+        assert_eq!(post("temporary").await.status(), 200);
+        assert_eq!(cancelled().await, 0);
+        assert_eq!(post("permanent").await.status(), 200);
+        assert_eq!(cancelled().await, 1);
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::models::{Book, Chapter};
+
+/// Events buffered for each stream. A stream which falls further behind is
+/// told to re-sync rather than holding the checks and deliveries up.
+const CAPACITY: usize = 256;
+
+static EVENTS: OnceLock<broadcast::Sender<UserEvent>> = OnceLock::new();
+
+fn sender() -> &'static broadcast::Sender<UserEvent> {
+    EVENTS.get_or_init(|| broadcast::channel(CAPACITY).0)
+}
+
+/// An event for the streams opened by one user.
+#[derive(Debug, Clone)]
+pub struct UserEvent {
+    pub user_id: String,
+    pub event: Event,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// New chapters of a book the user is subscribed to were found.
+    ChaptersDiscovered {
+        book_id: Uuid,
+        book_name: String,
+        chapters: Vec<DiscoveredChapter>,
+    },
+    DeliveryCompleted {
+        delivery_id: Uuid,
+        book_id: Uuid,
+        chapter_ids: Vec<Uuid>,
+    },
+    DeliveryFailed {
+        book_id: Uuid,
+        book_name: String,
+        error: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct DiscoveredChapter {
+    pub id: Uuid,
+    pub name: String,
+    pub published_at: DateTime<Utc>,
+}
+
+impl Event {
+    pub fn chapters_discovered(book: &Book, chapters: &[Chapter]) -> Self {
+        Self::ChaptersDiscovered {
+            book_id: book.id,
+            book_name: book.name.clone(),
+            chapters: chapters
+                .iter()
+                .map(|chap| DiscoveredChapter {
+                    id: chap.id,
+                    name: chap.name.clone(),
+                    published_at: chap.published_at,
+                })
+                .collect(),
+        }
+    }
+
+    /// The name the event is sent under, matching its `type`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ChaptersDiscovered { .. } => "chapters_discovered",
+            Self::DeliveryCompleted { .. } => "delivery_completed",
+            Self::DeliveryFailed { .. } => "delivery_failed",
+        }
+    }
+}
+
+/// Whether any stream is open, so publishers can skip work that would only
+/// produce events nobody receives.
+pub fn has_listeners() -> bool {
+    sender().receiver_count() > 0
+}
+
+pub fn publish(user_id: &str, event: Event) {
+    // Sending only fails when no stream is open, in which case the event is
+    // simply dropped.
+    let _ = sender().send(UserEvent {
+        user_id: user_id.to_owned(),
+        event,
+    });
+}
+
+pub fn subscribe() -> broadcast::Receiver<UserEvent> {
+    sender().subscribe()
+}
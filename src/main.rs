@@ -1,72 +1,176 @@
+#![recursion_limit = "256"]
+mod cache;
+mod cli;
 mod clients;
+mod clock;
+mod compression;
+mod config;
 mod connection_pool;
 mod controllers;
+mod events;
 mod models;
+mod preflight;
 mod providers;
 mod rate_limit;
 mod schema;
+mod shutdown;
 mod storage;
+mod summary;
+mod supervisor;
 mod tasks;
+mod templates;
+#[cfg(test)]
+mod test_support;
+mod unsubscribe;
 mod util;
 #[macro_use]
 extern crate diesel;
 
-use anyhow::Result;
-use tokio::signal;
-use tracing::error;
+use anyhow::{Context, Result};
+use clap::Parser;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use tracing::{info, warn};
 
-use crate::{connection_pool::establish, controllers::get_server_future};
-#[macro_use]
-extern crate diesel_migrations;
+use crate::cli::{CheckArgs, Cli, Command, SendTestArgs, ServeArgs};
+use crate::clients::{http, mailgun, pushover};
+use crate::config::Config;
+use crate::supervisor::Supervisor;
+use crate::util::InstrumentedPgConnectionPool;
+use crate::{connection_pool::establish, controllers::get_server_future, storage::Storage};
 use util::configure_tracing;
 
-embed_migrations!();
-
 #[tokio::main]
 async fn main() -> Result<()> {
-    configure_tracing();
+    let cli = Cli::parse();
+    let config = config::init(Config::from_env()?);
+    let _sentry = configure_tracing(&config.telemetry);
+    http::init(&config.proxies)?;
+    if config.dry_run {
+        warn!("Running as a dry run, emails and notifications will be logged instead of sent.");
+    }
+    let metrics_handle = PrometheusBuilder::new().install_recorder()?;
+    let pool = establish(&config.database);
 
-    let pool = establish();
-    util::run_db_migrations(pool.clone()).await.unwrap();
+    let result = match cli
+        .command
+        .unwrap_or_else(|| Command::Serve(ServeArgs::default()))
+    {
+        Command::Serve(args) => serve(config, pool, metrics_handle, args).await,
+        Command::Migrate => util::run_db_migrations(pool).await,
+        Command::Check(args) => check(config, &pool, args).await,
+        Command::Deliver { user } => deliver(config, &pool, &user).await,
+        Command::SendTest(args) => send_test(args).await,
+        Command::NumberChapters => tasks::number_royalroad_chapters(&pool, &config.endpoints)
+            .await
+            .map(|_| ()),
+    };
+    // Flush spans still queued in the batch exporter.
+    opentelemetry::global::shutdown_tracer_provider();
+    result
+}
 
-    let cancel = tokio::spawn(signal::ctrl_c());
-    tokio::pin!(cancel);
-    let mut server = Box::pin(tokio::spawn(get_server_future(&pool)));
-    let mut check_for_new_chapters =
-        Box::pin(tokio::spawn(tasks::check_new_chap_loop(pool.clone())));
-    let mut send_notification =
-        Box::pin(tokio::spawn(tasks::send_notifications_loop(pool.clone())));
+async fn check(
+    config: &Config,
+    pool: &InstrumentedPgConnectionPool,
+    args: CheckArgs,
+) -> Result<()> {
+    let storage = Storage::new(&config.storage, config.email_bucket.as_ref());
+    match args.book {
+        Some(book_id) => {
+            let chapters = tasks::check_book(pool, &storage, &config.endpoints, book_id).await?;
+            info!("Found {} new chapters.", chapters.len());
+        }
+        None => tasks::check_and_queue_chapters(pool, &storage, &config.endpoints).await?,
+    }
+    Ok(())
+}
 
-    loop {
-        tokio::select! {
-        x = &mut server => {
-            error!("API server thread failed. Restarting the thread.");
-            match x {
-                Ok(_) => error!("New chapter check returned OK. This should not be possible."),
-                Err(err) => error!(?err, "New chapter check has paniced. This should not be possible."),
-            };
-            server.set(tokio::spawn(get_server_future(&pool)));
+async fn deliver(
+    config: &Config,
+    pool: &InstrumentedPgConnectionPool,
+    user_id: &str,
+) -> Result<()> {
+    let storage = Storage::new(&config.storage, config.email_bucket.as_ref());
+    let due = tasks::deliver_to_user(pool, &storage, user_id).await?;
+    info!("Delivered {} unsent chapters to {}.", due, user_id);
+    Ok(())
+}
 
-        },
-        x = &mut check_for_new_chapters => {
-            error!("New chapter check thread failed. Restarting the thread.");
-            match x {
-                Ok(_) => error!("New chapter check returned OK. This should not be possible."),
-                Err(err) => error!(?err, "New chapter check has paniced. This should not be possible."),
-            };
-            check_for_new_chapters.set(tokio::spawn(tasks::check_new_chap_loop(pool.clone())));
+async fn send_test(args: SendTestArgs) -> Result<()> {
+    if let Some(email) = args.email {
+        mailgun::send_message(mailgun::Message::new(
+            &email,
+            "Cereal test email",
+            Some("This is a test email from cereal."),
+            None,
+            None,
+        ))
+        .await?;
+        info!("Sent a test email to {}.", email);
+    }
+    if let Some(pushover_key) = args.pushover {
+        pushover::send_message(&pushover_key, "This is a test notification from cereal.").await?;
+        info!("Sent a test pushover notification.");
+    }
+    Ok(())
+}
 
-        }
-        x = &mut send_notification => {
-            error!("Chapter notification thread failed. Restarting the thread.");
-            match x {
-                Ok(_) => error!("Chapter notification thread returned OK. This should not be possible."),
-                Err(err) => error!(?err, "Chapter notification thread returned has paniced. This should not be possible."),
-            };
-            send_notification.set(tokio::spawn(tasks::send_notifications_loop(pool.clone())));
-        }
-        _ = &mut cancel => { println!("Received exit signal, exiting."); break}
-        }
+async fn serve(
+    config: &'static Config,
+    pool: InstrumentedPgConnectionPool,
+    metrics_handle: PrometheusHandle,
+    args: ServeArgs,
+) -> Result<()> {
+    let storage = Storage::new(&config.storage, config.email_bucket.as_ref());
+    let report = preflight::run(config, &pool, &storage, !args.skip_bucket_check).await;
+    report.log(args.allow_degraded);
+    report.ensure(args.allow_degraded)?;
+
+    if args.skip_migrations || config.database.skip_migrations {
+        info!("Skipping db migrations.");
+    } else {
+        util::run_db_migrations(pool.clone())
+            .await
+            .context("Startup aborted as db migrations failed.")?;
     }
+    tasks::move_embedded_daily_grind_html(&pool, &storage).await?;
+
+    let mut supervisor = Supervisor::default();
+    {
+        let (pool, storage) = (pool.clone(), storage.clone());
+        let tasks = supervisor.statuses();
+        supervisor.add("api server", move |shutdown| {
+            let server = get_server_future(
+                config,
+                &pool,
+                &storage,
+                &metrics_handle,
+                !args.skip_bucket_check,
+                tasks.clone(),
+                shutdown,
+            );
+            async move {
+                server?.await;
+                Ok(())
+            }
+        });
+    }
+    {
+        let (pool, storage) = (pool.clone(), storage.clone());
+        supervisor.add("new chapter check", move |shutdown| {
+            tasks::check_new_chap_loop(config, pool.clone(), storage.clone(), shutdown)
+        });
+    }
+    {
+        let (pool, storage) = (pool.clone(), storage.clone());
+        supervisor.add("chapter notification", move |shutdown| {
+            tasks::send_notifications_loop(config, pool.clone(), storage.clone(), shutdown)
+        });
+    }
+    supervisor.add("operational summary", move |shutdown| {
+        summary::summary_loop(config, pool.clone(), shutdown)
+    });
+
+    supervisor.run(shutdown::signal()).await;
     Ok(())
 }
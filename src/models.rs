@@ -1,24 +1,70 @@
+use crate::clients::calibre;
+use crate::clients::pushover::Priority;
+use crate::config::Endpoints;
 use crate::providers::{
     apparatus_of_change_patreon, pale, practical_guide,
     royalroad::{self, RoyalRoadBookKind},
     the_daily_grind_patreon, wandering_inn, wandering_inn_patreon,
 };
 use crate::schema::{
-    books, chapter_bodies, chapters, delivery_methods, subscriptions, unsent_chapters,
+    books, chapter_bodies, chapters, chapters_archive, deliveries, delivery_methods, subscriptions,
+    unsent_chapters, user_preferences,
 };
+use crate::storage::StorageLocation;
+use crate::templates::Templates;
 
-use anyhow::Result;
-use chrono::{DateTime, Utc};
+use anyhow::{bail, Result};
+use chrono::{DateTime, FixedOffset, Timelike, Utc};
 use derive_more::{DebugCustom, IsVariant, Unwrap};
 use diesel::{
+    deserialize::{self, FromSql},
+    dsl::{Asc, IsNull, NullsFirst},
+    pg::{Pg, PgValue},
+    serialize::{self, Output, ToSql},
     sql_types::{self},
-    types::{FromSql, ToSql},
-    Identifiable, Queryable,
+    ExpressionMethods, Identifiable, PgSortExpressionMethods, Queryable,
 };
-use rusoto_s3::S3Location;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// Implements Serialize and Deserialize for a JSONB enum so that variants
+/// unknown to this version load as `Unknown` instead of failing the query.
+/// The enum derives its serde impls with `#[serde(remote = "Self")]`, which
+/// are called for the known variants.
+macro_rules! forward_compatible_serde {
+    ($kind:ident, $variants:ident) => {
+        impl Serialize for $kind {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                match self {
+                    Self::Unknown(value) => value.serialize(serializer),
+                    known => $kind::serialize(known, serializer),
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $kind {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let value = serde_json::Value::deserialize(deserializer)?;
+                match variant_name(&value) {
+                    Some(name) if $variants.contains(&name) => {
+                        $kind::deserialize(value).map_err(serde::de::Error::custom)
+                    }
+                    _ => Ok(Self::Unknown(value)),
+                }
+            }
+        }
+    };
+}
+
+/// The variant name of an externally tagged enum serialized to JSON.
+fn variant_name(value: &serde_json::Value) -> Option<&str> {
+    match value {
+        serde_json::Value::String(name) => Some(name),
+        serde_json::Value::Object(map) if map.len() == 1 => map.keys().next().map(String::as_str),
+        _ => None,
+    }
+}
+
 #[derive(
     Debug,
     PartialEq,
@@ -32,7 +78,8 @@ use uuid::Uuid;
     IsVariant,
     Unwrap,
 )]
-#[sql_type = "sql_types::Jsonb"]
+#[diesel(sql_type = sql_types::Jsonb)]
+#[serde(remote = "Self")]
 pub enum BookKind {
     RoyalRoad(RoyalRoadBookKind),
     Pale,
@@ -41,59 +88,87 @@ pub enum BookKind {
     TheWanderingInnPatreon,
     TheDailyGrindPatreon,
     ApparatusOfChangePatreon,
+    /// A kind written by a newer version, kept as is so it round-trips.
+    #[serde(skip)]
+    Unknown(serde_json::Value),
 }
 
+const BOOK_KIND_VARIANTS: &[&str] = &[
+    "RoyalRoad",
+    "Pale",
+    "APracticalGuideToEvil",
+    "TheWanderingInn",
+    "TheWanderingInnPatreon",
+    "TheDailyGrindPatreon",
+    "ApparatusOfChangePatreon",
+];
+
+forward_compatible_serde!(BookKind, BOOK_KIND_VARIANTS);
+
 impl BookKind {
-    pub async fn to_new_book(&self) -> Result<NewBook> {
+    pub async fn to_new_book(&self, endpoints: &Endpoints) -> Result<NewBook> {
         match &self {
-            Self::RoyalRoad(x) => Ok(royalroad::as_new_book(x).await?),
+            Self::RoyalRoad(x) => Ok(royalroad::as_new_book(&endpoints.royalroad, x, false).await?),
             Self::Pale => Ok(pale::get_book()),
             Self::APracticalGuideToEvil => Ok(practical_guide::get_book()),
             Self::TheWanderingInn => Ok(wandering_inn::get_book()),
             Self::TheWanderingInnPatreon => Ok(wandering_inn_patreon::get_book()),
             Self::TheDailyGrindPatreon => Ok(the_daily_grind_patreon::get_book()),
             Self::ApparatusOfChangePatreon => Ok(apparatus_of_change_patreon::get_book()),
+            Self::Unknown(_) => bail!("Books of an unrecognized kind cannot be created."),
+        }
+    }
+
+    /// The provider the book's chapters are fetched through, as named in
+    /// metrics and provider health.
+    pub const fn provider(&self) -> Option<&'static str> {
+        match self {
+            Self::RoyalRoad(_) => Some("royalroad"),
+            Self::Pale => Some("pale"),
+            Self::APracticalGuideToEvil => Some("practical_guide"),
+            Self::TheWanderingInn => Some("wandering_inn"),
+            Self::TheWanderingInnPatreon => Some("wandering_inn_patreon"),
+            Self::TheDailyGrindPatreon => Some("the_daily_grind_patreon"),
+            Self::ApparatusOfChangePatreon => Some("apparatus_of_change_patreon"),
+            Self::Unknown(_) => None,
+        }
+    }
+
+    /// The kind without any credentials stored with it, which identifies the
+    /// book as its url does.
+    pub fn without_credentials(&self) -> Self {
+        match self {
+            Self::RoyalRoad(kind) => Self::RoyalRoad(RoyalRoadBookKind::new(kind.id)),
+            kind => kind.clone(),
         }
     }
+
+    /// Whether the book's name and author are read from its site, rather
+    /// than being fixed for the kind.
+    pub fn has_remote_metadata(&self) -> bool {
+        matches!(self, Self::RoyalRoad(_))
+    }
 }
 
-impl<DB> ToSql<sql_types::Jsonb, DB> for BookKind
-where
-    DB: diesel::backend::Backend,
-    serde_json::Value: ToSql<sql_types::Jsonb, DB>,
-{
-    fn to_sql<W: std::io::Write>(
-        &self,
-        out: &mut diesel::serialize::Output<W, DB>,
-    ) -> diesel::serialize::Result {
-        serde_json::to_value(self)?.to_sql(out)
+impl ToSql<sql_types::Jsonb, Pg> for BookKind {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        let value = serde_json::to_value(self)?;
+        <serde_json::Value as ToSql<sql_types::Jsonb, Pg>>::to_sql(&value, &mut out.reborrow())
     }
 }
 
-impl<DB> FromSql<sql_types::Jsonb, DB> for BookKind
-where
-    DB: diesel::backend::Backend,
-    serde_json::Value: FromSql<sql_types::Jsonb, DB>,
-{
-    fn from_sql(bytes: Option<&DB::RawValue>) -> diesel::deserialize::Result<Self> {
-        let value = serde_json::Value::from_sql(bytes)?;
+impl FromSql<sql_types::Jsonb, Pg> for BookKind {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let value = <serde_json::Value as FromSql<sql_types::Jsonb, Pg>>::from_sql(bytes)?;
         Ok(serde_json::from_value(value)?)
     }
 }
 
 #[derive(
-    DebugCustom,
-    PartialEq,
-    Serialize,
-    Deserialize,
-    AsExpression,
-    FromSqlRow,
-    Hash,
-    Eq,
-    Ord,
-    PartialOrd,
+    DebugCustom, PartialEq, Serialize, Deserialize, AsExpression, FromSqlRow, Hash, Eq, Clone,
 )]
-#[sql_type = "sql_types::Jsonb"]
+#[diesel(sql_type = sql_types::Jsonb)]
+#[serde(remote = "Self")]
 pub enum ChapterKind {
     RoyalRoad {
         id: u64,
@@ -112,46 +187,85 @@ pub enum ChapterKind {
         url: String,
         password: Option<String>,
     },
-    #[debug(fmt = "TheDailyGrindPatreon")]
+    /// The email body, stored as a source document.
+    #[debug(fmt = "TheDailyGrindPatreon {}", key)]
     TheDailyGrindPatreon {
-        html: String,
+        bucket: String,
+        key: String,
     },
     #[debug(fmt = "ApparatusOfChangePatreon")]
     ApparatusOfChangePatreon {
         html: String,
     },
+    /// A kind written by a newer version, kept as is so it round-trips.
+    #[debug(fmt = "Unknown")]
+    #[serde(skip)]
+    Unknown(serde_json::Value),
 }
 
-impl<DB> ToSql<sql_types::Jsonb, DB> for ChapterKind
-where
-    DB: diesel::backend::Backend,
-    serde_json::Value: ToSql<sql_types::Jsonb, DB>,
-{
-    fn to_sql<W: std::io::Write>(
-        &self,
-        out: &mut diesel::serialize::Output<W, DB>,
-    ) -> diesel::serialize::Result {
-        serde_json::to_value(self)?.to_sql(out)
+const CHAPTER_KIND_VARIANTS: &[&str] = &[
+    "RoyalRoad",
+    "Pale",
+    "APracticalGuideToEvil",
+    "TheWanderingInn",
+    "TheWanderingInnPatreon",
+    "TheDailyGrindPatreon",
+    "ApparatusOfChangePatreon",
+];
+
+forward_compatible_serde!(ChapterKind, CHAPTER_KIND_VARIANTS);
+
+impl ChapterKind {
+    /// The chapter's page on its site, if it has one. Chapters read from
+    /// emails only exist in storage.
+    pub fn source_url(&self, endpoints: &Endpoints) -> Option<String> {
+        match self {
+            Self::RoyalRoad { id } => endpoints
+                .royalroad
+                .join(&format!("fiction/chapter/{}", id))
+                .ok()
+                .map(String::from),
+            Self::Pale { url }
+            | Self::APracticalGuideToEvil { url }
+            | Self::TheWanderingInn { url }
+            | Self::TheWanderingInnPatreon { url, .. } => Some(url.clone()),
+            Self::TheDailyGrindPatreon { .. }
+            | Self::ApparatusOfChangePatreon { .. }
+            | Self::Unknown(_) => None,
+        }
     }
 }
 
-impl<DB> FromSql<sql_types::Jsonb, DB> for ChapterKind
-where
-    DB: diesel::backend::Backend,
-    serde_json::Value: FromSql<sql_types::Jsonb, DB>,
-{
-    fn from_sql(bytes: Option<&DB::RawValue>) -> diesel::deserialize::Result<Self> {
-        let value = serde_json::Value::from_sql(bytes)?;
+impl ToSql<sql_types::Jsonb, Pg> for ChapterKind {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        let value = serde_json::to_value(self)?;
+        <serde_json::Value as ToSql<sql_types::Jsonb, Pg>>::to_sql(&value, &mut out.reborrow())
+    }
+}
+
+impl FromSql<sql_types::Jsonb, Pg> for ChapterKind {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let value = <serde_json::Value as FromSql<sql_types::Jsonb, Pg>>::from_sql(bytes)?;
         Ok(serde_json::from_value(value)?)
     }
 }
 
-#[derive(Insertable, Debug)]
-#[table_name = "books"]
+/// Books are shown without the credentials their chapters are fetched with.
+fn serialize_without_credentials<S: serde::Serializer>(
+    kind: &BookKind,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    kind.without_credentials().serialize(serializer)
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = books)]
 pub struct NewBook {
     pub name: String,
     pub author: String,
     pub metadata: BookKind,
+    pub tags: Vec<String>,
+    pub content_warnings: Vec<String>,
 }
 
 #[derive(Identifiable, Queryable, PartialEq, Debug, Serialize, Hash, Eq, Clone)]
@@ -161,11 +275,122 @@ pub struct Book {
     pub author: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    #[serde(serialize_with = "serialize_without_credentials")]
     pub metadata: BookKind,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub chapter_count: i64,
+    pub latest_chapter_published_at: Option<DateTime<Utc>>,
+    /// When the book was last checked for new chapters.
+    pub last_checked_at: Option<DateTime<Utc>>,
+    /// When a check last read the book's chapter list.
+    pub last_success_at: Option<DateTime<Utc>>,
+    /// Why the last check failed, cleared once one succeeds.
+    pub last_error: Option<String>,
+    /// When the name, author and tags were last read again from the book's site.
+    pub metadata_refreshed_at: Option<DateTime<Utc>>,
+    /// The tags the book's site lists, such as "LitRPG".
+    pub tags: Vec<String>,
+    /// The content warnings the book's site lists, such as "Gore".
+    pub content_warnings: Vec<String>,
+    /// The book isn't checked for new chapters again before this.
+    pub next_check_at: Option<DateTime<Utc>>,
+    /// Why checks are failing in a way retrying won't fix, until one succeeds.
+    pub check_problem: Option<CheckProblem>,
+}
+
+/// Why a book's checks keep failing, for an admin or its subscribers to act on.
+#[derive(
+    Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize, AsExpression, FromSqlRow,
+)]
+#[diesel(sql_type = sql_types::Text)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckProblem {
+    /// The book was removed from its site.
+    Gone,
+    /// The site's pages changed so they no longer parse.
+    ParseChanged,
+    /// The site needs credentials, such as a patreon password, which are
+    /// missing or wrong.
+    AuthRequired,
+}
+
+impl CheckProblem {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gone => "gone",
+            Self::ParseChanged => "parse_changed",
+            Self::AuthRequired => "auth_required",
+        }
+    }
+}
+
+impl ToSql<sql_types::Text, Pg> for CheckProblem {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<sql_types::Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<sql_types::Text, Pg> for CheckProblem {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match <String as FromSql<sql_types::Text, Pg>>::from_sql(bytes)?.as_str() {
+            "gone" => Ok(Self::Gone),
+            "parse_changed" => Ok(Self::ParseChanged),
+            "auth_required" => Ok(Self::AuthRequired),
+            other => Err(format!("Unrecognized check problem {}", other).into()),
+        }
+    }
+}
+
+/// Whether a chapter's body made it into storage. Chapters which failed are
+/// kept so their bodies can be fetched again on a later check.
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Clone,
+    Copy,
+    Serialize,
+    Deserialize,
+    AsExpression,
+    FromSqlRow,
+)]
+#[diesel(sql_type = sql_types::Text)]
+#[serde(rename_all = "snake_case")]
+pub enum ChapterStatus {
+    Fetched,
+    FetchFailed,
+}
+
+impl ChapterStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Fetched => "fetched",
+            Self::FetchFailed => "fetch_failed",
+        }
+    }
+}
+
+impl ToSql<sql_types::Text, Pg> for ChapterStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<sql_types::Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<sql_types::Text, Pg> for ChapterStatus {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match <String as FromSql<sql_types::Text, Pg>>::from_sql(bytes)?.as_str() {
+            "fetched" => Ok(Self::Fetched),
+            "fetch_failed" => Ok(Self::FetchFailed),
+            other => Err(format!("Unrecognized chapter status {}", other).into()),
+        }
+    }
 }
 
 #[derive(Insertable, PartialEq, Debug)]
-#[table_name = "chapters"]
+#[diesel(table_name = chapters)]
 pub struct NewChapter {
     pub name: String,
     pub author: String,
@@ -175,19 +400,10 @@ pub struct NewChapter {
 }
 
 #[derive(
-    Identifiable,
-    Queryable,
-    QueryableByName,
-    PartialEq,
-    Debug,
-    Associations,
-    Hash,
-    Eq,
-    PartialOrd,
-    Ord,
+    Identifiable, Queryable, QueryableByName, PartialEq, Debug, Associations, Hash, Eq, Clone,
 )]
-#[belongs_to(Book)]
-#[table_name = "chapters"]
+#[diesel(belongs_to(Book))]
+#[diesel(table_name = chapters)]
 pub struct Chapter {
     pub id: Uuid,
     pub name: String,
@@ -197,21 +413,213 @@ pub struct Chapter {
     pub book_id: Uuid,
     pub published_at: DateTime<Utc>,
     pub metadata: ChapterKind,
+    pub status: ChapterStatus,
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Words in the stored body, counted when it was fetched.
+    pub word_count: Option<i64>,
+    /// The chapter's position in the book, from zero, in the order the
+    /// provider lists the book's chapters.
+    pub ordinal: Option<i32>,
+}
+
+impl Chapter {
+    /// Orders chapters as `chapter_order` does.
+    pub fn sort_key(&self) -> (Option<i32>, DateTime<Utc>, Uuid) {
+        (self.ordinal, self.published_at, self.id)
+    }
+}
+
+/// A chapter moved out of `chapters` once every subscriber had been sent a
+/// later one, along with where its body was stored.
+#[derive(Identifiable, Queryable, PartialEq, Debug, Associations, Clone)]
+#[diesel(belongs_to(Book))]
+#[diesel(table_name = chapters_archive)]
+pub struct ArchivedChapter {
+    pub id: Uuid,
+    pub name: String,
+    pub author: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub book_id: Uuid,
+    pub published_at: DateTime<Utc>,
+    pub metadata: ChapterKind,
+    pub status: ChapterStatus,
+    pub word_count: Option<i64>,
+    pub ordinal: Option<i32>,
+    pub body_bucket: Option<String>,
+    pub body_key: Option<String>,
+    pub archived_at: DateTime<Utc>,
+}
+
+impl ArchivedChapter {
+    /// Where the chapter's body was stored, if it had one.
+    pub fn body_location(&self) -> Option<StorageLocation> {
+        Some(StorageLocation {
+            bucket: self.body_bucket.clone()?,
+            key: self.body_key.clone()?,
+        })
+    }
+}
+
+/// Filters out soft-deleted books.
+pub fn book_not_deleted() -> IsNull<books::deleted_at> {
+    books::deleted_at.is_null()
+}
+
+/// Filters out soft-deleted chapters.
+pub fn chapter_not_deleted() -> IsNull<chapters::deleted_at> {
+    chapters::deleted_at.is_null()
+}
+
+pub type ChapterOrder = (
+    NullsFirst<Asc<chapters::ordinal>>,
+    Asc<chapters::published_at>,
+    Asc<chapters::id>,
+);
+
+/// Orders chapters as the provider orders the book, which can differ from
+/// when they were published. Chapters without an ordinal come first, ordered
+/// by when they were published, then by id so the order is stable.
+pub fn chapter_order() -> ChapterOrder {
+    (
+        chapters::ordinal.asc().nulls_first(),
+        chapters::published_at.asc(),
+        chapters::id.asc(),
+    )
 }
 
 #[derive(Identifiable, Queryable, PartialEq, Debug, Associations, Serialize, Clone)]
-#[belongs_to(Book)]
-#[primary_key(user_id, book_id)]
+#[diesel(belongs_to(Book))]
+#[diesel(primary_key(user_id, book_id))]
 pub struct Subscription {
     pub book_id: Uuid,
     pub created_at: DateTime<Utc>,
     pub user_id: String,
     pub grouping_quantity: i64,
     pub last_chapter_id: Option<Uuid>,
+    /// The last chapter of the group most recently announced over pushover,
+    /// so a group whose ebook failed to send isn't announced again.
+    #[serde(skip)]
+    pub pushover_notified_chapter_id: Option<Uuid>,
+    pub include_author_notes: AuthorNotes,
+    pub include_comment_count: bool,
+    /// The priority pushover announcements are sent at, from -2 to 2, or
+    /// `None` for normal priority.
+    pub pushover_priority: Option<i16>,
+    /// Seconds between the repeats of an emergency announcement.
+    pub pushover_retry: Option<i32>,
+    /// Seconds an emergency announcement repeats for if unacknowledged.
+    pub pushover_expire: Option<i32>,
+    /// Chapters aren't delivered until this has passed.
+    pub snoozed_until: Option<DateTime<Utc>>,
+    /// Paused subscriptions get no deliveries until resumed.
+    pub active: bool,
 }
 
-#[derive(Identifiable, Queryable, PartialEq, Debug, Associations)]
-#[primary_key(user_id)]
+/// Seconds between the repeats of an emergency announcement, unless the
+/// subscription sets them.
+pub const DEFAULT_PUSHOVER_RETRY_SECS: i32 = 60;
+/// Seconds an emergency announcement repeats for, unless the subscription
+/// sets them.
+pub const DEFAULT_PUSHOVER_EXPIRE_SECS: i32 = 3600;
+
+impl Subscription {
+    pub fn extras(&self) -> ExtrasPreference {
+        ExtrasPreference {
+            author_notes: self.include_author_notes,
+            comment_count: self.include_comment_count,
+        }
+    }
+
+    /// Whether deliveries are held at `now`.
+    pub fn is_snoozed(&self, now: DateTime<Utc>) -> bool {
+        self.snoozed_until.is_some_and(|until| until > now)
+    }
+
+    pub fn pushover_priority(&self) -> Priority {
+        match self.pushover_priority {
+            Some(priority @ (-2 | -1)) => Priority::Quiet(priority),
+            Some(1) => Priority::High,
+            Some(2) => Priority::Emergency {
+                retry: self.pushover_retry.unwrap_or(DEFAULT_PUSHOVER_RETRY_SECS),
+                expire: self.pushover_expire.unwrap_or(DEFAULT_PUSHOVER_EXPIRE_SECS),
+            },
+            _ => Priority::Normal,
+        }
+    }
+}
+
+/// Which of the author's notes around a chapter are delivered with it.
+#[derive(
+    Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, AsExpression, FromSqlRow,
+)]
+#[diesel(sql_type = sql_types::Text)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthorNotes {
+    #[default]
+    Omit,
+    /// Only the note after the chapter, which is less often an advert.
+    After,
+    All,
+}
+
+impl AuthorNotes {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Omit => "omit",
+            Self::After => "after",
+            Self::All => "all",
+        }
+    }
+}
+
+impl ToSql<sql_types::Text, Pg> for AuthorNotes {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<sql_types::Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<sql_types::Text, Pg> for AuthorNotes {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match <String as FromSql<sql_types::Text, Pg>>::from_sql(bytes)?.as_str() {
+            "omit" => Ok(Self::Omit),
+            "after" => Ok(Self::After),
+            "all" => Ok(Self::All),
+            other => Err(format!("Unrecognized author notes preference {}", other).into()),
+        }
+    }
+}
+
+/// What a subscriber wants added to the end of each delivered chapter.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct ExtrasPreference {
+    pub author_notes: AuthorNotes,
+    pub comment_count: bool,
+}
+
+impl ExtrasPreference {
+    pub fn wants_any(&self) -> bool {
+        self.author_notes != AuthorNotes::Omit || self.comment_count
+    }
+}
+
+/// Parts of a chapter's page kept beside its body, as only some subscribers
+/// want them delivered.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ChapterExtras {
+    pub note_before: Option<String>,
+    pub note_after: Option<String>,
+    pub comment_count: Option<u32>,
+}
+
+impl ChapterExtras {
+    pub fn is_empty(&self) -> bool {
+        self.note_before.is_none() && self.note_after.is_none() && self.comment_count.is_none()
+    }
+}
+
+#[derive(Identifiable, Queryable, PartialEq, Debug)]
+#[diesel(primary_key(user_id))]
 #[allow(clippy::struct_excessive_bools)]
 pub struct DeliveryMethod {
     pub user_id: String,
@@ -227,6 +635,16 @@ pub struct DeliveryMethod {
     pub updated_at: DateTime<Utc>,
     pub pushover_verification_code_time: Option<DateTime<Utc>>,
     pub pushover_verification_code: Option<String>,
+    /// Authenticates the user's chapter feed, as feed readers can't send
+    /// bearer tokens.
+    pub feed_token: Option<String>,
+    /// Announce every book delivered in a notification cycle in one pushover
+    /// message rather than one message per book.
+    pub pushover_combine_books: bool,
+    /// Send the chapters inline in the email when they fail to convert to an
+    /// ebook, rather than waiting for the conversion to be fixed.
+    pub kindle_inline_fallback: bool,
+    pub kindle_conversion_mode: KindleConversionMode,
 }
 
 impl DeliveryMethod {
@@ -247,8 +665,188 @@ impl DeliveryMethod {
     }
 }
 
+/// Who converts the chapters sent to a kindle.
+#[derive(
+    Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, AsExpression, FromSqlRow,
+)]
+#[diesel(sql_type = sql_types::Text)]
+#[serde(rename_all = "snake_case")]
+pub enum KindleConversionMode {
+    /// Calibre converts them to an epub.
+    #[default]
+    Cereal,
+    /// They are sent as HTML with the subject "Convert", which has Amazon
+    /// convert them instead.
+    Amazon,
+}
+
+impl KindleConversionMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Cereal => "cereal",
+            Self::Amazon => "amazon",
+        }
+    }
+}
+
+impl ToSql<sql_types::Text, Pg> for KindleConversionMode {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<sql_types::Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<sql_types::Text, Pg> for KindleConversionMode {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match <String as FromSql<sql_types::Text, Pg>>::from_sql(bytes)?.as_str() {
+            "cereal" => Ok(Self::Cereal),
+            "amazon" => Ok(Self::Amazon),
+            other => Err(format!("Unrecognized kindle conversion mode {}", other).into()),
+        }
+    }
+}
+
+/// The format chapters are sent to a kindle in.
+#[derive(
+    Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, AsExpression, FromSqlRow,
+)]
+#[diesel(sql_type = sql_types::Text)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryFormat {
+    #[default]
+    Epub,
+    /// The chapters inline in the email, without converting them.
+    Html,
+}
+
+impl DeliveryFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Epub => "epub",
+            Self::Html => "html",
+        }
+    }
+}
+
+impl ToSql<sql_types::Text, Pg> for DeliveryFormat {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<sql_types::Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<sql_types::Text, Pg> for DeliveryFormat {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match <String as FromSql<sql_types::Text, Pg>>::from_sql(bytes)?.as_str() {
+            "epub" => Ok(Self::Epub),
+            "html" => Ok(Self::Html),
+            other => Err(format!("Unrecognized delivery format {}", other).into()),
+        }
+    }
+}
+
+/// A user's defaults for their subscriptions and deliveries. Every value is
+/// optional, leaving it to the subscription or the built in default.
+#[derive(Identifiable, Queryable, PartialEq, Debug, Serialize, Clone)]
+#[diesel(primary_key(user_id))]
+#[diesel(table_name = user_preferences)]
+pub struct UserPreferences {
+    pub user_id: String,
+    pub default_format: Option<DeliveryFormat>,
+    /// Used for new subscriptions which don't give their own.
+    pub default_grouping_quantity: Option<i64>,
+    /// The calibre output profile ebooks are converted with.
+    pub output_profile: Option<String>,
+    /// Announce every book delivered in a cycle in one pushover message.
+    pub combine_deliveries: Option<bool>,
+    /// The hour deliveries are held from, in the user's timezone.
+    pub quiet_hours_start: Option<i16>,
+    /// The hour held deliveries are sent again, in the user's timezone.
+    pub quiet_hours_end: Option<i16>,
+    /// A fixed UTC offset such as "+02:00". Daylight saving isn't followed.
+    pub timezone: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// The subject of kindle emails, see `templates::Template`.
+    pub kindle_subject_template: Option<String>,
+    pub pushover_template: Option<String>,
+    /// The message announcing several books' chapters at once.
+    pub pushover_combined_template: Option<String>,
+    /// Skip the chapters published while a subscription was snoozed, except
+    /// the latest group, rather than delivering them all when it ends.
+    pub skip_snoozed_chapters: Option<bool>,
+}
+
+impl UserPreferences {
+    /// Whether deliveries are held at `now`. Quiet hours may span midnight,
+    /// and an unset or unparsable timezone is taken as UTC.
+    pub fn in_quiet_hours(&self, now: DateTime<Utc>) -> bool {
+        let (Some(start), Some(end)) = (self.quiet_hours_start, self.quiet_hours_end) else {
+            return false;
+        };
+        let offset = self
+            .timezone
+            .as_deref()
+            .and_then(|timezone| timezone.parse::<FixedOffset>().ok())
+            .unwrap_or(FixedOffset::east_opt(0).unwrap());
+        let hour = now.with_timezone(&offset).hour() as i16;
+        if start <= end {
+            (start..end).contains(&hour)
+        } else {
+            hour >= start || hour < end
+        }
+    }
+
+    /// The user's notification templates, which were validated when set.
+    pub fn templates(&self) -> Templates {
+        let parse = |template: &Option<String>| template.as_deref().and_then(|t| t.parse().ok());
+        Templates {
+            kindle_subject: parse(&self.kindle_subject_template),
+            pushover: parse(&self.pushover_template),
+            pushover_combined: parse(&self.pushover_combined_template),
+        }
+    }
+}
+
+/// What a delivery is made with. Each value is taken from the subscription,
+/// then the user's preferences, then the built in default.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DeliverySettings {
+    pub format: DeliveryFormat,
+    pub grouping_quantity: i64,
+    pub output_profile: String,
+    pub combine_deliveries: bool,
+}
+
+impl DeliverySettings {
+    pub const DEFAULT_GROUPING_QUANTITY: i64 = 1;
+
+    pub fn resolve(
+        subscription: Option<&Subscription>,
+        preferences: Option<&UserPreferences>,
+        delivery_method: &DeliveryMethod,
+    ) -> Self {
+        Self {
+            format: preferences
+                .and_then(|preferences| preferences.default_format)
+                .unwrap_or_default(),
+            grouping_quantity: subscription
+                .map(|subscription| subscription.grouping_quantity)
+                .or(preferences.and_then(|preferences| preferences.default_grouping_quantity))
+                .unwrap_or(Self::DEFAULT_GROUPING_QUANTITY)
+                .max(1),
+            output_profile: preferences
+                .and_then(|preferences| preferences.output_profile.clone())
+                .unwrap_or_else(|| calibre::DEFAULT_OUTPUT_PROFILE.to_string()),
+            // The delivery method's setting predates preferences, so it is
+            // kept as the fallback for users who set it there.
+            combine_deliveries: preferences
+                .and_then(|preferences| preferences.combine_deliveries)
+                .unwrap_or(delivery_method.pushover_combine_books),
+        }
+    }
+}
+
 #[derive(Identifiable, Queryable, PartialEq, Debug, Associations)]
-#[belongs_to(Chapter)]
+#[diesel(belongs_to(Chapter))]
 pub struct UnsentChapter {
     pub id: Uuid,
     pub user_id: String,
@@ -257,45 +855,417 @@ pub struct UnsentChapter {
 }
 
 #[derive(Insertable, Debug)]
-#[table_name = "unsent_chapters"]
+#[diesel(table_name = unsent_chapters)]
 pub struct NewUnsentChapter {
     pub user_id: String,
     pub chapter_id: Uuid,
 }
 
+#[derive(Identifiable, Queryable, PartialEq, Debug, Associations, Serialize, Clone)]
+#[diesel(belongs_to(Book))]
+#[diesel(table_name = deliveries)]
+pub struct Delivery {
+    pub id: Uuid,
+    pub user_id: String,
+    /// The book delivered, which test deliveries have none of.
+    pub book_id: Option<Uuid>,
+    pub chapter_ids: Vec<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub artifact_bucket: Option<String>,
+    pub artifact_key: Option<String>,
+    pub artifact_size: Option<i64>,
+    pub artifact_format: Option<String>,
+    /// Whether the delivery was only logged, by an instance running with
+    /// `CEREAL_DRY_RUN`.
+    pub dry_run: bool,
+    /// Whether the chapters were sent inline in the email body because they
+    /// failed to convert to an ebook.
+    pub degraded: bool,
+    /// The delivery this one resent, if an admin redelivered it.
+    pub redelivery_of: Option<Uuid>,
+    /// Whether this was a sample sent to check a delivery method.
+    pub test: bool,
+    /// The receipt of the delivery's emergency pushover announcement.
+    #[serde(skip)]
+    pub pushover_receipt: Option<String>,
+    /// When the user acknowledged the emergency announcement.
+    pub pushover_acknowledged_at: Option<DateTime<Utc>>,
+    /// When the emergency announcement was withdrawn, as its email bounced.
+    pub pushover_cancelled_at: Option<DateTime<Utc>>,
+}
+
+impl Delivery {
+    pub fn artifact_location(&self) -> Option<StorageLocation> {
+        match (&self.artifact_bucket, &self.artifact_key) {
+            (Some(bucket), Some(key)) => Some(StorageLocation {
+                bucket: bucket.clone(),
+                key: key.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = deliveries)]
+pub struct NewDelivery {
+    pub user_id: String,
+    pub book_id: Uuid,
+    pub chapter_ids: Vec<Uuid>,
+    pub artifact_bucket: Option<String>,
+    pub artifact_key: Option<String>,
+    pub artifact_size: Option<i64>,
+    pub artifact_format: Option<String>,
+    /// Whether the delivery was only logged, by an instance running with
+    /// `CEREAL_DRY_RUN`.
+    pub dry_run: bool,
+    /// Whether the chapters were sent inline in the email body because they
+    /// failed to convert to an ebook.
+    pub degraded: bool,
+    /// The delivery this one resent, if an admin redelivered it.
+    pub redelivery_of: Option<Uuid>,
+    /// The receipt of the delivery's emergency pushover announcement.
+    pub pushover_receipt: Option<String>,
+}
+
 #[derive(Identifiable, Queryable, PartialEq, Debug, Associations, Insertable, Hash, Eq, Clone)]
-#[table_name = "chapter_bodies"]
-#[belongs_to(Chapter)]
-#[primary_key(chapter_id)]
+#[diesel(table_name = chapter_bodies)]
+#[diesel(belongs_to(Chapter))]
+#[diesel(primary_key(chapter_id))]
 pub struct ChapterBody {
     pub key: String,
     pub bucket: String,
     pub chapter_id: Uuid,
+    /// The stored size in bytes, unknown for bodies stored before sizes
+    /// were recorded.
+    pub size: Option<i64>,
+}
+
+/// A Daily Grind chapter from before its html was moved to storage.
+#[derive(Debug, QueryableByName)]
+pub(crate) struct EmbeddedDailyGrindHtml {
+    #[diesel(sql_type = diesel::sql_types::Uuid)]
+    pub(crate) id: Uuid,
+    #[diesel(sql_type = diesel::sql_types::Uuid)]
+    pub(crate) book_id: Uuid,
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub(crate) html: String,
 }
 
-impl From<ChapterBody> for S3Location {
+impl From<&Chapter> for NewChapter {
+    fn from(chapter: &Chapter) -> Self {
+        NewChapter {
+            name: chapter.name.clone(),
+            author: chapter.author.clone(),
+            book_id: chapter.book_id,
+            metadata: chapter.metadata.clone(),
+            published_at: chapter.published_at,
+        }
+    }
+}
+
+impl From<ChapterBody> for StorageLocation {
     fn from(val: ChapterBody) -> Self {
-        S3Location {
-            prefix: val.key,
-            bucket_name: val.bucket,
-            ..Default::default()
+        StorageLocation {
+            bucket: val.bucket,
+            key: val.key,
         }
     }
 }
 
-#[derive(PartialEq, Debug, Hash, Eq, QueryableByName)]
-#[table_name = "chapters"]
-pub(crate) struct ChapterWithUser {
-    #[sql_type = "diesel::sql_types::Text"]
-    pub(crate) user_id: String,
-    #[sql_type = "diesel::sql_types::BigInt"]
-    pub(crate) grouping_quantity: i64,
-    pub(crate) id: Uuid,
-    pub(crate) name: String,
-    pub(crate) author: String,
-    pub(crate) created_at: DateTime<Utc>,
-    pub(crate) updated_at: DateTime<Utc>,
-    pub(crate) book_id: Uuid,
-    pub(crate) published_at: DateTime<Utc>,
-    pub(crate) metadata: ChapterKind,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn subscription(grouping_quantity: i64) -> Subscription {
+        Subscription {
+            book_id: Uuid::new_v4(),
+            created_at: Utc::now(),
+            user_id: "reader".into(),
+            grouping_quantity,
+            last_chapter_id: None,
+            pushover_notified_chapter_id: None,
+            include_author_notes: AuthorNotes::Omit,
+            include_comment_count: false,
+            pushover_priority: None,
+            pushover_retry: None,
+            pushover_expire: None,
+            snoozed_until: None,
+            active: true,
+        }
+    }
+
+    fn preferences() -> UserPreferences {
+        UserPreferences {
+            user_id: "reader".into(),
+            default_format: None,
+            default_grouping_quantity: None,
+            output_profile: None,
+            combine_deliveries: None,
+            quiet_hours_start: None,
+            quiet_hours_end: None,
+            timezone: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            kindle_subject_template: None,
+            pushover_template: None,
+            pushover_combined_template: None,
+            skip_snoozed_chapters: None,
+        }
+    }
+
+    fn delivery_method(pushover_combine_books: bool) -> DeliveryMethod {
+        DeliveryMethod {
+            user_id: "reader".into(),
+            kindle_email: None,
+            kindle_email_verified: false,
+            kindle_email_enabled: false,
+            kindle_email_verification_code_time: None,
+            kindle_email_verification_code: None,
+            pushover_key: None,
+            pushover_key_verified: false,
+            pushover_enabled: false,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            pushover_verification_code_time: None,
+            pushover_verification_code: None,
+            feed_token: None,
+            pushover_combine_books,
+            kindle_inline_fallback: false,
+            kindle_conversion_mode: KindleConversionMode::Cereal,
+        }
+    }
+
+    #[test]
+    fn resolves_settings_from_the_subscription_then_preferences_then_defaults() {
+        assert_eq!(
+            DeliverySettings::resolve(None, None, &delivery_method(false)),
+            DeliverySettings {
+                format: DeliveryFormat::Epub,
+                grouping_quantity: 1,
+                output_profile: "kindle_oasis".into(),
+                combine_deliveries: false,
+            }
+        );
+
+        let preferences = UserPreferences {
+            default_format: Some(DeliveryFormat::Html),
+            default_grouping_quantity: Some(5),
+            output_profile: Some("kobo".into()),
+            combine_deliveries: Some(false),
+            ..preferences()
+        };
+        assert_eq!(
+            DeliverySettings::resolve(None, Some(&preferences), &delivery_method(true)),
+            DeliverySettings {
+                format: DeliveryFormat::Html,
+                grouping_quantity: 5,
+                output_profile: "kobo".into(),
+                combine_deliveries: false,
+            }
+        );
+
+        let settings = DeliverySettings::resolve(
+            Some(&subscription(3)),
+            Some(&preferences),
+            &delivery_method(true),
+        );
+        assert_eq!(settings.grouping_quantity, 3);
+
+        let settings =
+            DeliverySettings::resolve(None, Some(&self::preferences()), &delivery_method(true));
+        assert!(settings.combine_deliveries);
+    }
+
+    #[test]
+    fn holds_deliveries_in_quiet_hours() {
+        let at = |hour| Utc.with_ymd_and_hms(2026, 10, 17, hour, 30, 0).unwrap();
+        let overnight = UserPreferences {
+            quiet_hours_start: Some(22),
+            quiet_hours_end: Some(7),
+            timezone: Some("+02:00".into()),
+            ..preferences()
+        };
+        // 22:30 and 06:30 at +02:00.
+        assert!(overnight.in_quiet_hours(at(20)));
+        assert!(overnight.in_quiet_hours(at(4)));
+        assert!(!overnight.in_quiet_hours(at(5)));
+        assert!(!overnight.in_quiet_hours(at(19)));
+
+        let afternoon = UserPreferences {
+            quiet_hours_start: Some(13),
+            quiet_hours_end: Some(15),
+            ..preferences()
+        };
+        assert!(afternoon.in_quiet_hours(at(14)));
+        assert!(!afternoon.in_quiet_hours(at(15)));
+        assert!(!preferences().in_quiet_hours(at(14)));
+    }
+
+    #[test]
+    fn resolves_pushover_priority() {
+        assert_eq!(subscription(1).pushover_priority(), Priority::Normal);
+        let with = |priority, retry| Subscription {
+            pushover_priority: Some(priority),
+            pushover_retry: retry,
+            ..subscription(1)
+        };
+        assert_eq!(with(-2, None).pushover_priority(), Priority::Quiet(-2));
+        assert_eq!(with(1, None).pushover_priority(), Priority::High);
+        assert_eq!(
+            with(2, Some(30)).pushover_priority(),
+            Priority::Emergency {
+                retry: 30,
+                expire: DEFAULT_PUSHOVER_EXPIRE_SECS,
+            }
+        );
+    }
+
+    /// Loads a row of every table through the models and `schema.rs`, so a
+    /// column the migrations and the schema disagree on fails here rather
+    /// than in the check loop.
+    #[tokio::test]
+    async fn loads_every_model_from_a_migrated_database() {
+        use crate::schema::{book_aliases, book_metadata_changes, verification_sends};
+        use crate::test_support::TestDatabase;
+        use diesel::QueryDsl;
+        use diesel_async::RunQueryDsl;
+
+        let Some(db) = TestDatabase::required().await else {
+            return;
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        let book: Book = diesel::insert_into(books::table)
+            .values(NewBook {
+                name: "The Wandering Inn".into(),
+                author: "pirateaba".into(),
+                metadata: BookKind::TheWanderingInn,
+                tags: vec!["fantasy".into()],
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        let chapter: Chapter = diesel::insert_into(chapters::table)
+            .values(NewChapter {
+                name: "1.00".into(),
+                author: book.author.clone(),
+                book_id: book.id,
+                published_at: Utc::now(),
+                metadata: ChapterKind::TheWanderingInn {
+                    url: "https://wanderinginn.com/2016/07/27/1-00/".into(),
+                },
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        diesel::insert_into(chapter_bodies::table)
+            .values(ChapterBody {
+                key: "body".into(),
+                bucket: "bucket".into(),
+                chapter_id: chapter.id,
+                size: Some(1),
+            })
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        diesel::sql_query(
+            "INSERT INTO chapters_archive \
+             (id, name, author, created_at, updated_at, book_id, published_at, metadata, status) \
+             SELECT gen_random_uuid(), name, author, created_at, updated_at, book_id, \
+             published_at, metadata, status FROM chapters",
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+        diesel::insert_into(subscriptions::table)
+            .values((
+                subscriptions::user_id.eq("reader"),
+                subscriptions::book_id.eq(book.id),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        diesel::insert_into(delivery_methods::table)
+            .values(delivery_methods::user_id.eq("reader"))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        diesel::insert_into(user_preferences::table)
+            .values(user_preferences::user_id.eq("reader"))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        diesel::insert_into(unsent_chapters::table)
+            .values(NewUnsentChapter {
+                user_id: "reader".into(),
+                chapter_id: chapter.id,
+            })
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        diesel::insert_into(deliveries::table)
+            .values(NewDelivery {
+                user_id: "reader".into(),
+                book_id: book.id,
+                chapter_ids: vec![chapter.id],
+                artifact_bucket: None,
+                artifact_key: None,
+                artifact_size: None,
+                artifact_format: None,
+                dry_run: false,
+                degraded: false,
+                redelivery_of: None,
+                pushover_receipt: None,
+            })
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            books::table.load::<Book>(&mut *conn).await.unwrap().len(),
+            1
+        );
+        assert_eq!(
+            chapters::table
+                .load::<Chapter>(&mut *conn)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        let archived = chapters_archive::table.load::<ArchivedChapter>(&mut *conn);
+        assert_eq!(archived.await.unwrap().len(), 1);
+        let bodies = chapter_bodies::table.load::<ChapterBody>(&mut *conn);
+        assert_eq!(bodies.await.unwrap().len(), 1);
+        let subscriptions = subscriptions::table.load::<Subscription>(&mut *conn);
+        assert_eq!(subscriptions.await.unwrap().len(), 1);
+        let methods = delivery_methods::table.load::<DeliveryMethod>(&mut *conn);
+        assert_eq!(methods.await.unwrap().len(), 1);
+        let preferences = user_preferences::table.load::<UserPreferences>(&mut *conn);
+        assert_eq!(preferences.await.unwrap().len(), 1);
+        let unsent = unsent_chapters::table.load::<UnsentChapter>(&mut *conn);
+        assert_eq!(unsent.await.unwrap().len(), 1);
+        let deliveries = deliveries::table.load::<Delivery>(&mut *conn);
+        assert_eq!(deliveries.await.unwrap().len(), 1);
+
+        // The tables without a model are only read column by column, so
+        // selecting every column is enough to catch a missing one.
+        book_aliases::table
+            .select(book_aliases::all_columns)
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        book_metadata_changes::table
+            .select(book_metadata_changes::all_columns)
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        verification_sends::table
+            .select(verification_sends::all_columns)
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+    }
 }
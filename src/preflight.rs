@@ -0,0 +1,174 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use tokio::process::Command;
+use tracing::{error, info, warn};
+
+use crate::config::{Config, TlsConfig};
+use crate::storage::Storage;
+use crate::util::{error_chain, InstrumentedPgConnectionPool};
+
+/// How long the bucket check may take, as the S3 client retries a storage
+/// endpoint which is down for some time before giving up.
+const BUCKET_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The outcome of checking one external dependency.
+pub struct Check {
+    pub name: &'static str,
+    /// Whether the service can run at all without the dependency, rather than
+    /// with the features using it failing.
+    pub required: bool,
+    pub result: Result<()>,
+}
+
+/// The outcome of every preflight check.
+pub struct Report(pub Vec<Check>);
+
+/// Verifies the database, calibre, the delivery credentials and storage, so
+/// misconfiguration is reported at startup rather than by the first delivery
+/// or conversion which needs them. The bucket is only contacted when
+/// `check_bucket` is set.
+pub async fn run(
+    config: &Config,
+    pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+    check_bucket: bool,
+) -> Report {
+    let mut checks = vec![
+        Check {
+            name: "database",
+            required: true,
+            result: check_database(pool).await,
+        },
+        Check {
+            name: "calibre",
+            required: false,
+            result: check_binary(&config.ebook_convert).await,
+        },
+        Check {
+            name: "mailgun",
+            required: false,
+            result: configured(
+                config.mailgun.is_some() || config.dry_run,
+                "CEREAL_MAILGUN_API_KEY, CEREAL_MAILGUN_API_ENDPOINT and CEREAL_FROM_EMAIL_ADDRESS",
+            ),
+        },
+        Check {
+            name: "pushover",
+            required: false,
+            result: configured(
+                config.pushover_token.is_some() || config.dry_run,
+                "CEREAL_PUSHOVER_TOKEN",
+            ),
+        },
+    ];
+    if let Some(tls) = &config.tls {
+        checks.push(Check {
+            name: "tls certificate",
+            required: true,
+            result: check_tls(tls).await,
+        });
+    }
+    if check_bucket {
+        checks.push(Check {
+            name: "storage bucket",
+            required: false,
+            result: tokio::time::timeout(BUCKET_CHECK_TIMEOUT, storage.check_bucket())
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("Timed out accessing the storage bucket."))),
+        });
+    }
+    Report(checks)
+}
+
+impl Report {
+    /// Logs the outcome of every check. Failures are warnings when the
+    /// service will start regardless.
+    pub fn log(&self, allow_degraded: bool) {
+        for check in &self.0 {
+            match &check.result {
+                Ok(()) => info!(check = check.name, "Preflight check passed."),
+                Err(err) if allow_degraded && !check.required => warn!(
+                    check = check.name,
+                    error = %error_chain(err),
+                    "Preflight check failed, continuing with it degraded."
+                ),
+                Err(err) => error!(
+                    check = check.name,
+                    error = %error_chain(err),
+                    "Preflight check failed."
+                ),
+            }
+        }
+    }
+
+    /// Fails if any check did, unless `allow_degraded` is set and only checks
+    /// which aren't required failed.
+    pub fn ensure(&self, allow_degraded: bool) -> Result<()> {
+        let failed: Vec<_> = self
+            .0
+            .iter()
+            .filter(|check| check.result.is_err())
+            .filter(|check| check.required || !allow_degraded)
+            .map(|check| check.name)
+            .collect();
+        if !failed.is_empty() {
+            bail!("Preflight checks failed: {}.", failed.join(", "));
+        }
+        Ok(())
+    }
+}
+
+pub async fn check_database(pool: &InstrumentedPgConnectionPool) -> Result<()> {
+    pool.get().await?;
+    Ok(())
+}
+
+async fn check_binary(name: &str) -> Result<()> {
+    let status = Command::new(name)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .with_context(|| format!("Failed to run {}. Perhaps calibre is not installed?", name))?;
+    if !status.success() {
+        bail!("{} --version exited with {}.", name, status);
+    }
+    Ok(())
+}
+
+/// Reads the certificate and key, as the server would otherwise only fail
+/// on them once it binds.
+async fn check_tls(tls: &TlsConfig) -> Result<()> {
+    let cert = tokio::fs::read(&tls.cert_path).await.with_context(|| {
+        format!(
+            "Failed to read the TLS certificate at {}.",
+            tls.cert_path.display()
+        )
+    })?;
+    let certs = rustls_pemfile::certs(&mut &cert[..])
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("{} is not a valid PEM file.", tls.cert_path.display()))?;
+    if certs.is_empty() {
+        bail!("{} holds no certificates.", tls.cert_path.display());
+    }
+    let key = tokio::fs::read(&tls.key_path).await.with_context(|| {
+        format!(
+            "Failed to read the TLS private key at {}.",
+            tls.key_path.display()
+        )
+    })?;
+    rustls_pemfile::private_key(&mut &key[..])
+        .with_context(|| format!("{} is not a valid PEM file.", tls.key_path.display()))?
+        .ok_or_else(|| anyhow!("{} holds no private key.", tls.key_path.display()))?;
+    Ok(())
+}
+
+fn configured(configured: bool, variables: &str) -> Result<()> {
+    if !configured {
+        bail!("{} must be set.", variables);
+    }
+    Ok(())
+}
@@ -0,0 +1,247 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::providers::Unparsed;
+use crate::util::error_chain;
+
+/// The window over which each provider's fetches are counted.
+const WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Each provider's recent fetches. They are not stored in the database, so
+/// they are only counted since the process started.
+static STATS: Mutex<BTreeMap<&'static str, ProviderStats>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Outcome {
+    Success,
+    ParseFailure,
+    /// The status class, such as "5xx", or how the request failed without one.
+    HttpError(String),
+    Other,
+}
+
+impl Outcome {
+    fn of(result: Result<(), &Error>) -> Self {
+        let Err(err) = result else {
+            return Self::Success;
+        };
+        if err.downcast_ref::<Unparsed>().is_some() {
+            return Self::ParseFailure;
+        }
+        match err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        {
+            Some(err) => Self::HttpError(http_error_class(err).into()),
+            None => Self::Other,
+        }
+    }
+
+    fn label(&self) -> &str {
+        match self {
+            Self::Success => "success",
+            Self::ParseFailure => "parse_failure",
+            Self::HttpError(class) => class,
+            Self::Other => "other",
+        }
+    }
+}
+
+fn http_error_class(err: &reqwest::Error) -> &'static str {
+    match err.status().map(|status| status.as_u16() / 100) {
+        Some(4) => "4xx",
+        Some(5) => "5xx",
+        Some(_) => "other_status",
+        None if err.is_timeout() => "timeout",
+        None if err.is_connect() => "connect",
+        None => "request",
+    }
+}
+
+#[derive(Debug)]
+struct Fetch {
+    at: Instant,
+    latency: Duration,
+    outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ProviderError {
+    pub message: String,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+struct ProviderStats {
+    fetches: VecDeque<Fetch>,
+    last_error: Option<ProviderError>,
+}
+
+impl ProviderStats {
+    fn record(&mut self, now: Instant, latency: Duration, result: Result<(), &Error>) {
+        if let Err(err) = result {
+            self.last_error = Some(ProviderError {
+                message: error_chain(err),
+                at: Utc::now(),
+            });
+        }
+        self.fetches.push_back(Fetch {
+            at: now,
+            latency,
+            outcome: Outcome::of(result),
+        });
+        self.prune(now);
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while self
+            .fetches
+            .front()
+            .is_some_and(|fetch| now.duration_since(fetch.at) > WINDOW)
+        {
+            self.fetches.pop_front();
+        }
+    }
+
+    fn health(&mut self, provider: &'static str, now: Instant) -> ProviderHealth {
+        self.prune(now);
+        let mut http_errors = BTreeMap::new();
+        for fetch in &self.fetches {
+            if let Outcome::HttpError(class) = &fetch.outcome {
+                *http_errors.entry(class.clone()).or_insert(0) += 1;
+            }
+        }
+        let count = |outcome: &Outcome| {
+            self.fetches
+                .iter()
+                .filter(|fetch| fetch.outcome == *outcome)
+                .count()
+        };
+        let fetch_attempts = self.fetches.len();
+        let successes = count(&Outcome::Success);
+        ProviderHealth {
+            provider,
+            fetch_attempts,
+            successes,
+            failures: fetch_attempts - successes,
+            parse_failures: count(&Outcome::ParseFailure),
+            http_errors,
+            average_latency_ms: (fetch_attempts > 0).then(|| {
+                let total: Duration = self.fetches.iter().map(|fetch| fetch.latency).sum();
+                (total / fetch_attempts as u32).as_millis() as u64
+            }),
+            last_error: self.last_error.clone(),
+        }
+    }
+}
+
+/// A provider's fetches over the last day.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ProviderHealth {
+    pub provider: &'static str,
+    /// Chapter listings and chapter bodies fetched.
+    pub fetch_attempts: usize,
+    pub successes: usize,
+    pub failures: usize,
+    /// Fetches whose response couldn't be parsed, as when a site's markup
+    /// changes.
+    pub parse_failures: usize,
+    /// Failed requests by status class, such as "5xx", or "timeout" and
+    /// "connect" for those which got no response.
+    pub http_errors: BTreeMap<String, usize>,
+    pub average_latency_ms: Option<u64>,
+    /// The most recent failure, however long ago.
+    pub last_error: Option<ProviderError>,
+}
+
+/// Records a fetch from `provider` which took `latency`.
+pub fn record_fetch(provider: &'static str, latency: Duration, result: Result<(), &Error>) {
+    let outcome = Outcome::of(result);
+    metrics::counter!(
+        "provider_fetches_total",
+        "provider" => provider,
+        "outcome" => outcome.label().to_owned()
+    )
+    .increment(1);
+    STATS
+        .lock()
+        .unwrap()
+        .entry(provider)
+        .or_default()
+        .record(Instant::now(), latency, result);
+}
+
+/// The health of each provider fetched from since the process started.
+pub fn provider_health() -> Vec<ProviderHealth> {
+    let now = Instant::now();
+    STATS
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .map(|(provider, stats)| stats.health(provider, now))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::providers::keep_unparsed;
+
+    #[test]
+    fn counts_fetches_within_the_window() {
+        let start = Instant::now();
+        let mut stats = ProviderStats::default();
+        let unparsed = keep_unparsed::<()>(
+            Err(anyhow!("No chapter table.")),
+            "https://example.com",
+            "<html>",
+        )
+        .unwrap_err();
+        stats.record(start, Duration::from_millis(100), Ok(()));
+        stats.record(
+            start + Duration::from_secs(60),
+            Duration::from_millis(300),
+            Err(&unparsed),
+        );
+        stats.record(
+            start + Duration::from_secs(120),
+            Duration::from_millis(200),
+            Err(&anyhow!("Storage is down.")),
+        );
+
+        let health = stats.health("royalroad", start + Duration::from_secs(120));
+        assert_eq!(health.fetch_attempts, 3);
+        assert_eq!(health.successes, 1);
+        assert_eq!(health.failures, 2);
+        assert_eq!(health.parse_failures, 1);
+        assert!(health.http_errors.is_empty());
+        assert_eq!(health.average_latency_ms, Some(200));
+        assert_eq!(health.last_error.unwrap().message, "Storage is down.");
+
+        // The first fetch falls out of the window a day after it was made.
+        let health = stats.health("royalroad", start + WINDOW + Duration::from_secs(1));
+        assert_eq!(health.fetch_attempts, 2);
+        assert_eq!(health.successes, 0);
+    }
+
+    #[tokio::test]
+    async fn classifies_http_errors_by_status() {
+        let server = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::any())
+            .respond_with(wiremock::ResponseTemplate::new(503))
+            .mount(&server)
+            .await;
+        let err: Error = crate::clients::http::text(reqwest::Client::new().get(server.uri()))
+            .await
+            .unwrap_err();
+        let err = err.context("Failed to fetch new pale chapters.");
+        assert_eq!(Outcome::of(Err(&err)), Outcome::HttpError("5xx".into()));
+    }
+}
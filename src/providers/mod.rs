@@ -1,7 +1,246 @@
 pub mod apparatus_of_change_patreon;
+pub mod health;
 pub mod pale;
 pub mod practical_guide;
 pub mod royalroad;
 pub mod the_daily_grind_patreon;
 pub mod wandering_inn;
 pub mod wandering_inn_patreon;
+
+use std::fmt::Display;
+use std::time::Duration;
+
+use anyhow::{Context, Error, Result};
+use mailparse::ParsedMail;
+use reqwest::StatusCode;
+
+use crate::models::CheckProblem;
+use crate::util::retry_with_backoff;
+
+/// A fetched page or feed a provider failed to parse, attached to the parse
+/// error so the response can be kept as evidence.
+#[derive(Debug)]
+pub struct Unparsed {
+    pub url: String,
+    pub content: Vec<u8>,
+}
+
+impl Display for Unparsed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to parse {}", self.url)
+    }
+}
+
+/// How a provider failed, which decides when its book is checked again.
+/// Providers attach it to errors where their site tells them, and
+/// [`ProviderError::of`] works it out for the rest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProviderError {
+    /// Likely to pass on its own, such as a timeout or a 503.
+    Transient {
+        /// How long the site asked to be left alone for.
+        retry_after: Option<Duration>,
+    },
+    /// The book or chapter no longer exists on the site.
+    Gone,
+    /// The site's pages changed so they no longer parse.
+    ParseChanged,
+    /// The site needs credentials which are missing or wrong.
+    AuthRequired,
+    Other,
+}
+
+impl Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transient {
+                retry_after: Some(retry_after),
+            } => write!(
+                f,
+                "The site failed for now and asked to be retried in {} seconds",
+                retry_after.as_secs()
+            ),
+            Self::Transient { retry_after: None } => write!(f, "The site failed for now"),
+            Self::Gone => write!(f, "The site no longer has it"),
+            Self::ParseChanged => write!(f, "The site's pages no longer parse"),
+            Self::AuthRequired => write!(f, "The site needs credentials"),
+            Self::Other => write!(f, "The site failed"),
+        }
+    }
+}
+
+impl ProviderError {
+    /// Classifies a failure from its chain. A class a provider attached wins,
+    /// then parse failures, then the status of a failed request.
+    pub fn of(err: &Error) -> Self {
+        if let Some(class) = err.downcast_ref::<ProviderError>() {
+            return class.clone();
+        }
+        if err.downcast_ref::<Unparsed>().is_some() {
+            return Self::ParseChanged;
+        }
+        match err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        {
+            Some(err) => match err.status() {
+                Some(status) => Self::of_status(status, None),
+                None if err.is_timeout() || err.is_connect() || err.is_request() => {
+                    Self::Transient { retry_after: None }
+                }
+                None => Self::Other,
+            },
+            None => Self::Other,
+        }
+    }
+
+    /// Classifies an error status, with the wait the response's `Retry-After`
+    /// header asked for.
+    pub fn of_status(status: StatusCode, retry_after: Option<Duration>) -> Self {
+        match status {
+            StatusCode::NOT_FOUND | StatusCode::GONE => Self::Gone,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Self::AuthRequired,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::REQUEST_TIMEOUT => {
+                Self::Transient { retry_after }
+            }
+            status if status.is_server_error() => Self::Transient { retry_after },
+            _ => Self::Other,
+        }
+    }
+
+    /// The problem recorded on a book whose check failed this way, for those
+    /// which retrying soon won't fix.
+    pub fn problem(&self) -> Option<CheckProblem> {
+        match self {
+            Self::Gone => Some(CheckProblem::Gone),
+            Self::ParseChanged => Some(CheckProblem::ParseChanged),
+            Self::AuthRequired => Some(CheckProblem::AuthRequired),
+            Self::Transient { .. } | Self::Other => None,
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+/// How many times a provider requests a page before giving up on it.
+pub const FETCH_ATTEMPTS: u32 = 3;
+
+/// The wait before a provider's first retry, doubling for each one after.
+const FETCH_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// Runs a provider's `fetch`, retrying it with backoff when the site fails
+/// with a server error or can't be reached, so a blip doesn't fail a check.
+pub async fn retry_fetch<T, F, Fut>(fetch: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    retry_with_backoff(FETCH_ATTEMPTS, FETCH_BASE_DELAY, fetch).await
+}
+
+/// Attaches the `content` fetched from `url` to a failure to parse it.
+pub fn keep_unparsed<T>(
+    parsed: Result<T>,
+    url: impl Display,
+    content: impl Into<Vec<u8>>,
+) -> Result<T> {
+    parsed.map_err(|err| {
+        err.context(Unparsed {
+            url: url.to_string(),
+            content: content.into(),
+        })
+    })
+}
+
+/// A chapter read from a patreon email.
+#[derive(Debug, PartialEq)]
+pub struct EmailChapter {
+    pub name: String,
+    /// The chapter's HTML.
+    pub body: String,
+}
+
+/// The body of an email, from its last part when it has several.
+///
+/// The body of a multipart email itself is only the preamble before its
+/// first part, which is usually empty.
+pub fn email_body(email: &ParsedMail) -> Result<String> {
+    let body = match email.subparts.last() {
+        Some(part) => part.get_body(),
+        None => email.get_body(),
+    };
+    body.context("Unable to find parsable email body.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use wiremock::matchers::any;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn fetch_failure(response: ResponseTemplate) -> Error {
+        let server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(response)
+            .mount(&server)
+            .await;
+        crate::clients::http::text(reqwest::Client::new().get(server.uri()))
+            .await
+            .unwrap_err()
+            .context("Failed to fetch new chapters.")
+    }
+
+    #[tokio::test]
+    async fn classifies_failed_requests_by_status() {
+        let err = fetch_failure(ResponseTemplate::new(404)).await;
+        assert_eq!(ProviderError::of(&err), ProviderError::Gone);
+        let err = fetch_failure(ResponseTemplate::new(410)).await;
+        assert_eq!(ProviderError::of(&err), ProviderError::Gone);
+        let err = fetch_failure(ResponseTemplate::new(403)).await;
+        assert_eq!(ProviderError::of(&err), ProviderError::AuthRequired);
+        let err = fetch_failure(ResponseTemplate::new(503)).await;
+        assert_eq!(
+            ProviderError::of(&err),
+            ProviderError::Transient { retry_after: None }
+        );
+        let err =
+            fetch_failure(ResponseTemplate::new(429).insert_header("retry-after", "120")).await;
+        assert_eq!(
+            ProviderError::of(&err),
+            ProviderError::Transient {
+                retry_after: Some(Duration::from_secs(120))
+            }
+        );
+        let err = fetch_failure(ResponseTemplate::new(400)).await;
+        assert_eq!(ProviderError::of(&err), ProviderError::Other);
+    }
+
+    #[tokio::test]
+    async fn classifies_unreachable_sites_as_transient() {
+        // Nothing listens on the discard port.
+        let err: Error =
+            crate::clients::http::text(reqwest::Client::new().get("http://127.0.0.1:9"))
+                .await
+                .unwrap_err();
+        assert_eq!(
+            ProviderError::of(&err),
+            ProviderError::Transient { retry_after: None }
+        );
+    }
+
+    #[test]
+    fn classifies_parse_failures_and_attached_classes() {
+        let unparsed = keep_unparsed::<()>(Err(anyhow!("No chapter table.")), "/fiction/1", "")
+            .unwrap_err()
+            .context("Failed to list chapters.");
+        assert_eq!(ProviderError::of(&unparsed), ProviderError::ParseChanged);
+        // A class the provider attached wins over the parse failure.
+        let auth = unparsed.context(ProviderError::AuthRequired);
+        assert_eq!(ProviderError::of(&auth), ProviderError::AuthRequired);
+        assert_eq!(
+            ProviderError::of(&anyhow!("Object had no body.")),
+            ProviderError::Other
+        );
+    }
+}
@@ -4,10 +4,13 @@ use anyhow::Context;
 use anyhow::Result;
 use itertools::Itertools;
 use scraper::{Html, Selector};
+use url::Url;
 use uuid::Uuid;
 
+use crate::clients::http;
 use crate::models::Book;
 use crate::models::{BookKind, ChapterKind, NewBook, NewChapter};
+use crate::providers::{keep_unparsed, retry_fetch};
 use crate::util::parse_from_rfc2822;
 use crate::util::validate_hostname;
 
@@ -16,15 +19,20 @@ pub fn get_book() -> NewBook {
         name: "Pale".into(),
         author: "Wildbow".into(),
         metadata: BookKind::Pale,
+        tags: Vec::new(),
+        content_warnings: Vec::new(),
     }
 }
 
-pub async fn get_chapters(book_uuid: &Uuid) -> Result<Vec<NewChapter>> {
-    let content = reqwest::get("https://palewebserial.wordpress.com/feed/")
-        .await?
-        .bytes()
-        .await?;
-    let channel = rss::Channel::read_from(&content[..])?;
+pub async fn get_chapters(base_url: &Url, book_uuid: &Uuid) -> Result<Vec<NewChapter>> {
+    let link = base_url.join("feed/")?;
+    let content = retry_fetch(|| http::bytes(http::scrape_client().get(link.clone()))).await?;
+    keep_unparsed(parse_feed(&content, book_uuid), link, content)
+}
+
+/// Reads the chapters listed in the RSS feed.
+pub fn parse_feed(content: &[u8], book_uuid: &Uuid) -> Result<Vec<NewChapter>> {
+    let channel = rss::Channel::read_from(content)?;
     channel
         .items()
         .iter()
@@ -59,8 +67,17 @@ pub async fn get_chapter_body(
     book: &Book,
     chapter: &NewChapter,
 ) -> Result<String, anyhow::Error> {
-    let res = reqwest::get(link).await?.text().await?;
-    let doc = Html::parse_document(&res);
+    let res = retry_fetch(|| http::text(http::scrape_client().get(link))).await?;
+    let body = keep_unparsed(parse_chapter_body(&res), link, res)?;
+    let mut header = format!("<h1>{}: {}</h1>", book.name, chapter.name);
+    header.push_str(&body);
+    Ok(body)
+}
+
+/// Extracts the chapter text from a chapter's page, without the links to the
+/// previous and next chapters.
+pub fn parse_chapter_body(html: &str) -> Result<String> {
+    let doc = Html::parse_document(html);
     let chapter_body_elem_selector = Selector::parse("div.entry-content > *").unwrap();
 
     let body = doc
@@ -73,8 +90,6 @@ pub async fn get_chapter_body(
     if body.trim().is_empty() {
         bail!("Failed to find chapter body.");
     }
-    let mut header = format!("<h1>{}: {}</h1>", book.name, chapter.name);
-    header.push_str(&body);
     Ok(body)
 }
 
@@ -82,3 +97,109 @@ pub fn try_parse_url(url: &str) -> Result<()> {
     let valid_host = "practicalguidetoevil.wordpress.com";
     validate_hostname(url, valid_host)
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    #[test]
+    fn parses_feed() {
+        let feed = include_bytes!("../../tests/fixtures/wordpress/feed.xml");
+        let book_uuid = Uuid::new_v4();
+        let chapters = parse_feed(feed, &book_uuid).unwrap();
+        assert_eq!(chapters.len(), 2);
+        assert_eq!(
+            chapters[0],
+            NewChapter {
+                name: "Gone Astray – 1.1".into(),
+                author: "Wildbow".into(),
+                book_id: book_uuid,
+                metadata: ChapterKind::Pale {
+                    url: "https://testserial.wordpress.com/2023/01/02/gone-astray-1-1/".into(),
+                },
+                published_at: parse_from_rfc2822("Mon, 02 Jan 2023 22:00:00 +0000").unwrap(),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_chapter_body_without_navigation_or_sharing() {
+        let html = include_str!("../../tests/fixtures/wordpress/chapter.html");
+        let body = parse_chapter_body(html).unwrap();
+        assert!(body.contains("The first paragraph of the chapter."));
+        assert!(body.contains("The <em>second</em> paragraph of the chapter."));
+        assert!(!body.contains("Next Chapter"));
+        assert!(!body.contains("Previous Chapter"));
+        assert!(!body.contains("Share this:"));
+    }
+
+    #[test]
+    fn rejects_page_without_chapter_body() {
+        let html = include_str!("../../tests/fixtures/wordpress/chapter_empty.html");
+        let err = parse_chapter_body(html).unwrap_err();
+        assert_eq!(err.to_string(), "Failed to find chapter body.");
+    }
+
+    #[tokio::test]
+    async fn fetches_feed_then_chapter_bodies() {
+        let server = MockServer::start().await;
+        // The feed links to chapters on the mock server rather than the blog.
+        let feed = include_str!("../../tests/fixtures/wordpress/feed.xml")
+            .replace("https://testserial.wordpress.com", &server.uri());
+        Mock::given(method("GET"))
+            .and(path("/feed/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(feed))
+            .expect(1)
+            .mount(&server)
+            .await;
+        for chapter in [
+            "/2023/01/02/gone-astray-1-1/",
+            "/2023/01/05/gone-astray-1-2/",
+        ] {
+            Mock::given(method("GET"))
+                .and(path(chapter))
+                .respond_with(
+                    ResponseTemplate::new(200).set_body_string(include_str!(
+                        "../../tests/fixtures/wordpress/chapter.html"
+                    )),
+                )
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        let base_url = Url::parse(&server.uri()).unwrap();
+        let book = Book {
+            id: Uuid::new_v4(),
+            name: "Pale".into(),
+            author: "Wildbow".into(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            metadata: BookKind::Pale,
+            deleted_at: None,
+            chapter_count: 0,
+            latest_chapter_published_at: None,
+            last_checked_at: None,
+            last_success_at: None,
+            last_error: None,
+            metadata_refreshed_at: None,
+            tags: Vec::new(),
+            content_warnings: Vec::new(),
+            next_check_at: None,
+            check_problem: None,
+        };
+        let chapters = get_chapters(&base_url, &book.id).await.unwrap();
+        assert_eq!(chapters.len(), 2);
+        for chapter in &chapters {
+            let ChapterKind::Pale { url } = &chapter.metadata else {
+                panic!("Unexpected chapter kind {:?}", chapter.metadata);
+            };
+            let body = get_chapter_body(url, &book, chapter).await.unwrap();
+            assert!(body.contains("The first paragraph of the chapter."));
+        }
+    }
+}
@@ -4,10 +4,13 @@ use anyhow::Context;
 use anyhow::Result;
 use itertools::Itertools;
 use scraper::{Html, Selector};
+use url::Url;
 use uuid::Uuid;
 
+use crate::clients::http;
 use crate::models::Book;
 use crate::models::{BookKind, ChapterKind, NewBook, NewChapter};
+use crate::providers::{keep_unparsed, retry_fetch};
 use crate::util::parse_from_rfc2822;
 use crate::util::validate_hostname;
 
@@ -16,15 +19,20 @@ pub fn get_book() -> NewBook {
         name: "A Practical Guide To Evil".into(),
         author: "erraticerrata".into(),
         metadata: BookKind::APracticalGuideToEvil,
+        tags: Vec::new(),
+        content_warnings: Vec::new(),
     }
 }
 
-pub async fn get_chapters(book_uuid: &Uuid) -> Result<Vec<NewChapter>> {
-    let content = reqwest::get("https://practicalguidetoevil.wordpress.com/feed/")
-        .await?
-        .bytes()
-        .await?;
-    let channel = rss::Channel::read_from(&content[..])?;
+pub async fn get_chapters(base_url: &Url, book_uuid: &Uuid) -> Result<Vec<NewChapter>> {
+    let link = base_url.join("feed/")?;
+    let content = retry_fetch(|| http::bytes(http::scrape_client().get(link.clone()))).await?;
+    keep_unparsed(parse_feed(&content, book_uuid), link, content)
+}
+
+/// Reads the chapters listed in the RSS feed.
+pub fn parse_feed(content: &[u8], book_uuid: &Uuid) -> Result<Vec<NewChapter>> {
+    let channel = rss::Channel::read_from(content)?;
     channel
         .items()
         .iter()
@@ -55,8 +63,17 @@ pub async fn get_chapters(book_uuid: &Uuid) -> Result<Vec<NewChapter>> {
 }
 
 pub async fn get_chapter_body(link: &str, book: &Book, chapter: &NewChapter) -> Result<String> {
-    let res = reqwest::get(link).await?.text().await?;
-    let doc = Html::parse_document(&res);
+    let res = retry_fetch(|| http::text(http::scrape_client().get(link))).await?;
+    let body = keep_unparsed(parse_chapter_body(&res), link, res)?;
+    let mut header = format!("<h1>{}: {}</h1>", book.name, chapter.name);
+    header.push_str(&body);
+    Ok(body)
+}
+
+/// Extracts the chapter text from a chapter's page, without the links to the
+/// previous and next chapters.
+pub fn parse_chapter_body(html: &str) -> Result<String> {
+    let doc = Html::parse_document(html);
     let chapter_body_elem_selector = Selector::parse("div.entry-content > *").unwrap();
 
     let body = doc
@@ -69,8 +86,6 @@ pub async fn get_chapter_body(link: &str, book: &Book, chapter: &NewChapter) ->
     if body.trim().is_empty() {
         bail!("Failed to find chapter body.");
     }
-    let mut header = format!("<h1>{}: {}</h1>", book.name, chapter.name);
-    header.push_str(&body);
     Ok(body)
 }
 
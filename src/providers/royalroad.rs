@@ -2,33 +2,70 @@ extern crate futures;
 extern crate reqwest;
 extern crate url;
 
+use crate::cache::TtlCache;
+use crate::clients::http;
+use crate::clock;
 use crate::models::Book;
 use crate::models::BookKind;
+use crate::models::ChapterExtras;
 use crate::models::ChapterKind;
 use crate::models::NewBook;
 use crate::models::NewChapter;
+use crate::providers::{keep_unparsed, retry_fetch, ProviderError};
+use crate::util::error_chain;
 
 use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Context;
 use chrono::Utc;
+use derive_more::DebugCustom;
+use reqwest::cookie::Jar;
 use rss::Item;
-use scraper::{Html, Selector};
+use scraper::{ElementRef, Html, Selector};
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::{Arc, OnceLock};
+use tracing::{info, warn};
 use url::Url;
 use uuid::Uuid;
 
 use anyhow::Result;
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Hash)]
+/// How long a fiction's metadata is reused, as bulk operations can ask for
+/// the same fiction several times within seconds.
+const BOOK_CACHE_TTL: chrono::Duration = chrono::Duration::minutes(10);
+
+static BOOK_CACHE: OnceLock<TtlCache<Url, NewBook>> = OnceLock::new();
+
+/// Fiction metadata by the url of the fiction's page.
+fn book_cache() -> &'static TtlCache<Url, NewBook> {
+    BOOK_CACHE.get_or_init(|| TtlCache::new(BOOK_CACHE_TTL, clock::system()))
+}
+
+#[derive(Clone, DebugCustom, Eq, PartialEq, Serialize, Deserialize, Hash)]
+#[debug(fmt = "RoyalRoadBookKind {{ id: {} }}", id)]
 pub struct RoyalRoadBookKind {
     pub id: u64,
+    /// The cookies of a logged in session, sent with chapter requests so
+    /// chapters only the author's patrons can read, such as advance
+    /// chapters, are fetched. Books added before it existed have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_cookie: Option<String>,
+}
+
+impl RoyalRoadBookKind {
+    pub fn new(id: u64) -> Self {
+        Self {
+            id,
+            session_cookie: None,
+        }
+    }
 }
 
 pub fn try_parse_url(request_url: &str) -> Result<RoyalRoadBookKind> {
     let request_url = Url::parse(request_url)?;
-    let valid_hosts = vec!["www.royalroad.com", "royalroad.com"];
+    let valid_hosts = ["www.royalroad.com", "royalroad.com"];
     if request_url.host_str().is_none()
         || !valid_hosts
             .iter()
@@ -51,11 +88,11 @@ pub fn try_parse_url(request_url: &str) -> Result<RoyalRoadBookKind> {
     if royalroad_id.is_none() {
         bail!("Book id in url {} not valid.", request_url);
     }
-    Ok(RoyalRoadBookKind {
-        id: royalroad_id.unwrap(),
-    })
+    Ok(RoyalRoadBookKind::new(royalroad_id.unwrap()))
 }
 
+/// Fetches a fiction's title and author, reusing a recent fetch unless
+/// `bypass_cache` is set.
 #[tracing::instrument(
 name = "Fetching Book Metadata",
 err,
@@ -64,14 +101,28 @@ fields(
     request_id = %Uuid::new_v4(),
 )
 )]
-pub async fn as_new_book(book_meta: &RoyalRoadBookKind) -> Result<NewBook> {
-    return fetch_book_meta(book_meta).await;
+pub async fn as_new_book(
+    base_url: &Url,
+    book_meta: &RoyalRoadBookKind,
+    bypass_cache: bool,
+) -> Result<NewBook> {
+    let link = base_url.join(&format!("fiction/{}", book_meta.id))?;
+    if !bypass_cache {
+        if let Some(book) = book_cache().get(&link) {
+            metrics::counter!("royalroad_book_cache_total", "result" => "hit").increment(1);
+            return Ok(book);
+        }
+        metrics::counter!("royalroad_book_cache_total", "result" => "miss").increment(1);
+    }
+    let html = retry_fetch(|| http::text(http::scrape_client().get(link.clone()))).await?;
+    let book = parse_book_page(&html, book_meta)?;
+    book_cache().insert(link, book.clone());
+    Ok(book)
 }
 
-async fn fetch_book_meta(book_meta: &RoyalRoadBookKind) -> Result<NewBook> {
-    let link = format!("https://royalroad.com/fiction/{}", book_meta.id);
-    let html = reqwest::get(&link).await?.text().await?;
-    let doc = Html::parse_document(&html);
+/// Reads the title and author from a fiction's page.
+pub fn parse_book_page(html: &str, book_meta: &RoyalRoadBookKind) -> Result<NewBook> {
+    let doc = Html::parse_document(html);
     let title_selector = Selector::parse("div.fic-header h1").unwrap();
     let author_selector = Selector::parse("div.fic-header h4 span[property=name]").unwrap();
 
@@ -103,35 +154,328 @@ async fn fetch_book_meta(book_meta: &RoyalRoadBookKind) -> Result<NewBook> {
         name: title,
         author,
         metadata: BookKind::RoyalRoad(book_meta.clone()),
+        tags: list_texts(&doc, "span.tags a.fiction-tag"),
+        content_warnings: list_texts(&doc, "div.font-red-sunglo ul.list-inline li"),
     })
 }
 
+/// The trimmed, non-empty text of each element matching `selector`, in page
+/// order and without repeats.
+fn list_texts(doc: &Html, selector: &str) -> Vec<String> {
+    let selector = Selector::parse(selector).unwrap();
+    let mut texts: Vec<String> = Vec::new();
+    for element in doc.select(&selector) {
+        let text = element.text().collect::<String>().trim().to_string();
+        if !text.is_empty() && !texts.contains(&text) {
+            texts.push(text);
+        }
+    }
+    texts
+}
+
 pub async fn get_chapter_body(
+    base_url: &Url,
     chapter_id: &u64,
     book: &Book,
     chapter: &NewChapter,
-) -> Result<String> {
-    let link = format!("https://www.royalroad.com/fiction/chapter/{}", chapter_id);
-    let res = reqwest::get(&link).await?.text().await?;
-    let doc = Html::parse_document(&res);
+) -> Result<(String, ChapterExtras)> {
+    let link = base_url.join(&format!("fiction/chapter/{}", chapter_id))?;
+    let session_cookie = match &book.metadata {
+        BookKind::RoyalRoad(kind) => kind.session_cookie.as_deref(),
+        _ => None,
+    };
+    let client = match session_cookie {
+        Some(session_cookie) => &session_client(base_url, session_cookie)?,
+        None => http::scrape_client(),
+    };
+    let res = retry_fetch(|| http::text(client.get(link.clone()))).await?;
+    let extras = parse_chapter_extras(&res);
+    let login_required = is_login_page(&res);
+    let body = keep_unparsed(parse_chapter_body(&res), link, res);
+    // Gated chapters show a login form in place of the chapter to readers
+    // without access, which no change to the parser would fix.
+    let body = match login_required {
+        true => body.context(ProviderError::AuthRequired)?,
+        false => body?,
+    };
+    let mut header = format!("<h1>{}: {}</h1>", book.name, chapter.name);
+    header.push_str(&body);
+    Ok((header, extras))
+}
+
+/// A client which sends a session's cookies to RoyalRoad, given as
+/// `name=value` pairs separated by semicolons as in a `Cookie` header.
+fn session_client(base_url: &Url, session_cookie: &str) -> Result<reqwest::Client> {
+    let jar = Jar::default();
+    for cookie in session_cookie
+        .split(';')
+        .map(str::trim)
+        .filter(|cookie| !cookie.is_empty())
+    {
+        jar.add_cookie_str(cookie, base_url);
+    }
+    Ok(http::scrape_builder()
+        .cookie_provider(Arc::new(jar))
+        .build()?)
+}
+
+/// Whether `html` asks the reader to log in rather than showing a chapter.
+fn is_login_page(html: &str) -> bool {
+    let doc = Html::parse_document(html);
+    let chapter_selector = Selector::parse("div.chapter-inner").unwrap();
+    let login_selector = Selector::parse("form[action*='/account/login']").unwrap();
+    doc.select(&chapter_selector).next().is_none() && doc.select(&login_selector).next().is_some()
+}
+
+/// Reads the author's notes either side of the chapter text, and the count
+/// in the caption of the comments section, which reads "Comments(<count>)".
+pub fn parse_chapter_extras(html: &str) -> ChapterExtras {
+    let doc = Html::parse_document(html);
+    let sections_selector = Selector::parse("div.author-note-portlet, div.chapter-inner").unwrap();
+    let note_selector = Selector::parse(".author-note").unwrap();
+    let caption_selector = Selector::parse(".comments-container .caption-subject").unwrap();
+
+    let mut extras = ChapterExtras::default();
+    let mut after_chapter = false;
+    for section in doc.select(&sections_selector) {
+        if section
+            .value()
+            .classes()
+            .any(|class| class == "chapter-inner")
+        {
+            after_chapter = true;
+            continue;
+        }
+        let note = section
+            .select(&note_selector)
+            .next()
+            .unwrap_or(section)
+            .inner_html();
+        let note = Some(note.trim().to_owned()).filter(|note| !note.is_empty());
+        if after_chapter {
+            extras.note_after = extras.note_after.or(note);
+        } else {
+            extras.note_before = extras.note_before.or(note);
+        }
+    }
+    extras.comment_count = doc.select(&caption_selector).find_map(|caption| {
+        let caption = caption.text().collect::<String>();
+        let (_, count) = caption.trim().strip_prefix("Comments")?.split_once('(')?;
+        count
+            .split_once(')')?
+            .0
+            .replace(',', "")
+            .trim()
+            .parse()
+            .ok()
+    });
+    extras
+}
+
+/// Extracts the chapter text from a chapter's page.
+///
+/// Royalroad inserts paragraphs telling readers the chapter was stolen, with a
+/// randomly named class hidden by a style rule on the page. Those are removed,
+/// as ebook readers don't apply the page's styles.
+pub fn parse_chapter_body(html: &str) -> Result<String> {
+    let doc = Html::parse_document(html);
     let chapter_body_selector = Selector::parse("div.chapter-inner").unwrap();
 
-    let body = doc
+    let chapter_body = doc
         .select(&chapter_body_selector)
         .next()
-        .ok_or_else(|| anyhow!("Failed to find body in {}", link))?
-        .html();
-    let mut header = format!("<h1>{}: {}</h1>", book.name, chapter.name);
-    header.push_str(&body);
-    Ok(header)
+        .ok_or_else(|| anyhow!("Failed to find chapter body."))?;
+    let hidden_classes = hidden_classes(&doc);
+    let mut body = chapter_body.html();
+    for element in chapter_body.descendants().filter_map(ElementRef::wrap) {
+        if element
+            .value()
+            .classes()
+            .any(|class| hidden_classes.contains(class))
+        {
+            body = body.replace(&element.html(), "");
+        }
+    }
+    Ok(body)
+}
+
+/// A chapter read from its page alone, for chapters of fictions which aren't
+/// followed.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChapterPage {
+    pub fiction: String,
+    pub title: String,
+    pub body: String,
+}
+
+pub async fn get_chapter_page(base_url: &Url, chapter_id: u64) -> Result<ChapterPage> {
+    let link = base_url.join(&format!("fiction/chapter/{}", chapter_id))?;
+    let res = retry_fetch(|| http::text(http::scrape_client().get(link.clone()))).await?;
+    parse_chapter_page(&res).with_context(|| format!("Failed to parse {}", link))
+}
+
+/// Reads a chapter's title and fiction from the page title, which reads
+/// "<chapter> - <fiction> | Royal Road", along with its body.
+pub fn parse_chapter_page(html: &str) -> Result<ChapterPage> {
+    let doc = Html::parse_document(html);
+    let title_selector = Selector::parse("head title").unwrap();
+    let page_title = doc
+        .select(&title_selector)
+        .next()
+        .ok_or_else(|| anyhow!("Failed to find title element on royalroad page."))?
+        .text()
+        .collect::<String>();
+    let page_title = page_title.trim();
+    let (title, fiction) = page_title
+        .strip_suffix("| Royal Road")
+        .and_then(|title| title.trim().rsplit_once(" - "))
+        .ok_or_else(|| anyhow!("Unrecognized royalroad page title {}.", page_title))?;
+    Ok(ChapterPage {
+        fiction: fiction.trim().into(),
+        title: title.trim().into(),
+        body: parse_chapter_body(html)?,
+    })
+}
+
+/// The classes given `display: none` by the page's style elements.
+fn hidden_classes(doc: &Html) -> HashSet<String> {
+    let style_selector = Selector::parse("style").unwrap();
+    doc.select(&style_selector)
+        .flat_map(|style| {
+            let css = style.text().collect::<String>();
+            css.split('}')
+                .filter_map(|rule| rule.split_once('{'))
+                .filter(|(_, declarations)| {
+                    declarations
+                        .replace(char::is_whitespace, "")
+                        .contains("display:none")
+                })
+                .flat_map(|(selectors, _)| {
+                    selectors
+                        .split(',')
+                        .filter_map(|selector| selector.trim().strip_prefix('.'))
+                        .map(str::to_owned)
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Lists a fiction's chapters from its RSS feed. The feed sometimes fails or
+/// comes back empty while the fiction's page lists chapters, so the page's
+/// chapter table is read instead, with the span's `fallback` recording why.
+#[tracing::instrument(
+    name = "Listing royalroad chapters",
+    level = "info",
+    err,
+    fields(fallback = tracing::field::Empty)
+)]
+pub async fn get_chapters(
+    base_url: &Url,
+    book_id: u64,
+    book_uuid: &Uuid,
+    author: &str,
+) -> Result<Vec<NewChapter>> {
+    let feed = get_feed_chapters(base_url, book_id, book_uuid, author).await;
+    let reason = match &feed {
+        Ok(chapters) if !chapters.is_empty() => return feed,
+        Ok(_) => "empty",
+        Err(_) => "failed",
+    };
+    tracing::Span::current().record("fallback", reason);
+    metrics::counter!("royalroad_feed_fallback_total", "reason" => reason).increment(1);
+    match get_fiction_page_chapters(base_url, book_id, book_uuid, author).await {
+        Ok(chapters) => {
+            info!(
+                reason,
+                chapters = chapters.len(),
+                "Listed chapters from the fiction page in place of the feed."
+            );
+            Ok(chapters)
+        }
+        Err(err) => {
+            warn!(
+                reason,
+                error = %error_chain(&err),
+                "Failed to list chapters from the fiction page as well as the feed."
+            );
+            feed
+        }
+    }
+}
+
+async fn get_feed_chapters(
+    base_url: &Url,
+    book_id: u64,
+    book_uuid: &Uuid,
+    author: &str,
+) -> Result<Vec<NewChapter>> {
+    let link = base_url.join(&format!("syndication/{}", book_id))?;
+    let content = retry_fetch(|| http::bytes(http::scrape_client().get(link.clone()))).await?;
+    keep_unparsed(parse_feed(&content, book_uuid, author), link, content)
+}
+
+/// Lists a fiction's chapters from the table on its page, in the order the
+/// fiction's table of contents lists them.
+pub async fn get_fiction_page_chapters(
+    base_url: &Url,
+    book_id: u64,
+    book_uuid: &Uuid,
+    author: &str,
+) -> Result<Vec<NewChapter>> {
+    let link = base_url.join(&format!("fiction/{}", book_id))?;
+    let html = retry_fetch(|| http::text(http::scrape_client().get(link.clone()))).await?;
+    keep_unparsed(
+        parse_chapter_table(&html, book_uuid, author),
+        link,
+        html.into_bytes(),
+    )
+}
+
+/// Reads the chapters listed in the table on a fiction's page, as the feed
+/// would list them.
+pub fn parse_chapter_table(html: &str, book_uuid: &Uuid, author: &str) -> Result<Vec<NewChapter>> {
+    let doc = Html::parse_document(html);
+    let table_selector = Selector::parse("table#chapters").unwrap();
+    let row_selector = Selector::parse("tbody tr.chapter-row").unwrap();
+    let link_selector = Selector::parse("td a[href]").unwrap();
+    let time_selector = Selector::parse("time[unixtime]").unwrap();
+    let table = doc
+        .select(&table_selector)
+        .next()
+        .ok_or_else(|| anyhow!("Failed to find the chapter table on royalroad page."))?;
+    table
+        .select(&row_selector)
+        .map(|row| {
+            let link = row
+                .select(&link_selector)
+                .next()
+                .ok_or_else(|| anyhow!("No chapter link in royalroad chapter row."))?;
+            let unixtime = row
+                .select(&time_selector)
+                .next()
+                .and_then(|time| time.value().attr("unixtime"))
+                .and_then(|unixtime| unixtime.parse().ok())
+                .ok_or_else(|| anyhow!("No release time in royalroad chapter row."))?;
+            Ok(NewChapter {
+                book_id: *book_uuid,
+                metadata: ChapterKind::RoyalRoad {
+                    id: get_chapter_id_from_path(link.value().attr("href"))?,
+                },
+                author: author.into(),
+                name: link.text().collect::<String>().trim().into(),
+                published_at: chrono::DateTime::from_timestamp(unixtime, 0).ok_or_else(|| {
+                    anyhow!("Invalid release time {} in royalroad row.", unixtime)
+                })?,
+            })
+        })
+        .collect()
 }
 
-pub async fn get_chapters(book_id: u64, book_uuid: &Uuid, author: &str) -> Result<Vec<NewChapter>> {
-    let content = reqwest::get(format!("https://www.royalroad.com/syndication/{}", book_id))
-        .await?
-        .bytes()
-        .await?;
-    let channel = rss::Channel::read_from(&content[..])?;
+/// Reads the chapters listed in a fiction's RSS feed.
+pub fn parse_feed(content: &[u8], book_uuid: &Uuid, author: &str) -> Result<Vec<NewChapter>> {
+    let channel = rss::Channel::read_from(content)?;
     channel
         .items()
         .iter()
@@ -176,6 +520,392 @@ fn get_chapter_id_from_link(link: Option<&str>) -> Result<u64> {
     .ok_or_else(|| anyhow!("No valid royalroad chapter link in RSS Item."))
 }
 
+/// The id in a chapter path such as
+/// "/fiction/12345/the-serial/chapter/1000001/chapter-one".
+fn get_chapter_id_from_path(path: Option<&str>) -> Result<u64> {
+    path.and_then(|path| {
+        path.split('/')
+            .skip_while(|segment| *segment != "chapter")
+            .nth(1)
+            .and_then(|id| id.parse().ok())
+    })
+    .ok_or_else(|| anyhow!("No valid royalroad chapter link in chapter row."))
+}
+
 fn parse_from_rfc2822(pub_date: &str) -> Result<chrono::DateTime<Utc>> {
     Ok(chrono::DateTime::parse_from_rfc2822(pub_date)?.with_timezone(&Utc))
 }
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+    use wiremock::matchers::{header_regex, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    fn book_meta() -> RoyalRoadBookKind {
+        RoyalRoadBookKind::new(12345)
+    }
+
+    #[test]
+    fn parses_book_page() {
+        let html = include_str!("../../tests/fixtures/royalroad/fiction.html");
+        let book = parse_book_page(html, &book_meta()).unwrap();
+        assert_eq!(book.name, "The Test Serial");
+        assert_eq!(book.author, "Test Author");
+        assert_eq!(book.metadata, BookKind::RoyalRoad(book_meta()));
+        assert!(book.tags.is_empty());
+        assert!(book.content_warnings.is_empty());
+    }
+
+    #[test]
+    fn parses_tags_and_content_warnings() {
+        let html = include_str!("../../tests/fixtures/royalroad/fiction_with_warnings.html");
+        let book = parse_book_page(html, &book_meta()).unwrap();
+        assert_eq!(book.name, "The Tagged Serial");
+        assert_eq!(book.tags, vec!["LitRPG", "Progression", "Fantasy"]);
+        assert_eq!(book.content_warnings, vec!["Profanity", "Gore"]);
+    }
+
+    #[test]
+    fn rejects_book_page_with_empty_title() {
+        let html = include_str!("../../tests/fixtures/royalroad/fiction_empty_title.html");
+        let err = parse_book_page(html, &book_meta()).unwrap_err();
+        assert_eq!(err.to_string(), "Empty title element on royalroad page.");
+    }
+
+    #[test]
+    fn rejects_page_without_fiction_header() {
+        let html = include_str!("../../tests/fixtures/royalroad/chapter.html");
+        assert!(parse_book_page(html, &book_meta()).is_err());
+    }
+
+    #[test]
+    fn parses_chapter_body_without_watermarks() {
+        let html = include_str!("../../tests/fixtures/royalroad/chapter.html");
+        let body = parse_chapter_body(html).unwrap();
+        assert!(body.contains("The first paragraph of the chapter."));
+        assert!(body.contains("The <em>second</em> paragraph of the chapter."));
+        assert!(!body.contains("Unauthorized usage"));
+        assert!(!body.contains("Thanks for reading!"));
+    }
+
+    #[test]
+    fn parses_author_notes_and_comment_count() {
+        let html = include_str!("../../tests/fixtures/royalroad/chapter.html");
+        assert_eq!(
+            parse_chapter_extras(html),
+            ChapterExtras {
+                note_before: Some("<p>Two chapters this week.</p>".into()),
+                note_after: Some("<p>Thanks for reading!</p>".into()),
+                comment_count: Some(1204),
+            }
+        );
+        let html = include_str!("../../tests/fixtures/royalroad/fiction.html");
+        assert!(parse_chapter_extras(html).is_empty());
+    }
+
+    #[test]
+    fn parses_chapter_page() {
+        let html = include_str!("../../tests/fixtures/royalroad/chapter.html");
+        let page = parse_chapter_page(html).unwrap();
+        assert_eq!(page.fiction, "The Test Serial");
+        assert_eq!(page.title, "Chapter 1: Beginnings");
+        assert_eq!(page.body, parse_chapter_body(html).unwrap());
+
+        let html = include_str!("../../tests/fixtures/royalroad/fiction.html");
+        assert!(parse_chapter_page(html).is_err());
+    }
+
+    #[test]
+    fn rejects_page_without_chapter_body() {
+        let html = include_str!("../../tests/fixtures/royalroad/fiction.html");
+        assert!(parse_chapter_body(html).is_err());
+    }
+
+    #[test]
+    fn parses_feed() {
+        let feed = include_bytes!("../../tests/fixtures/royalroad/feed.xml");
+        let book_uuid = Uuid::new_v4();
+        let chapters = parse_feed(feed, &book_uuid, "Test Author").unwrap();
+        assert_eq!(
+            chapters,
+            vec![
+                NewChapter {
+                    name: "Chapter 1: Beginnings".into(),
+                    author: "Test Author".into(),
+                    book_id: book_uuid,
+                    metadata: ChapterKind::RoyalRoad { id: 1000001 },
+                    published_at: parse_from_rfc2822("Mon, 02 Jan 2023 10:00:00 GMT").unwrap(),
+                },
+                NewChapter {
+                    name: "Interlude".into(),
+                    author: "Test Author".into(),
+                    book_id: book_uuid,
+                    metadata: ChapterKind::RoyalRoad { id: 1000002 },
+                    published_at: parse_from_rfc2822("Tue, 03 Jan 2023 10:30:00 GMT").unwrap(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_chapter_table_as_the_feed_lists_chapters() {
+        let book_uuid = Uuid::new_v4();
+        let feed = include_bytes!("../../tests/fixtures/royalroad/feed.xml");
+        let page = include_str!("../../tests/fixtures/royalroad/fiction.html");
+        assert_eq!(
+            parse_chapter_table(page, &book_uuid, "Test Author").unwrap(),
+            parse_feed(feed, &book_uuid, "Test Author").unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_feed_item_without_pub_date() {
+        let feed = include_bytes!("../../tests/fixtures/royalroad/feed_missing_pub_date.xml");
+        let err = parse_feed(feed, &Uuid::new_v4(), "Test Author").unwrap_err();
+        assert!(err.to_string().starts_with("No publish date in RSS item."));
+    }
+
+    async fn serve(server: &MockServer, route: &str, body: &'static str) {
+        Mock::given(method("GET"))
+            .and(path(route))
+            .respond_with(ResponseTemplate::new(200).set_body_string(body))
+            .expect(1)
+            .mount(server)
+            .await;
+    }
+
+    fn book() -> Book {
+        Book {
+            id: Uuid::new_v4(),
+            name: "The Test Serial".into(),
+            author: "Test Author".into(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            metadata: BookKind::RoyalRoad(book_meta()),
+            deleted_at: None,
+            chapter_count: 0,
+            latest_chapter_published_at: None,
+            last_checked_at: None,
+            last_success_at: None,
+            last_error: None,
+            metadata_refreshed_at: None,
+            tags: Vec::new(),
+            content_warnings: Vec::new(),
+            next_check_at: None,
+            check_problem: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_book_page() {
+        let server = MockServer::start().await;
+        serve(
+            &server,
+            "/fiction/12345",
+            include_str!("../../tests/fixtures/royalroad/fiction.html"),
+        )
+        .await;
+
+        let base_url = Url::parse(&server.uri()).unwrap();
+        let book = as_new_book(&base_url, &book_meta(), false).await.unwrap();
+        assert_eq!(book.name, "The Test Serial");
+    }
+
+    #[tokio::test]
+    async fn caches_book_pages_unless_bypassed() {
+        // Mock servers are pooled, so a fiction no other test fetches keeps
+        // their cached pages out of this one.
+        let book_meta = RoyalRoadBookKind::new(54321);
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/fiction/54321"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../../tests/fixtures/royalroad/fiction.html")),
+            )
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let base_url = Url::parse(&server.uri()).unwrap();
+        for bypass_cache in [false, false, true] {
+            let book = as_new_book(&base_url, &book_meta, bypass_cache)
+                .await
+                .unwrap();
+            assert_eq!(book.author, "Test Author");
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_feed_then_chapter_bodies() {
+        let server = MockServer::start().await;
+        serve(
+            &server,
+            "/syndication/12345",
+            include_str!("../../tests/fixtures/royalroad/feed.xml"),
+        )
+        .await;
+        for id in ["1000001", "1000002"] {
+            serve(
+                &server,
+                &format!("/fiction/chapter/{}", id),
+                include_str!("../../tests/fixtures/royalroad/chapter.html"),
+            )
+            .await;
+        }
+
+        Mock::given(method("GET"))
+            .and(path("/fiction/12345"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let base_url = Url::parse(&server.uri()).unwrap();
+        let book = book();
+        let chapters = get_chapters(&base_url, 12345, &book.id, &book.author)
+            .await
+            .unwrap();
+        assert_eq!(chapters.len(), 2);
+        for chapter in &chapters {
+            let ChapterKind::RoyalRoad { id } = chapter.metadata else {
+                panic!("Unexpected chapter kind {:?}", chapter.metadata);
+            };
+            let (body, extras) = get_chapter_body(&base_url, &id, &book, chapter)
+                .await
+                .unwrap();
+            assert_eq!(extras.comment_count, Some(1204));
+            let header = format!("<h1>The Test Serial: {}</h1>", chapter.name);
+            assert!(body.starts_with(&header));
+            assert!(!body.contains("Unauthorized usage"));
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_the_session_cookie_for_gated_chapters() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/fiction/chapter/1000003"))
+            .and(header_regex("cookie", "session=abc"))
+            .and(header_regex("cookie", "remember=1"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../../tests/fixtures/royalroad/chapter.html")),
+            )
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/fiction/chapter/1000003"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(include_str!(
+                "../../tests/fixtures/royalroad/chapter_login.html"
+            )))
+            .mount(&server)
+            .await;
+        let base_url = Url::parse(&server.uri()).unwrap();
+        let chapter = NewChapter {
+            name: "Advance Chapter".into(),
+            author: "Test Author".into(),
+            book_id: Uuid::new_v4(),
+            metadata: ChapterKind::RoyalRoad { id: 1000003 },
+            published_at: Utc::now(),
+        };
+        let patron = Book {
+            metadata: BookKind::RoyalRoad(RoyalRoadBookKind {
+                session_cookie: Some("session=abc; remember=1".into()),
+                ..book_meta()
+            }),
+            ..book()
+        };
+
+        let (body, _) = get_chapter_body(&base_url, &1000003, &patron, &chapter)
+            .await
+            .unwrap();
+        assert!(body.contains("The first paragraph of the chapter."));
+        let err = get_chapter_body(&base_url, &1000003, &book(), &chapter)
+            .await
+            .unwrap_err();
+        assert_eq!(ProviderError::of(&err), ProviderError::AuthRequired);
+    }
+
+    #[test]
+    fn keeps_session_cookies_out_of_sight() {
+        let legacy: BookKind = serde_json::from_str(r#"{"RoyalRoad":{"id":12345}}"#).unwrap();
+        assert_eq!(legacy, BookKind::RoyalRoad(book_meta()));
+        assert_eq!(
+            serde_json::to_string(&legacy).unwrap(),
+            r#"{"RoyalRoad":{"id":12345}}"#
+        );
+
+        let kind = RoyalRoadBookKind {
+            session_cookie: Some("session=abc".into()),
+            ..book_meta()
+        };
+        let stored = serde_json::to_value(BookKind::RoyalRoad(kind.clone())).unwrap();
+        assert_eq!(stored["RoyalRoad"]["session_cookie"], "session=abc");
+        let shown = serde_json::to_value(Book {
+            metadata: BookKind::RoyalRoad(kind.clone()),
+            ..book()
+        })
+        .unwrap();
+        assert_eq!(
+            shown["metadata"],
+            serde_json::json!({"RoyalRoad": {"id": 12345}})
+        );
+        assert!(!format!("{:?}", kind).contains("abc"));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_fiction_page() {
+        let book = book();
+        let expected = parse_feed(
+            include_bytes!("../../tests/fixtures/royalroad/feed.xml"),
+            &book.id,
+            &book.author,
+        )
+        .unwrap();
+        let failed = ResponseTemplate::new(500).set_body_string("Internal error");
+        let empty = ResponseTemplate::new(200).set_body_string(include_str!(
+            "../../tests/fixtures/royalroad/feed_empty.xml"
+        ));
+        for feed in [failed, empty] {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/syndication/12345"))
+                .respond_with(feed)
+                .mount(&server)
+                .await;
+            serve(
+                &server,
+                "/fiction/12345",
+                include_str!("../../tests/fixtures/royalroad/fiction.html"),
+            )
+            .await;
+
+            let base_url = Url::parse(&server.uri()).unwrap();
+            let chapters = get_chapters(&base_url, 12345, &book.id, &book.author)
+                .await
+                .unwrap();
+            assert_eq!(chapters, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_with_error_status() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(404).set_body_string("Not found"))
+            .mount(&server)
+            .await;
+
+        let base_url = Url::parse(&server.uri()).unwrap();
+        let err = get_chapters(&base_url, 12345, &Uuid::new_v4(), "Test Author")
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<reqwest::Error>().unwrap();
+        assert_eq!(err.status(), Some(StatusCode::NOT_FOUND));
+    }
+}
@@ -1,70 +1,58 @@
-use std::env;
-
 use anyhow::anyhow;
 use anyhow::bail;
+use anyhow::Context;
 use anyhow::Result;
+use aws_sdk_s3::types::Object;
+use aws_sdk_s3::Client as S3Client;
 use chrono::DateTime;
 use chrono::Utc;
 use futures::future::join_all;
 use itertools::Itertools;
 use mailparse::MailHeaderMap;
 use reqwest::Url;
-use rusoto_core::credential::StaticProvider;
-use rusoto_core::HttpClient;
-use rusoto_core::Region;
-use rusoto_s3::GetObjectRequest;
-use rusoto_s3::ListObjectsV2Request;
-use rusoto_s3::Object;
-use rusoto_s3::S3Client;
-use rusoto_s3::S3;
 use scraper::Html;
 use scraper::Selector;
-use tokio::io::AsyncReadExt;
 use uuid::Uuid;
 
 use crate::models::{BookKind, ChapterKind, NewBook, NewChapter};
+use crate::providers::{email_body, EmailChapter};
+use crate::storage::{self, EmailBucket, Storage};
 
 pub fn get_book() -> NewBook {
     NewBook {
         name: "The Daily Grind".into(),
         author: "argusthecat".into(),
         metadata: BookKind::TheDailyGrindPatreon,
+        tags: Vec::new(),
+        content_warnings: Vec::new(),
     }
 }
 
 #[tracing::instrument(
     name = "Checking for new patreon daily grind chapters.",
     ret,
-    level = "info"
+    level = "info",
+    skip(storage)
 )]
-pub async fn get_chapters(book_uuid: &Uuid) -> Result<Vec<NewChapter>> {
-    let s3 = S3Client::new_with(
-        HttpClient::new().expect("failed to create request dispatcher"),
-        StaticProvider::new_minimal(
-            env::var("AWS_ACCESS_KEY")?,
-            env::var("AWS_SECRET_ACCESS_KEY")?,
-        ),
-        Region::default(),
-    );
-    let bucket = env::var("AWS_EMAIL_BUCKET")?;
-    let objects = s3
-        .list_objects_v2(ListObjectsV2Request {
-            bucket: bucket.clone(),
-            ..Default::default()
-        })
-        .await?;
+pub async fn get_chapters(book_uuid: &Uuid, storage: &Storage) -> Result<Vec<NewChapter>> {
+    let EmailBucket { client: s3, bucket } = storage.email_bucket()?;
+    let objects = storage::traced(
+        "ListObjectsV2",
+        bucket,
+        "",
+        s3.list_objects_v2().bucket(bucket).send(),
+    )
+    .await
+    .with_context(|| format!("Failed to list s3://{}", bucket))?;
     let chapters = objects
         .contents
         .ok_or_else(|| anyhow!("Object had no body."))?
         .into_iter()
-        .map(|obj| get_chapter_meta(obj, &bucket, &s3, book_uuid));
+        .map(|obj| get_chapter_meta(obj, bucket, s3, storage, book_uuid));
     let chapters = join_all(chapters)
         .await
         .into_iter()
-        .filter_map(|r| match r {
-            Ok(c) => Some(c),
-            Err(_) => None,
-        })
+        .filter_map(Result::ok)
         .collect_vec();
     Ok(chapters)
 }
@@ -72,69 +60,75 @@ pub async fn get_chapters(book_uuid: &Uuid) -> Result<Vec<NewChapter>> {
 #[tracing::instrument(
     name = "Reading email files for new daily grind patreon chapters.",
     level = "info"
-    skip(s3),
+    skip(s3, storage),
     ret
 )]
 async fn get_chapter_meta(
     s3_obj: Object,
     bucket_name: &str,
     s3: &S3Client,
+    storage: &Storage,
     book_id: &Uuid,
 ) -> Result<NewChapter> {
-    let chapter_object = s3
-        .get_object(GetObjectRequest {
-            bucket: bucket_name.to_owned(),
-            key: s3_obj
-                .key
-                .ok_or_else(|| anyhow!("No key found on s3 object."))?,
-            ..Default::default()
-        })
-        .await?;
+    let key = s3_obj
+        .key
+        .ok_or_else(|| anyhow!("No key found on s3 object."))?;
+    let chapter_object = storage::traced(
+        "GetObject",
+        bucket_name,
+        &key,
+        s3.get_object().bucket(bucket_name).key(&key).send(),
+    )
+    .await
+    .with_context(|| format!("Failed to fetch s3://{}/{}", bucket_name, key))?;
     tracing::info!("Last modified at {:?}", chapter_object.last_modified);
     let published_at = chapter_object
         .last_modified
         .ok_or_else(|| anyhow!("No modification date on email s3 object."))?;
-    let published_at: DateTime<Utc> = DateTime::parse_from_rfc2822(&published_at)?.into();
+    let published_at: DateTime<Utc> = storage::to_chrono(published_at)?;
     tracing::info!("Published at {:?}", published_at);
-    let mut chapter_bytes = Vec::new();
-    chapter_object
+    let chapter_bytes = chapter_object
         .body
-        .ok_or_else(|| anyhow!("No body on s3 object."))?
-        .into_async_read()
-        .read_to_end(&mut chapter_bytes)
+        .collect()
+        .await
+        .with_context(|| format!("Failed to read s3://{}/{}", bucket_name, key))?
+        .into_bytes();
+    let chapter = parse_email(&chapter_bytes)?;
+    let location = storage
+        .store_source(book_id, chapter.body.into_bytes())
         .await?;
-    let chapter_email = mailparse::parse_mail(&chapter_bytes)?;
-    let subject = chapter_email.headers.get_first_value("Subject");
-    match &subject {
-        Some(x) => {
-            if !x.to_lowercase().contains("daily grind") {
-                bail!("Not a the daily grind Email")
-            }
-        }
-        None => bail!("Not a the daily grind Email"),
-    }
-    let singlepart_email_body = chapter_email.get_body();
-    let multipart_email_body = chapter_email.subparts.iter().last().map(|x| x.get_body());
-    let body = match (singlepart_email_body, multipart_email_body) {
-        (Ok(x), _) => x,
-        (Err(_), Some(x)) => x?,
-        (Err(_), None) => bail!("Unable to find parsable email body."),
+
+    Ok(NewChapter {
+        name: chapter.name,
+        author: String::from("argusthecat"),
+        book_id: *book_id,
+        published_at,
+        metadata: ChapterKind::TheDailyGrindPatreon {
+            bucket: location.bucket,
+            key: location.key,
+        },
+    })
+}
+
+/// Reads the chapter title from the subject of a patreon email, and the
+/// chapter from its body.
+pub fn parse_email(email: &[u8]) -> Result<EmailChapter> {
+    let chapter_email = mailparse::parse_mail(email)?;
+    let subject = match chapter_email.headers.get_first_value("Subject") {
+        Some(x) if x.to_lowercase().contains("daily grind") => x,
+        _ => bail!("Not a the daily grind Email"),
     };
-    let doc = Html::parse_document(&body);
-    let body = doc
+    let body = email_body(&chapter_email)?;
+    let body = Html::parse_document(&body)
         .select(&Selector::parse("td > div > span > div > div > div > div + div").unwrap())
         .map(|x| x.html())
         .next()
         .ok_or_else(|| anyhow!("No matching body in html."))?;
-
-    Ok(NewChapter {
-        name: chapter_title_from_subject(&subject.unwrap())
+    Ok(EmailChapter {
+        name: chapter_title_from_subject(&subject)
             .ok_or_else(|| anyhow!("Failed to find chapter title from email subject"))?
             .into(),
-        author: String::from("argusthecat"),
-        book_id: *book_id,
-        published_at,
-        metadata: ChapterKind::TheDailyGrindPatreon { html: body },
+        body,
     })
 }
 
@@ -157,3 +151,36 @@ pub fn try_parse_url(url: &str) -> Result<()> {
         _ => Err(anyhow!("Not a patreon daily grind url.")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_email() {
+        let email = include_bytes!("../../tests/fixtures/patreon/daily_grind.eml");
+        let chapter = parse_email(email).unwrap();
+        assert_eq!(chapter.name, "Chapter 101: Overtime");
+        assert!(chapter
+            .body
+            .starts_with("<div><p>The first paragraph of the chapter.</p>"));
+        assert!(!chapter.body.contains("New post"));
+    }
+
+    #[test]
+    fn rejects_other_emails() {
+        let email = include_bytes!("../../tests/fixtures/patreon/apparatus_of_change.eml");
+        let err = parse_email(email).unwrap_err();
+        assert_eq!(err.to_string(), "Not a the daily grind Email");
+    }
+
+    #[test]
+    fn rejects_email_without_quoted_title() {
+        let email = include_bytes!("../../tests/fixtures/patreon/untitled.eml");
+        let err = parse_email(email).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Failed to find chapter title from email subject"
+        );
+    }
+}
@@ -1,9 +1,9 @@
-use std::collections::HashMap;
-use std::env;
-
 use anyhow::anyhow;
 use anyhow::bail;
+use anyhow::Context;
 use anyhow::Result;
+use aws_sdk_s3::types::Object;
+use aws_sdk_s3::Client as S3Client;
 use chrono::DateTime;
 use chrono::Utc;
 use futures::future::join_all;
@@ -11,54 +11,46 @@ use itertools::Itertools;
 use mailparse::MailHeaderMap;
 use reqwest::Method;
 use reqwest::Url;
-use rusoto_core::credential::StaticProvider;
-use rusoto_core::HttpClient;
-use rusoto_core::Region;
-use rusoto_s3::GetObjectRequest;
-use rusoto_s3::ListObjectsV2Request;
-use rusoto_s3::Object;
-use rusoto_s3::S3Client;
-use rusoto_s3::S3;
 use scraper::{Html, Selector};
 use selectors::Element;
-use tokio::io::AsyncReadExt;
+use std::collections::HashMap;
 use uuid::Uuid;
 
+use crate::clients::http;
 use crate::models::Book;
 use crate::models::{BookKind, ChapterKind, NewBook, NewChapter};
+use crate::providers::{email_body, keep_unparsed, wandering_inn, ProviderError};
+use crate::storage::{self, EmailBucket};
 
 pub fn get_book() -> NewBook {
     NewBook {
         name: "The Wandering Inn".into(),
         author: "Pirateaba".into(),
         metadata: BookKind::TheWanderingInnPatreon,
+        tags: Vec::new(),
+        content_warnings: Vec::new(),
     }
 }
 
 #[tracing::instrument(
     name = "Checking for new patreon wandering inn chapters.",
     ret,
-    level = "info"
+    level = "info",
+    skip(email_bucket)
 )]
-pub async fn get_chapters(book_uuid: &Uuid) -> Result<Vec<NewChapter>> {
-    let s3 = S3Client::new_with(
-        HttpClient::new().expect("failed to create request dispatcher"),
-        StaticProvider::new_minimal(
-            env::var("AWS_ACCESS_KEY")?,
-            env::var("AWS_SECRET_ACCESS_KEY")?,
-        ),
-        Region::default(),
-    );
-    let bucket = env::var("AWS_EMAIL_BUCKET")?;
-    let objects = s3
-        .list_objects_v2(ListObjectsV2Request {
-            bucket: bucket.clone(),
-            ..Default::default()
-        })
-        .await?;
+pub async fn get_chapters(book_uuid: &Uuid, email_bucket: &EmailBucket) -> Result<Vec<NewChapter>> {
+    let EmailBucket { client: s3, bucket } = email_bucket;
+    let objects = storage::traced(
+        "ListObjectsV2",
+        bucket,
+        "",
+        s3.list_objects_v2().bucket(bucket).send(),
+    )
+    .await
+    .with_context(|| format!("Failed to list s3://{}", bucket))?;
     let chapters = objects.contents.map(|c| {
         c.into_iter()
-            .map(|obj| get_chapter_metas(obj, &bucket, &s3, book_uuid))
+            .map(|obj| get_chapter_metas(obj, bucket, s3, book_uuid))
     });
     match chapters {
         Some(chapters) => {
@@ -89,45 +81,50 @@ async fn get_chapter_metas(
     s3: &S3Client,
     book_id: &Uuid,
 ) -> Result<Vec<NewChapter>> {
-    let chapter_object = s3
-        .get_object(GetObjectRequest {
-            bucket: bucket_name.to_owned(),
-            key: s3_obj
-                .key
-                .ok_or_else(|| anyhow!("No key found on s3 object."))?,
-            ..Default::default()
-        })
-        .await?;
+    let key = s3_obj
+        .key
+        .ok_or_else(|| anyhow!("No key found on s3 object."))?;
+    let chapter_object = storage::traced(
+        "GetObject",
+        bucket_name,
+        &key,
+        s3.get_object().bucket(bucket_name).key(&key).send(),
+    )
+    .await
+    .with_context(|| format!("Failed to fetch s3://{}/{}", bucket_name, key))?;
     tracing::info!("Last modified at {:?}", chapter_object.last_modified);
     let published_at = chapter_object
         .last_modified
         .ok_or_else(|| anyhow!("No modification date on email s3 object."))?;
-    let published_at: DateTime<Utc> = DateTime::parse_from_rfc2822(&published_at)?.into();
+    let published_at: DateTime<Utc> = storage::to_chrono(published_at)?;
     tracing::info!("Published at {:?}", published_at);
-    let mut chapter_bytes = Vec::new();
-    chapter_object
+    let chapter_bytes = chapter_object
         .body
-        .ok_or_else(|| anyhow!("No body on s3 object."))?
-        .into_async_read()
-        .read_to_end(&mut chapter_bytes)
-        .await?;
-    let chapter_email = mailparse::parse_mail(&chapter_bytes)?;
+        .collect()
+        .await
+        .with_context(|| format!("Failed to read s3://{}/{}", bucket_name, key))?
+        .into_bytes();
+    parse_email(&chapter_bytes, book_id, published_at)
+}
+
+/// Reads the chapter links and the password protecting them from a patreon
+/// email.
+///
+/// The password is taken from the paragraph following the one mentioning it.
+/// When an email gives different passwords none is used, as there is no way
+/// to tell which chapter each belongs to.
+pub fn parse_email(
+    email: &[u8],
+    book_id: &Uuid,
+    published_at: DateTime<Utc>,
+) -> Result<Vec<NewChapter>> {
+    let chapter_email = mailparse::parse_mail(email)?;
     match chapter_email.headers.get_first_value("Subject") {
-        Some(x) => {
-            if !x.to_lowercase().contains("pirateaba") {
-                bail!("Not a Wandering Inn Email")
-            }
-        }
-        None => bail!("Not a Wandering Inn Email"),
+        Some(x) if x.to_lowercase().contains("pirateaba") => {}
+        _ => bail!("Not a Wandering Inn Email"),
     }
 
-    let singlepart_email_body = chapter_email.get_body();
-    let multipart_email_body = chapter_email.subparts.iter().last().map(|x| x.get_body());
-    let body = match (singlepart_email_body, multipart_email_body) {
-        (Ok(x), _) => x,
-        (Err(_), Some(x)) => x?,
-        (Err(_), None) => bail!("Unable to find parsable email body."),
-    };
+    let body = email_body(&chapter_email)?;
     tracing::info!("Found wandering inn patreon email with body: {}", body);
     let doc = Html::parse_document(&body);
     let para_tags_selector = Selector::parse("div > p").unwrap();
@@ -135,10 +132,11 @@ async fn get_chapter_metas(
     let password = doc
         .select(&para_tags_selector)
         .filter(|x| x.text().any(|t| t.to_lowercase().contains("password")))
-        .map(|x| x.next_sibling_element().map(|sib| sib.text().join("")))
+        .filter_map(|x| x.next_sibling_element().map(|sib| sib.text().join("")))
+        .map(|password| password.trim().to_owned())
+        .unique()
         .exactly_one()
-        .ok()
-        .flatten();
+        .ok();
     tracing::info!("Found password {:?}", password);
 
     let links_selector = Selector::parse("div > p a").unwrap();
@@ -169,40 +167,48 @@ async fn get_chapter_metas(
     ret
 )]
 fn chapter_title_from_link(link: &str) -> Option<&str> {
-    link.split('/').filter(|x| !x.trim().is_empty()).last()
+    link.split('/').rfind(|x| !x.trim().is_empty())
+}
+
+/// Whether `html` is wordpress asking for a post's password.
+fn is_password_form(html: &str) -> bool {
+    let selector = Selector::parse("input[name=post_password]").unwrap();
+    Html::parse_document(html)
+        .select(&selector)
+        .next()
+        .is_some()
 }
 
 #[tracing::instrument(name = "Fetching chapter text from link.", level = "info")]
 pub async fn get_chapter_body(
+    base_url: &Url,
     link: &str,
     password: Option<&str>,
     book: &Book,
     chapter: &NewChapter,
 ) -> Result<String> {
-    let reqwest_client = reqwest::Client::builder().cookie_store(true).build()?;
+    let reqwest_client = http::scrape_builder().cookie_store(true).build()?;
     if let Some(password) = password {
         let mut form_data = HashMap::with_capacity(2);
         form_data.insert("post_password", password);
         form_data.insert("Submit", "Enter");
-        let _password_submit_result = reqwest_client
-            .request(Method::POST, "https://wanderinginn.com/wp-pass.php")
-            .form(&form_data)
-            .send()
-            .await?;
-    }
-    let res = reqwest_client.get(link).send().await?.text().await?;
-    let doc = Html::parse_document(&res);
-    let chapter_body_elem_selector = Selector::parse("div.entry-content > *").unwrap();
-
-    let body = doc
-        .select(&chapter_body_elem_selector)
-        .filter(|x| !x.text().any(|t| t == "Next Chapter"))
-        .filter(|x| !x.text().any(|t| t == "Previous Chapter"))
-        .map(|x| x.html())
-        .join("\n");
-    if body.trim().is_empty() {
-        bail!("Failed to find chapter body.");
+        let _password_submit_result = http::send(
+            reqwest_client
+                .request(Method::POST, base_url.join("wp-pass.php")?)
+                .form(&form_data),
+        )
+        .await?;
     }
+    let res = http::text(reqwest_client.get(link)).await?;
+    let protected = is_password_form(&res);
+    // Protected chapters are laid out the same as public ones.
+    let body = keep_unparsed(wandering_inn::parse_chapter_body(&res), link, res);
+    // A wrong or missing password leaves the password form in place of the
+    // chapter, which no change to the parser would fix.
+    let body = match protected {
+        true => body.context(ProviderError::AuthRequired)?,
+        false => body?,
+    };
     let mut header = format!("<h1>{}: {}</h1>", book.name, chapter.name);
     header.push_str(&body);
     Ok(body)
@@ -215,3 +221,180 @@ pub fn try_parse_url(url: &str) -> Result<()> {
         _ => Err(anyhow!("Not a patreon wandering inn url.")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use wiremock::matchers::{body_string_contains, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    fn parse(email: &[u8]) -> Result<Vec<NewChapter>> {
+        parse_email(email, &Uuid::nil(), Utc::now())
+    }
+
+    fn passwords(chapters: &[NewChapter]) -> Vec<Option<String>> {
+        chapters
+            .iter()
+            .map(|chapter| match &chapter.metadata {
+                ChapterKind::TheWanderingInnPatreon { password, .. } => password.clone(),
+                kind => panic!("Unexpected chapter kind {:?}", kind),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_chapter_and_password() {
+        let email =
+            include_bytes!("../../tests/fixtures/wandering_inn_patreon/single_password.eml");
+        let chapters = parse(email).unwrap();
+        assert_eq!(chapters.len(), 1);
+        assert_eq!(chapters[0].name, "9-50");
+        assert_eq!(chapters[0].author, "pirateaba");
+        assert_eq!(
+            chapters[0].metadata,
+            ChapterKind::TheWanderingInnPatreon {
+                url: "https://wanderinginn.com/2023/01/02/9-50/".into(),
+                password: Some("hunter2".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn uses_password_repeated_for_each_chapter() {
+        let email =
+            include_bytes!("../../tests/fixtures/wandering_inn_patreon/repeated_password.eml");
+        let chapters = parse(email).unwrap();
+        assert_eq!(
+            chapters.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(),
+            ["9-50", "9-51"]
+        );
+        assert_eq!(
+            passwords(&chapters),
+            [Some("hunter2".into()), Some("hunter2".into())]
+        );
+    }
+
+    #[test]
+    fn uses_no_password_when_they_differ() {
+        let email =
+            include_bytes!("../../tests/fixtures/wandering_inn_patreon/different_passwords.eml");
+        let chapters = parse(email).unwrap();
+        assert_eq!(chapters.len(), 2);
+        assert_eq!(passwords(&chapters), [None, None]);
+    }
+
+    #[test]
+    fn rejects_other_emails() {
+        let email = include_bytes!("../../tests/fixtures/patreon/daily_grind.eml");
+        let err = parse(email).unwrap_err();
+        assert_eq!(err.to_string(), "Not a Wandering Inn Email");
+    }
+
+    fn book() -> Book {
+        Book {
+            id: Uuid::nil(),
+            name: "The Wandering Inn Patreon".into(),
+            author: "pirateaba".into(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            metadata: BookKind::TheWanderingInnPatreon,
+            deleted_at: None,
+            chapter_count: 0,
+            latest_chapter_published_at: None,
+            last_checked_at: None,
+            last_success_at: None,
+            last_error: None,
+            metadata_refreshed_at: None,
+            tags: Vec::new(),
+            content_warnings: Vec::new(),
+            next_check_at: None,
+            check_problem: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn submits_password_before_fetching_chapter() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/wp-pass.php"))
+            .and(body_string_contains("post_password=hunter2"))
+            .and(body_string_contains("Submit=Enter"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/2023/01/02/9-50/"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../../tests/fixtures/wordpress/chapter.html")),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let base_url = Url::parse(&server.uri()).unwrap();
+        let book = book();
+        let link = format!("{}/2023/01/02/9-50/", server.uri());
+        let chapter = NewChapter {
+            name: "9-50".into(),
+            author: "pirateaba".into(),
+            book_id: book.id,
+            published_at: Utc::now(),
+            metadata: ChapterKind::TheWanderingInnPatreon {
+                url: link.clone(),
+                password: Some("hunter2".into()),
+            },
+        };
+        let body = get_chapter_body(&base_url, &link, Some("hunter2"), &book, &chapter)
+            .await
+            .unwrap();
+        assert!(body.contains("The first paragraph of the chapter."));
+
+        // The password has to be submitted first for its cookie to be sent.
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(
+            requests
+                .iter()
+                .map(|x| x.method.as_str())
+                .collect::<Vec<_>>(),
+            ["POST", "GET"]
+        );
+    }
+
+    #[tokio::test]
+    async fn requires_auth_when_the_password_is_wrong() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/wp-pass.php"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/2023/01/02/9-50/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(include_str!(
+                "../../tests/fixtures/wordpress/chapter_empty.html"
+            )))
+            .mount(&server)
+            .await;
+
+        let base_url = Url::parse(&server.uri()).unwrap();
+        let book = book();
+        let link = format!("{}/2023/01/02/9-50/", server.uri());
+        let chapter = NewChapter {
+            name: "9-50".into(),
+            author: "pirateaba".into(),
+            book_id: book.id,
+            published_at: Utc::now(),
+            metadata: ChapterKind::TheWanderingInnPatreon {
+                url: link.clone(),
+                password: Some("wrong".into()),
+            },
+        };
+        let err = get_chapter_body(&base_url, &link, Some("wrong"), &book, &chapter)
+            .await
+            .unwrap_err();
+        assert_eq!(ProviderError::of(&err), ProviderError::AuthRequired);
+    }
+}
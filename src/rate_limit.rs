@@ -1,6 +1,13 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
-use governor::{clock, state::keyed::DefaultKeyedStateStore, RateLimiter};
+use governor::{
+    clock, middleware::RateLimitingMiddleware, state::keyed::DefaultKeyedStateStore, RateLimiter,
+};
 use reqwest::{Method, StatusCode};
 use warp::{
     filters::BoxedFilter,
@@ -9,33 +16,121 @@ use warp::{
     Filter, Rejection, Reply,
 };
 
+use crate::config::IpAccess;
 use crate::util::ErrorMessage;
 
-pub fn ip_rate_limit_filter(
-    limiter: Arc<
-        RateLimiter<
-            Option<SocketAddr>,
-            DefaultKeyedStateStore<Option<SocketAddr>>,
-            clock::DefaultClock,
-        >,
+/// How the access lists treat a client's address.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Access {
+    Denied,
+    /// Exempt from the IP rate limits.
+    Allowed,
+    Limited,
+}
+
+/// Checks `ip` against the access lists. Denial takes precedence, so an
+/// address in both lists is denied.
+pub fn access(lists: &IpAccess, ip: IpAddr) -> Access {
+    // IPv4 clients of a dual stack listener arrive as IPv4-mapped IPv6.
+    let ip = ip.to_canonical();
+    if lists.deny.iter().any(|network| network.contains(&ip)) {
+        Access::Denied
+    } else if lists.allow.iter().any(|network| network.contains(&ip)) {
+        Access::Allowed
+    } else {
+        Access::Limited
+    }
+}
+
+fn forbidden() -> WithStatus<Json> {
+    warp::reply::with_status(
+        warp::reply::json(&ErrorMessage {
+            message: "Forbidden".into(),
+        }),
+        StatusCode::FORBIDDEN,
+    )
+}
+
+/// Refuses denied addresses, and rejects every other request so it is passed
+/// on to the routes.
+pub fn ip_deny_filter(lists: Arc<IpAccess>) -> BoxedFilter<(impl Reply,)> {
+    warp::addr::remote()
+        .and(warp::any().map(move || lists.clone()))
+        .and_then(check_ip_denied)
+        .boxed()
+}
+
+async fn check_ip_denied(
+    ip: Option<SocketAddr>,
+    lists: Arc<IpAccess>,
+) -> Result<WithStatus<Json>, Rejection> {
+    match ip {
+        Some(ip) if access(&lists, ip.ip()) == Access::Denied => Ok(forbidden()),
+        _ => Err(warp::reject()),
+    }
+}
+
+/// Refuses admin routes to addresses outside the admin allowlist, when one is
+/// set.
+pub fn admin_ip_filter(lists: Arc<IpAccess>) -> BoxedFilter<(impl Reply,)> {
+    warp::path("admin")
+        .and(warp::addr::remote())
+        .and(warp::any().map(move || lists.clone()))
+        .and_then(check_admin_ip)
+        .boxed()
+}
+
+async fn check_admin_ip(
+    ip: Option<SocketAddr>,
+    lists: Arc<IpAccess>,
+) -> Result<WithStatus<Json>, Rejection> {
+    let Some(admin) = &lists.admin else {
+        return Err(warp::reject());
+    };
+    // Connections over a unix socket have no address to check, so are refused.
+    let allowed = ip.is_some_and(|ip| {
+        let ip = ip.ip().to_canonical();
+        admin.iter().any(|network| network.contains(&ip))
+    });
+    if allowed {
+        Err(warp::reject())
+    } else {
+        Ok(forbidden())
+    }
+}
+
+pub type IpLimiter = Arc<
+    RateLimiter<
+        Option<SocketAddr>,
+        DefaultKeyedStateStore<Option<SocketAddr>>,
+        clock::DefaultClock,
     >,
+>;
+
+pub fn ip_rate_limit_filter(
+    limiter: IpLimiter,
+    lists: Arc<IpAccess>,
 ) -> BoxedFilter<(impl Reply,)> {
     warp::addr::remote()
         .and(warp::any().map(move || limiter.clone()))
+        .and(warp::any().map(move || lists.clone()))
         .and_then(check_ip_limiter)
         .boxed()
 }
 
 async fn check_ip_limiter(
     ip: Option<SocketAddr>,
-    limiter: Arc<
-        RateLimiter<
-            Option<SocketAddr>,
-            DefaultKeyedStateStore<Option<SocketAddr>>,
-            clock::DefaultClock,
-        >,
-    >,
+    limiter: IpLimiter,
+    lists: Arc<IpAccess>,
 ) -> Result<WithStatus<Json>, Rejection> {
+    // Connections over a unix socket have no address, and all come from the
+    // proxy in front, so they are left to the proxy to limit.
+    let Some(addr) = ip else {
+        return Err(warp::reject());
+    };
+    if access(&lists, addr.ip()) == Access::Allowed {
+        return Err(warp::reject());
+    }
     let rate_limit_reply = warp::reply::with_status(
         warp::reply::json(&ErrorMessage {
             message: "IP Rate Limit".into(),
@@ -49,7 +144,7 @@ async fn check_ip_limiter(
     }
 }
 
-type PathLimiter = Arc<
+pub type PathLimiter = Arc<
     RateLimiter<(String, Method), DefaultKeyedStateStore<(String, Method)>, clock::DefaultClock>,
 >;
 
@@ -78,3 +173,141 @@ async fn check_path_limiter(
         Err(_) => Ok(rate_limit_reply),
     }
 }
+
+/// How often keys whose limits have fully recovered are dropped from the
+/// keyed rate limiters, which otherwise keep every client and route ever seen.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Prunes the API's rate limiters every [`PRUNE_INTERVAL`], for as long as
+/// the server runs.
+pub async fn prune_limiters(ip: IpLimiter, route: PathLimiter, convert: IpLimiter) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        prune("ip", &ip);
+        prune("route", &route);
+        prune("convert", &convert);
+    }
+}
+
+/// Drops the keys of `limiter` which are no longer limited, as they behave
+/// the same as keys never seen, and reports how many are left.
+fn prune<K, C, MW>(name: &'static str, limiter: &RateLimiter<K, DefaultKeyedStateStore<K>, C, MW>)
+where
+    K: Hash + Eq + Clone,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    limiter.retain_recent();
+    limiter.shrink_to_fit();
+    metrics::gauge!("rate_limiter_keys", "limiter" => name).set(limiter.len() as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use governor::Quota;
+    use std::num::NonZeroU32;
+
+    use super::*;
+
+    fn lists(allow: &[&str], deny: &[&str], admin: Option<&[&str]>) -> Arc<IpAccess> {
+        let parse = |networks: &[&str]| networks.iter().map(|n| n.parse().unwrap()).collect();
+        Arc::new(IpAccess {
+            allow: parse(allow),
+            deny: parse(deny),
+            admin: admin.map(parse),
+        })
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_ipv4_and_ipv6_networks() {
+        let lists = lists(&["10.0.0.0/8", "2001:db8::/32"], &[], None);
+        assert_eq!(access(&lists, ip("10.1.2.3")), Access::Allowed);
+        assert_eq!(access(&lists, ip("11.1.2.3")), Access::Limited);
+        assert_eq!(access(&lists, ip("2001:db8:1::1")), Access::Allowed);
+        assert_eq!(access(&lists, ip("2001:db9::1")), Access::Limited);
+        assert_eq!(access(&lists, ip("::ffff:10.1.2.3")), Access::Allowed);
+    }
+
+    #[test]
+    fn denial_beats_allowance() {
+        let lists = lists(&["10.0.0.0/8"], &["10.0.0.7/32"], None);
+        assert_eq!(access(&lists, ip("10.0.0.7")), Access::Denied);
+        assert_eq!(access(&lists, ip("10.0.0.8")), Access::Allowed);
+    }
+
+    #[test]
+    fn prunes_keys_which_are_no_longer_limited() {
+        use governor::clock::{Clock, FakeRelativeClock};
+        use governor::middleware::NoOpMiddleware;
+        use governor::state::keyed::DashMapStateStore;
+
+        let clock = FakeRelativeClock::default();
+        let limiter: RateLimiter<
+            &str,
+            DashMapStateStore<&str>,
+            FakeRelativeClock,
+            NoOpMiddleware<<FakeRelativeClock as Clock>::Instant>,
+        > = RateLimiter::new(
+            Quota::per_second(NonZeroU32::new(1).unwrap()),
+            DashMapStateStore::default(),
+            &clock,
+        );
+        limiter.check_key(&"stale").unwrap();
+        clock.advance(Duration::from_secs(5));
+        limiter.check_key(&"active").unwrap();
+        assert!(limiter.check_key(&"active").is_err());
+        assert_eq!(limiter.len(), 2);
+
+        prune("test", &limiter);
+
+        assert_eq!(limiter.len(), 1);
+        assert!(limiter.check_key(&"active").is_err());
+        clock.advance(Duration::from_secs(5));
+        prune("test", &limiter);
+        assert!(limiter.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_denied_and_lets_allowed_past_the_limit() {
+        let lists = lists(&["10.0.0.1/32"], &["203.0.113.0/24"], Some(&["10.0.0.0/8"]));
+        let limiter = Arc::new(RateLimiter::keyed(Quota::per_second(
+            NonZeroU32::new(1).unwrap(),
+        )));
+        let routes = ip_deny_filter(lists.clone())
+            .or(admin_ip_filter(lists.clone()))
+            .or(ip_rate_limit_filter(limiter, lists))
+            .or(warp::any().map(|| "ok"));
+        let status = |path: &'static str, addr: &'static str| {
+            let routes = routes.clone();
+            async move {
+                warp::test::request()
+                    .path(path)
+                    .remote_addr(addr.parse().unwrap())
+                    .reply(&routes)
+                    .await
+                    .status()
+            }
+        };
+
+        assert_eq!(status("/", "203.0.113.9:1").await, StatusCode::FORBIDDEN);
+        for _ in 0..3 {
+            assert_eq!(status("/", "10.0.0.1:1").await, StatusCode::OK);
+        }
+        assert_eq!(status("/", "192.0.2.1:1").await, StatusCode::OK);
+        assert_eq!(
+            status("/", "192.0.2.1:1").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(status("/admin/x", "10.0.0.1:1").await, StatusCode::OK);
+        assert_eq!(
+            status("/admin/x", "198.51.100.1:1").await,
+            StatusCode::FORBIDDEN
+        );
+    }
+}
@@ -1,3 +1,23 @@
+table! {
+    book_aliases (metadata) {
+        metadata -> Jsonb,
+        book_id -> Uuid,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    book_metadata_changes (id) {
+        id -> Uuid,
+        book_id -> Uuid,
+        old_name -> Text,
+        new_name -> Text,
+        old_author -> Text,
+        new_author -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
 table! {
     books (id) {
         id -> Uuid,
@@ -6,6 +26,17 @@ table! {
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
         metadata -> Jsonb,
+        deleted_at -> Nullable<Timestamptz>,
+        chapter_count -> Int8,
+        latest_chapter_published_at -> Nullable<Timestamptz>,
+        last_checked_at -> Nullable<Timestamptz>,
+        last_success_at -> Nullable<Timestamptz>,
+        last_error -> Nullable<Text>,
+        metadata_refreshed_at -> Nullable<Timestamptz>,
+        tags -> Array<Text>,
+        content_warnings -> Array<Text>,
+        next_check_at -> Nullable<Timestamptz>,
+        check_problem -> Nullable<Text>,
     }
 }
 
@@ -14,6 +45,7 @@ table! {
         key -> Text,
         bucket -> Text,
         chapter_id -> Uuid,
+        size -> Nullable<Int8>,
     }
 }
 
@@ -27,6 +59,50 @@ table! {
         book_id -> Uuid,
         published_at -> Timestamptz,
         metadata -> Jsonb,
+        status -> Text,
+        deleted_at -> Nullable<Timestamptz>,
+        word_count -> Nullable<Int8>,
+        ordinal -> Nullable<Int4>,
+    }
+}
+
+table! {
+    chapters_archive (id) {
+        id -> Uuid,
+        name -> Text,
+        author -> Text,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        book_id -> Uuid,
+        published_at -> Timestamptz,
+        metadata -> Jsonb,
+        status -> Text,
+        word_count -> Nullable<Int8>,
+        ordinal -> Nullable<Int4>,
+        body_bucket -> Nullable<Text>,
+        body_key -> Nullable<Text>,
+        archived_at -> Timestamptz,
+    }
+}
+
+table! {
+    deliveries (id) {
+        id -> Uuid,
+        user_id -> Text,
+        book_id -> Nullable<Uuid>,
+        chapter_ids -> Array<Uuid>,
+        created_at -> Timestamptz,
+        artifact_bucket -> Nullable<Text>,
+        artifact_key -> Nullable<Text>,
+        artifact_size -> Nullable<Int8>,
+        artifact_format -> Nullable<Text>,
+        dry_run -> Bool,
+        degraded -> Bool,
+        redelivery_of -> Nullable<Uuid>,
+        test -> Bool,
+        pushover_receipt -> Nullable<Text>,
+        pushover_acknowledged_at -> Nullable<Timestamptz>,
+        pushover_cancelled_at -> Nullable<Timestamptz>,
     }
 }
 
@@ -45,6 +121,10 @@ table! {
         updated_at -> Timestamptz,
         pushover_verification_code_time -> Nullable<Timestamptz>,
         pushover_verification_code -> Nullable<Text>,
+        feed_token -> Nullable<Text>,
+        pushover_combine_books -> Bool,
+        kindle_inline_fallback -> Bool,
+        kindle_conversion_mode -> Text,
     }
 }
 
@@ -55,6 +135,14 @@ table! {
         user_id -> Text,
         grouping_quantity -> Int8,
         last_chapter_id -> Nullable<Uuid>,
+        pushover_notified_chapter_id -> Nullable<Uuid>,
+        include_author_notes -> Text,
+        include_comment_count -> Bool,
+        pushover_priority -> Nullable<Int2>,
+        pushover_retry -> Nullable<Int4>,
+        pushover_expire -> Nullable<Int4>,
+        snoozed_until -> Nullable<Timestamptz>,
+        active -> Bool,
     }
 }
 
@@ -67,15 +155,52 @@ table! {
     }
 }
 
+table! {
+    user_preferences (user_id) {
+        user_id -> Text,
+        default_format -> Nullable<Text>,
+        default_grouping_quantity -> Nullable<Int8>,
+        output_profile -> Nullable<Text>,
+        combine_deliveries -> Nullable<Bool>,
+        quiet_hours_start -> Nullable<Int2>,
+        quiet_hours_end -> Nullable<Int2>,
+        timezone -> Nullable<Text>,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        kindle_subject_template -> Nullable<Text>,
+        pushover_template -> Nullable<Text>,
+        pushover_combined_template -> Nullable<Text>,
+        skip_snoozed_chapters -> Nullable<Bool>,
+    }
+}
+
+table! {
+    verification_sends (id) {
+        id -> Uuid,
+        target -> Text,
+        sent_at -> Timestamptz,
+    }
+}
+
+joinable!(book_aliases -> books (book_id));
+joinable!(book_metadata_changes -> books (book_id));
 joinable!(chapter_bodies -> chapters (chapter_id));
+joinable!(chapters_archive -> books (book_id));
+joinable!(deliveries -> books (book_id));
 joinable!(subscriptions -> chapters (last_chapter_id));
 joinable!(unsent_chapters -> chapters (chapter_id));
 
 allow_tables_to_appear_in_same_query!(
+    book_aliases,
+    book_metadata_changes,
     books,
     chapter_bodies,
     chapters,
+    chapters_archive,
+    deliveries,
     delivery_methods,
     subscriptions,
     unsent_chapters,
+    user_preferences,
+    verification_sends,
 );
@@ -0,0 +1,58 @@
+use tokio::signal;
+use tokio::sync::watch;
+use tracing::error;
+
+/// Lets a background task find out that the process is shutting down, so it
+/// can finish its current cycle and return.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Returns the sender which triggers shutdown, and the first listener.
+    pub fn channel() -> (watch::Sender<bool>, Self) {
+        let (sender, receiver) = watch::channel(false);
+        (sender, Self(receiver))
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once shutdown has been requested.
+    pub async fn requested(&mut self) {
+        // The sender only goes away once every task has stopped, so an error
+        // can be treated the same as a request.
+        let _ = self.0.wait_for(|requested| *requested).await;
+    }
+}
+
+/// Resolves when the process is asked to stop, by ctrl-c or, on unix, by the
+/// SIGTERM sent by process supervisors such as Kubernetes.
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(err) = signal::ctrl_c().await {
+            error!(error = %err, "Failed to listen for ctrl-c.");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                error!(error = %err, "Failed to listen for SIGTERM.");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
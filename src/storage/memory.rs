@@ -0,0 +1,128 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use aws_sdk_s3::primitives::ByteStream;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::{StorageBackend, StorageLocation, StoredObject};
+
+/// Objects held in memory, so tests can run the pipeline without S3.
+#[derive(Debug)]
+pub struct MemoryBackend {
+    bucket: String,
+    objects: Mutex<HashMap<StorageLocation, Object>>,
+}
+
+#[derive(Debug, Clone)]
+struct Object {
+    bytes: Vec<u8>,
+    last_modified: DateTime<Utc>,
+}
+
+impl Object {
+    fn new(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes,
+            last_modified: Utc::now(),
+        }
+    }
+}
+
+impl MemoryBackend {
+    pub fn new(bucket: &str) -> Self {
+        Self {
+            bucket: bucket.to_owned(),
+            objects: Mutex::default(),
+        }
+    }
+
+    /// The stored bytes of an object, as they would be in the bucket.
+    pub fn raw(&self, location: &StorageLocation) -> Option<Vec<u8>> {
+        let objects = self.objects.lock().unwrap();
+        objects.get(location).map(|object| object.bytes.clone())
+    }
+
+    fn location(&self, key: &str) -> StorageLocation {
+        StorageLocation {
+            bucket: self.bucket.clone(),
+            key: key.to_owned(),
+        }
+    }
+
+    fn not_found(location: &StorageLocation) -> anyhow::Error {
+        anyhow!("{} does not exist.", location)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+    fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    async fn put(&self, key: &str, body: ByteStream) -> Result<u64> {
+        let bytes = body.collect().await?.to_vec();
+        let size = bytes.len() as u64;
+        let mut objects = self.objects.lock().unwrap();
+        objects.insert(self.location(key), Object::new(bytes));
+        Ok(size)
+    }
+
+    async fn get(&self, location: &StorageLocation) -> Result<ByteStream> {
+        let bytes = self
+            .raw(location)
+            .ok_or_else(|| Self::not_found(location))?;
+        Ok(ByteStream::from(bytes))
+    }
+
+    async fn size(&self, location: &StorageLocation) -> Result<u64> {
+        let bytes = self
+            .raw(location)
+            .ok_or_else(|| Self::not_found(location))?;
+        Ok(bytes.len() as u64)
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        let objects = self.objects.lock().unwrap();
+        Ok(objects.contains_key(&self.location(key)))
+    }
+
+    async fn copy(&self, from: &StorageLocation, key: &str) -> Result<()> {
+        let bytes = self.raw(from).ok_or_else(|| Self::not_found(from))?;
+        let mut objects = self.objects.lock().unwrap();
+        objects.insert(self.location(key), Object::new(bytes));
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>> {
+        let objects = self.objects.lock().unwrap();
+        let mut listed: Vec<_> = objects
+            .iter()
+            .filter(|(location, _)| location.bucket == self.bucket)
+            .filter(|(location, _)| location.key.starts_with(prefix))
+            .map(|(location, object)| StoredObject {
+                key: location.key.clone(),
+                size: object.bytes.len() as i64,
+                last_modified: Some(object.last_modified),
+            })
+            .collect();
+        listed.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(listed)
+    }
+
+    async fn delete(&self, location: &StorageLocation) -> Result<()> {
+        let mut objects = self.objects.lock().unwrap();
+        objects.remove(location);
+        Ok(())
+    }
+
+    async fn presign_get(&self, location: &StorageLocation, _: Duration) -> Result<String> {
+        Ok(format!("memory://{}/{}", location.bucket, location.key))
+    }
+
+    async fn check(&self) -> Result<()> {
+        Ok(())
+    }
+}
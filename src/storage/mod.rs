@@ -0,0 +1,584 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use aws_sdk_s3::{primitives::ByteStream, Client};
+use chrono::Utc;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::config::{EmailBucketConfig, StorageConfig};
+
+#[cfg(test)]
+mod memory;
+mod s3;
+
+#[cfg(test)]
+pub use memory::MemoryBackend;
+use s3::{s3_client, S3Backend};
+pub use s3::{to_chrono, traced};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StorageLocation {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl std::fmt::Display for StorageLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.key)
+    }
+}
+
+/// An object uploaded to the storage bucket and its stored size in bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uploaded {
+    pub location: StorageLocation,
+    pub size: i64,
+}
+
+/// A listed object in the storage bucket.
+#[derive(Debug, Serialize)]
+pub struct StoredObject {
+    pub key: String,
+    pub size: i64,
+    pub last_modified: Option<chrono::DateTime<Utc>>,
+}
+
+/// The prefixes under which each class of object is stored.
+pub const BODIES_PREFIX: &str = "bodies";
+pub const SOURCES_PREFIX: &str = "sources";
+pub const COVERS_PREFIX: &str = "covers";
+pub const ARTIFACTS_PREFIX: &str = "artifacts";
+pub const CONVERSIONS_PREFIX: &str = "artifacts/conversions";
+pub const DEBUG_PREFIX: &str = "debug";
+
+pub fn chapter_body_key(book_id: &Uuid, chapter_id: &Uuid) -> String {
+    format!("{}/{}/{}.html", BODIES_PREFIX, book_id, chapter_id)
+}
+
+/// The author's notes and comment count scraped with a chapter's body.
+pub fn chapter_extras_key(book_id: &Uuid, chapter_id: &Uuid) -> String {
+    format!("{}/{}/{}.extras.json", BODIES_PREFIX, book_id, chapter_id)
+}
+
+/// Source documents are keyed by a hash of their content, so storing the same
+/// document twice yields the same location.
+pub fn source_key(book_id: &Uuid, content: &[u8]) -> String {
+    format!(
+        "{}/{}/{:x}.html",
+        SOURCES_PREFIX,
+        book_id,
+        Sha256::digest(content)
+    )
+}
+
+pub fn artifact_key(book_id: &Uuid, artifact_id: &Uuid) -> String {
+    format!("{}/{}/{}.epub", ARTIFACTS_PREFIX, book_id, artifact_id)
+}
+
+/// Chapters converted on request without a book, keyed by a hash of the
+/// request.
+pub fn conversion_key(request_hash: &str) -> String {
+    format!("{}/{}.epub", CONVERSIONS_PREFIX, request_hash)
+}
+
+/// A single chapter converted on request, kept so it is only converted once.
+pub fn chapter_artifact_key(book_id: &Uuid, chapter_id: &Uuid) -> String {
+    format!(
+        "{}/{}/chapters/{}.epub",
+        ARTIFACTS_PREFIX, book_id, chapter_id
+    )
+}
+
+/// Responses providers failed to parse, grouped by the day they were kept so
+/// the daily cap is counted by listing.
+pub fn debug_snapshot_key(day: &str, book_id: &Uuid) -> String {
+    format!(
+        "{}/{}/{}/{}.html",
+        DEBUG_PREFIX,
+        day,
+        book_id,
+        Uuid::new_v4()
+    )
+}
+
+/// The object store behind [`Storage`]. Keys are written to the store's own
+/// bucket, while reads may name any bucket it can access.
+#[async_trait]
+pub trait StorageBackend: Send + Sync + Debug {
+    fn bucket(&self) -> &str;
+
+    /// Uploads `body` to `key`, returning the number of bytes stored.
+    async fn put(&self, key: &str, body: ByteStream) -> Result<u64>;
+
+    async fn get(&self, location: &StorageLocation) -> Result<ByteStream>;
+
+    /// The size of an object in bytes, without reading its body.
+    async fn size(&self, location: &StorageLocation) -> Result<u64>;
+
+    async fn exists(&self, key: &str) -> Result<bool>;
+
+    /// Copies an object to `key` within the store's bucket.
+    async fn copy(&self, from: &StorageLocation, key: &str) -> Result<()>;
+
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>>;
+
+    async fn delete(&self, location: &StorageLocation) -> Result<()>;
+
+    async fn presign_get(&self, location: &StorageLocation, expires_in: Duration)
+        -> Result<String>;
+
+    /// Verifies the bucket exists and can be accessed.
+    async fn check(&self) -> Result<()>;
+}
+
+/// The storage backend and email bucket, built once at startup and shared by
+/// the tasks.
+#[derive(Clone, Debug)]
+pub struct Storage {
+    backend: Arc<dyn StorageBackend>,
+    email_bucket: Option<EmailBucket>,
+    encryption: Option<Encryption>,
+    pub artifact_retention: chrono::Duration,
+    pub debug_snapshots_per_day: Option<usize>,
+}
+
+/// Prefix of an encrypted chapter body, followed by the nonce and ciphertext.
+/// Bodies without it were stored before encryption was enabled.
+const ENCRYPTED_MARKER: &[u8] = b"CEREALENC1";
+const NONCE_LEN: usize = 12;
+
+/// AES-256-GCM encryption of chapter bodies at rest.
+#[derive(Clone)]
+struct Encryption(Aes256Gcm);
+
+impl std::fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Encryption(..)")
+    }
+}
+
+impl Encryption {
+    fn new(key: &[u8; 32]) -> Self {
+        Self(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)))
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow!("Failed to encrypt chapter body."))?;
+        Ok([ENCRYPTED_MARKER, nonce.as_slice(), &ciphertext].concat())
+    }
+
+    /// Decrypts the nonce and ciphertext following the marker.
+    fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            bail!("Encrypted chapter body is truncated.");
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt chapter body."))
+    }
+}
+
+/// The AWS bucket which receives forwarded patreon emails.
+#[derive(Clone, Debug)]
+pub struct EmailBucket {
+    pub client: Client,
+    pub bucket: String,
+}
+
+impl Storage {
+    /// Builds the clients for the Spaces bucket and, when configured, the
+    /// email bucket.
+    pub fn new(config: &StorageConfig, email_bucket: Option<&EmailBucketConfig>) -> Self {
+        let email_bucket = match email_bucket {
+            Some(email_bucket) => Some(EmailBucket {
+                client: s3_client(
+                    email_bucket.access_key.clone(),
+                    email_bucket.secret_key.clone(),
+                    email_bucket.region.clone(),
+                    None,
+                ),
+                bucket: email_bucket.bucket.clone(),
+            }),
+            None => {
+                info!("AWS email bucket is not configured, patreon books will not be checked.");
+                None
+            }
+        };
+
+        Self::with_backend(Arc::new(S3Backend::new(config)), config, email_bucket)
+    }
+
+    /// Stores objects in `backend` rather than the configured bucket.
+    pub fn with_backend(
+        backend: Arc<dyn StorageBackend>,
+        config: &StorageConfig,
+        email_bucket: Option<EmailBucket>,
+    ) -> Self {
+        Self {
+            backend,
+            email_bucket,
+            encryption: config.encryption_key.as_ref().map(Encryption::new),
+            artifact_retention: config.artifact_retention,
+            debug_snapshots_per_day: config.debug_snapshots_per_day,
+        }
+    }
+
+    /// The location of `key` in the storage bucket.
+    pub fn location(&self, key: String) -> StorageLocation {
+        StorageLocation {
+            bucket: self.backend.bucket().to_owned(),
+            key,
+        }
+    }
+
+    pub fn email_bucket(&self) -> Result<&EmailBucket> {
+        self.email_bucket
+            .as_ref()
+            .ok_or_else(|| anyhow!("AWS email bucket is not configured."))
+    }
+
+    /// Stores a chapter body under `bodies/<book_id>/<chapter_id>.html`,
+    /// encrypting it when an encryption key is configured.
+    pub async fn store_book(
+        &self,
+        book_id: &Uuid,
+        chapter_id: &Uuid,
+        body: ByteStream,
+    ) -> Result<Uploaded> {
+        self.put_encrypted(chapter_body_key(book_id, chapter_id), body)
+            .await
+    }
+
+    /// Stores the extras scraped with a chapter's body beside it, encrypted
+    /// like the body.
+    pub async fn store_chapter_extras(
+        &self,
+        book_id: &Uuid,
+        chapter_id: &Uuid,
+        body: ByteStream,
+    ) -> Result<StorageLocation> {
+        self.put_encrypted(chapter_extras_key(book_id, chapter_id), body)
+            .await
+            .map(|uploaded| uploaded.location)
+    }
+
+    /// The stored extras of a chapter, if any were scraped.
+    pub async fn chapter_extras(
+        &self,
+        book_id: &Uuid,
+        chapter_id: &Uuid,
+    ) -> Result<Option<StorageLocation>> {
+        let key = chapter_extras_key(book_id, chapter_id);
+        Ok(self.exists(&key).await?.then(|| self.location(key)))
+    }
+
+    /// Stores a provider's source document for a chapter, such as the body of
+    /// a patreon email, skipping the upload if it is already stored.
+    pub async fn store_source(&self, book_id: &Uuid, content: Vec<u8>) -> Result<StorageLocation> {
+        let key = source_key(book_id, &content);
+        if self.exists(&key).await? {
+            return Ok(self.location(key));
+        }
+        self.put_encrypted(key, ByteStream::from(content))
+            .await
+            .map(|uploaded| uploaded.location)
+    }
+
+    async fn put_encrypted(&self, key: String, body: ByteStream) -> Result<Uploaded> {
+        let body = match &self.encryption {
+            Some(encryption) => {
+                let plaintext = body.collect().await?.into_bytes();
+                ByteStream::from(encryption.encrypt(&plaintext)?)
+            }
+            None => body,
+        };
+        self.put(key, body).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        self.backend
+            .exists(key)
+            .await
+            .with_context(|| format!("Failed to check for s3://{}/{}", self.backend.bucket(), key))
+    }
+
+    /// Keeps a response a provider failed to parse under `debug/`, encrypted
+    /// like the bodies parsed from such responses. Nothing is kept when
+    /// snapshots are off or the day's cap has been reached.
+    pub async fn store_debug_snapshot(
+        &self,
+        book_id: &Uuid,
+        content: Vec<u8>,
+    ) -> Result<Option<StorageLocation>> {
+        let Some(per_day) = self.debug_snapshots_per_day else {
+            return Ok(None);
+        };
+        let day = Utc::now().format("%Y-%m-%d").to_string();
+        let kept = self.list(&format!("{}/{}/", DEBUG_PREFIX, day)).await?;
+        if kept.len() >= per_day {
+            info!(
+                "Already kept {} debug snapshots today, skipping.",
+                kept.len()
+            );
+            return Ok(None);
+        }
+        let key = debug_snapshot_key(&day, book_id);
+        self.put_encrypted(key, ByteStream::from(content))
+            .await
+            .map(|uploaded| Some(uploaded.location))
+    }
+
+    /// Stores a converted ebook under `artifacts/<book_id>/`.
+    pub async fn store_artifact(&self, book_id: &Uuid, body: ByteStream) -> Result<Uploaded> {
+        self.put(artifact_key(book_id, &Uuid::new_v4()), body).await
+    }
+
+    /// The stored conversion of a single chapter, if there is one.
+    pub async fn chapter_artifact(
+        &self,
+        book_id: &Uuid,
+        chapter_id: &Uuid,
+    ) -> Result<Option<StorageLocation>> {
+        let key = chapter_artifact_key(book_id, chapter_id);
+        Ok(self.exists(&key).await?.then(|| self.location(key)))
+    }
+
+    pub async fn store_chapter_artifact(
+        &self,
+        book_id: &Uuid,
+        chapter_id: &Uuid,
+        body: ByteStream,
+    ) -> Result<StorageLocation> {
+        self.put(chapter_artifact_key(book_id, chapter_id), body)
+            .await
+            .map(|uploaded| uploaded.location)
+    }
+
+    pub async fn store_conversion(
+        &self,
+        request_hash: &str,
+        body: ByteStream,
+    ) -> Result<StorageLocation> {
+        self.put(conversion_key(request_hash), body)
+            .await
+            .map(|uploaded| uploaded.location)
+    }
+
+    async fn put(&self, key: String, body: ByteStream) -> Result<Uploaded> {
+        let location = self.location(key);
+        let size = self
+            .backend
+            .put(&location.key, body)
+            .await
+            .with_context(|| format!("Failed to upload {}", location))?;
+        Ok(Uploaded {
+            location,
+            size: size as i64,
+        })
+    }
+
+    /// The stored size of an object in bytes, for objects stored before sizes
+    /// were recorded.
+    pub async fn size(&self, location: &StorageLocation) -> Result<i64> {
+        self.backend
+            .size(location)
+            .await
+            .map(|size| size as i64)
+            .with_context(|| format!("Failed to find the size of {}", location))
+    }
+
+    /// Copies an object to `key` within the storage bucket.
+    pub async fn copy(&self, from: &StorageLocation, key: String) -> Result<StorageLocation> {
+        self.backend
+            .copy(from, &key)
+            .await
+            .with_context(|| format!("Failed to copy {} to {}", from, key))?;
+        Ok(self.location(key))
+    }
+
+    /// Verifies the bucket exists and the credentials can access it.
+    pub async fn check_bucket(&self) -> Result<()> {
+        self.backend
+            .check()
+            .await
+            .with_context(|| format!("Failed to access s3://{}", self.backend.bucket()))
+    }
+
+    /// Lists every object in the storage bucket whose key starts with `prefix`.
+    pub async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>> {
+        self.backend
+            .list(prefix)
+            .await
+            .with_context(|| format!("Failed to list s3://{}/{}", self.backend.bucket(), prefix))
+    }
+
+    pub async fn delete(&self, location: &StorageLocation) -> Result<()> {
+        self.backend
+            .delete(location)
+            .await
+            .with_context(|| format!("Failed to delete {}", location))
+    }
+
+    /// Generates a time-limited GET url for a stored object.
+    pub async fn presign_get(
+        &self,
+        location: &StorageLocation,
+        expires_in: Duration,
+    ) -> Result<String> {
+        self.backend
+            .presign_get(location, expires_in)
+            .await
+            .with_context(|| format!("Failed to presign {}", location))
+    }
+
+    /// Reads a whole stored object into memory.
+    pub async fn fetch(&self, location: StorageLocation) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.fetch_into(location, &mut bytes).await?;
+        Ok(bytes)
+    }
+
+    /// Streams a stored object into `writer`, returning the number of bytes
+    /// written. Encrypted chapter bodies are decrypted before being written.
+    #[tracing::instrument(
+        name = "Fetching chapter body from storage.",
+        level = "info",
+        err,
+        skip(self, writer)
+    )]
+    pub async fn fetch_into<W>(&self, location: StorageLocation, writer: &mut W) -> Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let body = self
+            .backend
+            .get(&location)
+            .await
+            .with_context(|| format!("Failed to fetch {}", location))?;
+        let mut body = body.into_async_read();
+        let read_error = || format!("Failed to read {}", location);
+
+        let mut prefix = Vec::with_capacity(ENCRYPTED_MARKER.len());
+        (&mut body)
+            .take(ENCRYPTED_MARKER.len() as u64)
+            .read_to_end(&mut prefix)
+            .await
+            .with_context(read_error)?;
+        if prefix != ENCRYPTED_MARKER {
+            writer.write_all(&prefix).await?;
+            let copied = tokio::io::copy(&mut body, writer)
+                .await
+                .with_context(read_error)?;
+            return Ok(prefix.len() as u64 + copied);
+        }
+
+        let encryption = self
+            .encryption
+            .as_ref()
+            .ok_or_else(|| anyhow!("{} is encrypted but no encryption key is set.", location))?;
+        let mut sealed = Vec::new();
+        body.read_to_end(&mut sealed)
+            .await
+            .with_context(read_error)?;
+        let plaintext = encryption
+            .decrypt(&sealed)
+            .with_context(|| format!("Failed to decrypt {}", location))?;
+        writer.write_all(&plaintext).await?;
+        Ok(plaintext.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn storage(encryption_key: Option<[u8; 32]>) -> (Storage, Arc<MemoryBackend>) {
+        let backend = Arc::new(MemoryBackend::new(test_support::BUCKET));
+        let config = StorageConfig {
+            encryption_key,
+            ..test_support::config().storage.clone()
+        };
+        let storage = Storage::with_backend(backend.clone(), &config, None);
+        (storage, backend)
+    }
+
+    #[tokio::test]
+    async fn encrypts_chapter_bodies() {
+        let (storage, backend) = storage(Some([7; 32]));
+        let Uploaded { location, size } = storage
+            .store_book(
+                &Uuid::new_v4(),
+                &Uuid::new_v4(),
+                ByteStream::from_static(b"<p>Chapter text.</p>"),
+            )
+            .await
+            .unwrap();
+
+        let raw = backend.raw(&location).unwrap();
+        assert_eq!(size, raw.len() as i64);
+        assert_eq!(storage.size(&location).await.unwrap(), size);
+        assert!(raw.starts_with(ENCRYPTED_MARKER));
+        assert!(!raw.windows(7).any(|window| window == b"Chapter"));
+        assert_eq!(
+            storage.fetch(location).await.unwrap(),
+            b"<p>Chapter text.</p>"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_bodies_stored_before_encryption() {
+        let (plain, backend) = storage(None);
+        let location = plain
+            .store_book(
+                &Uuid::new_v4(),
+                &Uuid::new_v4(),
+                ByteStream::from_static(b"<p>Chapter text.</p>"),
+            )
+            .await
+            .unwrap()
+            .location;
+
+        let config = StorageConfig {
+            encryption_key: Some([7; 32]),
+            ..test_support::config().storage.clone()
+        };
+        let encrypted = Storage::with_backend(backend, &config, None);
+        assert_eq!(
+            encrypted.fetch(location).await.unwrap(),
+            b"<p>Chapter text.</p>"
+        );
+    }
+
+    #[tokio::test]
+    async fn stores_each_source_once() {
+        let (storage, _) = storage(None);
+        let book_id = Uuid::new_v4();
+        let first = storage
+            .store_source(&book_id, b"<html></html>".to_vec())
+            .await
+            .unwrap();
+        let second = storage
+            .store_source(&book_id, b"<html></html>".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(storage.list(SOURCES_PREFIX).await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,571 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    config::http::HttpResponse,
+    config::{
+        retry::RetryConfig, BehaviorVersion, Credentials, Region, RequestChecksumCalculation,
+        ResponseChecksumValidation,
+    },
+    error::{ProvideErrorMetadata, SdkError},
+    presigning::PresigningConfig,
+    primitives::{ByteStream, DateTime},
+    types::{CompletedMultipartUpload, CompletedPart},
+    Client,
+};
+use chrono::{TimeZone, Utc};
+use std::future::Future;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tracing::{error, field, Instrument, Span};
+
+use super::{StorageBackend, StorageLocation, StoredObject};
+use crate::config::StorageConfig;
+
+/// Attempts made for each S3 operation before giving up, including the first.
+const S3_MAX_ATTEMPTS: u32 = 5;
+const S3_INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const S3_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Builds an S3 client with static credentials.
+///
+/// Checksums are only sent when an operation requires them, as S3 compatible
+/// stores such as Spaces reject the newer default checksum headers. Transient
+/// failures are retried with exponential backoff by the client itself.
+pub fn s3_client(
+    access_key: String,
+    secret_key: String,
+    region: String,
+    endpoint: Option<String>,
+) -> Client {
+    let mut config = aws_sdk_s3::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .credentials_provider(Credentials::new(
+            access_key, secret_key, None, None, "cereal",
+        ))
+        .region(Region::new(region))
+        .retry_config(
+            RetryConfig::standard()
+                .with_max_attempts(S3_MAX_ATTEMPTS)
+                .with_initial_backoff(S3_INITIAL_BACKOFF)
+                .with_max_backoff(S3_MAX_BACKOFF),
+        )
+        .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+        .response_checksum_validation(ResponseChecksumValidation::WhenRequired);
+    if let Some(endpoint) = endpoint {
+        config = config.endpoint_url(endpoint).force_path_style(true);
+    }
+    Client::from_conf(config.build())
+}
+
+/// The Spaces bucket, or any other S3 compatible store.
+#[derive(Debug)]
+pub struct S3Backend {
+    client: Client,
+    bucket: String,
+    /// Bodies of at least this many bytes, or of unknown size, are uploaded
+    /// in parts of this size.
+    multipart_threshold: u64,
+}
+
+impl S3Backend {
+    pub fn new(config: &StorageConfig) -> Self {
+        Self {
+            client: s3_client(
+                config.key.clone(),
+                config.secret.clone(),
+                "SPACES".to_string(),
+                Some(config.endpoint.clone()),
+            ),
+            bucket: config.bucket.clone(),
+            multipart_threshold: config.multipart_threshold,
+        }
+    }
+
+    /// Uploads `body` in parts, reading one part at a time from the stream so
+    /// the whole body is never held in memory. A failed upload is aborted, as
+    /// the parts of incomplete uploads are kept, and billed, until it is.
+    async fn put_multipart(&self, key: &str, body: ByteStream) -> Result<u64> {
+        let upload = traced(
+            "CreateMultipartUpload",
+            &self.bucket,
+            key,
+            self.client
+                .create_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .send(),
+        )
+        .await?;
+        let upload_id = upload
+            .upload_id
+            .ok_or_else(|| anyhow!("No upload id was returned for {}.", key))?;
+        let uploaded = self.upload_parts(key, &upload_id, body).await;
+        if uploaded.is_err() {
+            if let Err(err) = traced(
+                "AbortMultipartUpload",
+                &self.bucket,
+                key,
+                self.client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(&upload_id)
+                    .send(),
+            )
+            .await
+            {
+                error!(
+                    error = %err,
+                    "Failed to abort multipart upload {} of {}.", upload_id, key
+                );
+            }
+        }
+        uploaded
+    }
+
+    async fn upload_parts(&self, key: &str, upload_id: &str, body: ByteStream) -> Result<u64> {
+        let mut body = body.into_async_read();
+        let mut parts = Vec::new();
+        let mut size = 0;
+        loop {
+            let mut part = Vec::new();
+            (&mut body)
+                .take(self.multipart_threshold)
+                .read_to_end(&mut part)
+                .await?;
+            // An upload needs at least one part, even for an empty body.
+            if part.is_empty() && !parts.is_empty() {
+                break;
+            }
+            let last = (part.len() as u64) < self.multipart_threshold;
+            size += part.len() as u64;
+            let part_number = parts.len() as i32 + 1;
+            let uploaded = traced(
+                "UploadPart",
+                &self.bucket,
+                key,
+                self.client
+                    .upload_part()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .body(ByteStream::from(part))
+                    .send(),
+            )
+            .await?;
+            parts.push(
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(uploaded.e_tag)
+                    .build(),
+            );
+            if last {
+                break;
+            }
+        }
+        traced(
+            "CompleteMultipartUpload",
+            &self.bucket,
+            key,
+            self.client
+                .complete_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder()
+                        .set_parts(Some(parts))
+                        .build(),
+                )
+                .send(),
+        )
+        .await?;
+        Ok(size)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+    fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    async fn put(&self, key: &str, body: ByteStream) -> Result<u64> {
+        let size = match body.size_hint() {
+            (lower, Some(upper)) if lower == upper && upper < self.multipart_threshold => upper,
+            _ => return self.put_multipart(key, body).await,
+        };
+        traced(
+            "PutObject",
+            &self.bucket,
+            key,
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .body(body)
+                .send(),
+        )
+        .await?;
+        Ok(size)
+    }
+
+    async fn get(&self, location: &StorageLocation) -> Result<ByteStream> {
+        let response = traced(
+            "GetObject",
+            &location.bucket,
+            &location.key,
+            self.client
+                .get_object()
+                .bucket(&location.bucket)
+                .key(&location.key)
+                .send(),
+        )
+        .await?;
+        Ok(response.body)
+    }
+
+    /// Reads the size from the `Content-Range` of a one byte ranged GET.
+    async fn size(&self, location: &StorageLocation) -> Result<u64> {
+        let response = match traced(
+            "GetObject",
+            &location.bucket,
+            &location.key,
+            self.client
+                .get_object()
+                .bucket(&location.bucket)
+                .key(&location.key)
+                .range("bytes=0-0")
+                .send(),
+        )
+        .await
+        {
+            Ok(response) => response,
+            // Empty objects have no first byte to return.
+            Err(err)
+                if err.as_service_error().and_then(|err| err.code()) == Some("InvalidRange") =>
+            {
+                return Ok(0)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let total = response
+            .content_range()
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, total)| total.parse().ok());
+        match (total, response.content_length()) {
+            (Some(total), _) => Ok(total),
+            // Stores which ignore the range send the whole object instead.
+            (None, Some(length)) => Ok(length as u64),
+            (None, None) => Err(anyhow!("No size was returned for {}.", location)),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        match traced(
+            "HeadObject",
+            &self.bucket,
+            key,
+            self.client
+                .head_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .send(),
+        )
+        .await
+        {
+            Ok(_) => Ok(true),
+            Err(err) if err.as_service_error().is_some_and(|err| err.is_not_found()) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn copy(&self, from: &StorageLocation, key: &str) -> Result<()> {
+        traced(
+            "CopyObject",
+            &self.bucket,
+            key,
+            self.client
+                .copy_object()
+                .copy_source(format!("{}/{}", from.bucket, from.key))
+                .bucket(&self.bucket)
+                .key(key)
+                .send(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(prefix)
+            .into_paginator()
+            .send();
+        let mut objects = Vec::new();
+        while let Some(page) = pages
+            .next()
+            .instrument(s3_span("ListObjectsV2", &self.bucket, prefix))
+            .await
+        {
+            for object in page?.contents.unwrap_or_default() {
+                objects.push(StoredObject {
+                    key: object.key.unwrap_or_default(),
+                    size: object.size.unwrap_or_default(),
+                    last_modified: object.last_modified.map(to_chrono).transpose()?,
+                });
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn delete(&self, location: &StorageLocation) -> Result<()> {
+        traced(
+            "DeleteObject",
+            &location.bucket,
+            &location.key,
+            self.client
+                .delete_object()
+                .bucket(&location.bucket)
+                .key(&location.key)
+                .send(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn presign_get(
+        &self,
+        location: &StorageLocation,
+        expires_in: Duration,
+    ) -> Result<String> {
+        let request = self
+            .client
+            .get_object()
+            .bucket(&location.bucket)
+            .key(&location.key)
+            .presigned(PresigningConfig::expires_in(expires_in)?)
+            .await?;
+        Ok(request.uri().to_string())
+    }
+
+    async fn check(&self) -> Result<()> {
+        traced(
+            "HeadBucket",
+            &self.bucket,
+            "",
+            self.client.head_bucket().bucket(&self.bucket).send(),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// A span for one S3 call, so storage latency shows up in traces alongside
+/// the outbound HTTP requests.
+pub fn s3_span(operation: &'static str, bucket: &str, key: &str) -> Span {
+    tracing::info_span!(
+        "S3 request",
+        otel.kind = "client",
+        s3.operation = operation,
+        s3.bucket = bucket,
+        s3.key = key,
+        http.status_code = field::Empty,
+    )
+}
+
+/// Runs an S3 call inside an [`s3_span`], recording the status code when the
+/// call fails.
+pub async fn traced<T, E>(
+    operation: &'static str,
+    bucket: &str,
+    key: &str,
+    call: impl Future<Output = std::result::Result<T, SdkError<E, HttpResponse>>>,
+) -> std::result::Result<T, SdkError<E, HttpResponse>> {
+    let span = s3_span(operation, bucket, key);
+    let result = call.instrument(span.clone()).await;
+    if let Some(response) = result.as_ref().err().and_then(SdkError::raw_response) {
+        span.record("http.status_code", response.status().as_u16());
+    }
+    result
+}
+
+pub fn to_chrono(date_time: DateTime) -> Result<chrono::DateTime<Utc>> {
+    Utc.timestamp_opt(date_time.secs(), date_time.subsec_nanos())
+        .single()
+        .ok_or_else(|| anyhow!("Timestamp {} is out of range.", date_time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use wiremock::matchers::{method, path, query_param, query_param_is_missing};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const KEY: &str = "artifacts/book/bundle.epub";
+
+    fn object_path() -> String {
+        format!("/{}/{}", test_support::BUCKET, KEY)
+    }
+
+    async fn backend(server: &MockServer) -> S3Backend {
+        Mock::given(method("POST"))
+            .and(path(object_path()))
+            .and(query_param("uploads", ""))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                "<InitiateMultipartUploadResult><UploadId>upload-1</UploadId></InitiateMultipartUploadResult>",
+            ))
+            .mount(server)
+            .await;
+        S3Backend::new(&StorageConfig {
+            endpoint: server.uri(),
+            multipart_threshold: 8,
+            ..test_support::config().storage.clone()
+        })
+    }
+
+    async fn requests(server: &MockServer, method: &str, query: &str) -> Vec<Vec<u8>> {
+        server
+            .received_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|request| request.method.as_str() == method)
+            .filter(|request| request.url.query().unwrap_or_default().contains(query))
+            .map(|request| request.body)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn uploads_in_parts_from_the_threshold() {
+        let server = MockServer::start().await;
+        let backend = backend(&server).await;
+        Mock::given(method("PUT"))
+            .and(path(object_path()))
+            .and(query_param_is_missing("uploadId"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path(object_path()))
+            .and(query_param("uploadId", "upload-1"))
+            .respond_with(ResponseTemplate::new(200).insert_header("ETag", "\"part\""))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(object_path()))
+            .and(query_param("uploadId", "upload-1"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                "<CompleteMultipartUploadResult><ETag>\"whole\"</ETag></CompleteMultipartUploadResult>",
+            ))
+            .mount(&server)
+            .await;
+
+        let below = backend
+            .put(KEY, ByteStream::from_static(b"1234567"))
+            .await
+            .unwrap();
+        assert_eq!(below, 7);
+        assert_eq!(requests(&server, "PUT", "").await, [b"1234567".to_vec()]);
+        assert!(requests(&server, "POST", "uploads").await.is_empty());
+
+        let at = backend
+            .put(KEY, ByteStream::from_static(b"12345678"))
+            .await
+            .unwrap();
+        assert_eq!(at, 8);
+        assert_eq!(
+            requests(&server, "PUT", "partNumber").await,
+            [b"12345678".to_vec()]
+        );
+        assert_eq!(requests(&server, "POST", "uploadId").await.len(), 1);
+
+        server.reset().await;
+        let backend = self::backend(&server).await;
+        Mock::given(method("PUT"))
+            .and(query_param("uploadId", "upload-1"))
+            .respond_with(ResponseTemplate::new(200).insert_header("ETag", "\"part\""))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(query_param("uploadId", "upload-1"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                "<CompleteMultipartUploadResult><ETag>\"whole\"</ETag></CompleteMultipartUploadResult>",
+            ))
+            .mount(&server)
+            .await;
+        let above = backend
+            .put(KEY, ByteStream::from_static(b"12345678901234567890"))
+            .await
+            .unwrap();
+        assert_eq!(above, 20);
+        assert_eq!(
+            requests(&server, "PUT", "partNumber").await,
+            [b"12345678".to_vec(), b"90123456".to_vec(), b"7890".to_vec()]
+        );
+        let completed = requests(&server, "POST", "uploadId").await;
+        let completed = String::from_utf8(completed[0].clone()).unwrap();
+        assert_eq!(completed.matches("<PartNumber>").count(), 3);
+    }
+
+    #[tokio::test]
+    async fn aborts_failed_uploads() {
+        let server = MockServer::start().await;
+        let backend = backend(&server).await;
+        Mock::given(method("PUT"))
+            .and(path(object_path()))
+            .and(query_param("partNumber", "1"))
+            .respond_with(ResponseTemplate::new(200).insert_header("ETag", "\"part\""))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path(object_path()))
+            .and(query_param("partNumber", "2"))
+            .respond_with(ResponseTemplate::new(400).set_body_string(
+                "<Error><Code>EntityTooSmall</Code><Message>Too small.</Message></Error>",
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path(object_path()))
+            .and(query_param("uploadId", "upload-1"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        assert!(backend
+            .put(KEY, ByteStream::from_static(b"1234567890"))
+            .await
+            .is_err());
+        assert!(requests(&server, "POST", "uploadId").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn probes_sizes_with_a_ranged_get() {
+        let server = MockServer::start().await;
+        let backend = backend(&server).await;
+        Mock::given(method("GET"))
+            .and(path(object_path()))
+            .respond_with(
+                ResponseTemplate::new(206)
+                    .insert_header("Content-Range", "bytes 0-0/52428800")
+                    .set_body_string("P"),
+            )
+            .mount(&server)
+            .await;
+
+        let location = StorageLocation {
+            bucket: test_support::BUCKET.into(),
+            key: KEY.into(),
+        };
+        assert_eq!(backend.size(&location).await.unwrap(), 52428800);
+        let requests = server.received_requests().await.unwrap();
+        let range = requests[0].headers.get("range").unwrap();
+        assert_eq!(range, "bytes=0-0");
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{Error, Result};
+use chrono::Utc;
+use diesel::{ExpressionMethods, JoinOnDsl, QueryDsl};
+use diesel_async::RunQueryDsl;
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info};
+
+use crate::config::Config;
+use crate::models::{book_not_deleted, chapter_not_deleted};
+use crate::schema::{books, chapters, deliveries, subscriptions};
+use crate::shutdown::Shutdown;
+use crate::util::{error_chain, InstrumentedPgConnectionPool};
+
+/// The window over which recent chapters and deliveries are counted.
+const WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// When each loop last completed a cycle without errors.
+static LAST_CHECK_CYCLE: Mutex<Option<Instant>> = Mutex::new(None);
+static LAST_NOTIFICATION_CYCLE: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Failed deliveries within the window. They are not stored in the database,
+/// so they are only counted since the process started.
+static DELIVERY_FAILURES: Mutex<VecDeque<Instant>> = Mutex::new(VecDeque::new());
+
+pub fn record_check_cycle() {
+    *LAST_CHECK_CYCLE.lock().unwrap() = Some(Instant::now());
+}
+
+pub fn record_notification_cycle() {
+    *LAST_NOTIFICATION_CYCLE.lock().unwrap() = Some(Instant::now());
+}
+
+pub fn record_delivery_failure() {
+    DELIVERY_FAILURES.lock().unwrap().push_back(Instant::now());
+}
+
+/// Periodically logs and publishes a summary of the service's activity, so
+/// a stall where both loops are running but nothing is flowing can be
+/// alerted on.
+pub async fn summary_loop(
+    config: &Config,
+    pool: InstrumentedPgConnectionPool,
+    mut shutdown: Shutdown,
+) -> Result<(), Error> {
+    // Loops which have never completed a cycle are as old as the process.
+    let started = Instant::now();
+    let mut interval = tokio::time::interval(config.intervals.summary);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.requested() => return Ok(()),
+        }
+        if let Err(err) = emit_summary(&pool, started).await {
+            error!(error = %error_chain(&err), "Error emitting the operational summary.");
+        }
+    }
+}
+
+async fn emit_summary(pool: &InstrumentedPgConnectionPool, started: Instant) -> Result<()> {
+    let since = Utc::now() - chrono::Duration::from_std(WINDOW)?;
+    let mut conn = pool.get().await?;
+    let books_tracked: i64 = books::table
+        .filter(book_not_deleted())
+        .count()
+        .get_result(&mut *conn)
+        .await?;
+    let active_subscriptions: i64 = subscriptions::table
+        .inner_join(books::table.on(books::id.eq(subscriptions::book_id)))
+        .filter(book_not_deleted())
+        .count()
+        .get_result(&mut *conn)
+        .await?;
+    let chapters_discovered: i64 = chapters::table
+        .filter(chapters::created_at.gt(since))
+        .filter(chapter_not_deleted())
+        .count()
+        .get_result(&mut *conn)
+        .await?;
+    let deliveries_sent: i64 = deliveries::table
+        .filter(deliveries::created_at.gt(since))
+        .filter(deliveries::test.eq(false))
+        .count()
+        .get_result(&mut *conn)
+        .await?;
+    drop(conn);
+
+    let deliveries_failed = {
+        let mut failures = DELIVERY_FAILURES.lock().unwrap();
+        while failures.front().is_some_and(|at| at.elapsed() > WINDOW) {
+            failures.pop_front();
+        }
+        failures.len()
+    };
+    let age = |last: &Mutex<Option<Instant>>| {
+        last.lock()
+            .unwrap()
+            .map_or_else(|| started.elapsed(), |at| at.elapsed())
+    };
+    let check_cycle_age = age(&LAST_CHECK_CYCLE);
+    let notification_cycle_age = age(&LAST_NOTIFICATION_CYCLE);
+
+    metrics::gauge!("books_tracked").set(books_tracked as f64);
+    metrics::gauge!("subscriptions_active").set(active_subscriptions as f64);
+    metrics::gauge!("chapters_discovered_last_hour").set(chapters_discovered as f64);
+    metrics::gauge!("deliveries_sent_last_hour").set(deliveries_sent as f64);
+    metrics::gauge!("deliveries_failed_last_hour").set(deliveries_failed as f64);
+    metrics::gauge!("check_cycle_age_seconds").set(check_cycle_age.as_secs_f64());
+    metrics::gauge!("notification_cycle_age_seconds").set(notification_cycle_age.as_secs_f64());
+    info!(
+        books_tracked,
+        active_subscriptions,
+        chapters_discovered,
+        deliveries_sent,
+        deliveries_failed,
+        check_cycle_age_secs = check_cycle_age.as_secs(),
+        notification_cycle_age_secs = notification_cycle_age.as_secs(),
+        "Operational summary."
+    );
+    Ok(())
+}
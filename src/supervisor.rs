@@ -0,0 +1,229 @@
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::watch;
+use tokio::task::{Id, JoinSet};
+use tracing::{error, info};
+
+use crate::shutdown::Shutdown;
+use crate::util::error_chain;
+
+/// Delay before the first restart of a failed task, doubled for each
+/// consecutive failure up to [`MAX_BACKOFF`].
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// A task which runs at least this long before failing is considered to have
+/// recovered, so its next restart is immediate again.
+const HEALTHY_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+type TaskFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+type Factory = Box<dyn Fn(Shutdown) -> TaskFuture + Send + Sync>;
+
+/// What is known of a supervised task's failures, as `GET /readyz` reports.
+#[derive(Debug, Default, Clone, Serialize, PartialEq)]
+pub struct TaskStatus {
+    /// Times the task has been restarted since the process started.
+    pub restarts: u64,
+    /// Why the task last stopped, `None` until it has.
+    pub last_error: Option<String>,
+    pub last_failed_at: Option<DateTime<Utc>>,
+}
+
+/// The status of each registered task, shared with the health endpoint.
+pub type TaskStatuses = Arc<Mutex<BTreeMap<&'static str, TaskStatus>>>;
+
+struct Task {
+    name: &'static str,
+    start: Factory,
+    consecutive_failures: u32,
+    started_at: Instant,
+}
+
+/// How long tasks are given to finish their current work once shutdown is
+/// requested, within the 30 second grace period Kubernetes allows by default.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Runs long-lived background tasks, restarting any which exit or panic with
+/// an exponential backoff so a persistent failure can't become a crash loop.
+pub struct Supervisor {
+    tasks: Vec<Task>,
+    statuses: TaskStatuses,
+    stop: watch::Sender<bool>,
+    shutdown: Shutdown,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        let (stop, shutdown) = Shutdown::channel();
+        Self {
+            tasks: Vec::new(),
+            statuses: TaskStatuses::default(),
+            stop,
+            shutdown,
+        }
+    }
+}
+
+impl Supervisor {
+    /// Registers a task. `start` is called for the first run and each restart,
+    /// and the task should return once its [`Shutdown`] is requested.
+    pub fn add<F, Fut>(&mut self, name: &'static str, start: F)
+    where
+        F: Fn(Shutdown) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.statuses
+            .lock()
+            .unwrap()
+            .insert(name, TaskStatus::default());
+        self.tasks.push(Task {
+            name,
+            start: Box::new(move |shutdown| Box::pin(start(shutdown))),
+            consecutive_failures: 0,
+            started_at: Instant::now(),
+        });
+    }
+
+    /// The status of each task, updated as they fail and restart.
+    pub fn statuses(&self) -> TaskStatuses {
+        self.statuses.clone()
+    }
+
+    /// Runs every task until `shutdown` completes, then waits for them to
+    /// return, aborting any still running after [`DRAIN_TIMEOUT`].
+    pub async fn run(mut self, shutdown: impl Future<Output = ()>) {
+        let mut set = JoinSet::new();
+        let mut running: HashMap<Id, usize> = HashMap::new();
+        for (index, task) in self.tasks.iter_mut().enumerate() {
+            task.started_at = Instant::now();
+            running.insert(set.spawn((task.start)(self.shutdown.clone())).id(), index);
+        }
+        tokio::pin!(shutdown);
+
+        loop {
+            let (id, outcome) = tokio::select! {
+                Some(joined) = set.join_next_with_id() => match joined {
+                    Ok((id, result)) => (id, Ok(result)),
+                    Err(err) => (err.id(), Err(err)),
+                },
+                _ = &mut shutdown => break,
+            };
+            let Some(index) = running.remove(&id) else {
+                continue;
+            };
+            let task = &mut self.tasks[index];
+            let reason = match outcome {
+                Ok(Ok(())) => "Task returned, which should not be possible.".to_owned(),
+                Ok(Err(err)) => error_chain(&err),
+                Err(err) if err.is_panic() => {
+                    let payload = err.into_panic();
+                    format!("Task panicked: {}", panic_message(&*payload))
+                }
+                Err(err) => format!("Task was cancelled: {}", err),
+            };
+            error!(task = task.name, reason = %reason, "Task stopped.");
+            if let Some(status) = self.statuses.lock().unwrap().get_mut(task.name) {
+                status.restarts += 1;
+                status.last_error = Some(reason);
+                status.last_failed_at = Some(Utc::now());
+            }
+            metrics::counter!("task_restarts_total", "task" => task.name).increment(1);
+
+            if task.started_at.elapsed() >= HEALTHY_PERIOD {
+                task.consecutive_failures = 0;
+            }
+            let backoff = backoff(task.consecutive_failures);
+            task.consecutive_failures += 1;
+            task.started_at = Instant::now() + backoff;
+            info!(task = task.name, ?backoff, "Restarting task.");
+            let restart = (task.start)(self.shutdown.clone());
+            let id = set
+                .spawn(async move {
+                    tokio::time::sleep(backoff).await;
+                    restart.await
+                })
+                .id();
+            running.insert(id, index);
+        }
+
+        info!("Shutting down, waiting for tasks to finish.");
+        let _ = self.stop.send(true);
+        let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+            while set.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            error!("Tasks did not finish in time, aborting them.");
+            set.shutdown().await;
+        }
+    }
+}
+
+/// No delay for the first failure, then doubling from [`BASE_BACKOFF`].
+fn backoff(consecutive_failures: u32) -> Duration {
+    match consecutive_failures {
+        0 => Duration::ZERO,
+        failures => BASE_BACKOFF
+            .saturating_mul(2u32.saturating_pow(failures - 1))
+            .min(MAX_BACKOFF),
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_failed_tasks_and_records_why() {
+        let mut supervisor = Supervisor::default();
+        supervisor.add("failing", |_| async { bail!("The site is down.") });
+        supervisor.add("panicking", |_| async { panic!("Out of bounds.") });
+        supervisor.add("steady", |mut shutdown: Shutdown| async move {
+            shutdown.requested().await;
+            Ok(())
+        });
+        let statuses = supervisor.statuses();
+
+        // The first restart is immediate and the next two wait one then two
+        // seconds, so each failing task is restarted four times.
+        supervisor
+            .run(tokio::time::sleep(Duration::from_millis(3500)))
+            .await;
+
+        let statuses = statuses.lock().unwrap();
+        let failing = &statuses["failing"];
+        assert_eq!(failing.restarts, 4);
+        assert_eq!(failing.last_error.as_deref(), Some("The site is down."));
+        assert!(failing.last_failed_at.is_some());
+        assert_eq!(
+            statuses["panicking"].last_error.as_deref(),
+            Some("Task panicked: Out of bounds.")
+        );
+        assert_eq!(statuses["steady"], TaskStatus::default());
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        assert_eq!(backoff(0), Duration::ZERO);
+        assert_eq!(backoff(1), BASE_BACKOFF);
+        assert_eq!(backoff(3), BASE_BACKOFF * 4);
+        assert_eq!(backoff(30), MAX_BACKOFF);
+    }
+}
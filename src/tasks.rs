@@ -1,34 +1,72 @@
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Error;
 use anyhow::Result;
+use aws_sdk_s3::primitives::ByteStream;
+use chrono::DateTime;
+use chrono::Utc;
 use diesel::sql_query;
+use diesel::sql_types::{Array, BigInt, Nullable, Text, Timestamptz};
 use diesel::BelongingToDsl;
+use diesel::BoolExpressionMethods;
 use diesel::ExpressionMethods;
-use diesel::JoinOnDsl;
+use diesel::NullableExpressionMethods;
+use diesel::OptionalExtension;
+use diesel::PgSortExpressionMethods;
 use diesel::QueryDsl;
-use diesel::RunQueryDsl;
+use diesel::TextExpressionMethods;
+use diesel_async::{AsyncConnection, RunQueryDsl};
 use futures::future::join_all;
 use itertools::Itertools;
-use rusoto_s3::S3Location;
-use std::collections::HashMap;
-use std::time::Duration;
+use scraper::Html;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
 use tokio::time::MissedTickBehavior;
 use tracing::error;
 use tracing::info;
-use tracing::info_span;
+use tracing::instrument::WithSubscriber;
+use tracing::subscriber::NoSubscriber;
+use tracing::warn;
 use tracing::Instrument;
 use uuid::Uuid;
 
 use crate::clients::calibre;
 use crate::clients::mailgun;
-use crate::clients::pushover;
+use crate::clients::otel::current_trace_id;
+use crate::clients::pushover::{self, Priority};
+use crate::clients::sentry;
+use crate::config::{self, BodyLimits, Config, DeliveryBacklog, Endpoints};
+use crate::events::{self, Event};
+use crate::models::book_not_deleted;
+use crate::models::chapter_not_deleted;
+use crate::models::chapter_order;
+use crate::models::AuthorNotes;
 use crate::models::ChapterBody;
+use crate::models::ChapterExtras;
 use crate::models::ChapterKind;
-use crate::models::ChapterWithUser;
+use crate::models::ChapterStatus;
+use crate::models::CheckProblem;
+use crate::models::Delivery;
+use crate::models::DeliveryFormat;
 use crate::models::DeliveryMethod;
+use crate::models::DeliverySettings;
+use crate::models::EmbeddedDailyGrindHtml;
+use crate::models::ExtrasPreference;
+use crate::models::KindleConversionMode;
+use crate::models::NewBook;
 use crate::models::NewChapter;
+use crate::models::NewDelivery;
+use crate::models::Subscription;
+use crate::models::UserPreferences;
 use crate::providers::apparatus_of_change_patreon;
+use crate::providers::health;
 use crate::providers::pale;
 use crate::providers::practical_guide;
 use crate::providers::royalroad;
@@ -36,127 +74,482 @@ use crate::providers::royalroad::RoyalRoadBookKind;
 use crate::providers::the_daily_grind_patreon;
 use crate::providers::wandering_inn;
 use crate::providers::wandering_inn_patreon;
+use crate::providers::ProviderError;
+use crate::providers::Unparsed;
+use crate::schema::book_metadata_changes;
 use crate::schema::chapter_bodies;
 use crate::schema::chapters;
+use crate::schema::chapters_archive;
+use crate::schema::deliveries;
 use crate::schema::delivery_methods;
+use crate::shutdown::Shutdown;
 use crate::storage;
-use crate::util::InstrumentedPgConnectionPool;
+use crate::storage::Storage;
+use crate::storage::StorageLocation;
+use crate::storage::Uploaded;
+use crate::storage::CONVERSIONS_PREFIX;
+use crate::storage::DEBUG_PREFIX;
+use crate::summary;
+use crate::templates::{Message, Templates};
+use crate::unsubscribe;
+use crate::util::error_chain;
+use crate::util::ApiError;
 use crate::util::ResultExt;
+use crate::util::{
+    is_connection_lost, is_pool_exhausted, retry_read, InstrumentedPgConnectionPool,
+};
 use crate::{
     models::{Book, BookKind, Chapter},
     schema::books,
 };
 
-pub async fn check_new_chap_loop(pool: InstrumentedPgConnectionPool) -> Result<(), Error> {
-    // 5 min check interval for all book.
-    let mut interval = tokio::time::interval(Duration::from_secs(5 * 60));
+pub async fn check_new_chap_loop(
+    config: &Config,
+    pool: InstrumentedPgConnectionPool,
+    storage: Storage,
+    mut shutdown: Shutdown,
+) -> Result<(), Error> {
+    let mut interval = tokio::time::interval(config.intervals.check);
     interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
+    let mut last_counts_check: Option<Instant> = None;
+    let mut last_metadata_refresh: Option<Instant> = None;
+    let mut last_archive: Option<Instant> = None;
+
     loop {
-        interval.tick().await;
-        match check_and_queue_chapters(&pool).await {
-            Ok(_) => {}
-            Err(err) => {
-                error!(error = ?err, "Error checking for new chapters.");
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.requested() => return Ok(()),
+        }
+        check_cycle(
+            &pool,
+            &storage,
+            &config.endpoints,
+            config.archive_chapters_after,
+            &mut last_counts_check,
+            &mut last_metadata_refresh,
+            &mut last_archive,
+        )
+        .await;
+    }
+}
+
+/// One pass of the chapter check loop. Every step runs under this span so
+/// per-book work shares a trace with the cycle that triggered it.
+#[tracing::instrument(
+name = "Running the chapter check cycle.",
+level = "info"
+skip(pool, storage, endpoints, last_counts_check, last_metadata_refresh, last_archive),
+)]
+async fn check_cycle(
+    pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+    endpoints: &Endpoints,
+    archive_chapters_after: Option<chrono::Duration>,
+    last_counts_check: &mut Option<Instant>,
+    last_metadata_refresh: &mut Option<Instant>,
+    last_archive: &mut Option<Instant>,
+) {
+    match check_and_queue_chapters(pool, storage, endpoints).await {
+        Ok(()) => summary::record_check_cycle(),
+        Err(err) => {
+            if report_task_error(&err, "Error checking for new chapters.").is_break() {
+                return;
+            }
+        }
+    }
+    if let Err(err) = purge_expired_artifacts(pool, storage).await {
+        if report_task_error(&err, "Error removing expired delivery artifacts.").is_break() {
+            return;
+        }
+    }
+    if let Err(err) = purge_debug_snapshots(storage).await {
+        if report_task_error(&err, "Error removing expired debug snapshots.").is_break() {
+            return;
+        }
+    }
+    if let Err(err) = migrate_storage_keys(pool, storage).await {
+        if report_task_error(&err, "Error moving objects to prefixed storage keys.").is_break() {
+            return;
+        }
+    }
+    if let Err(err) = purge_deleted(pool, storage).await {
+        if report_task_error(&err, "Error purging deleted books and chapters.").is_break() {
+            return;
+        }
+    }
+    if last_counts_check.is_none_or(|last| last.elapsed() >= BOOK_COUNTS_CHECK_INTERVAL) {
+        *last_counts_check = Some(Instant::now());
+        if let Err(err) = check_book_counts(pool).await {
+            if report_task_error(&err, "Error checking book chapter counts.").is_break() {
+                // Retry next cycle rather than waiting out the full interval.
+                *last_counts_check = None;
+            }
+        }
+    }
+    if last_metadata_refresh.is_none_or(|last| last.elapsed() >= METADATA_REFRESH_INTERVAL) {
+        *last_metadata_refresh = Some(Instant::now());
+        if let Err(err) = refresh_book_metadata(pool, endpoints).await {
+            if report_task_error(&err, "Error refreshing book metadata.").is_break() {
+                *last_metadata_refresh = None;
+                return;
+            }
+        }
+    }
+    let Some(archive_after) = archive_chapters_after else {
+        return;
+    };
+    if last_archive.is_none_or(|last| last.elapsed() >= ARCHIVE_INTERVAL) {
+        *last_archive = Some(Instant::now());
+        if let Err(err) = archive_chapters(pool, Utc::now() - archive_after).await {
+            if report_task_error(&err, "Error archiving old chapters.").is_break() {
+                *last_archive = None;
             }
         }
     }
 }
 
+/// Logs a failed step of a background loop. An exhausted connection pool or a
+/// lost database connection is transient, so it is logged as a warning and
+/// the rest of the cycle is skipped rather than run against a database that
+/// can't be reached.
+fn log_task_error(err: &Error, message: &str) -> ControlFlow<()> {
+    if is_pool_exhausted(err) {
+        warn!("Database connection pool is exhausted, skipping this cycle.");
+        return ControlFlow::Break(());
+    }
+    if is_connection_lost(err) {
+        warn!(error = %error_chain(err), "Lost the database connection, skipping this cycle.");
+        return ControlFlow::Break(());
+    }
+    error!(error = %error_chain(err), trace_id = current_trace_id(), "{}", message);
+    ControlFlow::Continue(())
+}
+
+/// Logs a failed step of a background loop and reports it to Sentry, tagged
+/// with the step. Exhausted pools are only logged.
+fn report_task_error(err: &Error, message: &str) -> ControlFlow<()> {
+    let flow = log_task_error(err, message);
+    if flow.is_continue() {
+        sentry::capture(err, &[("task", message)]);
+    }
+    flow
+}
+
+/// Reports a failure in the work for a single book to Sentry.
+fn report_book_error(err: &Error, book: &Book) {
+    sentry::capture(
+        err,
+        &[("book_id", &book.id.to_string()), ("book", &book.name)],
+    );
+}
+
+// Debug level so it is filtered out: it only wraps "Discovering new chapters."
+// and would otherwise add an event to every cycle.
 #[tracing::instrument(
 name = "Discovering and queueing new chapters.",
 err,
-level = "info"
-skip(pool),
+level = "debug"
+skip(pool, storage, endpoints),
 )]
-async fn check_and_queue_chapters(pool: &InstrumentedPgConnectionPool) -> Result<(), Error> {
+pub async fn check_and_queue_chapters(
+    pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+    endpoints: &Endpoints,
+) -> Result<(), Error> {
     info!("Checking for new chapters");
-    let _book_chaps_subs = check_for_all_new_chapters(pool).await?;
+    let _book_chaps_subs = check_for_all_new_chapters(pool, storage, endpoints).await?;
 
     Ok(())
 }
 
+/// Checks a single book for new chapters once, outside the check loop.
+pub async fn check_book(
+    pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+    endpoints: &Endpoints,
+    book_id: Uuid,
+) -> Result<Vec<Chapter>> {
+    let book = {
+        let mut conn = pool.get().await?;
+        books::table
+            .find(book_id)
+            .filter(book_not_deleted())
+            .first::<Book>(&mut *conn)
+            .await
+            .optional()?
+            .ok_or_else(|| anyhow!("No book found with id {}.", book_id))?
+    };
+    let (_book, chapters) = check_for_new_chapters(pool.clone(), storage, endpoints, book).await?;
+    Ok(chapters)
+}
+
 #[tracing::instrument(
 name = "Discovering new chapters for a single book.",
 err,
 level = "info"
-skip(pool),
+skip(pool, storage, endpoints),
 )]
 async fn check_for_new_chapters(
     pool: InstrumentedPgConnectionPool,
+    storage: &Storage,
+    endpoints: &Endpoints,
     book: Book,
 ) -> Result<(Book, Vec<Chapter>)> {
-    let chaps = get_new_chapters(&book, &pool)
+    let chaps = match get_new_chapters(&book, &pool, storage, endpoints).await {
+        Err(err) => Err(keep_unparsed(storage, &book, err).await),
+        found => found,
+    };
+    record_check(&pool, &book, chaps.as_ref().err())
         .await
+        .unwrap_or_else_log(|| ());
+    let chaps = chaps
+        .inspect_err(|err| report_book_error(err, &book))
         .unwrap_or_else_log(|| Vec::with_capacity(0));
-    let locations = fetch_chapter_bodies(&chaps, &book).await;
-    let (chaps, locations): (Vec<_>, Vec<_>) = chaps
+    // Chapter ids are assigned up front so bodies can be stored under them.
+    let (ordinals, chaps): (Vec<i32>, Vec<_>) = chaps
         .into_iter()
-        .zip(locations.into_iter())
-        .filter_map(|(chap, loc)| match loc {
-            Ok(loc) => Some((chap, loc)),
+        .map(|(ordinal, chap)| (ordinal, (Uuid::new_v4(), chap)))
+        .unzip();
+    let stored = fetch_chapter_bodies(&chaps, &book, storage, endpoints).await;
+    record_auth_failures(&pool, &book, &stored)
+        .await
+        .unwrap_or_else_log(|| ());
+    let statuses = stored
+        .iter()
+        .map(|stored| match stored {
+            Ok(_) => ChapterStatus::Fetched,
             Err(err) => {
-                tracing::error!(?err);
-                None
+                tracing::error!(
+                    error = %error_chain(err),
+                    trace_id = current_trace_id(),
+                    "Failed to store a chapter body, it will be retried."
+                );
+                report_book_error(err, &book);
+                ChapterStatus::FetchFailed
             }
         })
-        .unzip();
-    let chaps: Vec<Chapter> = {
-        let conn = pool.get().await?;
-        diesel::insert_into(chapters::table)
-            .values(chaps)
-            .get_results(&*conn)?
-    };
-    {
-        let bodies = chaps
-            .iter()
-            .zip(locations.iter())
-            .map(|(chap, location)| ChapterBody {
-                key: location.prefix.clone(),
-                bucket: location.bucket_name.clone(),
-                chapter_id: chap.id,
+        .collect_vec();
+    let word_counts = stored
+        .iter()
+        .map(|stored| stored.as_ref().ok().map(|stored| stored.word_count))
+        .collect_vec();
+    let bodies = chaps
+        .iter()
+        .zip(stored)
+        .filter_map(|((id, _chap), stored)| {
+            stored.ok().map(|stored| ChapterBody {
+                key: stored.location.key,
+                bucket: stored.location.bucket,
+                chapter_id: *id,
+                size: Some(stored.size),
             })
-            .collect_vec();
-        let conn = pool.get().await?;
-        diesel::insert_into(chapter_bodies::table)
-            .values(&bodies)
-            .execute(&*conn)?;
+        })
+        .collect_vec();
+    let latest_published_at = chaps.iter().map(|(_id, chap)| chap.published_at).max();
+    let chaps: Vec<Chapter> = match latest_published_at {
+        None => Vec::new(),
+        Some(latest_published_at) => {
+            let mut conn = pool.get().await?;
+            conn.transaction::<_, diesel::result::Error, _>(async |conn| {
+                let chaps: Vec<Chapter> = diesel::insert_into(chapters::table)
+                    .values(
+                        chaps
+                            .into_iter()
+                            .zip(statuses)
+                            .zip(word_counts)
+                            .zip(ordinals)
+                            .map(|((((id, chap), status), word_count), ordinal)| {
+                                (
+                                    chapters::id.eq(id),
+                                    chap,
+                                    chapters::status.eq(status),
+                                    chapters::word_count.eq(word_count),
+                                    chapters::ordinal.eq(ordinal),
+                                )
+                            })
+                            .collect_vec(),
+                    )
+                    .get_results(&mut *conn)
+                    .await?;
+                diesel::insert_into(chapter_bodies::table)
+                    .values(&bodies)
+                    .execute(&mut *conn)
+                    .await?;
+                diesel::update(books::table.find(book.id))
+                    .set((
+                        books::chapter_count.eq(books::chapter_count + chaps.len() as i64),
+                        books::latest_chapter_published_at.eq(greatest(
+                            books::latest_chapter_published_at,
+                            Some(latest_published_at),
+                        )),
+                    ))
+                    .execute(&mut *conn)
+                    .await?;
+                Ok(chaps)
+            })
+            .await?
+        }
+    };
+    if !chaps.is_empty() && events::has_listeners() {
+        publish_discovered(&pool, &book, &chaps)
+            .await
+            .unwrap_or_else_log(|| ());
+    }
+    if let Err(err) = retry_failed_chapter_bodies(&pool, storage, endpoints, &book).await {
+        tracing::error!(
+            error = %error_chain(&err),
+            trace_id = current_trace_id(),
+            "Failed to retry chapter bodies for {}.",
+            book.name
+        );
+        report_book_error(&err, &book);
     }
     Ok((book, chaps))
 }
 
+#[tracing::instrument(
+name = "Retrying chapters whose bodies failed to store.",
+err,
+level = "info"
+skip(pool, storage, endpoints),
+)]
+async fn retry_failed_chapter_bodies(
+    pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+    endpoints: &Endpoints,
+    book: &Book,
+) -> Result<()> {
+    let failed: Vec<Chapter> = {
+        let mut conn = pool.get().await?;
+        Chapter::belonging_to(book)
+            .filter(chapters::status.eq(ChapterStatus::FetchFailed))
+            .filter(chapter_not_deleted())
+            .load(&mut *conn)
+            .await?
+    };
+    if failed.is_empty() {
+        return Ok(());
+    }
+    let new_chaps = failed
+        .iter()
+        .map(|chap| (chap.id, NewChapter::from(chap)))
+        .collect_vec();
+    let stored = fetch_chapter_bodies(&new_chaps, book, storage, endpoints).await;
+    record_auth_failures(pool, book, &stored).await?;
+    for (chap, stored) in failed.iter().zip(stored) {
+        let StoredBody {
+            location,
+            size,
+            word_count,
+        } = match stored {
+            Ok(stored) => stored,
+            Err(err) => {
+                tracing::error!(
+                    error = %error_chain(&err),
+                    trace_id = current_trace_id(),
+                    "Chapter {} body failed to store again.",
+                    chap.name
+                );
+                report_book_error(&err, book);
+                continue;
+            }
+        };
+        let mut conn = pool.get().await?;
+        conn.transaction::<_, diesel::result::Error, _>(async |conn| {
+            diesel::insert_into(chapter_bodies::table)
+                .values(ChapterBody {
+                    key: location.key,
+                    bucket: location.bucket,
+                    chapter_id: chap.id,
+                    size: Some(size),
+                })
+                .execute(&mut *conn)
+                .await?;
+            diesel::update(chapters::table.find(chap.id))
+                .set((
+                    chapters::status.eq(ChapterStatus::Fetched),
+                    chapters::word_count.eq(word_count),
+                ))
+                .execute(&mut *conn)
+                .await?;
+            Ok(())
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+/// The books checked for new chapters, which are those with active subscribers,
+/// except those left until later after a failed check. Each book is listed
+/// once however many subscribers it has.
+pub(crate) async fn books_to_check(pool: &InstrumentedPgConnectionPool) -> Result<Vec<Book>> {
+    use crate::schema::subscriptions;
+    let mut conn = pool.get().await?;
+    let books = books::table
+        .filter(diesel::dsl::exists(
+            subscriptions::table
+                .filter(subscriptions::book_id.eq(books::id))
+                .filter(subscriptions::active),
+        ))
+        .filter(book_not_deleted())
+        .filter(
+            books::next_check_at
+                .is_null()
+                .or(books::next_check_at.le(Utc::now())),
+        )
+        .select(books::all_columns)
+        .load::<Book>(&mut *conn)
+        .await?;
+    Ok(books)
+}
+
+/// Tells the book's subscribers' open event streams about its new chapters.
+async fn publish_discovered(
+    pool: &InstrumentedPgConnectionPool,
+    book: &Book,
+    chapters: &[Chapter],
+) -> Result<()> {
+    use crate::schema::subscriptions;
+    let mut conn = pool.get().await?;
+    let user_ids: Vec<String> = subscriptions::table
+        .filter(subscriptions::book_id.eq(book.id))
+        .select(subscriptions::user_id)
+        .load(&mut *conn)
+        .await?;
+    for user_id in user_ids {
+        events::publish(&user_id, Event::chapters_discovered(book, chapters));
+    }
+    Ok(())
+}
+
 #[tracing::instrument(
 name = "Discovering new chapters.",
 err,
 level = "info"
-skip(pool),
+skip(pool, storage, endpoints),
 )]
 async fn check_for_all_new_chapters(
     pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+    endpoints: &Endpoints,
 ) -> Result<Vec<(Book, Vec<Chapter>)>, Error> {
-    // Fetch only books which have subscribers.
-    let books = {
-        use crate::schema::subscriptions;
-        let conn = pool.get().await?;
-        books::table
-            .inner_join(
-                subscriptions::table.on(subscriptions::columns::book_id.eq(books::columns::id)),
-            )
-            .select(books::all_columns)
-            .load::<Book>(&*conn)?
-    };
+    let books = books_to_check(pool).await?;
 
     let book_chaps = join_all(
         books
-            .into_iter()
-            .map(|book| check_for_new_chapters(pool.clone(), book)),
+            .iter()
+            .cloned()
+            .map(|book| check_for_new_chapters(pool.clone(), storage, endpoints, book)),
     )
     .await
     .into_iter()
-    .filter_map(|x| match x {
+    .zip(&books)
+    .filter_map(|(x, book)| match x {
         Ok(x) => Some(x),
         Err(err) => {
-            tracing::error!(?err);
+            tracing::error!(error = %error_chain(&err), trace_id = current_trace_id());
+            report_book_error(&err, book);
             None
         }
     })
@@ -165,10 +558,101 @@ async fn check_for_all_new_chapters(
     Ok(book_chaps)
 }
 
-#[tracing::instrument(name = "Fetching a new chapter body.", err, level = "info")]
-async fn fetch_chapter_body(chapter: &NewChapter, book: &Book) -> Result<String> {
-    match &chapter.metadata {
-        ChapterKind::RoyalRoad { id } => royalroad::get_chapter_body(id, book, chapter).await,
+#[tracing::instrument(
+    name = "Fetching a new chapter body.",
+    err,
+    level = "info",
+    skip(storage, endpoints),
+    fields(bytes = tracing::field::Empty)
+)]
+pub(crate) async fn fetch_chapter_body(
+    chapter: &NewChapter,
+    book: &Book,
+    storage: &Storage,
+    endpoints: &Endpoints,
+) -> Result<ScrapedChapter> {
+    let started = Instant::now();
+    let scraped = scrape_chapter_body(chapter, book, storage, endpoints).await;
+    if let Some(provider) = book.metadata.provider() {
+        health::record_fetch(provider, started.elapsed(), scraped.as_ref().map(drop));
+    }
+    let scraped = match scraped {
+        Ok(scraped) => scraped,
+        Err(err) => return Err(keep_unparsed(storage, book, err).await),
+    };
+    tracing::Span::current().record("bytes", scraped.body.len());
+    check_body_size(scraped.body.len(), &config::get().body_limits)?;
+    Ok(scraped)
+}
+
+/// Keeps the response behind a provider's parse failure as a debug snapshot,
+/// adding where it was kept to the error so its trace leads to it.
+async fn keep_unparsed(storage: &Storage, book: &Book, err: Error) -> Error {
+    let Some(unparsed) = err.downcast_ref::<Unparsed>() else {
+        return err;
+    };
+    match storage
+        .store_debug_snapshot(&book.id, unparsed.content.clone())
+        .await
+    {
+        Ok(Some(location)) => {
+            info!(snapshot = %location, url = %unparsed.url, "Kept the response which failed to parse.");
+            err.context(format!("The unparsed response is kept at {}", location))
+        }
+        Ok(None) => err,
+        Err(store_err) => {
+            warn!(error = %error_chain(&store_err), "Failed to keep the response which failed to parse.");
+            err
+        }
+    }
+}
+
+/// A chapter's body as scraped, with the extras of providers which have any.
+pub(crate) struct ScrapedChapter {
+    pub body: String,
+    pub extras: Option<ChapterExtras>,
+}
+
+impl From<String> for ScrapedChapter {
+    fn from(body: String) -> Self {
+        Self { body, extras: None }
+    }
+}
+
+/// Fails bodies over the size limit, warning of those over the soft limit.
+fn check_body_size(bytes: usize, limits: &BodyLimits) -> Result<()> {
+    if bytes > limits.max {
+        bail!(
+            "Chapter body too large, {} bytes is over the limit of {}.",
+            bytes,
+            limits.max
+        );
+    }
+    if bytes > limits.warn {
+        warn!(
+            bytes,
+            limit = limits.warn,
+            "Chapter body is unusually large."
+        );
+    }
+    Ok(())
+}
+
+async fn scrape_chapter_body(
+    chapter: &NewChapter,
+    book: &Book,
+    storage: &Storage,
+    endpoints: &Endpoints,
+) -> Result<ScrapedChapter> {
+    let body = match &chapter.metadata {
+        ChapterKind::RoyalRoad { id } => {
+            let (body, extras) =
+                royalroad::get_chapter_body(&endpoints.royalroad, id, book, chapter).await?;
+            return Ok(ScrapedChapter {
+                body,
+                extras: Some(extras),
+            });
+        }
         ChapterKind::Pale { url } => pale::get_chapter_body(url, book, chapter).await,
         ChapterKind::APracticalGuideToEvil { url } => {
             practical_guide::get_chapter_body(url, book, chapter).await
@@ -177,455 +661,4147 @@ async fn fetch_chapter_body(chapter: &NewChapter, book: &Book) -> Result<String>
             wandering_inn::get_chapter_body(url, book, chapter).await
         }
         ChapterKind::TheWanderingInnPatreon { url, password } => {
-            wandering_inn_patreon::get_chapter_body(url, password.as_deref(), book, chapter).await
+            wandering_inn_patreon::get_chapter_body(
+                &endpoints.wandering_inn,
+                url,
+                password.as_deref(),
+                book,
+                chapter,
+            )
+            .await
         }
-        ChapterKind::TheDailyGrindPatreon { html } => {
+        ChapterKind::TheDailyGrindPatreon { bucket, key } => {
+            let location = StorageLocation {
+                bucket: bucket.clone(),
+                key: key.clone(),
+            };
+            let html = String::from_utf8(storage.fetch(location).await?)?;
             Ok(format!("<h1>{}: {}</h1>{}", book.name, chapter.name, html))
         }
         ChapterKind::ApparatusOfChangePatreon { html } => {
             Ok(format!("<h1>{}: {}</h1>{}", book.name, chapter.name, html))
         }
-    }
-}
-
-#[tracing::instrument(name = "Fetching all new chapter bodies.", level = "info")]
-async fn fetch_chapter_bodies(chapters: &[NewChapter], book: &Book) -> Vec<Result<S3Location>> {
-    // Fetch all bodies as strings from the web.
-    let bodies_and_chapters: Vec<(String, &NewChapter)> =
-        join_all(chapters.iter().map(|chap| fetch_chapter_body(chap, book)))
-            .await
-            .into_iter()
-            .zip(chapters.iter())
-            .filter_map(|(x, chap)| match x {
-                Ok(x) => Some((x, chap)),
-                Err(_err) => None,
-            })
-            .collect();
-    // Store all chapters in S3.
-    let locations = join_all(
-        bodies_and_chapters
-            .iter()
-            .map(|(body, _chapter)| storage::store_book(body.as_bytes())),
-    )
-    .await;
-    locations
+        ChapterKind::Unknown(_) => Err(anyhow!(
+            "Chapter {} is of an unrecognized kind and cannot be fetched.",
+            chapter.name
+        )),
+    };
+    body.map(ScrapedChapter::from)
 }
 
 #[tracing::instrument(
-name = "Discovering new chapters for a single book.",
-err,
-level = "info"
-skip(pool),
+    name = "Fetching all new chapter bodies.",
+    level = "info",
+    skip(storage, endpoints)
 )]
-async fn get_new_chapters(
+async fn fetch_chapter_bodies(
+    chapters: &[(Uuid, NewChapter)],
     book: &Book,
+    storage: &Storage,
+    endpoints: &Endpoints,
+) -> Vec<Result<StoredBody>> {
+    // Fetch each body from the web and store it in S3, keeping one result per chapter.
+    join_all(chapters.iter().map(|(id, chap)| async move {
+        let ScrapedChapter { body, extras } =
+            fetch_chapter_body(chap, book, storage, endpoints).await?;
+        let word_count = count_words(&body);
+        let Uploaded { location, size } = storage
+            .store_book(&book.id, id, ByteStream::from(body.into_bytes()))
+            .await?;
+        store_extras(storage, book, id, extras).await;
+        Ok(StoredBody {
+            location,
+            size,
+            word_count,
+        })
+    }))
+    .await
+}
+
+/// How long a book is left after a check fails in a way retrying soon won't
+/// fix, such as the book being removed from its site.
+const PROBLEM_BACKOFF_HOURS: i64 = 24;
+
+/// The longest a site's `Retry-After` is followed for, so a mistaken header
+/// can't stop a book being checked.
+const MAX_RETRY_AFTER_HOURS: i64 = 6;
+
+/// Records the outcome of checking a book for new chapters, so books which
+/// stopped being checked successfully can be found. Failures put off the
+/// book's next check by how they are classified, and those retrying won't fix
+/// are recorded as its problem until a check succeeds.
+async fn record_check(
     pool: &InstrumentedPgConnectionPool,
-) -> Result<Vec<NewChapter>, Error> {
-    let rss_chapters = match book.metadata {
-        BookKind::RoyalRoad(RoyalRoadBookKind { id }) => {
-            royalroad::get_chapters(id, &book.id, &book.author)
-                .await
-                .with_context(|| "Failed to fetch new royalroad chapters.")?
+    book: &Book,
+    error: Option<&Error>,
+) -> Result<()> {
+    let now = Utc::now();
+    let mut conn = pool.get().await?;
+    let update = diesel::update(books::table.find(book.id));
+    match error {
+        None => {
+            update
+                .set((
+                    books::last_checked_at.eq(now),
+                    books::last_success_at.eq(now),
+                    books::last_error.eq(None::<String>),
+                    books::next_check_at.eq(None::<DateTime<Utc>>),
+                    books::check_problem.eq(None::<CheckProblem>),
+                ))
+                .execute(&mut *conn)
+                .await?
+        }
+        Some(err) => {
+            let class = ProviderError::of(err);
+            let next_check_at = next_check_after(&class, now);
+            let problem = class.problem();
+            if problem.is_some() && problem != book.check_problem {
+                warn!(
+                    book_id = %book.id,
+                    ?problem,
+                    ?next_check_at,
+                    "A book's checks are failing in a way retrying won't fix."
+                );
+            }
+            update
+                .set((
+                    books::last_checked_at.eq(now),
+                    books::last_error.eq(error_chain(err)),
+                    books::next_check_at.eq(next_check_at),
+                    // Transient failures say nothing of an earlier problem.
+                    books::check_problem.eq(problem.or(book.check_problem)),
+                ))
+                .execute(&mut *conn)
+                .await?
         }
-        BookKind::Pale => pale::get_chapters(&book.id)
-            .await
-            .with_context(|| "Failed to fetch new pale chapters.")?,
-        BookKind::APracticalGuideToEvil => practical_guide::get_chapters(&book.id)
-            .await
-            .with_context(|| "Failed to fetch new practical guide to evil chapters.")?,
-        BookKind::TheWanderingInn => wandering_inn::get_chapters(&book.id)
-            .await
-            .with_context(|| "Failed to fetch new practical guide to evil chapters.")?,
-        BookKind::TheWanderingInnPatreon => wandering_inn_patreon::get_chapters(&book.id)
-            .await
-            .with_context(|| "Failed to fetch new wandering inn patreon chapters.")?,
-        BookKind::TheDailyGrindPatreon => the_daily_grind_patreon::get_chapters(&book.id)
-            .await
-            .with_context(|| "Failed to fetch new daily grind patreon chapters.")?,
-        BookKind::ApparatusOfChangePatreon => apparatus_of_change_patreon::get_chapters(&book.id)
-            .await
-            .with_context(|| "Failed to fetch new apparatus of change patreon chapters.")?,
     };
-    if rss_chapters.is_empty() {
-        return Ok(rss_chapters);
+    Ok(())
+}
+
+/// When a book whose check failed as `class` is next checked, `None` for the
+/// next cycle.
+fn next_check_after(class: &ProviderError, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    match class {
+        ProviderError::Transient {
+            retry_after: Some(retry_after),
+        } => {
+            let max = chrono::Duration::hours(MAX_RETRY_AFTER_HOURS);
+            let wait = chrono::Duration::from_std(*retry_after).unwrap_or(max);
+            Some(now + wait.min(max))
+        }
+        ProviderError::Gone | ProviderError::ParseChanged => {
+            Some(now + chrono::Duration::hours(PROBLEM_BACKOFF_HOURS))
+        }
+        ProviderError::Transient { retry_after: None }
+        | ProviderError::AuthRequired
+        | ProviderError::Other => None,
     }
-    let oldest_rss_chapter = rss_chapters
-        .iter()
-        .min_by(|x, y| x.published_at.cmp(&y.published_at))
-        .unwrap();
-    let existing_chapters = {
-        use crate::schema::chapters::dsl::*;
-        let conn = pool.get().await?;
-        Chapter::belonging_to(book)
-            .filter(published_at.ge(oldest_rss_chapter.published_at))
-            .order_by(published_at.desc())
-            .load::<Chapter>(&*conn)?
+}
+
+/// Marks a book as needing credentials when any of its chapter bodies failed
+/// for want of them, so its subscribers are told why chapters are missing.
+async fn record_auth_failures(
+    pool: &InstrumentedPgConnectionPool,
+    book: &Book,
+    stored: &[Result<StoredBody>],
+) -> Result<()> {
+    let auth_required = stored.iter().any(|stored| {
+        stored
+            .as_ref()
+            .is_err_and(|err| ProviderError::of(err) == ProviderError::AuthRequired)
+    });
+    if !auth_required {
+        return Ok(());
     }
-    .into_iter()
-    .map(|chap| chap.metadata)
-    .collect_vec();
+    let mut conn = pool.get().await?;
+    diesel::update(books::table.find(book.id))
+        .set(books::check_problem.eq(CheckProblem::AuthRequired))
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
 
-    Ok(rss_chapters
-        .into_iter()
-        .filter(|rss_chap| !existing_chapters.contains(&rss_chap.metadata))
-        .collect())
+/// A chapter body scraped again, compared with the body it replaced.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct RefetchedBody {
+    pub chapter_id: Uuid,
+    /// The size of the replaced body, unless there was none or it couldn't
+    /// be read.
+    pub old_bytes: Option<usize>,
+    pub new_bytes: usize,
+    pub old_sha256: Option<String>,
+    pub new_sha256: String,
+    pub changed: bool,
+    pub word_count: i64,
 }
 
-pub async fn send_notifications_loop(pool: InstrumentedPgConnectionPool) -> Result<(), Error> {
-    let mut interval = tokio::time::interval(Duration::from_secs(30));
-    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+/// Scrapes a chapter's body from its source again, replacing the stored body
+/// and dropping the chapter's cached ebook, which was converted from the old
+/// one.
+pub(crate) async fn refetch_chapter_body(
+    pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+    endpoints: &Endpoints,
+    book: &Book,
+    chapter: &Chapter,
+) -> Result<RefetchedBody> {
+    let old_body: Option<ChapterBody> = {
+        let mut conn = pool.get().await?;
+        chapter_bodies::table
+            .find(chapter.id)
+            .first(&mut *conn)
+            .await
+            .optional()?
+    };
+    let old = match &old_body {
+        None => None,
+        Some(body) => match storage.fetch(body.clone().into()).await {
+            Ok(old) => Some(old),
+            Err(err) => {
+                warn!(error = %error_chain(&err), "Failed to read the body being replaced.");
+                None
+            }
+        },
+    };
 
-    loop {
-        interval.tick().await;
-        match send_notifications(pool.clone()).await {
-            Ok(_) => {}
-            Err(err) => error!({%err}, "An error occurred sending notifications."),
-        };
+    let ScrapedChapter { body: html, extras } =
+        fetch_chapter_body(&NewChapter::from(chapter), book, storage, endpoints).await?;
+    store_extras(storage, book, &chapter.id, extras).await;
+    let word_count = count_words(&html);
+    let new = html.into_bytes();
+    let new_sha256 = format!("{:x}", Sha256::digest(&new));
+    let new_bytes = new.len();
+    let Uploaded { location, size } = storage
+        .store_book(&book.id, &chapter.id, ByteStream::from(new))
+        .await?;
+    let body = ChapterBody {
+        key: location.key,
+        bucket: location.bucket,
+        chapter_id: chapter.id,
+        size: Some(size),
+    };
+    let mut conn = pool.get().await?;
+    conn.transaction::<_, diesel::result::Error, _>(async |conn| {
+        diesel::insert_into(chapter_bodies::table)
+            .values(&body)
+            .on_conflict(chapter_bodies::chapter_id)
+            .do_update()
+            .set((
+                chapter_bodies::key.eq(&body.key),
+                chapter_bodies::bucket.eq(&body.bucket),
+                chapter_bodies::size.eq(body.size),
+            ))
+            .execute(&mut *conn)
+            .await?;
+        diesel::update(chapters::table.find(chapter.id))
+            .set((
+                chapters::status.eq(ChapterStatus::Fetched),
+                chapters::word_count.eq(word_count),
+            ))
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    })
+    .await?;
+    drop(conn);
+
+    // Bodies stored before keys were prefixed by book are left behind by the
+    // new key.
+    if let Some(old_body) = old_body.filter(|old| old.key != body.key) {
+        storage.delete(&old_body.into()).await?;
     }
+    if let Some(artifact) = storage.chapter_artifact(&book.id, &chapter.id).await? {
+        storage.delete(&artifact).await?;
+    }
+
+    let old_sha256 = old.as_ref().map(|old| format!("{:x}", Sha256::digest(old)));
+    Ok(RefetchedBody {
+        chapter_id: chapter.id,
+        old_bytes: old.as_ref().map(Vec::len),
+        new_bytes,
+        changed: old_sha256.as_ref() != Some(&new_sha256),
+        old_sha256,
+        new_sha256,
+        word_count,
+    })
 }
 
-#[tracing::instrument(
-name = "Delivering any unsent chapters",
-err,
-level = "info"
-skip(pool),
-)]
-async fn send_notifications(pool: InstrumentedPgConnectionPool) -> Result<()> {
-    info!("Checking for new unsent chapters.");
-
-    let chaps: Vec<ChapterWithUser> = {
-        let conn = pool.get().await?;
-        let chapters_query = "
-            select subs_with_timestamp.user_id, subs_with_timestamp.grouping_quantity, chapters.* from (
-                select subscriptions.*, coalesce(max(chapters.published_at), TIMESTAMP '1982-05-20 22:06:05.944623+00') as last_chapter_timestamp from subscriptions
-                left join chapters on chapters.id = last_chapter_id
-                group by subscriptions.user_id, subscriptions.book_id) as subs_with_timestamp
-            left join books on books.id = subs_with_timestamp.book_id
-            left join chapters on chapters.book_id = books.id
-            where chapters.published_at > subs_with_timestamp.last_chapter_timestamp
-            ";
-        sql_query(chapters_query).load(&*conn)?
-    };
-
-    let mut user_id_to_book_ids_to_chapters: HashMap<String, HashMap<(Uuid, i64), Vec<Chapter>>> =
-        HashMap::new();
-    for chap in chaps {
-        let book_ids_to_chapters = user_id_to_book_ids_to_chapters
-            .entry(chap.user_id)
-            .or_default();
-        let chap_list = book_ids_to_chapters
-            .entry((chap.book_id, chap.grouping_quantity))
-            .or_default();
-        let new_chap = Chapter {
-            id: chap.id,
-            name: chap.name,
-            author: chap.author,
-            created_at: chap.created_at,
-            published_at: chap.published_at,
-            book_id: chap.book_id,
-            updated_at: chap.updated_at,
-            metadata: chap.metadata,
-        };
-        match chap_list.binary_search_by(|a| a.published_at.cmp(&new_chap.published_at)) {
-            Ok(_pos) => {} // element already in vector @ `pos`
-            Err(pos) => chap_list.insert(pos, new_chap),
+/// Stores the extras scraped with a chapter beside its body. They are only
+/// wanted by some subscribers, so failing to store them doesn't fail the
+/// chapter.
+async fn store_extras(
+    storage: &Storage,
+    book: &Book,
+    chapter_id: &Uuid,
+    extras: Option<ChapterExtras>,
+) {
+    let Some(extras) = extras.filter(|extras| !extras.is_empty()) else {
+        return;
+    };
+    let stored = match serde_json::to_vec(&extras) {
+        Ok(json) => {
+            storage
+                .store_chapter_extras(&book.id, chapter_id, ByteStream::from(json))
+                .await
         }
+        Err(err) => Err(err.into()),
+    };
+    if let Err(err) = stored {
+        warn!(error = %error_chain(&err), %chapter_id, "Failed to store a chapter's extras.");
     }
+}
 
-    let user_ids = user_id_to_book_ids_to_chapters.keys().collect_vec();
-    let book_ids = user_id_to_book_ids_to_chapters
-        .values()
-        .flat_map(|x| x.keys().map(|(book_id, _group_size)| book_id))
-        .collect_vec();
+/// A fetched chapter body and where it was stored.
+struct StoredBody {
+    location: StorageLocation,
+    size: i64,
+    word_count: i64,
+}
 
-    let user_to_delivery_method: HashMap<String, DeliveryMethod> = {
-        let conn = pool.get().await?;
-        delivery_methods::table
-            .select(delivery_methods::all_columns)
-            .filter(delivery_methods::user_id.eq_any(user_ids))
-            .load::<DeliveryMethod>(&*conn)?
-            .into_iter()
-            .map(|x| (x.user_id.clone(), x))
-            .collect()
-    };
+/// Counts the whitespace separated words in the text of an html document,
+/// ignoring stray punctuation.
+fn count_words(html: &str) -> i64 {
+    Html::parse_fragment(html)
+        .root_element()
+        .text()
+        .flat_map(str::split_whitespace)
+        .filter(|word| word.chars().any(char::is_alphanumeric))
+        .count() as i64
+}
 
-    let book_id_to_book: HashMap<Uuid, Book> = {
-        let conn = pool.get().await?;
-        books::table
-            .select(books::all_columns)
-            .filter(books::id.eq_any(book_ids))
-            .load::<Book>(&*conn)?
-            .into_iter()
-            .map(|x| (x.id, x))
-            .collect()
-    };
+/// Words read per minute when estimating how long chapters take to read.
+const WORDS_PER_MINUTE: i64 = 275;
 
-    let delivery_errors = deliver_new_chapters(
-        user_id_to_book_ids_to_chapters,
-        user_to_delivery_method,
-        book_id_to_book,
-        pool.clone(),
-    )
-    .await;
+/// Describes the length of chapters, such as "~12,400 words (~45 min)", or
+/// nothing if any of them were not counted.
+fn reading_estimate(chapters: &[&Chapter]) -> Option<String> {
+    let words: i64 = chapters
+        .iter()
+        .map(|chap| chap.word_count)
+        .sum::<Option<i64>>()?;
+    let rounded = if words >= 1000 {
+        (words + 50) / 100 * 100
+    } else {
+        words
+    };
+    let minutes = ((words + WORDS_PER_MINUTE / 2) / WORDS_PER_MINUTE).max(1);
+    Some(format!(
+        "~{} words (~{} min)",
+        with_thousands_separators(rounded),
+        minutes
+    ))
+}
 
-    match delivery_errors.len() {
-        0 => Ok(()),
-        _len => Err(anyhow!("Failed to deliver some chapters to users"))
-            .with_context(|| format!("{delivery_errors:#?}")),
+fn with_thousands_separators(number: i64) -> String {
+    let digits = number.to_string();
+    let mut separated = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            separated.push(',');
+        }
+        separated.push(digit);
     }
+    separated
+}
+
+/// Lists the chapters the book's provider has, whether or not they are new.
+async fn list_chapters(
+    book: &Book,
+    storage: &Storage,
+    endpoints: &Endpoints,
+) -> Result<Vec<NewChapter>> {
+    Ok(match book.metadata {
+        BookKind::RoyalRoad(RoyalRoadBookKind { id, .. }) => {
+            royalroad::get_chapters(&endpoints.royalroad, id, &book.id, &book.author)
+                .await
+                .with_context(|| "Failed to fetch new royalroad chapters.")?
+        }
+        BookKind::Pale => pale::get_chapters(&endpoints.pale, &book.id)
+            .await
+            .with_context(|| "Failed to fetch new pale chapters.")?,
+        BookKind::APracticalGuideToEvil => {
+            practical_guide::get_chapters(&endpoints.practical_guide, &book.id)
+                .await
+                .with_context(|| "Failed to fetch new practical guide to evil chapters.")?
+        }
+        BookKind::TheWanderingInn => {
+            wandering_inn::get_chapters(&endpoints.wandering_inn, &book.id)
+                .await
+                .with_context(|| "Failed to fetch new practical guide to evil chapters.")?
+        }
+        BookKind::TheWanderingInnPatreon => {
+            wandering_inn_patreon::get_chapters(&book.id, storage.email_bucket()?)
+                .await
+                .with_context(|| "Failed to fetch new wandering inn patreon chapters.")?
+        }
+        BookKind::TheDailyGrindPatreon => the_daily_grind_patreon::get_chapters(&book.id, storage)
+            .await
+            .with_context(|| "Failed to fetch new daily grind patreon chapters.")?,
+        BookKind::ApparatusOfChangePatreon => {
+            apparatus_of_change_patreon::get_chapters(&book.id, storage.email_bucket()?)
+                .await
+                .with_context(|| "Failed to fetch new apparatus of change patreon chapters.")?
+        }
+        BookKind::Unknown(_) => {
+            warn!(
+                "Skipping book {} of a kind unrecognized by this version.",
+                book.name
+            );
+            return Ok(Vec::new());
+        }
+    })
 }
 
 #[tracing::instrument(
-name = "Delivering some unsent chapters",
+name = "Discovering new chapters for a single book.",
+err,
 level = "info"
-skip(pool, user_to_delivery_method),
+skip(pool, storage, endpoints),
 )]
-async fn deliver_new_chapters(
-    user_id_to_book_ids_to_chapters: HashMap<String, HashMap<(Uuid, i64), Vec<Chapter>>>,
-    user_to_delivery_method: HashMap<String, DeliveryMethod>,
-    book_id_to_book: HashMap<Uuid, Book>,
-    pool: InstrumentedPgConnectionPool,
-) -> Vec<Result<()>> {
-    let mut errors = Vec::new();
-    for (user_id, book_id_to_chapters) in user_id_to_book_ids_to_chapters {
-        let delivery_method = user_to_delivery_method.get(&user_id).unwrap();
-        for ((book_id, grouping_quantity), chapters) in book_id_to_chapters {
-            let book = book_id_to_book.get(&book_id).unwrap();
-
-            let chapter_bodies: Vec<ChapterBody> = {
-                let conn = match pool.get().await {
-                    Ok(x) => x,
-                    Err(e) => {
-                        errors.push(Err(e).with_context(|| {
-                            format!(
-                                "Failed to acquire a database connection
-                         while fetching bodies for book {}, chapters: [{}]",
-                                book.name,
-                                chapters.iter().map(|chap| &chap.name).join(", ")
-                            )
-                        }));
-                        continue;
-                    }
-                };
-                match chapter_bodies::table
-                    .filter(
-                        chapter_bodies::chapter_id
-                            .eq_any(chapters.iter().map(|x| x.id).collect_vec()),
-                    )
-                    .select(chapter_bodies::all_columns)
-                    .order(chapter_bodies::chapter_id.asc())
-                    .load(&*conn)
-                {
-                    Ok(x) => x,
-                    Err(e) => {
-                        errors.push(Err(e).with_context(|| {
-                            format!(
-                                "Failed to fetch bodies for book {}, chapters: [{}]",
-                                book.name,
-                                chapters.iter().map(|chap| &chap.name).join(", ")
-                            )
-                        }));
-                        continue;
-                    }
-                }
-            };
+async fn get_new_chapters(
+    book: &Book,
+    pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+    endpoints: &Endpoints,
+) -> Result<Vec<(i32, NewChapter)>, Error> {
+    let started = Instant::now();
+    let listed = list_chapters(book, storage, endpoints).await;
+    if let Some(provider) = book.metadata.provider() {
+        health::record_fetch(provider, started.elapsed(), listed.as_ref().map(drop));
+    }
+    let mut rss_chapters = listed?;
+    if rss_chapters.is_empty() {
+        return Ok(Vec::new());
+    }
+    let oldest_rss_chapter = rss_chapters
+        .iter()
+        .min_by(|x, y| x.published_at.cmp(&y.published_at))
+        .unwrap();
+    let existing_chapters = {
+        use crate::schema::chapters::dsl::*;
+        let mut conn = pool.get().await?;
+        let mut existing = Chapter::belonging_to(book)
+            .filter(published_at.ge(oldest_rss_chapter.published_at))
+            .order_by(published_at.desc())
+            .select(metadata)
+            .load::<ChapterKind>(&mut *conn)
+            .await?;
+        // Feeds reaching back past the archive cutoff list archived chapters too.
+        existing.extend(
+            chapters_archive::table
+                .filter(chapters_archive::book_id.eq(book.id))
+                .filter(chapters_archive::published_at.ge(oldest_rss_chapter.published_at))
+                .select(chapters_archive::metadata)
+                .load::<ChapterKind>(&mut *conn)
+                .await?,
+        );
+        existing
+    };
+    let next_ordinal = next_ordinal(pool, book.id).await?;
 
-            let chapters_with_body = chapters
-                .iter()
-                .sorted_by_key(|chap| chap.id)
-                .zip(chapter_bodies.iter())
-                .sorted_by_key(|(chap, _body)| chap.published_at)
-                .collect_vec();
-            if chapters_with_body.len() as i64 >= grouping_quantity {
-                match send_pushover_if_enabled(delivery_method, book, &chapters).await {
-                    Ok(()) => (),
-                    Err(e) => {
-                        errors.push(Err(e).with_context(|| {
-                            format!(
-                                "Failed to pushover notification to user {user_id} for book {}, chapters: [{}]",
-                                book.name,
-                                chapters.iter().map(|chap| &chap.name).join(", ")
-                            )
-                        }));
-                        continue;
-                    }
-                };
-                match send_kindle_if_enabled(delivery_method, book, &chapters_with_body).await {
-                    Ok(()) => (),
-                    Err(e) => {
-                        errors.push(Err(e).with_context(|| {
-                            format!(
-                                "Failed to send kindle emails for user {user_id} for book {}, chapters: [{}]",
-                                book.name,
-                                chapters.iter().map(|chap| &chap.name).join(", ")
-                            )
-                        }));
-                        continue;
-                    }
-                };
-                match update_subscription_last_chapter_id(pool.clone(), &user_id, &chapters).await {
-                    Ok(()) => (),
-                    Err(e) => {
-                        errors.push(Err(e).with_context(|| {
-                            format!(
-                                "Failed to update last_sent_chapter for user {user_id} for book {}, chapters: [{}]",
-                                book.name,
-                                chapters.iter().map(|chap| &chap.name).join(", ")
-                            )
-                        }));
-                        continue;
-                    }
-                };
-            }
-        }
+    // Feeds list the newest chapters first and tables of contents the oldest,
+    // told apart by the ends of the list. New chapters are numbered on from
+    // the book's last chapter in the provider's order, oldest first.
+    if rss_chapters[0].published_at > rss_chapters[rss_chapters.len() - 1].published_at {
+        rss_chapters.reverse();
     }
-    errors
+    Ok(rss_chapters
+        .into_iter()
+        .filter(|rss_chap| !existing_chapters.contains(&rss_chap.metadata))
+        .zip(next_ordinal..)
+        .map(|(chap, ordinal)| (ordinal, chap))
+        .collect())
 }
 
-async fn update_subscription_last_chapter_id(
-    pool: InstrumentedPgConnectionPool,
-    user_id_str: &str,
-    chapters: &[Chapter],
-) -> Result<()> {
-    use crate::schema::subscriptions::dsl::*;
-    let conn = pool.get().await?;
-    diesel::update(
-        subscriptions
-            .filter(user_id.eq(user_id_str))
-            .filter(book_id.eq(chapters[0].book_id)),
-    )
-    .set(last_chapter_id.eq(chapters[chapters.len() - 1].id))
-    .execute(&*conn)?;
-    Ok(())
+/// The ordinal following the last of a book's chapters, archived or not.
+async fn next_ordinal(pool: &InstrumentedPgConnectionPool, book_id: Uuid) -> Result<i32> {
+    let mut conn = pool.get().await?;
+    let current: Option<i32> = chapters::table
+        .filter(chapters::book_id.eq(book_id))
+        .select(diesel::dsl::max(chapters::ordinal))
+        .get_result(&mut *conn)
+        .await?;
+    let archived: Option<i32> = chapters_archive::table
+        .filter(chapters_archive::book_id.eq(book_id))
+        .select(diesel::dsl::max(chapters_archive::ordinal))
+        .get_result(&mut *conn)
+        .await?;
+    Ok(current.max(archived).map_or(0, |last| last + 1))
 }
 
+/// Moves Daily Grind html embedded in chapter metadata by older versions out
+/// to storage. Runs before the tasks start, as the old metadata no longer
+/// loads.
 #[tracing::instrument(
-    name = "Sending pushover notification",
+    name = "Moving embedded daily grind html to storage",
     level = "info",
     err,
-    skip(delivery_method)
+    skip(pool, storage)
 )]
-async fn send_pushover_if_enabled(
-    delivery_method: &DeliveryMethod,
-    book: &Book,
-    chapters: &[Chapter],
+pub async fn move_embedded_daily_grind_html(
+    pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
 ) -> Result<()> {
-    if let Some(pushover_key) = delivery_method.get_pushover_key() {
-        match chapters.len() {
-            1 => {
-                pushover::send_message(
-                    pushover_key,
-                    &format!(
-                        "A new chapter of {} by {} has been released: {}",
-                        book.name, book.author, chapters[0].name,
-                    ),
-                )
-                .await?
-            }
-            x => {
-                pushover::send_message(
-                    pushover_key,
-                    &format!(
-                        "{x} new chapters of {} by {} has been released: {} through {}",
-                        book.name,
-                        book.author,
-                        chapters[0].name,
-                        chapters[x - 1].name,
-                    ),
-                )
-                .await?
-            }
-        };
+    let embedded: Vec<EmbeddedDailyGrindHtml> = {
+        let mut conn = pool.get().await?;
+        sql_query(
+            "select id, book_id, metadata->'TheDailyGrindPatreon'->>'html' as html from chapters
+            where metadata->'TheDailyGrindPatreon'->>'html' is not null",
+        )
+        .load(&mut *conn)
+        .await?
+    };
+    if !embedded.is_empty() {
+        info!("Moving {} embedded daily grind chapters.", embedded.len());
+    }
+    for chapter in embedded {
+        let location = storage
+            .store_source(&chapter.book_id, chapter.html.into_bytes())
+            .await?;
+        let mut conn = pool.get().await?;
+        diesel::update(chapters::table.find(chapter.id))
+            .set(chapters::metadata.eq(ChapterKind::TheDailyGrindPatreon {
+                bucket: location.bucket,
+                key: location.key,
+            }))
+            .execute(&mut *conn)
+            .await?;
     }
     Ok(())
 }
 
+/// Numbers each RoyalRoad book's chapters by their position in the fiction's
+/// table of contents, for chapters numbered before ordinals ordered a whole
+/// book. Chapters no longer listed keep their ordinals. Returns how many
+/// chapters were numbered.
 #[tracing::instrument(
-    name = "Sending kindle mobi file notification",
+    name = "Numbering royalroad chapters from their table of contents",
     level = "info",
     err,
-    skip(delivery_method)
+    skip(pool, endpoints)
 )]
-async fn send_kindle_if_enabled(
-    delivery_method: &DeliveryMethod,
-    book: &Book,
-    chapters: &[(&Chapter, &ChapterBody)],
-) -> Result<()> {
-    let text_bytes: Vec<u8> = join_all(
-        chapters
-            .iter()
-            .map(|(_chap, body)| S3Location {
-                prefix: (*body).key.clone(),
-                bucket_name: (*body).bucket.clone(),
-                ..Default::default()
-            })
-            .map(storage::fetch_book),
-    )
-    .instrument(info_span!("Fetching chapter bodies from storage."))
-    .await
-    .into_iter()
-    .collect::<Result<Vec<Vec<u8>>>>()?
-    .into_iter()
-    .fold(Vec::new(), |mut acc, mut x| {
-        acc.append(&mut x);
-        acc
-    });
-    let just_chapters = chapters.iter().map(|(c, _b)| *c).collect_vec();
-    let cover_title = match just_chapters.len() {
-        1 => format!("{}: {}", book.name, just_chapters[0].name),
-        x => format!(
-            "{}: {} through {}",
-            book.name,
-            just_chapters[0].name,
-            just_chapters[x - 1].name
-        ),
+pub async fn number_royalroad_chapters(
+    pool: &InstrumentedPgConnectionPool,
+    endpoints: &Endpoints,
+) -> Result<usize> {
+    let books: Vec<Book> = {
+        let mut conn = pool.get().await?;
+        books::table
+            .filter(book_not_deleted())
+            .load(&mut *conn)
+            .await?
     };
-    let mobi_bytes = calibre::generate_epub(
-        "html",
-        &String::from_utf8(text_bytes)?,
-        &cover_title,
-        &book.name,
+    let mut numbered = 0;
+    for book in books {
+        let BookKind::RoyalRoad(RoyalRoadBookKind { id, .. }) = book.metadata else {
+            continue;
+        };
+        match number_royalroad_book(pool, endpoints, &book, id).await {
+            Ok(count) => numbered += count,
+            Err(err) => report_book_error(&err, &book),
+        }
+    }
+    info!("Numbered {} royalroad chapters.", numbered);
+    Ok(numbered)
+}
+
+async fn number_royalroad_book(
+    pool: &InstrumentedPgConnectionPool,
+    endpoints: &Endpoints,
+    book: &Book,
+    fiction_id: u64,
+) -> Result<usize> {
+    let contents = royalroad::get_fiction_page_chapters(
+        &endpoints.royalroad,
+        fiction_id,
+        &book.id,
         &book.author,
     )
-    .await?;
-    if let Some(kindle_email) = delivery_method.get_kindle_email() {
-        send_kindle(kindle_email, book, &just_chapters, &mobi_bytes).await?;
+    .await
+    .with_context(|| "Failed to list royalroad chapters from the fiction page.")?;
+    let positions: HashMap<ChapterKind, i32> = contents
+        .into_iter()
+        .zip(0..)
+        .map(|(chap, ordinal)| (chap.metadata, ordinal))
+        .collect();
+    let mut conn = pool.get().await?;
+    let stored: Vec<(Uuid, ChapterKind)> = chapters::table
+        .filter(chapters::book_id.eq(book.id))
+        .select((chapters::id, chapters::metadata))
+        .load(&mut *conn)
+        .await?;
+    let archived: Vec<(Uuid, ChapterKind)> = chapters_archive::table
+        .filter(chapters_archive::book_id.eq(book.id))
+        .select((chapters_archive::id, chapters_archive::metadata))
+        .load(&mut *conn)
+        .await?;
+    let mut numbered = 0;
+    for (chapter_id, metadata) in stored {
+        if let Some(ordinal) = positions.get(&metadata) {
+            numbered += diesel::update(chapters::table.find(chapter_id))
+                .set(chapters::ordinal.eq(ordinal))
+                .execute(&mut *conn)
+                .await?;
+        }
     }
-    Ok(())
+    for (chapter_id, metadata) in archived {
+        if let Some(ordinal) = positions.get(&metadata) {
+            numbered += diesel::update(chapters_archive::table.find(chapter_id))
+                .set(chapters_archive::ordinal.eq(ordinal))
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
+    Ok(numbered)
 }
 
-#[tracing::instrument(
-    name = "Sending kindle mobi file email with mailgun",
-    level = "info",
-    err,
-    skip(bytes, kindle_email)
-)]
-async fn send_kindle(
-    kindle_email: &str,
-    book: &Book,
-    chapters: &[&Chapter],
-    bytes: &[u8],
+pub async fn send_notifications_loop(
+    config: &Config,
+    pool: InstrumentedPgConnectionPool,
+    storage: Storage,
+    mut shutdown: Shutdown,
 ) -> Result<(), Error> {
-    match chapters.len() {
-        1 => {
-            let subject = format!("New Chapter of {}: {}", book.name, chapters[0].name);
-            mailgun::send_epub_file(bytes, kindle_email, &chapters[0].name, &subject).await?;
-        }
-        x => {
-            let subject = format!(
-                "{x} New Chapters of {}: {} through {}",
-                book.name,
-                chapters[0].name,
-                chapters[x - 1].name
+    let mut interval = tokio::time::interval(config.intervals.notification);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let limits = &config.delivery_backlog;
+    // Whether the backlog was over the warning threshold last cycle, so the
+    // warning is logged once when it is crossed rather than every cycle.
+    let mut backlogged = false;
+    let mut last_receipt_poll: Option<Instant> = None;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.requested() => return Ok(()),
+        }
+        if last_receipt_poll.is_none_or(|last| last.elapsed() >= RECEIPT_POLL_INTERVAL) {
+            last_receipt_poll = Some(Instant::now());
+            // Untraced like finding unsent chapters, as there are rarely any.
+            let polled = match pending_receipts(&pool, None)
+                .with_subscriber(NoSubscriber::default())
+                .await
+            {
+                Ok(pending) if pending.is_empty() => Ok(0),
+                Ok(pending) => poll_pushover_receipts(&pool, pending).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = polled {
+                let _ = report_task_error(&err, "Error checking pushover receipts.");
+            }
+        }
+        // Finding unsent chapters is untraced so idle cycles, which are nearly
+        // all of them, export no spans. Cycles with work are traced in full.
+        let (unsent, backlog) = match find_unsent_chapters(&pool, limits)
+            .with_subscriber(NoSubscriber::default())
+            .await
+        {
+            Ok(found) => found,
+            Err(err) => {
+                let _ = report_task_error(&err, "An error occurred finding unsent chapters.");
+                continue;
+            }
+        };
+        metrics::gauge!("delivery_backlog_groups").set(backlog as f64);
+        if backlog > limits.warn && !backlogged {
+            warn!(
+                backlog,
+                groups_per_cycle = limits.groups_per_cycle,
+                "Deliveries are backlogged, sending the oldest groups each cycle until caught up."
             );
-            mailgun::send_epub_file(
-                bytes,
-                kindle_email,
-                &format!("{} through {}", chapters[0].name, chapters[x - 1].name),
-                &subject,
-            )
+        } else if backlog <= limits.warn && backlogged {
+            info!(backlog, "The delivery backlog has cleared.");
+        }
+        backlogged = backlog > limits.warn;
+        if unsent.is_empty() {
+            summary::record_notification_cycle();
+            continue;
+        }
+        // The error is logged within the cycle's span so it carries its trace id.
+        // Failed deliveries are reported as they happen, so it is only logged.
+        let cycle = tracing::info_span!("Running the notification cycle.");
+        match send_notifications(unsent, pool.clone(), &storage)
+            .instrument(cycle.clone())
+            .await
+        {
+            Ok(()) => summary::record_notification_cycle(),
+            Err(err) => {
+                let _ = cycle
+                    .in_scope(|| log_task_error(&err, "An error occurred sending notifications."));
+            }
+        }
+    }
+}
+
+/// Delivers a single user's unsent chapters once, outside the notification
+/// loop. Returns how many chapters were due.
+pub async fn deliver_to_user(
+    pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+    user_id: &str,
+) -> Result<usize> {
+    let unsent = due_chapters(queued_chapters(pool, Some(user_id)).await?);
+    let due = unsent
+        .values()
+        .flat_map(HashMap::values)
+        .map(Vec::len)
+        .sum();
+    if due > 0 {
+        send_notifications(unsent, pool.clone(), storage).await?;
+    }
+    Ok(due)
+}
+
+/// What `redeliver` sent, or would send on a dry run.
+#[derive(Debug, Serialize)]
+pub struct Redelivery {
+    /// The new delivery, unless this was a dry run.
+    pub delivery_id: Option<Uuid>,
+    pub chapters: Vec<String>,
+    pub kindle_email: Option<String>,
+    pub pushover: bool,
+    pub refetched: bool,
+    pub degraded: bool,
+    pub dry_run: bool,
+}
+
+/// Converts and sends a past delivery's chapters again, to the user's current
+/// delivery methods, recording a new delivery linked to the original. With
+/// `refetch` the bodies are scraped from their source again first. A dry run
+/// only reports what would be sent.
+pub(crate) async fn redeliver(
+    pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+    delivery_id: Uuid,
+    refetch: bool,
+    dry_run: bool,
+) -> Result<Redelivery> {
+    let (delivery, book, chapters, delivery_method, extras, settings, templates) = {
+        let mut conn = pool.get().await?;
+        let delivery: Delivery = deliveries::table
+            .find(delivery_id)
+            .first(&mut *conn)
+            .await
+            .optional()?
+            .ok_or_else(|| {
+                ApiError::NotFound(format!("Delivery {} does not exist.", delivery_id))
+            })?;
+        let book_id = delivery.book_id.ok_or_else(|| {
+            ApiError::BadRequest(format!(
+                "Delivery {} was a test delivery, which has nothing to resend.",
+                delivery_id
+            ))
+        })?;
+        let book: Book = books::table.find(book_id).first(&mut *conn).await?;
+        let chapters: Vec<Chapter> = chapters::table
+            .filter(chapters::id.eq_any(&delivery.chapter_ids))
+            .order(chapter_order())
+            .load(&mut *conn)
+            .await?;
+        let delivery_method: DeliveryMethod = delivery_methods::table
+            .find(&delivery.user_id)
+            .first(&mut *conn)
             .await?;
+        // The book may since have been unsubscribed from.
+        use crate::schema::subscriptions;
+        let subscription: Option<Subscription> = subscriptions::table
+            .find((&delivery.user_id, book_id))
+            .first(&mut *conn)
+            .await
+            .optional()?;
+        let extras = subscription
+            .as_ref()
+            .map(Subscription::extras)
+            .unwrap_or_default();
+        use crate::schema::user_preferences;
+        let preferences: Option<UserPreferences> = user_preferences::table
+            .find(&delivery.user_id)
+            .first(&mut *conn)
+            .await
+            .optional()?;
+        let settings = DeliverySettings::resolve(
+            subscription.as_ref(),
+            preferences.as_ref(),
+            &delivery_method,
+        );
+        let templates = preferences
+            .as_ref()
+            .map(UserPreferences::templates)
+            .unwrap_or_default()
+            .or(&config::get().templates);
+        (
+            delivery,
+            book,
+            chapters,
+            delivery_method,
+            extras,
+            settings,
+            templates,
+        )
+    };
+    if chapters.is_empty() {
+        return Err(ApiError::NotFound(format!(
+            "Delivery {} has no chapters left to send.",
+            delivery.id
+        ))
+        .into());
+    }
+    let mut redelivery = Redelivery {
+        delivery_id: None,
+        chapters: chapters.iter().map(|chap| chap.name.clone()).collect(),
+        kindle_email: delivery_method.get_kindle_email().clone(),
+        pushover: delivery_method.get_pushover_key().is_some(),
+        refetched: refetch,
+        degraded: false,
+        dry_run,
+    };
+    if dry_run {
+        return Ok(redelivery);
+    }
+
+    if refetch {
+        let endpoints = &config::get().endpoints;
+        for chap in &chapters {
+            refetch_chapter_body(pool, storage, endpoints, &book, chap)
+                .await
+                .with_context(|| format!("Failed to refetch chapter {}", chap.name))?;
         }
     }
-    Ok(())
+
+    let bodies: HashMap<Uuid, ChapterBody> = {
+        let mut conn = pool.get().await?;
+        chapter_bodies::table
+            .filter(chapter_bodies::chapter_id.eq_any(&delivery.chapter_ids))
+            .load::<ChapterBody>(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|body| (body.chapter_id, body))
+            .collect()
+    };
+    let chapters_with_body = chapters
+        .iter()
+        .map(|chap| {
+            bodies
+                .get(&chap.id)
+                .map(|body| (chap, body))
+                .with_context(|| format!("Chapter {} has no stored body.", chap.name))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // Redeliveries are announced at normal priority, as the user was already
+    // alerted to these chapters once.
+    send_pushover_if_enabled(
+        &delivery_method,
+        &templates,
+        &book,
+        &chapters,
+        Priority::Normal,
+    )
+    .await?;
+    let sent = send_kindle_if_enabled(
+        &delivery_method,
+        &templates,
+        &book,
+        &chapters_with_body,
+        extras,
+        &settings,
+        storage,
+    )
+    .await?;
+    redelivery.degraded = sent.degraded;
+    redelivery.delivery_id = Some(
+        record_delivery(
+            pool.clone(),
+            &delivery.user_id,
+            &chapters,
+            sent,
+            Some(delivery.id),
+            None,
+        )
+        .await?,
+    );
+    Ok(redelivery)
+}
+
+/// Unsent chapters ready for delivery, in whole groups of the subscription's
+/// grouping quantity, keyed by user and then by book and grouping quantity.
+type UnsentChapters = HashMap<String, HashMap<(Uuid, i64), Vec<Chapter>>>;
+
+/// A subscription with the chapters published since the last one sent for it,
+/// oldest first.
+pub(crate) struct QueuedChapters {
+    pub subscription: Subscription,
+    pub chapters: Vec<Chapter>,
+}
+
+impl QueuedChapters {
+    /// Chapters still needed before the subscription's group is delivered.
+    pub fn chapters_needed(&self) -> i64 {
+        (self.subscription.grouping_quantity - self.chapters.len() as i64).max(0)
+    }
+
+    /// Whether a whole group is queued and the subscription isn't snoozed.
+    pub fn is_due(&self) -> bool {
+        !self.chapters.is_empty()
+            && self.chapters_needed() == 0
+            && !self.subscription.is_snoozed(Utc::now())
+    }
+}
+
+/// The oldest `limits.groups_per_cycle` delivery groups which are due, and how
+/// many more are waiting. Users in their quiet hours are left out, so groups
+/// held for them don't take a cycle's share from everyone else.
+async fn find_unsent_chapters(
+    pool: &InstrumentedPgConnectionPool,
+    limits: &DeliveryBacklog,
+) -> Result<(UnsentChapters, usize)> {
+    let mut unsent = due_chapters(queued_chapters(pool, None).await?);
+    let preferences: Vec<UserPreferences> = retry_read(|| async {
+        use crate::schema::user_preferences;
+        let mut conn = pool.get().await?;
+        Ok(user_preferences::table
+            .filter(user_preferences::user_id.eq_any(unsent.keys().collect_vec()))
+            .load(&mut *conn)
+            .await?)
+    })
+    .await?;
+    let now = Utc::now();
+    for preferences in preferences {
+        if preferences.in_quiet_hours(now) {
+            unsent.remove(&preferences.user_id);
+        }
+    }
+    Ok(oldest_groups(unsent, limits.groups_per_cycle))
+}
+
+/// Keeps the `max` oldest groups in `unsent`, by when each group's first
+/// chapter was published, and returns how many groups were left out. A book's
+/// groups are only kept in order, so none is sent ahead of an earlier one.
+fn oldest_groups(mut unsent: UnsentChapters, max: usize) -> (UnsentChapters, usize) {
+    let group_size = |(_, grouping_quantity): &(Uuid, i64)| (*grouping_quantity).max(1) as usize;
+    // The first group not yet kept for each subscription, oldest on top.
+    let mut next = BinaryHeap::new();
+    for (user_id, book_id_to_chapters) in &unsent {
+        for (key, chapters) in book_id_to_chapters {
+            if let [first, ..] = chapters.as_slice() {
+                next.push(Reverse((first.published_at, user_id.clone(), *key, 0)));
+            }
+        }
+    }
+    let mut kept: HashMap<(String, (Uuid, i64)), usize> = HashMap::new();
+    for _ in 0..max {
+        let Some(Reverse((_, user_id, key, group))) = next.pop() else {
+            break;
+        };
+        if let Some(first) = unsent[&user_id][&key].get((group + 1) * group_size(&key)) {
+            next.push(Reverse((
+                first.published_at,
+                user_id.clone(),
+                key,
+                group + 1,
+            )));
+        }
+        kept.insert((user_id, key), group + 1);
+    }
+
+    let mut left_out = 0;
+    for (user_id, book_id_to_chapters) in unsent.iter_mut() {
+        book_id_to_chapters.retain(|key, chapters| {
+            let size = group_size(key);
+            let groups = kept.get(&(user_id.clone(), *key)).copied().unwrap_or(0);
+            left_out += chapters.len() / size - groups;
+            chapters.truncate(groups * size);
+            groups > 0
+        });
+    }
+    unsent.retain(|_, book_id_to_chapters| !book_id_to_chapters.is_empty());
+    (unsent, left_out)
+}
+
+fn due_chapters(queued: Vec<QueuedChapters>) -> UnsentChapters {
+    let mut user_id_to_book_ids_to_chapters: UnsentChapters = HashMap::new();
+    for queued in queued.into_iter().filter(QueuedChapters::is_due) {
+        let sub = queued.subscription;
+        // Only whole groups are due, the rest wait for more chapters.
+        let mut chapters = queued.chapters;
+        chapters.truncate(chapters.len() - chapters.len() % sub.grouping_quantity.max(1) as usize);
+        user_id_to_book_ids_to_chapters
+            .entry(sub.user_id)
+            .or_default()
+            .insert((sub.book_id, sub.grouping_quantity), chapters);
+    }
+    user_id_to_book_ids_to_chapters
+}
+
+/// The chapters queued for each active subscription to a book which isn't
+/// deleted, or only for `user_id`'s subscriptions. Deliveries and the pending
+/// deliveries endpoint both select chapters through this, so they agree.
+/// Both queries only read, so they are retried if the connection drops.
+pub(crate) async fn queued_chapters(
+    pool: &InstrumentedPgConnectionPool,
+    user_id: Option<&str>,
+) -> Result<Vec<QueuedChapters>> {
+    // Each subscription with the sort key of the last chapter sent for it.
+    type LastSent = (Option<i32>, Option<DateTime<Utc>>, Option<Uuid>);
+    let subs: Vec<(Subscription, LastSent)> = retry_read(|| async {
+        use crate::schema::subscriptions;
+        let mut conn = pool.get().await?;
+        let mut query = subscriptions::table
+            .left_join(chapters::table)
+            .filter(subscriptions::active)
+            .filter(
+                subscriptions::book_id
+                    .eq_any(books::table.filter(book_not_deleted()).select(books::id)),
+            )
+            .select((
+                subscriptions::all_columns,
+                (
+                    chapters::ordinal.nullable(),
+                    chapters::published_at.nullable(),
+                    chapters::id.nullable(),
+                ),
+            ))
+            .into_boxed();
+        if let Some(user_id) = user_id {
+            query = query.filter(subscriptions::user_id.eq(user_id));
+        }
+        Ok(query.load(&mut *conn).await?)
+    })
+    .await?;
+
+    // Users who skip what was published while a subscription was snoozed,
+    // among those with a snooze which has ended.
+    let now = Utc::now();
+    let resumed = subs
+        .iter()
+        .filter(|(sub, _)| sub.snoozed_until.is_some_and(|until| until <= now))
+        .map(|(sub, _)| sub.user_id.clone())
+        .unique()
+        .collect_vec();
+    let skipping: HashSet<String> = if resumed.is_empty() {
+        HashSet::new()
+    } else {
+        retry_read(|| async {
+            use crate::schema::user_preferences;
+            let mut conn = pool.get().await?;
+            Ok(user_preferences::table
+                .filter(user_preferences::user_id.eq_any(&resumed))
+                .filter(user_preferences::skip_snoozed_chapters.eq(true))
+                .select(user_preferences::user_id)
+                .load::<String>(&mut *conn)
+                .await?)
+        })
+        .await?
+        .into_iter()
+        .collect()
+    };
+
+    // Load each book's chapters after the least recently sent subscription's.
+    // Nothing sent, or a last chapter without an ordinal, loads every chapter.
+    let mut book_id_to_oldest: HashMap<Uuid, Option<i32>> = HashMap::new();
+    for (sub, (last_sent, _, _)) in &subs {
+        book_id_to_oldest
+            .entry(sub.book_id)
+            .and_modify(|oldest| *oldest = (*oldest).min(*last_sent))
+            .or_insert(*last_sent);
+    }
+    let mut book_id_to_chapters: HashMap<Uuid, Vec<Chapter>> = HashMap::new();
+    for (book_id, oldest) in book_id_to_oldest {
+        let book_chapters = retry_read(|| async {
+            let mut conn = pool.get().await?;
+            let mut query = chapters::table
+                .filter(chapters::book_id.eq(book_id))
+                .filter(chapters::status.eq(ChapterStatus::Fetched))
+                .filter(chapter_not_deleted())
+                .order(chapter_order())
+                .into_boxed();
+            // Chapters sharing the last sent ordinal may still be unsent.
+            if let Some(oldest) = oldest {
+                query = query.filter(chapters::ordinal.ge(oldest));
+            }
+            Ok(query.load(&mut *conn).await?)
+        })
+        .await?;
+        book_id_to_chapters.insert(book_id, book_chapters);
+    }
+
+    Ok(subs
+        .into_iter()
+        .map(|(subscription, last_sent)| {
+            let mut chapters = book_id_to_chapters
+                .get(&subscription.book_id)
+                .into_iter()
+                .flatten()
+                .filter(|chap| match last_sent {
+                    (ordinal, Some(published_at), Some(id)) => {
+                        chap.sort_key() > (ordinal, published_at, id)
+                    }
+                    _ => true,
+                })
+                .cloned()
+                .collect_vec();
+            if let Some(until) = subscription.snoozed_until {
+                if until <= now && skipping.contains(&subscription.user_id) {
+                    skip_snoozed(&mut chapters, until, subscription.grouping_quantity);
+                }
+            }
+            QueuedChapters {
+                subscription,
+                chapters,
+            }
+        })
+        .collect())
+}
+
+/// Drops the chapters published before a snooze ended at `until`, except the
+/// latest group of them. The rest are passed over once the group is sent.
+fn skip_snoozed(chapters: &mut Vec<Chapter>, until: DateTime<Utc>, grouping_quantity: i64) {
+    let missed = chapters
+        .iter()
+        .filter(|chap| chap.published_at < until)
+        .count();
+    let mut skipped = missed.saturating_sub(grouping_quantity.max(1) as usize);
+    chapters.retain(|chap| {
+        if skipped > 0 && chap.published_at < until {
+            skipped -= 1;
+            return false;
+        }
+        true
+    });
+}
+
+#[tracing::instrument(
+name = "Delivering any unsent chapters",
+err,
+level = "info"
+skip(user_id_to_book_ids_to_chapters, pool, storage),
+)]
+async fn send_notifications(
+    user_id_to_book_ids_to_chapters: UnsentChapters,
+    pool: InstrumentedPgConnectionPool,
+    storage: &Storage,
+) -> Result<()> {
+    info!(
+        "Delivering unsent chapters to {} users.",
+        user_id_to_book_ids_to_chapters.len()
+    );
+
+    let user_ids = user_id_to_book_ids_to_chapters.keys().collect_vec();
+    let book_ids = user_id_to_book_ids_to_chapters
+        .values()
+        .flat_map(|x| x.keys().map(|(book_id, _group_size)| book_id))
+        .collect_vec();
+
+    let user_to_delivery_method: HashMap<String, DeliveryMethod> = {
+        let mut conn = pool.get().await?;
+        delivery_methods::table
+            .select(delivery_methods::all_columns)
+            .filter(delivery_methods::user_id.eq_any(&user_ids))
+            .load::<DeliveryMethod>(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|x| (x.user_id.clone(), x))
+            .collect()
+    };
+
+    let book_id_to_book: HashMap<Uuid, Book> = {
+        let mut conn = pool.get().await?;
+        books::table
+            .select(books::all_columns)
+            .filter(books::id.eq_any(book_ids))
+            .load::<Book>(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|x| (x.id, x))
+            .collect()
+    };
+
+    let user_book_to_subscription: HashMap<(String, Uuid), Subscription> = {
+        use crate::schema::subscriptions;
+        let mut conn = pool.get().await?;
+        subscriptions::table
+            .filter(subscriptions::user_id.eq_any(&user_ids))
+            .load::<Subscription>(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|sub| ((sub.user_id.clone(), sub.book_id), sub))
+            .collect()
+    };
+
+    let user_to_preferences: HashMap<String, UserPreferences> = {
+        use crate::schema::user_preferences;
+        let mut conn = pool.get().await?;
+        user_preferences::table
+            .filter(user_preferences::user_id.eq_any(&user_ids))
+            .load::<UserPreferences>(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|preferences| (preferences.user_id.clone(), preferences))
+            .collect()
+    };
+
+    let delivery_errors = deliver_new_chapters(
+        user_id_to_book_ids_to_chapters,
+        user_to_delivery_method,
+        book_id_to_book,
+        user_book_to_subscription,
+        user_to_preferences,
+        pool.clone(),
+        storage,
+    )
+    .await;
+
+    match delivery_errors.len() {
+        0 => Ok(()),
+        _len => Err(anyhow!("Failed to deliver some chapters to users"))
+            .with_context(|| format!("{delivery_errors:#?}")),
+    }
+}
+
+#[tracing::instrument(
+name = "Delivering some unsent chapters",
+level = "info"
+skip(pool, user_to_delivery_method, storage),
+)]
+async fn deliver_new_chapters(
+    user_id_to_book_ids_to_chapters: HashMap<String, HashMap<(Uuid, i64), Vec<Chapter>>>,
+    user_to_delivery_method: HashMap<String, DeliveryMethod>,
+    book_id_to_book: HashMap<Uuid, Book>,
+    user_book_to_subscription: HashMap<(String, Uuid), Subscription>,
+    user_to_preferences: HashMap<String, UserPreferences>,
+    pool: InstrumentedPgConnectionPool,
+    storage: &Storage,
+) -> Vec<Result<()>> {
+    let mut errors = Vec::new();
+    for (user_id, book_id_to_chapters) in user_id_to_book_ids_to_chapters {
+        let delivery_method = user_to_delivery_method.get(&user_id).unwrap();
+        let preferences = user_to_preferences.get(&user_id);
+        let templates = preferences
+            .map(UserPreferences::templates)
+            .unwrap_or_default()
+            .or(&config::get().templates);
+        // The chapters stay queued and go out on the first cycle after.
+        if preferences.is_some_and(|preferences| preferences.in_quiet_hours(Utc::now())) {
+            info!(user_id, "Holding deliveries during the user's quiet hours.");
+            continue;
+        }
+        // Groups announced together once all of the user's books are delivered.
+        let mut announcements: Vec<(&Book, Vec<Chapter>)> = Vec::new();
+        'books: for ((book_id, _grouping_quantity), chapters) in book_id_to_chapters {
+            let book = book_id_to_book.get(&book_id).unwrap();
+            let subscription = user_book_to_subscription.get(&(user_id.clone(), book_id));
+            let settings = DeliverySettings::resolve(subscription, preferences, delivery_method);
+            // Each chunk of `grouping_quantity` chapters is its own delivery. A
+            // failed chunk stops the book so later chunks aren't sent ahead of it.
+            for group in chapters.chunks(settings.grouping_quantity as usize) {
+                let chapter_bodies: HashMap<Uuid, ChapterBody> = {
+                    let mut conn = match pool.get().await {
+                        Ok(x) => x,
+                        Err(e) => {
+                            errors.push(report_delivery_error(
+                                &user_id,
+                                book,
+                                Err(e).with_context(|| {
+                                    format!(
+                                        "Failed to acquire a database connection
+                         while fetching bodies for book {}, chapters: [{}]",
+                                        book.name,
+                                        group.iter().map(|chap| &chap.name).join(", ")
+                                    )
+                                }),
+                            ));
+                            continue 'books;
+                        }
+                    };
+                    match chapter_bodies::table
+                        .filter(
+                            chapter_bodies::chapter_id
+                                .eq_any(group.iter().map(|x| x.id).collect_vec()),
+                        )
+                        .select(chapter_bodies::all_columns)
+                        .load::<ChapterBody>(&mut *conn)
+                        .await
+                    {
+                        Ok(x) => x.into_iter().map(|body| (body.chapter_id, body)).collect(),
+                        Err(e) => {
+                            errors.push(report_delivery_error(
+                                &user_id,
+                                book,
+                                Err(e).with_context(|| {
+                                    format!(
+                                        "Failed to fetch bodies for book {}, chapters: [{}]",
+                                        book.name,
+                                        group.iter().map(|chap| &chap.name).join(", ")
+                                    )
+                                }),
+                            ));
+                            continue 'books;
+                        }
+                    }
+                };
+
+                let chapters_with_body = pair_bodies(group, &chapter_bodies);
+                if chapters_with_body.len() < group.len() {
+                    if let Err(e) = mark_bodies_missing(&pool, group, &chapter_bodies).await {
+                        errors.push(report_delivery_error(
+                            &user_id,
+                            book,
+                            Err(e).with_context(|| {
+                                format!(
+                                    "Failed to mark chapters without a body for book {}",
+                                    book.name
+                                )
+                            }),
+                        ));
+                        continue 'books;
+                    }
+                }
+                let sent_chapters = chapters_with_body
+                    .iter()
+                    .map(|(chap, _body)| (*chap).clone())
+                    .collect_vec();
+                let chapters = sent_chapters.as_slice();
+                if chapters.is_empty() {
+                    if let Err(e) =
+                        update_subscription_last_chapter_id(pool.clone(), &user_id, group).await
+                    {
+                        errors.push(report_delivery_error(
+                            &user_id,
+                            book,
+                            Err(e).with_context(|| {
+                                format!(
+                                    "Failed to skip chapters without a body for user {user_id} for book {}",
+                                    book.name
+                                )
+                            }),
+                        ));
+                        continue 'books;
+                    }
+                    continue;
+                }
+                let notified =
+                    subscription.and_then(|subscription| subscription.pushover_notified_chapter_id);
+                // A group is only announced once, even if its ebook fails to
+                // send and the group is retried.
+                let announced = notified == chapters.last().map(|chap| chap.id);
+                let priority = subscription
+                    .map(Subscription::pushover_priority)
+                    .unwrap_or_default();
+                // Emergency announcements are sent alone, so their receipt is
+                // only for this book.
+                let emergency = matches!(priority, Priority::Emergency { .. });
+                let mut receipt = None;
+                if !announced && settings.combine_deliveries && !emergency {
+                    match announcements.last_mut() {
+                        Some((announced_book, announced)) if announced_book.id == book.id => {
+                            announced.extend_from_slice(chapters)
+                        }
+                        _ => announcements.push((book, chapters.to_vec())),
+                    }
+                } else if !announced {
+                    let sent = match send_pushover_if_enabled(
+                        delivery_method,
+                        &templates,
+                        book,
+                        chapters,
+                        priority,
+                    )
+                    .await
+                    {
+                        Ok(sent) => {
+                            receipt = sent;
+                            mark_pushover_notified(pool.clone(), &user_id, chapters).await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = sent {
+                        errors.push(report_delivery_error(&user_id, book, Err(e).with_context(|| {
+                            format!(
+                                "Failed to pushover notification to user {user_id} for book {}, chapters: [{}]",
+                                book.name,
+                                chapters.iter().map(|chap| &chap.name).join(", ")
+                            )
+                        })));
+                        continue 'books;
+                    }
+                }
+                let sent = match send_kindle_if_enabled(
+                    delivery_method,
+                    &templates,
+                    book,
+                    &chapters_with_body,
+                    subscription.map(Subscription::extras).unwrap_or_default(),
+                    &settings,
+                    storage,
+                )
+                .await
+                {
+                    Ok(sent) => sent,
+                    Err(e) => {
+                        // The announcement is withdrawn rather than repeating for
+                        // chapters which didn't arrive.
+                        if let Some(receipt) = &receipt {
+                            cancel_pushover_receipt(receipt).await;
+                        }
+                        errors.push(report_delivery_error(&user_id, book, Err(e).with_context(|| {
+                            format!(
+                                "Failed to send kindle emails for user {user_id} for book {}, chapters: [{}]",
+                                book.name,
+                                chapters.iter().map(|chap| &chap.name).join(", ")
+                            )
+                        })));
+                        continue 'books;
+                    }
+                };
+                match record_delivery(pool.clone(), &user_id, chapters, sent, None, receipt).await {
+                    Ok(delivery_id) => events::publish(
+                        &user_id,
+                        Event::DeliveryCompleted {
+                            delivery_id,
+                            book_id,
+                            chapter_ids: chapters.iter().map(|chap| chap.id).collect(),
+                        },
+                    ),
+                    Err(e) => errors.push(report_delivery_error(&user_id, book, Err(e).with_context(|| {
+                        format!(
+                            "Failed to record delivery for user {user_id} for book {}, chapters: [{}]",
+                            book.name,
+                            chapters.iter().map(|chap| &chap.name).join(", ")
+                        )
+                    }))),
+                };
+                // Past the whole group, so skipped chapters don't hold the book.
+                match update_subscription_last_chapter_id(pool.clone(), &user_id, group).await {
+                    Ok(()) => (),
+                    Err(e) => {
+                        errors.push(report_delivery_error(&user_id, book, Err(e).with_context(|| {
+                            format!(
+                                "Failed to update last_sent_chapter for user {user_id} for book {}, chapters: [{}]",
+                                book.name,
+                                chapters.iter().map(|chap| &chap.name).join(", ")
+                            )
+                        })));
+                        continue 'books;
+                    }
+                };
+            }
+        }
+        if !announcements.is_empty() && delivery_method.get_pushover_key().is_some() {
+            if let Err(e) = announce_books(&pool, delivery_method, &templates, &announcements).await
+            {
+                let books = announcements.iter().map(|(book, _)| &book.name).join(", ");
+                let e = e.context(format!(
+                    "Failed to pushover notification to user {user_id} for books: [{books}]"
+                ));
+                summary::record_delivery_failure();
+                sentry::capture(&e, &[("user_id", &user_id), ("books", &books)]);
+                errors.push(Err(e));
+            }
+        }
+    }
+    errors
+}
+
+/// Pairs each chapter with its stored body, in the chapters' order. Chapters
+/// without one are logged and skipped rather than shifting the pairing.
+fn pair_bodies<'a>(
+    chapters: &'a [Chapter],
+    bodies: &'a HashMap<Uuid, ChapterBody>,
+) -> Vec<(&'a Chapter, &'a ChapterBody)> {
+    chapters
+        .iter()
+        .filter_map(|chap| {
+            let body = bodies.get(&chap.id);
+            if body.is_none() {
+                warn!(
+                    chapter_id = %chap.id,
+                    chapter = chap.name,
+                    "Skipping a chapter with no stored body."
+                );
+            }
+            body.map(|body| (chap, body))
+        })
+        .collect()
+}
+
+/// Marks the chapters of `chapters` without a stored body as failed to fetch,
+/// so the check loop fetches their bodies again.
+async fn mark_bodies_missing(
+    pool: &InstrumentedPgConnectionPool,
+    chapters: &[Chapter],
+    bodies: &HashMap<Uuid, ChapterBody>,
+) -> Result<()> {
+    let missing = chapters
+        .iter()
+        .filter(|chap| !bodies.contains_key(&chap.id))
+        .map(|chap| chap.id)
+        .collect_vec();
+    let mut conn = pool.get().await?;
+    diesel::update(
+        chapters::table
+            .filter(chapters::id.eq_any(missing))
+            .filter(chapters::status.eq(ChapterStatus::Fetched)),
+    )
+    .set(chapters::status.eq(ChapterStatus::FetchFailed))
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Sends one pushover message covering every group in `announcements`, then
+/// marks each as announced.
+async fn announce_books(
+    pool: &InstrumentedPgConnectionPool,
+    delivery_method: &DeliveryMethod,
+    templates: &Templates,
+    announcements: &[(&Book, Vec<Chapter>)],
+) -> Result<()> {
+    match announcements {
+        [(book, chapters)] => {
+            send_pushover_if_enabled(delivery_method, templates, book, chapters, Priority::Normal)
+                .await?;
+        }
+        _ => send_combined_pushover_if_enabled(delivery_method, templates, announcements).await?,
+    }
+    for (_book, chapters) in announcements {
+        mark_pushover_notified(pool.clone(), &delivery_method.user_id, chapters).await?;
+    }
+    Ok(())
+}
+
+/// Reports a failed delivery to Sentry tagged with its user and book.
+fn report_delivery_error(user_id: &str, book: &Book, result: Result<()>) -> Result<()> {
+    if let Err(err) = &result {
+        summary::record_delivery_failure();
+        events::publish(
+            user_id,
+            Event::DeliveryFailed {
+                book_id: book.id,
+                book_name: book.name.clone(),
+                error: err.to_string(),
+            },
+        );
+        sentry::capture(
+            err,
+            &[
+                ("user_id", user_id),
+                ("book_id", &book.id.to_string()),
+                ("book", &book.name),
+            ],
+        );
+    }
+    result
+}
+
+async fn update_subscription_last_chapter_id(
+    pool: InstrumentedPgConnectionPool,
+    user_id_str: &str,
+    chapters: &[Chapter],
+) -> Result<()> {
+    use crate::schema::subscriptions::dsl::*;
+    let mut conn = pool.get().await?;
+    diesel::update(
+        subscriptions
+            .filter(user_id.eq(user_id_str))
+            .filter(book_id.eq(chapters[0].book_id)),
+    )
+    .set(last_chapter_id.eq(chapters[chapters.len() - 1].id))
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Records that the group ending in the last of `chapters` was announced over
+/// pushover.
+async fn mark_pushover_notified(
+    pool: InstrumentedPgConnectionPool,
+    user_id_str: &str,
+    chapters: &[Chapter],
+) -> Result<()> {
+    use crate::schema::subscriptions::dsl::*;
+    let mut conn = pool.get().await?;
+    diesel::update(
+        subscriptions
+            .filter(user_id.eq(user_id_str))
+            .filter(book_id.eq(chapters[0].book_id)),
+    )
+    .set(pushover_notified_chapter_id.eq(chapters[chapters.len() - 1].id))
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Sending pushover notification",
+    level = "info",
+    err,
+    skip(delivery_method)
+)]
+async fn send_pushover_if_enabled(
+    delivery_method: &DeliveryMethod,
+    templates: &Templates,
+    book: &Book,
+    chapters: &[Chapter],
+    priority: Priority,
+) -> Result<Option<String>> {
+    let Some(pushover_key) = delivery_method.get_pushover_key() else {
+        return Ok(None);
+    };
+    let message = templates.pushover(&notification(book, &chapters.iter().collect_vec()));
+    pushover::send_with_priority(pushover_key, &message, priority).await
+}
+
+#[tracing::instrument(
+    name = "Sending combined pushover notification",
+    level = "info",
+    err,
+    skip_all
+)]
+async fn send_combined_pushover_if_enabled(
+    delivery_method: &DeliveryMethod,
+    templates: &Templates,
+    announcements: &[(&Book, Vec<Chapter>)],
+) -> Result<()> {
+    if let Some(pushover_key) = delivery_method.get_pushover_key() {
+        let books = announcements.iter().map(|(book, _)| &book.name).join(", ");
+        let authors = announcements
+            .iter()
+            .map(|(book, _)| &book.author)
+            .unique()
+            .join(", ");
+        let message =
+            templates.pushover_combined(&combined_notification(&books, &authors, announcements));
+        pushover::send_message(pushover_key, &message).await?;
+    }
+    Ok(())
+}
+
+/// How often unacknowledged emergency announcements are checked.
+const RECEIPT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The deliveries whose emergency announcement may still be repeating, with
+/// its receipt, for every user or only `user_id`.
+async fn pending_receipts(
+    pool: &InstrumentedPgConnectionPool,
+    user_id: Option<&str>,
+) -> Result<Vec<(Uuid, String)>> {
+    let since = Utc::now() - chrono::Duration::seconds(pushover::MAX_EXPIRE_SECS.into());
+    let pending: Vec<(Uuid, Option<String>)> = retry_read(|| async {
+        let mut conn = pool.get().await?;
+        let mut query = deliveries::table
+            .filter(deliveries::pushover_receipt.is_not_null())
+            .filter(deliveries::pushover_acknowledged_at.is_null())
+            .filter(deliveries::pushover_cancelled_at.is_null())
+            .filter(deliveries::created_at.gt(since))
+            .select((deliveries::id, deliveries::pushover_receipt))
+            .into_boxed();
+        if let Some(user_id) = user_id {
+            query = query.filter(deliveries::user_id.eq(user_id));
+        }
+        Ok(query.load(&mut *conn).await?)
+    })
+    .await?;
+    Ok(pending
+        .into_iter()
+        .filter_map(|(id, receipt)| Some((id, receipt?)))
+        .collect())
+}
+
+/// Records when the user acknowledged each of the `pending` emergency
+/// announcements. Returns how many were newly acknowledged.
+#[tracing::instrument(name = "Checking pushover receipts", level = "info", err, skip_all)]
+async fn poll_pushover_receipts(
+    pool: &InstrumentedPgConnectionPool,
+    pending: Vec<(Uuid, String)>,
+) -> Result<usize> {
+    let mut acknowledged = 0;
+    for (delivery_id, receipt) in pending {
+        let status = match pushover::check_receipt(&receipt).await {
+            Ok(status) => status,
+            Err(err) => {
+                warn!(error = %error_chain(&err), %delivery_id, "Failed to check a pushover receipt.");
+                continue;
+            }
+        };
+        if !status.acknowledged {
+            continue;
+        }
+        let mut conn = pool.get().await?;
+        diesel::update(deliveries::table.find(delivery_id))
+            .set(
+                deliveries::pushover_acknowledged_at
+                    .eq(status.acknowledged_at.unwrap_or_else(Utc::now)),
+            )
+            .execute(&mut *conn)
+            .await?;
+        acknowledged += 1;
+    }
+    Ok(acknowledged)
+}
+
+/// Withdraws the user's emergency announcements which may still be
+/// repeating, as when the email they announced bounced. Returns how many were
+/// cancelled.
+pub(crate) async fn cancel_pushover_receipts(
+    pool: &InstrumentedPgConnectionPool,
+    user_id: &str,
+) -> Result<usize> {
+    let mut cancelled = 0;
+    for (delivery_id, receipt) in pending_receipts(pool, Some(user_id)).await? {
+        if !cancel_pushover_receipt(&receipt).await {
+            continue;
+        }
+        let mut conn = pool.get().await?;
+        diesel::update(deliveries::table.find(delivery_id))
+            .set(deliveries::pushover_cancelled_at.eq(Utc::now()))
+            .execute(&mut *conn)
+            .await?;
+        cancelled += 1;
+    }
+    Ok(cancelled)
+}
+
+/// Stops an emergency announcement repeating. A failure is only logged, as
+/// the announcement expires on its own.
+async fn cancel_pushover_receipt(receipt: &str) -> bool {
+    match pushover::cancel_receipt(receipt).await {
+        Ok(()) => true,
+        Err(err) => {
+            warn!(error = %error_chain(&err), "Failed to cancel a pushover notification.");
+            false
+        }
+    }
+}
+
+/// What a notification of `chapters` of `book` says, for its template.
+fn notification<'a>(book: &'a Book, chapters: &[&'a Chapter]) -> Message<'a> {
+    let first = chapters.first().map_or("", |chap| chap.name.as_str());
+    let last = chapters.last().map_or("", |chap| chap.name.as_str());
+    Message {
+        book: &book.name,
+        author: &book.author,
+        chapters: match chapters.len() {
+            1 => first.to_owned(),
+            _ => format!("{first} through {last}"),
+        },
+        count: chapters.len(),
+        first,
+        last,
+        words: reading_estimate(chapters),
+    }
+}
+
+/// Summarizes the groups for one announcement, with `{chapters}` such as
+/// "Pale ×2, Mother of Learning ×3".
+fn combined_notification<'a>(
+    books: &'a str,
+    authors: &'a str,
+    announcements: &'a [(&Book, Vec<Chapter>)],
+) -> Message<'a> {
+    let chapters = announcements
+        .iter()
+        .flat_map(|(_book, chapters)| chapters)
+        .collect_vec();
+    Message {
+        book: books,
+        author: authors,
+        chapters: announcements
+            .iter()
+            .map(|(book, chapters)| format!("{} ×{}", book.name, chapters.len()))
+            .join(", "),
+        count: chapters.len(),
+        first: chapters
+            .as_slice()
+            .first()
+            .map_or("", |chap| chap.name.as_str()),
+        last: chapters.last().map_or("", |chap| chap.name.as_str()),
+        words: reading_estimate(&chapters),
+    }
+}
+
+/// What `send_kindle_if_enabled` did with a group of chapters.
+struct KindleDelivery {
+    /// The converted ebook, if it was stored.
+    artifact: Option<(StorageLocation, i64)>,
+    /// Whether the chapters failed to convert and were sent inline instead.
+    degraded: bool,
+}
+
+/// Attempts at converting a group of chapters before giving up on the ebook.
+const CONVERSION_ATTEMPTS: usize = 2;
+
+#[tracing::instrument(
+    name = "Sending kindle mobi file notification",
+    level = "info",
+    err,
+    skip(delivery_method, storage)
+)]
+async fn send_kindle_if_enabled(
+    delivery_method: &DeliveryMethod,
+    templates: &Templates,
+    book: &Book,
+    chapters: &[(&Chapter, &ChapterBody)],
+    extras: ExtrasPreference,
+    settings: &DeliverySettings,
+    storage: &Storage,
+) -> Result<KindleDelivery> {
+    let just_chapters = chapters.iter().map(|(c, _b)| *c).collect_vec();
+    // Amazon converts the chapters itself, so calibre isn't run at all.
+    if delivery_method.kindle_conversion_mode == KindleConversionMode::Amazon {
+        if let Some(kindle_email) = delivery_method.get_kindle_email() {
+            let html = inline_html(chapters, storage).await?;
+            send_for_amazon_conversion(
+                kindle_email,
+                &delivery_method.user_id,
+                book,
+                &just_chapters,
+                &html,
+            )
+            .await?;
+        }
+        return Ok(KindleDelivery {
+            artifact: None,
+            degraded: false,
+        });
+    }
+    if settings.format == DeliveryFormat::Html {
+        if let Some(kindle_email) = delivery_method.get_kindle_email() {
+            let html = inline_html(chapters, storage).await?;
+            send_inline(
+                kindle_email,
+                templates,
+                &delivery_method.user_id,
+                book,
+                &just_chapters,
+                &html,
+            )
+            .await?;
+        }
+        return Ok(KindleDelivery {
+            artifact: None,
+            degraded: false,
+        });
+    }
+    let mut attempt = 1;
+    let mobi_bytes = loop {
+        match generate_ebook(book, chapters, extras, &settings.output_profile, storage).await {
+            Ok(bytes) => break bytes,
+            Err(err) if attempt < CONVERSION_ATTEMPTS => {
+                warn!(error = %error_chain(&err), attempt, "Failed to convert the ebook, retrying.");
+                attempt += 1;
+            }
+            Err(err) if delivery_method.kindle_inline_fallback => {
+                error!(
+                    error = %error_chain(&err),
+                    trace_id = current_trace_id(),
+                    "Failed to convert the ebook, sending the chapters inline."
+                );
+                if let Some(kindle_email) = delivery_method.get_kindle_email() {
+                    let html = format!(
+                        "<p>These chapters could not be converted to an ebook, so they are included below.</p>{}",
+                        inline_html(chapters, storage).await?
+                    );
+                    send_inline(
+                        kindle_email,
+                        templates,
+                        &delivery_method.user_id,
+                        book,
+                        &just_chapters,
+                        &html,
+                    )
+                    .await?;
+                }
+                return Ok(KindleDelivery {
+                    artifact: None,
+                    degraded: true,
+                });
+            }
+            Err(err) => return Err(err),
+        }
+    };
+    if let Some(kindle_email) = delivery_method.get_kindle_email() {
+        check_email_size(mobi_bytes.len(), KindleConversionMode::Cereal)?;
+        send_kindle(
+            kindle_email,
+            templates,
+            &delivery_method.user_id,
+            book,
+            &just_chapters,
+            &mobi_bytes,
+        )
+        .await?;
+    }
+    // Keep the converted ebook so downloads don't have to run calibre again.
+    let artifact = storage
+        .store_artifact(&book.id, ByteStream::from(mobi_bytes.clone()))
+        .await
+        .map(|uploaded| (uploaded.location, uploaded.size))
+        .map_err(|err| {
+            error!(
+                error = %error_chain(&err),
+                trace_id = current_trace_id(),
+                "Failed to store the converted ebook."
+            )
+        })
+        .ok();
+    Ok(KindleDelivery {
+        artifact,
+        degraded: false,
+    })
+}
+
+/// The chapters' sanitized bodies as an email body.
+async fn inline_html(chapters: &[(&Chapter, &ChapterBody)], storage: &Storage) -> Result<String> {
+    let mut html = String::new();
+    for (chapter, body) in chapters {
+        let location = StorageLocation {
+            bucket: body.bucket.clone(),
+            key: body.key.clone(),
+        };
+        let body = storage.fetch(location).await?;
+        html.push_str(&format!(
+            "<h1>{}</h1>{}",
+            ammonia::clean_text(&chapter.name),
+            ammonia::clean(&String::from_utf8_lossy(&body))
+        ));
+    }
+    Ok(html)
+}
+
+/// Fetches the stored bodies of the chapters and converts them into a single epub.
+#[tracing::instrument(name = "Generating ebook", level = "info", err, skip(storage))]
+pub(crate) async fn generate_ebook(
+    book: &Book,
+    chapters: &[(&Chapter, &ChapterBody)],
+    extras: ExtrasPreference,
+    output_profile: &str,
+    storage: &Storage,
+) -> Result<Vec<u8>> {
+    // Stream each body straight into calibre's input file rather than
+    // holding every chapter in memory.
+    let in_path = calibre::temp_path("html");
+    if let Err(err) = write_chapter_bodies(&in_path, book, chapters, extras, storage).await {
+        let _ = tokio::fs::remove_file(&in_path).await;
+        return Err(err);
+    }
+    let chapters = chapters.iter().map(|(chap, _)| *chap).collect_vec();
+    let cover_title = ebook_title(book, &chapters);
+    calibre::convert_file(
+        &in_path,
+        &cover_title,
+        &book.name,
+        &book.author,
+        output_profile,
+    )
+    .await
+}
+
+/// The title of an ebook holding `chapters`, in publication order.
+pub(crate) fn ebook_title(book: &Book, chapters: &[&Chapter]) -> String {
+    let names = chapters.iter().map(|chap| chap.name.as_str()).collect_vec();
+    cover_title(&book.name, &names)
+}
+
+/// The title of an ebook holding the chapters named `chapter_names`.
+pub(crate) fn cover_title(book_name: &str, chapter_names: &[&str]) -> String {
+    match chapter_names {
+        [] => book_name.to_owned(),
+        [chapter] => format!("{}: {}", book_name, chapter),
+        [first, .., last] => format!("{}: {} through {}", book_name, first, last),
+    }
+}
+
+async fn write_chapter_bodies(
+    path: &str,
+    book: &Book,
+    chapters: &[(&Chapter, &ChapterBody)],
+    extras: ExtrasPreference,
+    storage: &Storage,
+) -> Result<()> {
+    let mut input = BufWriter::new(File::create(path).await?);
+    for (chap, body) in chapters {
+        let location = StorageLocation {
+            bucket: body.bucket.clone(),
+            key: body.key.clone(),
+        };
+        storage.fetch_into(location, &mut input).await?;
+        if !extras.wants_any() {
+            continue;
+        }
+        if let Some(location) = storage.chapter_extras(&book.id, &chap.id).await? {
+            let stored: ChapterExtras = serde_json::from_slice(&storage.fetch(location).await?)?;
+            input
+                .write_all(render_extras(&stored, extras).as_bytes())
+                .await?;
+        }
+    }
+    input.flush().await?;
+    Ok(())
+}
+
+/// The extras a subscriber asked for, each in its own section after the
+/// chapter's text.
+fn render_extras(extras: &ChapterExtras, preference: ExtrasPreference) -> String {
+    let notes = match preference.author_notes {
+        AuthorNotes::Omit => vec![],
+        AuthorNotes::After => vec![("Author's note", &extras.note_after)],
+        AuthorNotes::All => vec![
+            ("Author's note before the chapter", &extras.note_before),
+            ("Author's note after the chapter", &extras.note_after),
+        ],
+    };
+    let mut html = String::new();
+    for (heading, note) in notes {
+        if let Some(note) = note {
+            html.push_str(&format!(
+                "<hr/><div class=\"author-note\"><h3>{}</h3>{}</div>",
+                heading, note
+            ));
+        }
+    }
+    if let Some(count) = extras.comment_count.filter(|_| preference.comment_count) {
+        html.push_str(&format!(
+            "<hr/><p class=\"comment-count\">{} {} when this chapter was fetched.</p>",
+            count,
+            if count == 1 { "comment" } else { "comments" }
+        ));
+    }
+    html
+}
+
+/// Records that `chapters` were sent, returning the delivery's id.
+async fn record_delivery(
+    pool: InstrumentedPgConnectionPool,
+    user_id: &str,
+    chapters: &[Chapter],
+    sent: KindleDelivery,
+    redelivery_of: Option<Uuid>,
+    pushover_receipt: Option<String>,
+) -> Result<Uuid> {
+    let (location, size) = sent.artifact.unzip();
+    let mut conn = pool.get().await?;
+    let id = diesel::insert_into(deliveries::table)
+        .values(NewDelivery {
+            user_id: user_id.into(),
+            book_id: chapters[0].book_id,
+            chapter_ids: chapters.iter().map(|chap| chap.id).collect(),
+            artifact_format: location.as_ref().map(|_| "epub".into()),
+            artifact_bucket: location.as_ref().map(|x| x.bucket.clone()),
+            artifact_key: location.map(|x| x.key),
+            artifact_size: size,
+            dry_run: config::get().dry_run,
+            degraded: sent.degraded,
+            redelivery_of,
+            pushover_receipt,
+        })
+        .returning(deliveries::id)
+        .get_result(&mut *conn)
+        .await?;
+    Ok(id)
+}
+
+#[tracing::instrument(
+    name = "Removing expired delivery artifacts",
+    level = "info",
+    err,
+    skip(pool, storage)
+)]
+async fn purge_expired_artifacts(
+    pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+) -> Result<()> {
+    let expired: Vec<Delivery> = {
+        let mut conn = pool.get().await?;
+        deliveries::table
+            .filter(deliveries::artifact_key.is_not_null())
+            .filter(deliveries::created_at.lt(Utc::now() - storage.artifact_retention))
+            .limit(100)
+            .load(&mut *conn)
+            .await?
+    };
+    for delivery in expired {
+        if let Some(location) = delivery.artifact_location() {
+            storage.delete(&location).await?;
+        }
+        let mut conn = pool.get().await?;
+        diesel::update(deliveries::table.find(delivery.id))
+            .set((
+                deliveries::artifact_bucket.eq(None::<String>),
+                deliveries::artifact_key.eq(None::<String>),
+                deliveries::artifact_size.eq(None::<i64>),
+                deliveries::artifact_format.eq(None::<String>),
+            ))
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    // Conversions made on request aren't recorded in the database, so their
+    // age is read from storage.
+    let cutoff = Utc::now() - storage.artifact_retention;
+    for object in storage.list(CONVERSIONS_PREFIX).await? {
+        if object
+            .last_modified
+            .is_some_and(|modified| modified < cutoff)
+        {
+            storage.delete(&storage.location(object.key)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// How long responses kept after failing to parse are kept for.
+const DEBUG_SNAPSHOT_RETENTION_DAYS: i64 = 7;
+
+/// Removes debug snapshots older than their retention. They are listed even
+/// while snapshots are off, so those kept before they were turned off expire.
+#[tracing::instrument(
+    name = "Purging expired debug snapshots",
+    level = "info",
+    err,
+    skip(storage)
+)]
+async fn purge_debug_snapshots(storage: &Storage) -> Result<()> {
+    let cutoff = Utc::now() - chrono::Duration::days(DEBUG_SNAPSHOT_RETENTION_DAYS);
+    for object in storage.list(&format!("{}/", DEBUG_PREFIX)).await? {
+        if object
+            .last_modified
+            .is_some_and(|modified| modified < cutoff)
+        {
+            storage.delete(&storage.location(object.key)).await?;
+        }
+    }
+    Ok(())
+}
+
+define_sql_function!(fn greatest(a: Nullable<Timestamptz>, b: Nullable<Timestamptz>) -> Nullable<Timestamptz>);
+
+/// How often the denormalized book chapter counts are checked against the chapters.
+const BOOK_COUNTS_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Recomputes each book's chapter count and latest publish time, correcting
+/// and reporting any book whose stored values had drifted.
+#[tracing::instrument(name = "Checking book chapter counts", level = "info", err, skip(pool))]
+async fn check_book_counts(pool: &InstrumentedPgConnectionPool) -> Result<()> {
+    let mut conn = pool.get().await?;
+    let corrected = sql_query(
+        "update books set chapter_count = counts.chapter_count,
+            latest_chapter_published_at = counts.latest_chapter_published_at
+        from (
+            select books.id, count(chapters.book_id) as chapter_count,
+                max(chapters.published_at) as latest_chapter_published_at
+            from books
+            left join (
+                select book_id, published_at from chapters where deleted_at is null
+                union all
+                select book_id, published_at from chapters_archive
+            ) as chapters on chapters.book_id = books.id
+            group by books.id
+        ) as counts
+        where books.id = counts.id
+        and (books.chapter_count, books.latest_chapter_published_at)
+            is distinct from (counts.chapter_count, counts.latest_chapter_published_at)",
+    )
+    .execute(&mut *conn)
+    .await?;
+    if corrected > 0 {
+        warn!("Corrected drifted chapter counts on {} books.", corrected);
+    }
+    Ok(())
+}
+
+/// How often books with stale metadata are read again from their sites.
+const METADATA_REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How old a book's metadata gets before it is read again.
+const METADATA_MAX_AGE_DAYS: i64 = 7;
+
+/// The most books refreshed per cycle, so a backlog is worked through over
+/// several days rather than in a burst of requests to one site.
+const METADATA_REFRESH_BATCH: i64 = 50;
+
+/// Reads the name, author, tags and content warnings of books with stale
+/// metadata from their sites again, recording any change of name or author so
+/// deliveries under a new title can be traced back to it. Kinds with fixed metadata are only marked as refreshed.
+#[tracing::instrument(
+    name = "Refreshing book metadata",
+    level = "info",
+    err,
+    skip(pool, endpoints)
+)]
+pub(crate) async fn refresh_book_metadata(
+    pool: &InstrumentedPgConnectionPool,
+    endpoints: &Endpoints,
+) -> Result<()> {
+    let cutoff = Utc::now() - chrono::Duration::days(METADATA_MAX_AGE_DAYS);
+    let mut conn = pool.get().await?;
+    let stale: Vec<Book> = books::table
+        .filter(book_not_deleted())
+        .filter(
+            books::metadata_refreshed_at
+                .is_null()
+                .or(books::metadata_refreshed_at.lt(cutoff)),
+        )
+        .order(books::metadata_refreshed_at.asc().nulls_first())
+        .limit(METADATA_REFRESH_BATCH)
+        .load(&mut *conn)
+        .await?;
+    drop(conn);
+
+    for book in stale {
+        let refreshed = if book.metadata.has_remote_metadata() {
+            book.metadata.to_new_book(endpoints).await
+        } else {
+            Ok(NewBook {
+                name: book.name.clone(),
+                author: book.author.clone(),
+                metadata: book.metadata.clone(),
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+        };
+        // Books whose site couldn't be read are still marked as refreshed, so
+        // they are retried after the usual wait instead of heading every batch.
+        let refreshed = refreshed
+            .inspect_err(|err| {
+                warn!(
+                    book_id = %book.id,
+                    error = %error_chain(err),
+                    "Failed to refresh a book's metadata."
+                )
+            })
+            .ok();
+        let renamed = refreshed
+            .as_ref()
+            .filter(|new| new.name != book.name || new.author != book.author);
+        let mut conn = pool.get().await?;
+        conn.transaction::<_, anyhow::Error, _>(async |conn| {
+            let update = diesel::update(books::table.find(book.id));
+            match &refreshed {
+                None => {
+                    update
+                        .set(books::metadata_refreshed_at.eq(Utc::now()))
+                        .execute(&mut *conn)
+                        .await?
+                }
+                Some(new) => {
+                    update
+                        .set((
+                            books::name.eq(&new.name),
+                            books::author.eq(&new.author),
+                            books::tags.eq(&new.tags),
+                            books::content_warnings.eq(&new.content_warnings),
+                            books::metadata_refreshed_at.eq(Utc::now()),
+                        ))
+                        .execute(&mut *conn)
+                        .await?
+                }
+            };
+            if let Some(new) = renamed {
+                diesel::insert_into(book_metadata_changes::table)
+                    .values((
+                        book_metadata_changes::book_id.eq(book.id),
+                        book_metadata_changes::old_name.eq(&book.name),
+                        book_metadata_changes::new_name.eq(&new.name),
+                        book_metadata_changes::old_author.eq(&book.author),
+                        book_metadata_changes::new_author.eq(&new.author),
+                    ))
+                    .execute(&mut *conn)
+                    .await?;
+            }
+            Ok(())
+        })
+        .await?;
+        if let Some(new) = renamed {
+            info!(
+                book_id = %book.id,
+                old_name = %book.name,
+                new_name = %new.name,
+                old_author = %book.author,
+                new_author = %new.author,
+                "A book's metadata changed."
+            );
+        }
+    }
+    Ok(())
+}
+
+/// How often chapters are checked for archiving.
+const ARCHIVE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The most chapters archived in one statement, so the first run over years
+/// of chapters doesn't hold locks on all of them at once.
+const ARCHIVE_BATCH: i64 = 1000;
+
+/// Moves chapters published before `cutoff` into `chapters_archive`, keeping
+/// where their bodies are stored, once every subscriber to their book has
+/// been sent a chapter published after them. Chapters still queued or
+/// referenced by a subscription stay, as do bodies under the old flat keys,
+/// which are archived once moved under their book's prefix.
+#[tracing::instrument(name = "Archiving old chapters", level = "info", err, skip(pool))]
+pub(crate) async fn archive_chapters(
+    pool: &InstrumentedPgConnectionPool,
+    cutoff: DateTime<Utc>,
+) -> Result<usize> {
+    let mut archived = 0;
+    loop {
+        let mut conn = pool.get().await?;
+        // Every part of the statement reads from the same snapshot, so bodies
+        // are copied before the delete cascades to them.
+        let moved = sql_query(
+            "with archived as (
+                delete from chapters
+                where id in (
+                    select chapters.id from chapters
+                    join books on books.id = chapters.book_id and books.deleted_at is null
+                    where chapters.published_at < $1
+                    and chapters.deleted_at is null
+                    and chapters.status = 'fetched'
+                    and not exists (select 1 from unsent_chapters
+                        where unsent_chapters.chapter_id = chapters.id)
+                    and not exists (select 1 from chapter_bodies
+                        where chapter_bodies.chapter_id = chapters.id
+                        and chapter_bodies.key not like $3)
+                    and not exists (
+                        select 1 from subscriptions
+                        left join chapters as last_sent
+                            on last_sent.id = subscriptions.last_chapter_id
+                        where subscriptions.book_id = chapters.book_id
+                        and (last_sent.id is null
+                            or (coalesce(last_sent.ordinal, -1), last_sent.published_at)
+                                <= (coalesce(chapters.ordinal, -1), chapters.published_at)
+                            or subscriptions.pushover_notified_chapter_id = chapters.id))
+                    limit $2
+                )
+                returning *
+            )
+            insert into chapters_archive (id, name, author, created_at, updated_at, book_id,
+                published_at, metadata, status, word_count, ordinal, body_bucket, body_key)
+            select archived.id, archived.name, archived.author, archived.created_at,
+                archived.updated_at, archived.book_id, archived.published_at, archived.metadata,
+                archived.status, archived.word_count, archived.ordinal,
+                chapter_bodies.bucket, chapter_bodies.key
+            from archived
+            left join chapter_bodies on chapter_bodies.chapter_id = archived.id",
+        )
+        .bind::<Timestamptz, _>(cutoff)
+        .bind::<BigInt, _>(ARCHIVE_BATCH)
+        .bind::<Text, _>(format!("{}/%", storage::BODIES_PREFIX))
+        .execute(&mut *conn)
+        .await?;
+        archived += moved;
+        if (moved as i64) < ARCHIVE_BATCH {
+            break;
+        }
+    }
+    if archived > 0 {
+        info!(
+            "Archived {} chapters published before {}.",
+            archived, cutoff
+        );
+    }
+    Ok(archived)
+}
+
+/// Moves the archived chapters of `book_ids` back into `chapters` with their
+/// bodies, for work which compares against every chapter of a book. They are
+/// archived again by the next run.
+pub(crate) async fn unarchive_chapters(
+    pool: &InstrumentedPgConnectionPool,
+    book_ids: &[Uuid],
+) -> Result<()> {
+    let mut conn = pool.get().await?;
+    sql_query(
+        "with restored as (
+            delete from chapters_archive where book_id = any($1) returning *
+        ), chapters as (
+            insert into chapters (id, name, author, created_at, updated_at, book_id,
+                published_at, metadata, status, word_count, ordinal)
+            select id, name, author, created_at, updated_at, book_id,
+                published_at, metadata, status, word_count, ordinal
+            from restored
+            returning id
+        )
+        insert into chapter_bodies (key, bucket, chapter_id)
+        select restored.body_key, restored.body_bucket, restored.id
+        from restored
+        join chapters on chapters.id = restored.id
+        where restored.body_key is not null",
+    )
+    .bind::<Array<diesel::sql_types::Uuid>, _>(book_ids)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// How long soft-deleted books and chapters are kept before being purged.
+const DELETION_GRACE_DAYS: i64 = 30;
+
+/// Removes books and chapters deleted longer ago than the grace period,
+/// along with their stored objects.
+#[tracing::instrument(
+    name = "Purging deleted books and chapters",
+    level = "info",
+    err,
+    skip(pool, storage)
+)]
+async fn purge_deleted(pool: &InstrumentedPgConnectionPool, storage: &Storage) -> Result<()> {
+    let cutoff = Utc::now() - chrono::Duration::days(DELETION_GRACE_DAYS);
+
+    let bodies: Vec<ChapterBody> = {
+        let mut conn = pool.get().await?;
+        chapter_bodies::table
+            .inner_join(chapters::table)
+            .filter(chapters::deleted_at.lt(cutoff))
+            .select(chapter_bodies::all_columns)
+            .load(&mut *conn)
+            .await?
+    };
+    for body in bodies {
+        storage.delete(&body.into()).await?;
+    }
+    {
+        let mut conn = pool.get().await?;
+        diesel::delete(chapters::table.filter(chapters::deleted_at.lt(cutoff)))
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    let expired_books: Vec<Book> = {
+        let mut conn = pool.get().await?;
+        books::table
+            .filter(books::deleted_at.lt(cutoff))
+            .load(&mut *conn)
+            .await?
+    };
+    for book in expired_books {
+        // Bodies stored before keys were prefixed by book aren't found by listing.
+        let bodies: Vec<ChapterBody> = {
+            let mut conn = pool.get().await?;
+            chapter_bodies::table
+                .inner_join(chapters::table)
+                .filter(chapters::book_id.eq(book.id))
+                .select(chapter_bodies::all_columns)
+                .load(&mut *conn)
+                .await?
+        };
+        for body in bodies {
+            storage.delete(&body.into()).await?;
+        }
+        for prefix in [
+            storage::BODIES_PREFIX,
+            storage::SOURCES_PREFIX,
+            storage::COVERS_PREFIX,
+            storage::ARTIFACTS_PREFIX,
+        ] {
+            for object in storage.list(&format!("{}/{}/", prefix, book.id)).await? {
+                storage.delete(&storage.location(object.key)).await?;
+            }
+        }
+        let mut conn = pool.get().await?;
+        diesel::delete(books::table.find(book.id))
+            .execute(&mut *conn)
+            .await?;
+        info!("Purged deleted book {}.", book.name);
+    }
+    Ok(())
+}
+
+/// Moves objects stored under the old flat random keys to their book and
+/// chapter prefixed keys, a batch at a time. The row is updated before the
+/// old object is deleted so a failure part way never loses a body.
+#[tracing::instrument(
+    name = "Moving objects to prefixed storage keys",
+    level = "info",
+    err,
+    skip(pool, storage)
+)]
+async fn migrate_storage_keys(
+    pool: &InstrumentedPgConnectionPool,
+    storage: &Storage,
+) -> Result<()> {
+    let bodies: Vec<(ChapterBody, Uuid)> = {
+        let mut conn = pool.get().await?;
+        chapter_bodies::table
+            .inner_join(chapters::table)
+            .filter(chapter_bodies::key.not_like(format!("{}/%", storage::BODIES_PREFIX)))
+            .select((chapter_bodies::all_columns, chapters::book_id))
+            .limit(100)
+            .load(&mut *conn)
+            .await?
+    };
+    for (body, book_id) in bodies {
+        let old = StorageLocation::from(body.clone());
+        let new = storage
+            .copy(&old, storage::chapter_body_key(&book_id, &body.chapter_id))
+            .await?;
+        {
+            let mut conn = pool.get().await?;
+            diesel::update(chapter_bodies::table.find(body.chapter_id))
+                .set((
+                    chapter_bodies::bucket.eq(&new.bucket),
+                    chapter_bodies::key.eq(&new.key),
+                ))
+                .execute(&mut *conn)
+                .await?;
+        }
+        storage.delete(&old).await?;
+    }
+
+    let artifacts: Vec<Delivery> = {
+        let mut conn = pool.get().await?;
+        deliveries::table
+            .filter(deliveries::artifact_key.not_like(format!("{}/%", storage::ARTIFACTS_PREFIX)))
+            .limit(100)
+            .load(&mut *conn)
+            .await?
+    };
+    for delivery in artifacts {
+        let (Some(old), Some(book_id)) = (delivery.artifact_location(), delivery.book_id) else {
+            continue;
+        };
+        let new = storage
+            .copy(&old, storage::artifact_key(&book_id, &delivery.id))
+            .await?;
+        {
+            let mut conn = pool.get().await?;
+            diesel::update(deliveries::table.find(delivery.id))
+                .set((
+                    deliveries::artifact_bucket.eq(&new.bucket),
+                    deliveries::artifact_key.eq(&new.key),
+                ))
+                .execute(&mut *conn)
+                .await?;
+        }
+        storage.delete(&old).await?;
+    }
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Sending kindle mobi file email with mailgun",
+    level = "info",
+    err,
+    skip(bytes, kindle_email)
+)]
+async fn send_kindle(
+    kindle_email: &str,
+    templates: &Templates,
+    user_id: &str,
+    book: &Book,
+    chapters: &[&Chapter],
+    bytes: &[u8],
+) -> Result<(), Error> {
+    let title = delivery_title(book, chapters);
+    let subject = templates.kindle_subject(&notification(book, chapters));
+    let message = mailgun::epub_message(bytes, kindle_email, &title, &subject);
+    mailgun::send_message(with_unsubscribe(message, user_id, book)).await?;
+    Ok(())
+}
+
+/// The chapters' names, or the book's if there are none.
+fn delivery_title(book: &Book, chapters: &[&Chapter]) -> String {
+    match chapters {
+        [chapter] => chapter.name.clone(),
+        [first, .., last] => format!("{} through {}", first.name, last.name),
+        [] => book.name.clone(),
+    }
+}
+
+/// Sends the chapters as an HTML document with the subject Amazon converts
+/// attachments for. The subject can't carry the title, so the file name does.
+async fn send_for_amazon_conversion(
+    kindle_email: &str,
+    user_id: &str,
+    book: &Book,
+    chapters: &[&Chapter],
+    html: &str,
+) -> Result<(), Error> {
+    let title = format!("{} - {}", book.name, delivery_title(book, chapters));
+    let document = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{}</title></head><body>{}</body></html>",
+        ammonia::clean_text(&title),
+        html
+    );
+    check_email_size(document.len(), KindleConversionMode::Amazon)?;
+    let message = mailgun::conversion_message(&document, kindle_email, &title);
+    mailgun::send_message(with_unsubscribe(message, user_id, book)).await
+}
+
+/// Refuses an attachment too large to email, explaining the limits of the
+/// conversion mode it was made for.
+fn check_email_size(bytes: usize, mode: KindleConversionMode) -> Result<()> {
+    if bytes <= mailgun::MAX_MESSAGE_BYTES {
+        return Ok(());
+    }
+    match mode {
+        KindleConversionMode::Cereal => bail!(
+            "The ebook is {} bytes, over the {} bytes an email can carry. \
+             Deliver fewer chapters at a time.",
+            bytes,
+            mailgun::MAX_MESSAGE_BYTES
+        ),
+        KindleConversionMode::Amazon => bail!(
+            "The chapters are {} bytes of HTML, over the {} bytes an email can carry, \
+             though Amazon converts emails of up to {} bytes. HTML isn't compressed \
+             like cereal's epubs, so deliver fewer chapters at a time or have cereal \
+             convert them.",
+            bytes,
+            mailgun::MAX_MESSAGE_BYTES,
+            mailgun::AMAZON_MAX_MESSAGE_BYTES
+        ),
+    }
+}
+
+/// Sends the chapters in the body of an email, for users who want html or
+/// when they couldn't be converted to an ebook.
+async fn send_inline(
+    kindle_email: &str,
+    templates: &Templates,
+    user_id: &str,
+    book: &Book,
+    chapters: &[&Chapter],
+    html: &str,
+) -> Result<(), Error> {
+    let subject = templates.kindle_subject(&notification(book, chapters));
+    let message = mailgun::Message::new(kindle_email, &subject, None, Some(html), None);
+    mailgun::send_message(with_unsubscribe(message, user_id, book)).await
+}
+
+/// Links `message` to unsubscribing from `book`, when links are configured.
+fn with_unsubscribe(message: mailgun::Message, user_id: &str, book: &Book) -> mailgun::Message {
+    match &config::get().unsubscribe {
+        Some(config) => {
+            let link = unsubscribe::link(config, user_id, book.id, Utc::now());
+            message.with_unsubscribe(&book.name, &link)
+        }
+        None => message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+    use wiremock::matchers::{method, path, path_regex};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    use crate::models::{ArchivedChapter, NewBook};
+    use crate::providers::royalroad::RoyalRoadBookKind;
+    use crate::schema::{subscriptions, user_preferences};
+    use crate::test_support::{self, TestDatabase};
+
+    const USER_ID: &str = "reader";
+
+    #[test]
+    fn skips_cycles_on_transient_database_errors() {
+        use diesel::result::{DatabaseErrorKind, Error as DieselError};
+        let lost: Error = DieselError::DatabaseError(
+            DatabaseErrorKind::ClosedConnection,
+            Box::new("server closed the connection".to_owned()),
+        )
+        .into();
+        assert!(log_task_error(&lost.context("Loading books."), "Failed.").is_break());
+        assert!(log_task_error(&ApiError::PoolExhausted.into(), "Failed.").is_break());
+        assert!(log_task_error(&anyhow!("Parsing failed."), "Failed.").is_continue());
+    }
+
+    #[test]
+    fn rejects_bodies_over_the_size_limit() {
+        let limits = BodyLimits { warn: 10, max: 20 };
+        check_body_size(5, &limits).unwrap();
+        check_body_size(20, &limits).unwrap();
+        let err = check_body_size(21, &limits).unwrap_err();
+        assert!(err.to_string().contains("too large"));
+    }
+
+    async fn insert_book(pool: &InstrumentedPgConnectionPool, metadata: BookKind) -> Book {
+        let mut conn = pool.get().await.unwrap();
+        diesel::insert_into(books::table)
+            .values(NewBook {
+                name: "Pale".into(),
+                author: "Wildbow".into(),
+                metadata,
+                tags: Vec::new(),
+                content_warnings: Vec::new(),
+            })
+            .get_result(&mut *conn)
+            .await
+            .unwrap()
+    }
+
+    /// Subscribes a user with a verified pushover key to `book`.
+    async fn subscribe(pool: &InstrumentedPgConnectionPool, book: &Book, grouping_quantity: i64) {
+        let mut conn = pool.get().await.unwrap();
+        diesel::insert_into(delivery_methods::table)
+            .values((
+                delivery_methods::user_id.eq(USER_ID),
+                delivery_methods::pushover_key.eq("pushover-key"),
+                delivery_methods::pushover_key_verified.eq(true),
+                delivery_methods::pushover_enabled.eq(true),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        diesel::insert_into(subscriptions::table)
+            .values((
+                subscriptions::user_id.eq(USER_ID),
+                subscriptions::book_id.eq(book.id),
+                subscriptions::grouping_quantity.eq(grouping_quantity),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+    }
+
+    /// Inserts a chapter after the book's others and stores its body.
+    async fn insert_chapter(
+        pool: &InstrumentedPgConnectionPool,
+        storage: &Storage,
+        book: &Book,
+        name: &str,
+    ) -> Uuid {
+        let id = Uuid::new_v4();
+        let chapter = NewChapter {
+            name: name.into(),
+            author: book.author.clone(),
+            book_id: book.id,
+            published_at: Utc::now(),
+            metadata: ChapterKind::Pale {
+                url: format!("https://palewebserial.wordpress.com/{}/", id),
+            },
+        };
+        let ordinal = next_ordinal(pool, book.id).await.unwrap();
+        let mut conn = pool.get().await.unwrap();
+        diesel::insert_into(chapters::table)
+            .values((
+                chapters::id.eq(id),
+                chapter,
+                chapters::status.eq(ChapterStatus::Fetched),
+                chapters::ordinal.eq(ordinal),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        let uploaded = storage
+            .store_book(
+                &book.id,
+                &id,
+                ByteStream::from_static(b"<p>Chapter text.</p>"),
+            )
+            .await
+            .unwrap();
+        diesel::insert_into(chapter_bodies::table)
+            .values(ChapterBody {
+                key: uploaded.location.key,
+                bucket: uploaded.location.bucket,
+                chapter_id: id,
+                size: Some(uploaded.size),
+            })
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        id
+    }
+
+    async fn last_chapter_id(pool: &InstrumentedPgConnectionPool) -> Option<Uuid> {
+        let mut conn = pool.get().await.unwrap();
+        subscriptions::table
+            .select(subscriptions::last_chapter_id)
+            .filter(subscriptions::user_id.eq(USER_ID))
+            .first(&mut *conn)
+            .await
+            .unwrap()
+    }
+
+    /// The chapters the notification loop would deliver next.
+    async fn unsent_chapters(pool: &InstrumentedPgConnectionPool) -> UnsentChapters {
+        let limits = &test_support::config().delivery_backlog;
+        find_unsent_chapters(pool, limits).await.unwrap().0
+    }
+
+    async fn deliveries(pool: &InstrumentedPgConnectionPool) -> Vec<Delivery> {
+        let mut conn = pool.get().await.unwrap();
+        deliveries::table.load(&mut *conn).await.unwrap()
+    }
+
+    #[test]
+    fn counts_words_in_text() {
+        let html = "<h1>1.1 Blood Run Cold</h1><p>Three\nwords <em>here</em>.</p>";
+        assert_eq!(count_words(html), 7);
+    }
+
+    #[test]
+    fn estimates_reading_time() {
+        let chapter = |word_count| Chapter {
+            id: Uuid::new_v4(),
+            name: "1.1".into(),
+            author: "Wildbow".into(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            book_id: Uuid::new_v4(),
+            published_at: Utc::now(),
+            metadata: ChapterKind::Pale { url: "".into() },
+            status: ChapterStatus::Fetched,
+            deleted_at: None,
+            word_count,
+            ordinal: None,
+        };
+        let (short, long, uncounted) = (chapter(Some(640)), chapter(Some(11_738)), chapter(None));
+        assert_eq!(
+            reading_estimate(&[&short]).as_deref(),
+            Some("~640 words (~2 min)")
+        );
+        assert_eq!(
+            reading_estimate(&[&short, &long]).as_deref(),
+            Some("~12,400 words (~45 min)")
+        );
+        assert_eq!(reading_estimate(&[&short, &uncounted]), None);
+    }
+
+    #[test]
+    fn explains_the_email_size_limit_of_each_conversion_mode() {
+        let limit = mailgun::MAX_MESSAGE_BYTES;
+        check_email_size(limit, KindleConversionMode::Cereal).unwrap();
+        check_email_size(limit, KindleConversionMode::Amazon).unwrap();
+
+        let err = check_email_size(limit + 1, KindleConversionMode::Cereal).unwrap_err();
+        assert!(err.to_string().starts_with("The ebook is 26214401 bytes"));
+        let err = check_email_size(limit + 1, KindleConversionMode::Amazon).unwrap_err();
+        assert!(err.to_string().contains("bytes of HTML"));
+        assert!(err.to_string().contains("have cereal convert them"));
+    }
+
+    #[test]
+    fn pairs_chapters_with_their_own_bodies() {
+        let now = Utc::now();
+        let chapter = |name: &str| Chapter {
+            id: Uuid::new_v4(),
+            name: name.into(),
+            author: "Wildbow".into(),
+            created_at: now,
+            updated_at: now,
+            book_id: Uuid::nil(),
+            published_at: now,
+            metadata: ChapterKind::Pale { url: "".into() },
+            status: ChapterStatus::Fetched,
+            deleted_at: None,
+            word_count: None,
+            ordinal: None,
+        };
+        let body = |chapter: &Chapter| ChapterBody {
+            key: format!("bodies/{}", chapter.name),
+            bucket: test_support::BUCKET.into(),
+            chapter_id: chapter.id,
+            size: None,
+        };
+        let chapters = vec![chapter("1.1"), chapter("1.2"), chapter("1.3")];
+        let mut bodies: HashMap<Uuid, ChapterBody> = chapters
+            .iter()
+            .rev()
+            .map(|chap| (chap.id, body(chap)))
+            .collect();
+
+        let paired = |bodies: &HashMap<Uuid, ChapterBody>| {
+            pair_bodies(&chapters, bodies)
+                .into_iter()
+                .map(|(chap, body)| (chap.name.clone(), body.key.clone()))
+                .collect_vec()
+        };
+        assert_eq!(
+            paired(&bodies),
+            [
+                ("1.1".into(), "bodies/1.1".into()),
+                ("1.2".into(), "bodies/1.2".into()),
+                ("1.3".into(), "bodies/1.3".into())
+            ]
+        );
+        // A chapter missing its body is skipped without shifting the others.
+        bodies.remove(&chapters[1].id);
+        assert_eq!(
+            paired(&bodies),
+            [
+                ("1.1".into(), "bodies/1.1".into()),
+                ("1.3".into(), "bodies/1.3".into())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_chapters_whose_body_never_arrives() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let storage = test_support::storage();
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+        subscribe(&db.pool, &book, 3).await;
+        let first = insert_chapter(&db.pool, &storage, &book, "1.1").await;
+        let second = insert_chapter(&db.pool, &storage, &book, "1.2").await;
+        let third = insert_chapter(&db.pool, &storage, &book, "1.3").await;
+        let mut conn = db.pool.get().await.unwrap();
+        diesel::delete(chapter_bodies::table.find(second))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+
+        send_notifications(unsent_chapters(&db.pool).await, db.pool.clone(), &storage)
+            .await
+            .unwrap();
+        assert_eq!(last_chapter_id(&db.pool).await, Some(third));
+        let sent = deliveries(&db.pool).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].chapter_ids, [first, third]);
+        // The body is fetched again by the check loop.
+        let mut conn = db.pool.get().await.unwrap();
+        let status: ChapterStatus = chapters::table
+            .find(second)
+            .select(chapters::status)
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        assert_eq!(status, ChapterStatus::FetchFailed);
+
+        // The book isn't held on the chapter, so nothing is left to send.
+        send_notifications(unsent_chapters(&db.pool).await, db.pool.clone(), &storage)
+            .await
+            .unwrap();
+        assert_eq!(deliveries(&db.pool).await.len(), 1);
+        let mut next = Vec::new();
+        for name in ["1.4", "1.5", "1.6"] {
+            next.push(insert_chapter(&db.pool, &storage, &book, name).await);
+        }
+        send_notifications(unsent_chapters(&db.pool).await, db.pool.clone(), &storage)
+            .await
+            .unwrap();
+        assert_eq!(deliveries(&db.pool).await[1].chapter_ids, next);
+    }
+
+    #[test]
+    fn keeps_the_oldest_delivery_groups_in_book_order() {
+        let start = Utc::now();
+        let chapter = |book_id, hours| Chapter {
+            id: Uuid::new_v4(),
+            name: "1.1".into(),
+            author: "Wildbow".into(),
+            created_at: start,
+            updated_at: start,
+            book_id,
+            published_at: start + chrono::Duration::hours(hours),
+            metadata: ChapterKind::Pale { url: "".into() },
+            status: ChapterStatus::Fetched,
+            deleted_at: None,
+            word_count: None,
+            ordinal: None,
+        };
+        let (pale, ward) = (Uuid::new_v4(), Uuid::new_v4());
+        // Pale's second chapter was published before its first, as when one is
+        // backdated, yet its groups are still kept in order.
+        let pale_chapters = vec![chapter(pale, 0), chapter(pale, -5), chapter(pale, 3)];
+        let ward_chapters = vec![
+            chapter(ward, 1),
+            chapter(ward, 2),
+            chapter(ward, 4),
+            chapter(ward, 5),
+        ];
+        let unsent: UnsentChapters = HashMap::from([
+            (
+                "reader".to_owned(),
+                HashMap::from([((pale, 1), pale_chapters.clone())]),
+            ),
+            (
+                "other".to_owned(),
+                HashMap::from([((ward, 2), ward_chapters.clone())]),
+            ),
+        ]);
+
+        let (kept, left_out) = oldest_groups(unsent.clone(), 3);
+        assert_eq!(left_out, 2);
+        let ids = |chapters: &[Chapter]| chapters.iter().map(|chap| chap.id).collect_vec();
+        assert_eq!(ids(&kept["reader"][&(pale, 1)]), ids(&pale_chapters[..2]));
+        assert_eq!(ids(&kept["other"][&(ward, 2)]), ids(&ward_chapters[..2]));
+
+        let (kept, left_out) = oldest_groups(unsent, 1);
+        assert_eq!(left_out, 4);
+        assert!(!kept.contains_key("other"));
+    }
+
+    #[tokio::test]
+    async fn delivers_unsent_chapters_once() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let storage = test_support::storage();
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+        subscribe(&db.pool, &book, 1).await;
+        let chapter_id = insert_chapter(&db.pool, &storage, &book, "1.1").await;
+
+        let unsent = unsent_chapters(&db.pool).await;
+        assert_eq!(unsent[USER_ID][&(book.id, 1)].len(), 1);
+        send_notifications(unsent, db.pool.clone(), &storage)
+            .await
+            .unwrap();
+
+        assert_eq!(last_chapter_id(&db.pool).await, Some(chapter_id));
+        let deliveries = deliveries(&db.pool).await;
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].chapter_ids, [chapter_id]);
+        assert!(deliveries[0].dry_run);
+        assert!(unsent_chapters(&db.pool).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn holds_snoozed_subscriptions_until_they_resume() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let storage = test_support::storage();
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+        subscribe(&db.pool, &book, 1).await;
+        let mut ids = Vec::new();
+        for name in ["1.1", "1.2", "1.3"] {
+            ids.push(insert_chapter(&db.pool, &storage, &book, name).await);
+        }
+        let snooze = |until: DateTime<Utc>| {
+            let pool = db.pool.clone();
+            async move {
+                let mut conn = pool.get().await.unwrap();
+                diesel::update(subscriptions::table)
+                    .set(subscriptions::snoozed_until.eq(until))
+                    .execute(&mut *conn)
+                    .await
+                    .unwrap();
+            }
+        };
+        let queued = || async {
+            unsent_chapters(&db.pool).await.get(USER_ID).map(|books| {
+                books[&(book.id, 1)]
+                    .iter()
+                    .map(|chap| chap.id)
+                    .collect_vec()
+            })
+        };
+
+        snooze(Utc::now() + chrono::Duration::hours(1)).await;
+        assert_eq!(queued().await, None);
+
+        // By default everything published while snoozed is delivered.
+        snooze(Utc::now()).await;
+        assert_eq!(queued().await, Some(ids.clone()));
+
+        let mut conn = db.pool.get().await.unwrap();
+        diesel::insert_into(user_preferences::table)
+            .values((
+                user_preferences::user_id.eq(USER_ID),
+                user_preferences::skip_snoozed_chapters.eq(true),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        assert_eq!(queued().await, Some(vec![ids[2]]));
+    }
+
+    #[tokio::test]
+    async fn announces_a_cycles_books_together() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let storage = test_support::storage();
+        let pale = insert_book(&db.pool, BookKind::Pale).await;
+        let inn = insert_book(&db.pool, BookKind::TheWanderingInn).await;
+        subscribe(&db.pool, &pale, 2).await;
+        let mut conn = db.pool.get().await.unwrap();
+        let inn: Book = diesel::update(books::table.find(inn.id))
+            .set(books::name.eq("The Wandering Inn"))
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        diesel::insert_into(subscriptions::table)
+            .values((
+                subscriptions::user_id.eq(USER_ID),
+                subscriptions::book_id.eq(inn.id),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        insert_chapter(&db.pool, &storage, &pale, "1.1").await;
+        let pale_last = insert_chapter(&db.pool, &storage, &pale, "1.2").await;
+        let inn_last = insert_chapter(&db.pool, &storage, &inn, "10.01").await;
+
+        let unsent = unsent_chapters(&db.pool).await;
+        let announcements = unsent[USER_ID]
+            .iter()
+            .map(|((book_id, _), chapters)| {
+                let book = if *book_id == pale.id { &pale } else { &inn };
+                (book, chapters.clone())
+            })
+            .sorted_by_key(|(book, _)| book.id != pale.id)
+            .collect_vec();
+        let message = combined_notification("Pale, The Wandering Inn", "", &announcements);
+        assert_eq!(
+            Templates::default().pushover_combined(&message),
+            "New chapters have been released: Pale ×2, The Wandering Inn ×1"
+        );
+        let template = "[cereal] {count}: {chapters}".parse().unwrap();
+        let templates = Templates {
+            pushover_combined: Some(template),
+            ..Templates::default()
+        };
+        assert_eq!(
+            templates.pushover_combined(&message),
+            "[cereal] 3: Pale ×2, The Wandering Inn ×1"
+        );
+        send_notifications(unsent, db.pool.clone(), &storage)
+            .await
+            .unwrap();
+
+        let mut conn = db.pool.get().await.unwrap();
+        let notified: Vec<(Uuid, Option<Uuid>)> = subscriptions::table
+            .select((
+                subscriptions::book_id,
+                subscriptions::pushover_notified_chapter_id,
+            ))
+            .filter(subscriptions::user_id.eq(USER_ID))
+            .load(&mut *conn)
+            .await
+            .unwrap();
+        assert!(notified.contains(&(pale.id, Some(pale_last))));
+        assert!(notified.contains(&(inn.id, Some(inn_last))));
+    }
+
+    #[tokio::test]
+    async fn orders_chapters_published_together_by_ordinal() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let storage = test_support::storage();
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+        subscribe(&db.pool, &book, 3).await;
+        let published_at = Utc::now();
+        let insert = |ordinal: i32| {
+            let (pool, storage, book) = (&db.pool, &storage, &book);
+            async move {
+                let id = insert_chapter(pool, storage, book, &format!("1.{ordinal}")).await;
+                let mut conn = pool.get().await.unwrap();
+                diesel::update(chapters::table.find(id))
+                    .set((
+                        chapters::published_at.eq(published_at),
+                        chapters::ordinal.eq(ordinal),
+                    ))
+                    .execute(&mut *conn)
+                    .await
+                    .unwrap();
+                id
+            }
+        };
+        let mut ids = HashMap::new();
+        for ordinal in [2, 0, 1] {
+            ids.insert(ordinal, insert(ordinal).await);
+        }
+
+        let unsent = unsent_chapters(&db.pool).await;
+        let names = unsent[USER_ID][&(book.id, 3)]
+            .iter()
+            .map(|chapter| chapter.name.as_str())
+            .collect_vec();
+        assert_eq!(names, ["1.0", "1.1", "1.2"]);
+        send_notifications(unsent, db.pool.clone(), &storage)
+            .await
+            .unwrap();
+        assert_eq!(last_chapter_id(&db.pool).await, Some(ids[&2]));
+        assert_eq!(
+            deliveries(&db.pool).await[0].chapter_ids,
+            [ids[&0], ids[&1], ids[&2]]
+        );
+
+        let later = insert(3).await;
+        let queued = queued_chapters(&db.pool, None).await.unwrap();
+        let queued = queued[0]
+            .chapters
+            .iter()
+            .map(|chapter| chapter.id)
+            .collect_vec();
+        assert_eq!(queued, [later]);
+    }
+
+    #[tokio::test]
+    async fn orders_chapters_by_ordinal_before_published_at() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let storage = test_support::storage();
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+        subscribe(&db.pool, &book, 3).await;
+        let now = Utc::now();
+        // A chapter the provider lists later may have been published earlier,
+        // such as one backdated by its author.
+        let insert = |ordinal: i32, hours_ago: i64| {
+            let (pool, storage, book) = (&db.pool, &storage, &book);
+            async move {
+                let id = insert_chapter(pool, storage, book, &format!("1.{ordinal}")).await;
+                let mut conn = pool.get().await.unwrap();
+                diesel::update(chapters::table.find(id))
+                    .set((
+                        chapters::published_at.eq(now - chrono::Duration::hours(hours_ago)),
+                        chapters::ordinal.eq(ordinal),
+                    ))
+                    .execute(&mut *conn)
+                    .await
+                    .unwrap();
+                id
+            }
+        };
+        let ids = [insert(0, 1).await, insert(1, 3).await, insert(2, 2).await];
+
+        let unsent = unsent_chapters(&db.pool).await;
+        let names = unsent[USER_ID][&(book.id, 3)]
+            .iter()
+            .map(|chapter| chapter.name.as_str())
+            .collect_vec();
+        assert_eq!(names, ["1.0", "1.1", "1.2"]);
+        send_notifications(unsent, db.pool.clone(), &storage)
+            .await
+            .unwrap();
+        assert_eq!(last_chapter_id(&db.pool).await, Some(ids[2]));
+        assert_eq!(deliveries(&db.pool).await[0].chapter_ids, ids);
+
+        let later = insert(3, 5).await;
+        let queued = queued_chapters(&db.pool, None).await.unwrap();
+        let queued = queued[0]
+            .chapters
+            .iter()
+            .map(|chapter| chapter.id)
+            .collect_vec();
+        assert_eq!(queued, [later]);
+    }
+
+    #[tokio::test]
+    async fn delivers_whole_groups_separately() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let storage = test_support::storage();
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+        subscribe(&db.pool, &book, 2).await;
+        let mut ids = Vec::new();
+        for name in ["1.1", "1.2", "1.3", "1.4", "1.5"] {
+            ids.push(insert_chapter(&db.pool, &storage, &book, name).await);
+        }
+
+        let unsent = unsent_chapters(&db.pool).await;
+        assert_eq!(unsent[USER_ID][&(book.id, 2)].len(), 4);
+        send_notifications(unsent, db.pool.clone(), &storage)
+            .await
+            .unwrap();
+
+        let groups = deliveries(&db.pool)
+            .await
+            .into_iter()
+            .map(|delivery| delivery.chapter_ids)
+            .sorted()
+            .collect_vec();
+        let mut expected = vec![ids[..2].to_vec(), ids[2..4].to_vec()];
+        expected.sort();
+        assert_eq!(groups, expected);
+        assert_eq!(last_chapter_id(&db.pool).await, Some(ids[3]));
+        assert!(unsent_chapters(&db.pool).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sends_chapters_inline_when_conversion_fails() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let storage = test_support::storage();
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+        subscribe(&db.pool, &book, 1).await;
+        let mut conn = db.pool.get().await.unwrap();
+        diesel::update(delivery_methods::table.find(USER_ID))
+            .set((
+                delivery_methods::kindle_email.eq("reader@kindle.com"),
+                delivery_methods::kindle_email_verified.eq(true),
+                delivery_methods::kindle_email_enabled.eq(true),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        let insert_failing = |name: &'static str| {
+            let (pool, storage, book) = (&db.pool, &storage, &book);
+            async move {
+                let id = insert_chapter(pool, storage, book, name).await;
+                let body = ByteStream::from_static(b"<p>calibre-fails</p><script>x()</script>");
+                storage.store_book(&book.id, &id, body).await.unwrap();
+                id
+            }
+        };
+
+        let chapter_id = insert_failing("1.1").await;
+        let unsent = unsent_chapters(&db.pool).await;
+        send_notifications(unsent, db.pool.clone(), &storage)
+            .await
+            .unwrap();
+        assert_eq!(last_chapter_id(&db.pool).await, Some(chapter_id));
+        let delivered = deliveries(&db.pool).await;
+        assert_eq!(delivered.len(), 1);
+        assert!(delivered[0].degraded);
+        assert_eq!(delivered[0].artifact_key, None);
+
+        let mut conn = db.pool.get().await.unwrap();
+        diesel::update(delivery_methods::table.find(USER_ID))
+            .set(delivery_methods::kindle_inline_fallback.eq(false))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        insert_failing("1.2").await;
+        let unsent = unsent_chapters(&db.pool).await;
+        assert!(send_notifications(unsent, db.pool.clone(), &storage)
+            .await
+            .is_err());
+        assert_eq!(last_chapter_id(&db.pool).await, Some(chapter_id));
+        assert_eq!(deliveries(&db.pool).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn leaves_conversion_to_amazon_when_asked() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let storage = test_support::storage();
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+        subscribe(&db.pool, &book, 1).await;
+        let mut conn = db.pool.get().await.unwrap();
+        diesel::update(delivery_methods::table.find(USER_ID))
+            .set((
+                delivery_methods::kindle_email.eq("reader@kindle.com"),
+                delivery_methods::kindle_email_verified.eq(true),
+                delivery_methods::kindle_email_enabled.eq(true),
+                delivery_methods::kindle_inline_fallback.eq(false),
+                delivery_methods::kindle_conversion_mode.eq(KindleConversionMode::Amazon),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        // A body calibre fails on, which Amazon mode never hands to calibre.
+        let chapter_id = insert_chapter(&db.pool, &storage, &book, "1.1").await;
+        let body = ByteStream::from_static(b"<p>calibre-fails</p>");
+        storage
+            .store_book(&book.id, &chapter_id, body)
+            .await
+            .unwrap();
+
+        let unsent = unsent_chapters(&db.pool).await;
+        send_notifications(unsent, db.pool.clone(), &storage)
+            .await
+            .unwrap();
+        let delivered = deliveries(&db.pool).await;
+        assert_eq!(delivered.len(), 1);
+        assert!(!delivered[0].degraded);
+        assert_eq!(delivered[0].artifact_key, None);
+    }
+
+    #[tokio::test]
+    async fn sanitizes_inline_chapters() {
+        let storage = test_support::storage();
+        let (book_id, chapter_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let uploaded = storage
+            .store_book(
+                &book_id,
+                &chapter_id,
+                ByteStream::from_static(b"<p onclick=\"x()\">Text.</p><script>x()</script>"),
+            )
+            .await
+            .unwrap();
+        let chapter = Chapter {
+            id: chapter_id,
+            name: "1.1 <Blood>".into(),
+            author: "Wildbow".into(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            book_id,
+            published_at: Utc::now(),
+            metadata: ChapterKind::Pale { url: "".into() },
+            status: ChapterStatus::Fetched,
+            deleted_at: None,
+            word_count: None,
+            ordinal: None,
+        };
+        let body = ChapterBody {
+            key: uploaded.location.key,
+            bucket: uploaded.location.bucket,
+            chapter_id,
+            size: Some(uploaded.size),
+        };
+
+        let html = inline_html(&[(&chapter, &body)], &storage).await.unwrap();
+        assert!(html.ends_with("<h1>1.1&#32;&lt;Blood&gt;</h1><p>Text.</p>"));
+    }
+
+    #[tokio::test]
+    async fn adds_requested_extras_to_ebooks() {
+        test_support::config();
+        let storage = test_support::storage();
+        let book = Book {
+            id: Uuid::new_v4(),
+            name: "Pale".into(),
+            author: "Wildbow".into(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            metadata: BookKind::Pale,
+            deleted_at: None,
+            chapter_count: 0,
+            latest_chapter_published_at: None,
+            last_checked_at: None,
+            last_success_at: None,
+            last_error: None,
+            metadata_refreshed_at: None,
+            tags: Vec::new(),
+            content_warnings: Vec::new(),
+            next_check_at: None,
+            check_problem: None,
+        };
+        let chapter_id = Uuid::new_v4();
+        let uploaded = storage
+            .store_book(
+                &book.id,
+                &chapter_id,
+                ByteStream::from_static(b"<p>Chapter text.</p>"),
+            )
+            .await
+            .unwrap();
+        let extras = ChapterExtras {
+            note_before: Some("<p>Patreon is ahead.</p>".into()),
+            note_after: Some("<p>Thanks for reading!</p>".into()),
+            comment_count: Some(42),
+        };
+        store_extras(&storage, &book, &chapter_id, Some(extras)).await;
+        let chapter = Chapter {
+            id: chapter_id,
+            name: "1.1".into(),
+            author: "Wildbow".into(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            book_id: book.id,
+            published_at: Utc::now(),
+            metadata: ChapterKind::Pale { url: "".into() },
+            status: ChapterStatus::Fetched,
+            deleted_at: None,
+            word_count: None,
+            ordinal: None,
+        };
+        let body = ChapterBody {
+            key: uploaded.location.key,
+            bucket: uploaded.location.bucket,
+            chapter_id,
+            size: Some(uploaded.size),
+        };
+        let ebook = |extras| {
+            let (book, chapter, body, storage) = (&book, &chapter, &body, &storage);
+            async move {
+                let bytes = generate_ebook(
+                    book,
+                    &[(chapter, body)],
+                    extras,
+                    calibre::DEFAULT_OUTPUT_PROFILE,
+                    storage,
+                )
+                .await
+                .unwrap();
+                String::from_utf8(bytes).unwrap()
+            }
+        };
+
+        assert_eq!(
+            ebook(ExtrasPreference::default()).await,
+            "<p>Chapter text.</p>"
+        );
+        let after = ebook(ExtrasPreference {
+            author_notes: AuthorNotes::After,
+            comment_count: false,
+        })
+        .await;
+        assert!(after.contains("<h3>Author's note</h3><p>Thanks for reading!</p>"));
+        assert!(!after.contains("Patreon is ahead."));
+        assert!(!after.contains("comments"));
+        let all = ebook(ExtrasPreference {
+            author_notes: AuthorNotes::All,
+            comment_count: true,
+        })
+        .await;
+        assert!(all.starts_with("<p>Chapter text.</p><hr/>"));
+        assert!(all.contains("Patreon is ahead."));
+        assert!(all.contains("Thanks for reading!"));
+        assert!(all.ends_with("42 comments when this chapter was fetched.</p>"));
+    }
+
+    #[tokio::test]
+    async fn redelivers_past_deliveries() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let storage = test_support::storage();
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+        subscribe(&db.pool, &book, 1).await;
+        let chapter_id = insert_chapter(&db.pool, &storage, &book, "1.1").await;
+        let unsent = unsent_chapters(&db.pool).await;
+        send_notifications(unsent, db.pool.clone(), &storage)
+            .await
+            .unwrap();
+        let original = deliveries(&db.pool).await.remove(0);
+
+        let report = redeliver(&db.pool, &storage, original.id, false, true)
+            .await
+            .unwrap();
+        assert_eq!(report.delivery_id, None);
+        assert_eq!(report.chapters, ["1.1"]);
+        assert!(report.pushover);
+        assert_eq!(deliveries(&db.pool).await.len(), 1);
+
+        let report = redeliver(&db.pool, &storage, original.id, false, false)
+            .await
+            .unwrap();
+        let redelivered = deliveries(&db.pool)
+            .await
+            .into_iter()
+            .find(|delivery| Some(delivery.id) == report.delivery_id)
+            .unwrap();
+        assert_eq!(redelivered.redelivery_of, Some(original.id));
+        assert_eq!(redelivered.chapter_ids, [chapter_id]);
+        assert!(redelivered.artifact_key.is_some());
+
+        let err = redeliver(&db.pool, &storage, Uuid::new_v4(), false, false)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn waits_for_grouping_quantity() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let storage = test_support::storage();
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+        subscribe(&db.pool, &book, 2).await;
+
+        insert_chapter(&db.pool, &storage, &book, "1.1").await;
+        assert!(unsent_chapters(&db.pool).await.is_empty());
+
+        insert_chapter(&db.pool, &storage, &book, "1.2").await;
+        let unsent = unsent_chapters(&db.pool).await;
+        let names = unsent[USER_ID][&(book.id, 2)]
+            .iter()
+            .map(|chapter| chapter.name.as_str())
+            .collect_vec();
+        assert_eq!(names, ["1.1", "1.2"]);
+    }
+
+    #[tokio::test]
+    async fn stores_new_chapters_from_feed() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let site = MockServer::start().await;
+        let feed = include_str!("../tests/fixtures/wordpress/feed.xml")
+            .replace("https://testserial.wordpress.com", &site.uri());
+        Mock::given(method("GET"))
+            .and(path("/feed/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(feed))
+            .expect(2)
+            .mount(&site)
+            .await;
+        // Bodies are only fetched on the first check, as the chapters are
+        // known by the second.
+        Mock::given(method("GET"))
+            .and(path_regex("^/2023/"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/wordpress/chapter.html")),
+            )
+            .expect(2)
+            .mount(&site)
+            .await;
+        let storage = test_support::storage();
+        let endpoints = Endpoints {
+            pale: Url::parse(&site.uri()).unwrap(),
+            ..Endpoints::default()
+        };
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+
+        let (_book, chapters) =
+            check_for_new_chapters(db.pool.clone(), &storage, &endpoints, book.clone())
+                .await
+                .unwrap();
+        assert_eq!(chapters.len(), 2);
+        assert!(chapters.iter().all(|chapter| {
+            chapter.status == ChapterStatus::Fetched
+                && chapter.word_count.is_some_and(|words| words > 0)
+        }));
+        let mut conn = db.pool.get().await.unwrap();
+        let bodies: Vec<ChapterBody> = chapter_bodies::table.load(&mut *conn).await.unwrap();
+        assert_eq!(bodies.len(), 2);
+        for body in bodies {
+            let body = storage.fetch(body.into()).await.unwrap();
+            assert!(!body.is_empty());
+        }
+        assert_eq!(storage.list(storage::BODIES_PREFIX).await.unwrap().len(), 2);
+        let chapter_count: i64 = books::table
+            .find(book.id)
+            .select(books::chapter_count)
+            .first(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(chapter_count, 2);
+        drop(conn);
+
+        let (_book, chapters) = check_for_new_chapters(db.pool.clone(), &storage, &endpoints, book)
+            .await
+            .unwrap();
+        assert!(chapters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn archives_delivered_chapters() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let site = MockServer::start().await;
+        let feed = include_str!("../tests/fixtures/wordpress/feed.xml")
+            .replace("https://testserial.wordpress.com", &site.uri());
+        Mock::given(method("GET"))
+            .and(path("/feed/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(feed))
+            .mount(&site)
+            .await;
+        Mock::given(method("GET"))
+            .and(path_regex("^/2023/"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/wordpress/chapter.html")),
+            )
+            .mount(&site)
+            .await;
+        let storage = test_support::storage();
+        let endpoints = Endpoints {
+            pale: Url::parse(&site.uri()).unwrap(),
+            ..Endpoints::default()
+        };
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+        subscribe(&db.pool, &book, 1).await;
+        let (_book, discovered) =
+            check_for_new_chapters(db.pool.clone(), &storage, &endpoints, book.clone())
+                .await
+                .unwrap();
+        assert_eq!(discovered.len(), 2);
+        // Nothing is archived before it has been sent.
+        assert_eq!(archive_chapters(&db.pool, Utc::now()).await.unwrap(), 0);
+        let unsent = unsent_chapters(&db.pool).await;
+        send_notifications(unsent, db.pool.clone(), &storage)
+            .await
+            .unwrap();
+
+        // The last chapter sent is still referred to by the subscription.
+        assert_eq!(archive_chapters(&db.pool, Utc::now()).await.unwrap(), 1);
+        let mut conn = db.pool.get().await.unwrap();
+        let archived: Vec<ArchivedChapter> =
+            chapters_archive::table.load(&mut *conn).await.unwrap();
+        assert_eq!(archived.len(), 1);
+        assert!(archived[0].name.ends_with("1.1"));
+        let body = archived[0].body_location().unwrap();
+        assert!(!storage.fetch(body).await.unwrap().is_empty());
+        let live: i64 = chapters::table
+            .count()
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(live, 1);
+        drop(conn);
+        check_book_counts(&db.pool).await.unwrap();
+        let mut conn = db.pool.get().await.unwrap();
+        let chapter_count: i64 = books::table
+            .find(book.id)
+            .select(books::chapter_count)
+            .first(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(chapter_count, 2);
+        drop(conn);
+
+        // The feed still lists the archived chapter, which isn't new.
+        let (_book, discovered) =
+            check_for_new_chapters(db.pool.clone(), &storage, &endpoints, book.clone())
+                .await
+                .unwrap();
+        assert!(discovered.is_empty());
+        let new_chapter = insert_chapter(&db.pool, &storage, &book, "1.3").await;
+        let unsent = unsent_chapters(&db.pool).await;
+        assert_eq!(unsent[USER_ID][&(book.id, 1)].len(), 1);
+        send_notifications(unsent, db.pool.clone(), &storage)
+            .await
+            .unwrap();
+        assert_eq!(last_chapter_id(&db.pool).await, Some(new_chapter));
+        assert!(deliveries(&db.pool)
+            .await
+            .iter()
+            .any(|delivery| delivery.chapter_ids == [new_chapter]));
+
+        unarchive_chapters(&db.pool, &[book.id]).await.unwrap();
+        let mut conn = db.pool.get().await.unwrap();
+        let archived: i64 = chapters_archive::table
+            .count()
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(archived, 0);
+        let bodies: i64 = chapter_bodies::table
+            .count()
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(bodies, 3);
+    }
+
+    #[tokio::test]
+    async fn keeps_responses_which_fail_to_parse() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let site = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/feed/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<html>Maintenance</html>"))
+            .mount(&site)
+            .await;
+        let mut storage = test_support::storage();
+        storage.debug_snapshots_per_day = Some(1);
+        let endpoints = Endpoints {
+            pale: Url::parse(&site.uri()).unwrap(),
+            ..Endpoints::default()
+        };
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+
+        let check = async || {
+            check_for_new_chapters(db.pool.clone(), &storage, &endpoints, book.clone())
+                .await
+                .unwrap();
+            let mut conn = db.pool.get().await.unwrap();
+            books::table
+                .find(book.id)
+                .select(books::last_error)
+                .first::<Option<String>>(&mut *conn)
+                .await
+                .unwrap()
+                .unwrap()
+        };
+
+        let last_error = check().await;
+        assert!(last_error.contains(&format!("Failed to parse {}/feed/", site.uri())));
+        let kept = storage.list(DEBUG_PREFIX).await.unwrap();
+        assert_eq!(kept.len(), 1);
+        let location = storage.location(kept[0].key.clone());
+        assert!(location.key.contains(&book.id.to_string()));
+        assert!(last_error.contains(&format!("kept at {}", location)));
+        let snapshot = storage.fetch(location).await.unwrap();
+        assert_eq!(snapshot, b"<html>Maintenance</html>");
+
+        // A second failure the same day is over the cap.
+        let last_error = check().await;
+        assert!(!last_error.contains("kept at"));
+        assert_eq!(storage.list(DEBUG_PREFIX).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn records_check_health() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let site = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/feed/"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(crate::providers::FETCH_ATTEMPTS.into())
+            .mount(&site)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/feed/"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string(
+                    include_str!("../tests/fixtures/wordpress/feed.xml")
+                        .replace("https://testserial.wordpress.com", &site.uri()),
+                ),
+            )
+            .mount(&site)
+            .await;
+        Mock::given(method("GET"))
+            .and(path_regex("^/2023/"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/wordpress/chapter.html")),
+            )
+            .mount(&site)
+            .await;
+        let storage = test_support::storage();
+        let endpoints = Endpoints {
+            pale: Url::parse(&site.uri()).unwrap(),
+            ..Endpoints::default()
+        };
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+        subscribe(&db.pool, &book, 1).await;
+        let health = async || -> Book {
+            let mut conn = db.pool.get().await.unwrap();
+            books::table.find(book.id).first(&mut *conn).await.unwrap()
+        };
+        let stale = async || {
+            crate::controllers::books::find_stale_books(
+                Utc::now() - chrono::Duration::hours(1),
+                &db.pool,
+            )
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|book| book.id)
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(stale().await, [book.id]);
+
+        check_for_new_chapters(db.pool.clone(), &storage, &endpoints, book.clone())
+            .await
+            .unwrap();
+        let failed = health().await;
+        assert!(failed.last_checked_at.is_some());
+        assert!(failed.last_success_at.is_none());
+        assert!(failed.last_error.is_some());
+        assert_eq!(stale().await, [book.id]);
+
+        check_for_new_chapters(db.pool.clone(), &storage, &endpoints, book.clone())
+            .await
+            .unwrap();
+        let succeeded = health().await;
+        assert!(succeeded.last_checked_at > failed.last_checked_at);
+        assert_eq!(succeeded.last_success_at, succeeded.last_checked_at);
+        assert_eq!(succeeded.last_error, None);
+        assert!(stale().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn schedules_checks_by_how_they_fail() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let site = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/feed/"))
+            .respond_with(ResponseTemplate::new(404))
+            .up_to_n_times(1)
+            .mount(&site)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/feed/"))
+            .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "60"))
+            .up_to_n_times(1)
+            .mount(&site)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/feed/"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string(
+                    include_str!("../tests/fixtures/wordpress/feed.xml")
+                        .replace("https://testserial.wordpress.com", &site.uri()),
+                ),
+            )
+            .mount(&site)
+            .await;
+        Mock::given(method("GET"))
+            .and(path_regex("^/2023/"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/wordpress/chapter.html")),
+            )
+            .mount(&site)
+            .await;
+        let storage = test_support::storage();
+        let endpoints = Endpoints {
+            pale: Url::parse(&site.uri()).unwrap(),
+            ..Endpoints::default()
+        };
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+        subscribe(&db.pool, &book, 1).await;
+        let check = async || -> Book {
+            let book = {
+                let mut conn = db.pool.get().await.unwrap();
+                books::table
+                    .find(book.id)
+                    .first::<Book>(&mut *conn)
+                    .await
+                    .unwrap()
+            };
+            check_for_new_chapters(db.pool.clone(), &storage, &endpoints, book.clone())
+                .await
+                .unwrap();
+            let mut conn = db.pool.get().await.unwrap();
+            books::table.find(book.id).first(&mut *conn).await.unwrap()
+        };
+        let to_check = async || -> Vec<Uuid> {
+            books_to_check(&db.pool)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|book| book.id)
+                .collect()
+        };
+
+        let gone = check().await;
+        assert_eq!(gone.check_problem, Some(CheckProblem::Gone));
+        assert!(gone.next_check_at.unwrap() > Utc::now() + chrono::Duration::hours(23));
+        assert!(to_check().await.is_empty());
+
+        let limited = check().await;
+        let next_check_at = limited.next_check_at.unwrap();
+        assert!(next_check_at > Utc::now() + chrono::Duration::seconds(50));
+        assert!(next_check_at < Utc::now() + chrono::Duration::seconds(70));
+        // Being asked to come back later says nothing of whether it's back.
+        assert_eq!(limited.check_problem, Some(CheckProblem::Gone));
+
+        let succeeded = check().await;
+        assert_eq!(succeeded.check_problem, None);
+        assert_eq!(succeeded.next_check_at, None);
+        assert_eq!(to_check().await, [book.id]);
+    }
+
+    #[tokio::test]
+    async fn checks_each_book_once_for_all_subscribers() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let site = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/feed/"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string(
+                    include_str!("../tests/fixtures/wordpress/feed.xml")
+                        .replace("https://testserial.wordpress.com", &site.uri()),
+                ),
+            )
+            .expect(1)
+            .mount(&site)
+            .await;
+        Mock::given(method("GET"))
+            .and(path_regex("^/2023/"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/wordpress/chapter.html")),
+            )
+            .mount(&site)
+            .await;
+        let storage = test_support::storage();
+        let endpoints = Endpoints {
+            pale: Url::parse(&site.uri()).unwrap(),
+            ..Endpoints::default()
+        };
+        let book = insert_book(&db.pool, BookKind::Pale).await;
+        subscribe(&db.pool, &book, 1).await;
+        let mut conn = db.pool.get().await.unwrap();
+        diesel::insert_into(subscriptions::table)
+            .values((
+                subscriptions::user_id.eq("another-reader"),
+                subscriptions::book_id.eq(book.id),
+            ))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+
+        let to_check = books_to_check(&db.pool).await.unwrap();
+        assert_eq!(to_check.len(), 1);
+        let checked = check_for_all_new_chapters(&db.pool, &storage, &endpoints)
+            .await
+            .unwrap();
+        assert_eq!(checked.len(), 1);
+        let mut conn = db.pool.get().await.unwrap();
+        let chapters: i64 = chapters::table
+            .filter(chapters::book_id.eq(book.id))
+            .count()
+            .get_result(&mut *conn)
+            .await
+            .unwrap();
+        let bodies = storage
+            .list(&format!("{}/{}/", storage::BODIES_PREFIX, book.id))
+            .await
+            .unwrap();
+        assert_eq!(bodies.len() as i64, chapters);
+    }
+
+    #[test]
+    fn puts_off_checks_by_failure_class() {
+        let now = Utc::now();
+        let retry = |secs| ProviderError::Transient {
+            retry_after: Some(Duration::from_secs(secs)),
+        };
+        assert_eq!(
+            next_check_after(&retry(120), now),
+            Some(now + chrono::Duration::seconds(120))
+        );
+        assert_eq!(
+            next_check_after(&retry(365 * 24 * 60 * 60), now),
+            Some(now + chrono::Duration::hours(MAX_RETRY_AFTER_HOURS))
+        );
+        assert_eq!(
+            next_check_after(&ProviderError::ParseChanged, now),
+            Some(now + chrono::Duration::hours(PROBLEM_BACKOFF_HOURS))
+        );
+        for class in [
+            ProviderError::Transient { retry_after: None },
+            ProviderError::AuthRequired,
+            ProviderError::Other,
+        ] {
+            assert_eq!(next_check_after(&class, now), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn numbers_royalroad_chapters_from_the_table_of_contents() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        let site = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/fiction/67892"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/royalroad/fiction.html")),
+            )
+            .mount(&site)
+            .await;
+        let endpoints = Endpoints {
+            royalroad: Url::parse(&site.uri()).unwrap(),
+            ..Endpoints::default()
+        };
+        let book = insert_book(&db.pool, BookKind::RoyalRoad(RoyalRoadBookKind::new(67892))).await;
+        let insert = async |chapter_id: u64, ordinal: i32| -> Uuid {
+            let mut conn = db.pool.get().await.unwrap();
+            diesel::insert_into(chapters::table)
+                .values((
+                    NewChapter {
+                        name: chapter_id.to_string(),
+                        author: book.author.clone(),
+                        book_id: book.id,
+                        published_at: Utc::now(),
+                        metadata: ChapterKind::RoyalRoad { id: chapter_id },
+                    },
+                    chapters::ordinal.eq(ordinal),
+                ))
+                .returning(chapters::id)
+                .get_result(&mut *conn)
+                .await
+                .unwrap()
+        };
+        let interlude = insert(1000002, 5).await;
+        let first = insert(1000001, 7).await;
+        let removed = insert(999, 3).await;
+        assert_eq!(
+            number_royalroad_chapters(&db.pool, &endpoints)
+                .await
+                .unwrap(),
+            2
+        );
+
+        let mut conn = db.pool.get().await.unwrap();
+        for (id, ordinal) in [(first, 0), (interlude, 1), (removed, 3)] {
+            let stored: Option<i32> = chapters::table
+                .find(id)
+                .select(chapters::ordinal)
+                .first(&mut *conn)
+                .await
+                .unwrap();
+            assert_eq!(stored, Some(ordinal));
+        }
+    }
+
+    #[tokio::test]
+    async fn refreshes_stale_book_metadata() {
+        let Some(db) = TestDatabase::new().await else {
+            return;
+        };
+        // Royalroad pages are cached by url and mock servers are pooled, so
+        // this uses a fiction no other test fetches.
+        let site = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/fiction/67890"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(include_str!(
+                "../tests/fixtures/royalroad/fiction_with_warnings.html"
+            )))
+            .expect(1)
+            .mount(&site)
+            .await;
+        let endpoints = Endpoints {
+            royalroad: Url::parse(&site.uri()).unwrap(),
+            ..Endpoints::default()
+        };
+        let renamed =
+            insert_book(&db.pool, BookKind::RoyalRoad(RoyalRoadBookKind::new(67890))).await;
+        let fixed = insert_book(&db.pool, BookKind::Pale).await;
+        let recent =
+            insert_book(&db.pool, BookKind::RoyalRoad(RoyalRoadBookKind::new(67891))).await;
+        let recently = Utc::now() - chrono::Duration::days(1);
+        let mut conn = db.pool.get().await.unwrap();
+        diesel::update(books::table.find(recent.id))
+            .set(books::metadata_refreshed_at.eq(recently))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+
+        refresh_book_metadata(&db.pool, &endpoints).await.unwrap();
+
+        let mut conn = db.pool.get().await.unwrap();
+        let renamed: Book = books::table
+            .find(renamed.id)
+            .first(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(renamed.name, "The Tagged Serial");
+        assert_eq!(renamed.author, "Test Author");
+        assert!(renamed.metadata_refreshed_at.is_some());
+        assert_eq!(renamed.tags, ["LitRPG", "Progression", "Fantasy"]);
+        assert_eq!(renamed.content_warnings, ["Profanity", "Gore"]);
+        let changes: Vec<(Uuid, String, String, String, String)> = book_metadata_changes::table
+            .select((
+                book_metadata_changes::book_id,
+                book_metadata_changes::old_name,
+                book_metadata_changes::new_name,
+                book_metadata_changes::old_author,
+                book_metadata_changes::new_author,
+            ))
+            .load(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(
+            changes,
+            [(
+                renamed.id,
+                "Pale".into(),
+                "The Tagged Serial".into(),
+                "Wildbow".into(),
+                "Test Author".into(),
+            )]
+        );
+        let fixed: Book = books::table.find(fixed.id).first(&mut *conn).await.unwrap();
+        assert_eq!(fixed.name, "Pale");
+        assert!(fixed.metadata_refreshed_at.is_some());
+        let recent: Book = books::table
+            .find(recent.id)
+            .first(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(recent.name, "Pale");
+        assert_eq!(
+            recent.metadata_refreshed_at.map(|at| at.timestamp_micros()),
+            Some(recently.timestamp_micros())
+        );
+    }
 }
@@ -0,0 +1,265 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use itertools::Itertools;
+
+const PLACEHOLDERS: &[&str] = &[
+    "book", "author", "chapters", "count", "first", "last", "words",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Placeholder(&'static str),
+}
+
+/// A notification subject or message with placeholders such as `{book}`,
+/// rendered against the chapters being delivered. `{{` and `}}` are literal
+/// braces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(source: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = source.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let Some((name, rest)) = chars.as_str().split_once('}') else {
+                        bail!("The template has a {{ which is never closed.");
+                    };
+                    let Some(placeholder) = PLACEHOLDERS.iter().find(|known| **known == name)
+                    else {
+                        bail!(
+                            "{{{name}}} is not a placeholder, expected one of {}.",
+                            PLACEHOLDERS
+                                .iter()
+                                .map(|known| format!("{{{known}}}"))
+                                .join(", ")
+                        );
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder(placeholder));
+                    chars = rest.chars();
+                }
+                '}' => bail!("The template has a }} which was never opened."),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl Template {
+    pub fn render(&self, message: &Message) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.clone(),
+                Part::Placeholder(name) => message.value(name),
+            })
+            .collect()
+    }
+}
+
+/// What a notification is about, as the placeholders see it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message<'a> {
+    pub book: &'a str,
+    pub author: &'a str,
+    /// "1.1" for a single chapter, "1.1 through 1.3" for a group, or
+    /// "Pale ×2, Ward ×1" for a combined announcement.
+    pub chapters: String,
+    pub count: usize,
+    pub first: &'a str,
+    pub last: &'a str,
+    /// How long the chapters take to read, empty if they weren't counted.
+    pub words: Option<String>,
+}
+
+impl Message<'_> {
+    fn value(&self, placeholder: &str) -> String {
+        match placeholder {
+            "book" => self.book.to_owned(),
+            "author" => self.author.to_owned(),
+            "chapters" => self.chapters.clone(),
+            "count" => self.count.to_string(),
+            "first" => self.first.to_owned(),
+            "last" => self.last.to_owned(),
+            "words" => self.words.clone().unwrap_or_default(),
+            _ => unreachable!("Templates only hold known placeholders."),
+        }
+    }
+}
+
+/// The templates a user's notifications are rendered with. Unset templates
+/// fall back to the built in wording.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Templates {
+    pub kindle_subject: Option<Template>,
+    pub pushover: Option<Template>,
+    pub pushover_combined: Option<Template>,
+}
+
+impl Templates {
+    /// Each of the user's templates, or else the global one.
+    pub fn or(self, global: &Templates) -> Templates {
+        Templates {
+            kindle_subject: self.kindle_subject.or(global.kindle_subject.clone()),
+            pushover: self.pushover.or(global.pushover.clone()),
+            pushover_combined: self.pushover_combined.or(global.pushover_combined.clone()),
+        }
+    }
+
+    pub fn kindle_subject(&self, message: &Message) -> String {
+        match &self.kindle_subject {
+            Some(template) => template.render(message),
+            None => with_words(
+                match message.count {
+                    1 => format!("New Chapter of {}: {}", message.book, message.chapters),
+                    count => format!(
+                        "{count} New Chapters of {}: {}",
+                        message.book, message.chapters
+                    ),
+                },
+                message,
+            ),
+        }
+    }
+
+    pub fn pushover(&self, message: &Message) -> String {
+        match &self.pushover {
+            Some(template) => template.render(message),
+            None => with_words(
+                match message.count {
+                    1 => format!(
+                        "A new chapter of {} by {} has been released: {}",
+                        message.book, message.author, message.chapters
+                    ),
+                    count => format!(
+                        "{count} new chapters of {} by {} has been released: {}",
+                        message.book, message.author, message.chapters
+                    ),
+                },
+                message,
+            ),
+        }
+    }
+
+    pub fn pushover_combined(&self, message: &Message) -> String {
+        match &self.pushover_combined {
+            Some(template) => template.render(message),
+            None => with_words(
+                format!("New chapters have been released: {}", message.chapters),
+                message,
+            ),
+        }
+    }
+}
+
+/// Appends the reading estimate to a built in message, when there is one.
+fn with_words(text: String, message: &Message) -> String {
+    match &message.words {
+        Some(words) => format!("{text}, {words}"),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(count: usize) -> Message<'static> {
+        Message {
+            book: "Pale",
+            author: "Wildbow",
+            chapters: match count {
+                1 => "1.1".into(),
+                _ => "1.1 through 1.3".into(),
+            },
+            count,
+            first: "1.1",
+            last: if count == 1 { "1.1" } else { "1.3" },
+            words: Some("~12,400 words (~45 min)".into()),
+        }
+    }
+
+    #[test]
+    fn defaults_keep_the_built_in_wording() {
+        let templates = Templates::default();
+        assert_eq!(
+            templates.kindle_subject(&message(1)),
+            "New Chapter of Pale: 1.1, ~12,400 words (~45 min)"
+        );
+        assert_eq!(
+            templates.kindle_subject(&Message {
+                words: None,
+                ..message(3)
+            }),
+            "3 New Chapters of Pale: 1.1 through 1.3"
+        );
+        assert_eq!(
+            templates.pushover(&message(1)),
+            "A new chapter of Pale by Wildbow has been released: 1.1, ~12,400 words (~45 min)"
+        );
+        assert_eq!(
+            templates.pushover(&Message {
+                words: None,
+                ..message(3)
+            }),
+            "3 new chapters of Pale by Wildbow has been released: 1.1 through 1.3"
+        );
+        assert_eq!(
+            templates.pushover_combined(&Message {
+                chapters: "Pale ×2, Ward ×1".into(),
+                words: None,
+                ..message(3)
+            }),
+            "New chapters have been released: Pale ×2, Ward ×1"
+        );
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let template: Template = "[cereal] {{{book}}} #{first}-{last} ({count}) {words}"
+            .parse()
+            .unwrap();
+        let templates = Templates {
+            kindle_subject: Some(template),
+            ..Templates::default()
+        };
+        assert_eq!(
+            templates.kindle_subject(&message(3)),
+            "[cereal] {Pale} #1.1-1.3 (3) ~12,400 words (~45 min)"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        let err = "{book} {chapter}".parse::<Template>().unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("{chapter} is not a placeholder"));
+        assert!("{book".parse::<Template>().is_err());
+        assert!("book}".parse::<Template>().is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use url::Url;
+use uuid::Uuid;
+
+use crate::config::{self, Config, DatabaseConfig};
+use crate::connection_pool::establish;
+use crate::storage::{MemoryBackend, Storage};
+use crate::util::{self, InstrumentedPgConnectionPool};
+
+/// The bucket of the storage returned by [`storage`].
+pub const BUCKET: &str = "cereal-test";
+
+/// The key inbound email webhooks are signed with.
+pub const WEBHOOK_KEY: &str = "webhook-key";
+
+/// The configuration for tests, loaded once per process. Deliveries are a dry
+/// run, and ebooks are "converted" by a script which copies its input.
+pub fn config() -> &'static Config {
+    config::init(
+        Config::from_vars(|name| {
+            let value = match name {
+                "DATABASE_URL" => "postgres://localhost/unused",
+                "CEREAL_SPACES_KEY" => "key",
+                "CEREAL_SPACES_SECRET" => "secret",
+                "CEREAL_SPACES_ENDPOINT" => "http://127.0.0.1:9",
+                "CEREAL_SPACES_NAME" => BUCKET,
+                "CEREAL_DRY_RUN" => "true",
+                "CEREAL_MAILGUN_WEBHOOK_SIGNING_KEY" => WEBHOOK_KEY,
+                "CEREAL_PUBLIC_URL" => "https://cereal.example.com",
+                "CEREAL_UNSUBSCRIBE_KEY" => "unsubscribe-key",
+                "CEREAL_EBOOK_CONVERT_PATH" => {
+                    concat!(
+                        env!("CARGO_MANIFEST_DIR"),
+                        "/tests/fixtures/bin/ebook-convert"
+                    )
+                }
+                _ => return None,
+            };
+            Some(value.to_owned())
+        })
+        .unwrap(),
+    )
+}
+
+/// Empty storage held in memory.
+pub fn storage() -> Storage {
+    Storage::with_backend(
+        Arc::new(MemoryBackend::new(BUCKET)),
+        &config().storage,
+        None,
+    )
+}
+
+/// A database with every migration applied, created for a single test and
+/// dropped once it is done.
+pub struct TestDatabase {
+    pub pool: InstrumentedPgConnectionPool,
+    name: String,
+    server_url: String,
+}
+
+impl TestDatabase {
+    /// Creates a database on the server at `TEST_DATABASE_URL`, or returns
+    /// `None` when it is unset so tests needing a database are skipped.
+    pub async fn new() -> Option<Self> {
+        let Ok(server_url) = env::var("TEST_DATABASE_URL") else {
+            eprintln!("TEST_DATABASE_URL is not set, skipping a test which needs a database.");
+            return None;
+        };
+        let name = format!("cereal_test_{}", Uuid::new_v4().simple());
+        let mut conn = AsyncPgConnection::establish(&server_url)
+            .await
+            .expect("Failed to connect to TEST_DATABASE_URL.");
+        diesel::sql_query(format!("CREATE DATABASE {}", name))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        let mut url = Url::parse(&server_url).unwrap();
+        url.set_path(&name);
+        let pool = establish(&DatabaseConfig {
+            url: url.into(),
+            skip_migrations: false,
+            acquire_timeout: Duration::from_secs(5),
+            max_connections: 5,
+        });
+        util::run_db_migrations(pool.clone()).await.unwrap();
+        Some(Self {
+            pool,
+            name,
+            server_url,
+        })
+    }
+
+    /// Like [`TestDatabase::new`], but fails on CI when `TEST_DATABASE_URL`
+    /// is unset, for guards which mustn't pass there without running.
+    pub async fn required() -> Option<Self> {
+        if env::var_os("TEST_DATABASE_URL").is_none() && env::var_os("CI").is_some() {
+            panic!("TEST_DATABASE_URL must be set on CI, as this test guards the schema.");
+        }
+        Self::new().await
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        let name = self.name.clone();
+        let server_url = self.server_url.clone();
+        // The test's runtime can't be blocked on from within it, so the
+        // database is dropped from a thread with its own.
+        let dropped = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            runtime.block_on(async {
+                let mut conn = AsyncPgConnection::establish(&server_url).await?;
+                // Forcing it closes the connections still held by the pool.
+                diesel::sql_query(format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", name))
+                    .execute(&mut conn)
+                    .await?;
+                anyhow::Ok(())
+            })
+        })
+        .join();
+        if let Ok(Err(err)) = dropped {
+            eprintln!("Failed to drop test database {}: {:?}", self.name, err);
+        }
+    }
+}
+
+/// Checks that the reply to `path` is tagged with an ETag and marked private,
+/// that a request already holding it is answered 304 with no body, and that
+/// one holding another tag is answered in full.
+pub async fn assert_etags<F>(routes: &F, path: &str)
+where
+    F: warp::Filter + 'static,
+    F::Extract: warp::Reply + Send,
+{
+    let response = warp::test::request().path(path).reply(routes).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["cache-control"], "private, max-age=0");
+    let etag = response.headers()["etag"].to_str().unwrap().to_owned();
+    assert!(etag.starts_with("W/\""));
+    let body = response.body().clone();
+
+    let response = warp::test::request()
+        .path(path)
+        .header("if-none-match", &etag)
+        .reply(routes)
+        .await;
+    assert_eq!(response.status(), 304);
+    assert_eq!(response.headers()["etag"], etag.as_str());
+    assert!(response.body().is_empty());
+
+    let response = warp::test::request()
+        .path(path)
+        .header("if-none-match", "W/\"stale\"")
+        .reply(routes)
+        .await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["etag"], etag.as_str());
+    assert_eq!(response.body(), &body);
+}
@@ -0,0 +1,105 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use url::Url;
+use uuid::Uuid;
+
+use crate::config::UnsubscribeConfig;
+
+/// How long a link keeps working after the email it was sent in, long enough
+/// for chapters read weeks late.
+const LINK_LIFETIME: chrono::Duration = chrono::Duration::days(180);
+
+/// What an unsubscribe token is signed over.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    pub user_id: String,
+    pub book_id: Uuid,
+    /// Seconds since the epoch.
+    expires_at: i64,
+}
+
+fn mac(key: &str) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length.")
+}
+
+/// A token unsubscribing `user_id` from `book_id`, the claims followed by
+/// their signature, each encoded as url safe base64.
+pub fn token(key: &str, user_id: &str, book_id: Uuid, now: DateTime<Utc>) -> String {
+    let claims = Claims {
+        user_id: user_id.to_owned(),
+        book_id,
+        expires_at: (now + LINK_LIFETIME).timestamp(),
+    };
+    let claims = serde_json::to_vec(&claims).expect("Claims serialize to json.");
+    let mut mac = mac(key);
+    mac.update(&claims);
+    format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(&claims),
+        URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    )
+}
+
+/// The claims of `token` if it was signed with `key` and hasn't expired.
+/// Why a token was refused isn't told apart, as nothing is shown for it.
+pub fn verify(key: &str, token: &str, now: DateTime<Utc>) -> Option<Claims> {
+    let (claims, signature) = token.split_once('.')?;
+    let claims = URL_SAFE_NO_PAD.decode(claims).ok()?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    let mut mac = mac(key);
+    mac.update(&claims);
+    mac.verify_slice(&signature).ok()?;
+    let claims: Claims = serde_json::from_slice(&claims).ok()?;
+    (claims.expires_at > now.timestamp()).then_some(claims)
+}
+
+/// The link which unsubscribes `user_id` from `book_id` in one click.
+pub fn link(config: &UnsubscribeConfig, user_id: &str, book_id: Uuid, now: DateTime<Utc>) -> Url {
+    let mut link = config
+        .public_url
+        .join("unsubscribe")
+        .expect("A path joins onto a base url.");
+    link.query_pairs_mut()
+        .append_pair("token", &token(&config.key, user_id, book_id, now));
+    link
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "unsubscribe-key";
+
+    #[test]
+    fn verifies_its_own_tokens() {
+        let now = Utc::now();
+        let book_id = Uuid::new_v4();
+        let token = token(KEY, "reader", book_id, now);
+        let claims = verify(KEY, &token, now).unwrap();
+        assert_eq!(claims.user_id, "reader");
+        assert_eq!(claims.book_id, book_id);
+    }
+
+    #[test]
+    fn refuses_forged_and_expired_tokens() {
+        let now = Utc::now();
+        let token = token(KEY, "reader", Uuid::new_v4(), now);
+        assert!(verify("another-key", &token, now).is_none());
+        assert!(verify(KEY, &token, now + LINK_LIFETIME).is_none());
+
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = serde_json::to_vec(&Claims {
+            user_id: "someone-else".into(),
+            book_id: Uuid::new_v4(),
+            expires_at: (now + LINK_LIFETIME).timestamp(),
+        })
+        .unwrap();
+        let forged = format!("{}.{}", URL_SAFE_NO_PAD.encode(forged), signature);
+        assert!(verify(KEY, &forged, now).is_none());
+        assert!(verify(KEY, "not a token", now).is_none());
+    }
+}
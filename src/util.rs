@@ -1,16 +1,24 @@
-use std::io::BufWriter;
+use std::time::{Duration, Instant};
 
-use anyhow::{bail, Result};
+use ::sentry::ClientInitGuard;
+use anyhow::{anyhow, bail, Context, Result};
 use chrono::Utc;
-use derive_more::From;
+use derive_more::{Display, From};
+use diesel_async::async_connection_wrapper::AsyncConnectionWrapper;
+use diesel_async::AsyncPgConnection;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use mobc::Pool;
-use reqwest::Url;
+use reqwest::{StatusCode, Url};
 use serde::Serialize;
-use tracing::{error, info, metadata::LevelFilter, Instrument};
-use tracing_subscriber::{prelude::*, Registry};
+use sha2::{Digest, Sha256};
+use tracing::{error, info, metadata::LevelFilter, warn, Instrument};
+use tracing_subscriber::{fmt, prelude::*, EnvFilter, Registry};
 
-use crate::clients::honeycomb;
-use crate::{connection_pool::PgConnectionManager, embedded_migrations};
+use crate::clients::{otel, sentry};
+use crate::config::{LogFormat, TelemetryConfig};
+use crate::connection_pool::PgConnectionManager;
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
 #[derive(Serialize, From)]
 pub struct ErrorMessage {
@@ -23,30 +31,95 @@ impl From<&str> for ErrorMessage {
     }
 }
 
-pub fn configure_tracing() {
+/// Installs the global subscriber, exporting spans and reporting errors where
+/// configured. The returned guard keeps Sentry reporting and must be held
+/// until shutdown.
+pub fn configure_tracing(config: &TelemetryConfig) -> Option<ClientInitGuard> {
+    let (tracer, export_error) = match otel::get_tracer(config) {
+        Ok(tracer) => (tracer, None),
+        Err(err) => (None, Some(err)),
+    };
+    let sentry_guard = sentry::init(config);
+    let (filter, filter_error) = match EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env()
+    {
+        Ok(filter) => (filter, None),
+        Err(err) => (EnvFilter::new("info"), Some(err)),
+    };
+    let exporting = tracer.is_some();
     let subscriber = Registry::default() // provide underlying span data store
-        .with(LevelFilter::INFO) // filter out low-level debug tracing (eg tokio executor)
-        .with(tracing_opentelemetry::layer().with_tracer(honeycomb::get_honeycomb_tracer())) // publish to honeycomb backend
-        .with(tracing_subscriber::fmt::Layer::default());
+        .with(filter) // filter out low-level debug tracing (eg tokio executor), RUST_LOG overrides
+        .with(otel::SampleErrors) // keep errored spans regardless of sampling
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer))) // publish to the OTLP collector
+        .with(sentry::layer()) // attach log lines to reported errors
+        .with(match config.log_format {
+            LogFormat::Text => fmt::layer().boxed(),
+            LogFormat::Json => fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(true)
+                .boxed(),
+        });
     tracing::subscriber::set_global_default(subscriber).unwrap();
+    if let Some(err) = filter_error {
+        warn!(error = %err, "RUST_LOG is invalid, logging at info.");
+    }
+    match export_error {
+        Some(err) => warn!(
+            error = %error_chain(&err),
+            "OTLP export is misconfigured, tracing export is disabled."
+        ),
+        None if !exporting => info!("No OTLP collector is configured, tracing export is disabled."),
+        None => {}
+    }
+    sentry_guard
 }
 
+#[tracing::instrument(name = "Running db migrations.", err, level = "info", skip(pool))]
 pub async fn run_db_migrations(pool: InstrumentedPgConnectionPool) -> Result<()> {
-    let conn = pool.get().await?;
-    let mut buf = BufWriter::new(Vec::new());
-    match embedded_migrations::run_with_output(&*conn, &mut buf) {
-        Ok(_) => {
-            let buf = buf.into_inner().unwrap();
-            let migration_out = String::from_utf8_lossy(&buf);
-            info!(%migration_out);
-        }
-        Err(err) => {
-            bail!("Failed to run db migrations. {:?}", err);
-        }
+    let conn = pool
+        .get()
+        .await
+        .context("Failed to connect to run db migrations.")?
+        .into_inner();
+    // The migration harness is synchronous, so it drives the async connection
+    // from a blocking thread. The span is carried over so the migration
+    // queries are traced under it.
+    let span = tracing::Span::current();
+    let applied = tokio::task::spawn_blocking(move || {
+        span.in_scope(|| {
+            let mut conn = AsyncConnectionWrapper::<AsyncPgConnection>::from(conn);
+            conn.run_pending_migrations(MIGRATIONS)
+                .map(|versions| versions.iter().map(ToString::to_string).collect::<Vec<_>>())
+        })
+    })
+    .await?;
+    match applied {
+        Ok(versions) => info!(?versions, "Applied pending db migrations."),
+        Err(err) => bail!("Failed to run db migrations. {:?}", err),
     };
     Ok(())
 }
 
+/// Fails if any embedded migration has not been applied, such as when the
+/// schema is managed separately and is behind this build.
+pub async fn check_db_migrations(pool: &InstrumentedPgConnectionPool) -> Result<()> {
+    let conn = pool.get().await?.into_inner();
+    let pending = tokio::task::spawn_blocking(move || {
+        let mut conn = AsyncConnectionWrapper::<AsyncPgConnection>::from(conn);
+        conn.pending_migrations(MIGRATIONS)
+            .map(|pending| pending.len())
+            .map_err(|err| anyhow!("Failed to read applied db migrations. {}", err))
+    })
+    .await??;
+    if pending > 0 {
+        bail!("{} db migrations are pending.", pending);
+    }
+    Ok(())
+}
+
 //
 // Extension trait for Result types.
 //
@@ -74,8 +147,9 @@ impl<T, E> ResultExt<T, E> for Result<T, E> {
             Err(e) => {
                 let e: anyhow::Error = e.into();
                 tracing::error!(
-                    "called `Result::unwrap_or_else_log()` on an `Err` value:\n {:?}",
-                    e
+                    error = %error_chain(&e),
+                    trace_id = otel::current_trace_id(),
+                    "called `Result::unwrap_or_else_log()` on an `Err` value."
                 );
                 else_case()
             }
@@ -83,6 +157,13 @@ impl<T, E> ResultExt<T, E> for Result<T, E> {
     }
 }
 
+/// Formats an error and its causes on a single line, such as
+/// `Failed to list s3://bucket: dispatch failure`, for the `error` field of
+/// log events.
+pub fn error_chain(err: &anyhow::Error) -> String {
+    format!("{:#}", err)
+}
+
 pub fn parse_from_rfc2822(pub_date: &str) -> Result<chrono::DateTime<Utc>> {
     Ok(chrono::DateTime::parse_from_rfc2822(pub_date)?.with_timezone(&Utc))
 }
@@ -100,30 +181,391 @@ pub fn validate_hostname(url: &str, valid_host: &str) -> Result<()> {
     Ok(())
 }
 
+/// Errors which are reported to API callers rather than as internal errors.
+#[derive(Debug, Display)]
+pub enum ApiError {
+    #[display(fmt = "{}", _0)]
+    BadRequest(String),
+    #[display(fmt = "{}", _0)]
+    NotFound(String),
+    #[display(fmt = "{}", _0)]
+    Unauthorized(String),
+    #[display(fmt = "{}", _0)]
+    Conflict(String),
+    #[display(fmt = "{}", _0)]
+    TooManyRequests(String),
+    /// A service the request relied on, such as Mailgun or Pushover, failed.
+    #[display(fmt = "{}", _0)]
+    Upstream(String),
+    /// No database connection freed up within the pool's acquire timeout.
+    #[display(fmt = "All database connections are busy, try again shortly.")]
+    PoolExhausted,
+}
+
+impl std::error::Error for ApiError {}
+
+impl ApiError {
+    const fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::Upstream(_) => StatusCode::BAD_GATEWAY,
+            Self::PoolExhausted => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+/// Whether an error was caused by the connection pool being exhausted, which
+/// is transient and resolves once in-flight queries finish.
+pub fn is_pool_exhausted(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<ApiError>(),
+        Some(ApiError::PoolExhausted)
+    )
+}
+
+/// Whether an error was caused by losing, or failing to open, a connection to
+/// the database, rather than by the query itself.
+pub fn is_connection_lost(err: &anyhow::Error) -> bool {
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+    err.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<DieselError>(),
+            Some(DieselError::DatabaseError(
+                DatabaseErrorKind::ClosedConnection | DatabaseErrorKind::UnableToSendCommand,
+                _
+            ))
+        ) || cause.is::<diesel::ConnectionError>()
+            || matches!(
+                cause.downcast_ref::<mobc::Error<diesel::ConnectionError>>(),
+                Some(mobc::Error::Inner(_) | mobc::Error::BadConn)
+            )
+    })
+}
+
+/// Whether running the same query again may succeed: the connection was lost
+/// or Postgres refused to serialize the transaction.
+fn is_transient_db_error(err: &anyhow::Error) -> bool {
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+    is_connection_lost(err)
+        || err.chain().any(|cause| {
+            matches!(
+                cause.downcast_ref::<DieselError>(),
+                Some(DieselError::DatabaseError(
+                    DatabaseErrorKind::SerializationFailure,
+                    _
+                ))
+            )
+        })
+}
+
+/// How many times a read is attempted before its error is returned.
+const READ_ATTEMPTS: u32 = 3;
+
+/// Runs a read-only `query`, running it again after a short pause when it
+/// fails with a transient database error. `query` should take its own
+/// connection from the pool, so a retry doesn't reuse a broken one.
+///
+/// Only for queries without side effects: a write may have been applied
+/// before its connection dropped, and running it again could apply it twice.
+pub async fn retry_read<T, F, Fut>(mut query: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match query().await {
+            Err(err) if attempt < READ_ATTEMPTS && is_transient_db_error(&err) => {
+                warn!(error = %error_chain(&err), attempt, "Retrying a read after a transient database error.");
+                metrics::counter!("db_read_retries_total").increment(1);
+                tokio::time::sleep(Duration::from_millis(50 * u64::from(attempt))).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Runs `fetch` up to `attempts` times while it fails with a server error or
+/// can't reach the site, waiting `base_delay` after the first failure and
+/// twice as long after each one since. The waits are jittered so checks which
+/// failed together don't retry together, and each attempt is traced in a span
+/// of its own.
+///
+/// Other failures, such as a 404 or a 429, are returned at once, leaving them
+/// to the check schedule.
+pub async fn retry_with_backoff<T, F, Fut>(
+    attempts: u32,
+    base_delay: Duration,
+    mut fetch: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        let span = tracing::info_span!("Fetch attempt", attempt);
+        match fetch().instrument(span).await {
+            Err(err) if attempt < attempts && is_transient_fetch_error(&err) => {
+                let delay = backoff_delay(base_delay, attempt);
+                warn!(error = %error_chain(&err), attempt, ?delay, "Retrying a fetch after a transient failure.");
+                metrics::counter!("provider_fetch_retries_total").increment(1);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether a fetch failed with a server error or without reaching the site.
+fn is_transient_fetch_error(err: &anyhow::Error) -> bool {
+    err.chain()
+        .find_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .is_some_and(|err| match err.status() {
+            Some(status) => status.is_server_error(),
+            None => err.is_connect() || err.is_timeout(),
+        })
+}
+
+/// The wait after the `attempt`th failure: half of the doubled delay, plus a
+/// random part of the other half.
+fn backoff_delay(base_delay: Duration, attempt: u32) -> Duration {
+    let delay = base_delay.saturating_mul(1 << (attempt - 1).min(16));
+    delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+}
+
 pub fn map_result(result: Result<impl Serialize>) -> impl warp::Reply {
     use warp::reply;
     match result {
-        Ok(x) => reply::with_status(reply::json(&x), reqwest::StatusCode::OK),
+        Ok(x) => reply::with_status(reply::json(&x), StatusCode::OK),
+        Err(err) => match err.downcast_ref::<ApiError>() {
+            Some(api_err) => reply::with_status(
+                reply::json(&ErrorMessage::from(api_err.to_string())),
+                api_err.status(),
+            ),
+            None => {
+                error!(
+                    error = %error_chain(&err),
+                    trace_id = otel::current_trace_id(),
+                    "An uncaught error occurred."
+                );
+                sentry::capture(&err, &[]);
+                reply::with_status(
+                    reply::json(&"An internal exception occurred."),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+            }
+        },
+    }
+}
+
+/// Replies with an XML document of `content_type`, or with the error as JSON.
+pub fn map_xml(result: Result<String>, content_type: &'static str) -> warp::reply::Response {
+    use warp::Reply;
+    match result {
+        Ok(xml) => warp::reply::with_header(xml, "content-type", content_type).into_response(),
+        Err(err) => map_result(Err::<(), _>(err)).into_response(),
+    }
+}
+
+/// Tags successful replies of `filter` with a weak ETag hashed from their
+/// body, replying 304 Not Modified without the body when the request's
+/// `If-None-Match` already has it. Replies are marked private and to be
+/// revalidated, so shared caches don't hand one user's reply to another.
+pub fn with_etag<R: warp::Reply>(
+    filter: impl warp::Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync,
+) -> impl warp::Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    use warp::Filter;
+    warp::header::optional::<String>("if-none-match")
+        .and(filter)
+        .then(etag_reply)
+}
+
+async fn etag_reply(
+    if_none_match: Option<String>,
+    reply: impl warp::Reply,
+) -> warp::reply::Response {
+    use warp::http::header::{CACHE_CONTROL, ETAG};
+    use warp::http::HeaderValue;
+    use warp::Reply;
+    let response = reply.into_response();
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = match warp::hyper::body::to_bytes(body).await {
+        Ok(body) => body,
         Err(err) => {
-            error!(?err, "An uncaught error occurred.");
-            reply::with_status(
-                reply::json(&"An internal exception occurred."),
-                reqwest::StatusCode::INTERNAL_SERVER_ERROR,
-            )
+            error!(error = %err, "Failed to read a reply to tag it.");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
+    };
+    let etag = format!("W/\"{:x}\"", Sha256::digest(&body));
+    parts
+        .headers
+        .insert(ETAG, HeaderValue::from_str(&etag).unwrap());
+    parts.headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("private, max-age=0"),
+    );
+    let matched = if_none_match.is_some_and(|tags| {
+        tags.split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag.trim_start_matches("W/"))
+    });
+    if matched {
+        parts.status = StatusCode::NOT_MODIFIED;
+        return warp::reply::Response::from_parts(parts, warp::hyper::Body::empty());
     }
+    warp::reply::Response::from_parts(parts, body.into())
 }
 
 #[derive(Clone)]
 pub struct InstrumentedPgConnectionPool(pub Pool<PgConnectionManager>);
 
+/// Acquisitions slower than this record their wait and the pool's state on
+/// the acquisition span.
+const SLOW_ACQUIRE: Duration = Duration::from_millis(100);
+
 impl InstrumentedPgConnectionPool {
-    pub async fn get(
-        &self,
-    ) -> Result<mobc::Connection<PgConnectionManager>, mobc::Error<diesel::ConnectionError>> {
-        self.0
-            .get()
-            .instrument(tracing::info_span!("Fetching Database Connection"))
-            .await
+    pub async fn get(&self) -> Result<mobc::Connection<PgConnectionManager>> {
+        let span = tracing::info_span!(
+            "Fetching Database Connection",
+            wait_ms = tracing::field::Empty,
+            open = tracing::field::Empty,
+            idle = tracing::field::Empty,
+        );
+        let started = Instant::now();
+        let result = self.0.get().instrument(span.clone()).await;
+        let waited = started.elapsed();
+        metrics::histogram!("db_pool_acquire_seconds").record(waited.as_secs_f64());
+
+        let state = self.0.state().await;
+        metrics::gauge!("db_pool_connections_open").set(state.connections as f64);
+        metrics::gauge!("db_pool_connections_idle").set(state.idle as f64);
+        if waited >= SLOW_ACQUIRE {
+            span.record("wait_ms", waited.as_millis() as u64);
+            span.record("open", state.connections);
+            span.record("idle", state.idle);
+        }
+
+        match result {
+            Ok(conn) => Ok(conn),
+            Err(mobc::Error::Timeout) => {
+                metrics::counter!("db_pool_exhausted_total").increment(1);
+                Err(ApiError::PoolExhausted.into())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn database_error(kind: DatabaseErrorKind) -> anyhow::Error {
+        DieselError::DatabaseError(kind, Box::new("server closed the connection".to_owned())).into()
+    }
+
+    #[tokio::test]
+    async fn retries_reads_after_transient_errors() {
+        let calls = AtomicU32::new(0);
+        let result = retry_read(|| async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(database_error(DatabaseErrorKind::ClosedConnection)),
+                1 => Err(database_error(DatabaseErrorKind::SerializationFailure)),
+                _ => Ok("read"),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), "read");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // A lost connection that never comes back gives up after the last attempt.
+        let calls = AtomicU32::new(0);
+        let result: Result<()> = retry_read(|| async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(database_error(DatabaseErrorKind::ClosedConnection).context("Loading books."))
+        })
+        .await;
+        assert!(is_connection_lost(&result.unwrap_err()));
+        assert_eq!(calls.load(Ordering::SeqCst), READ_ATTEMPTS);
+    }
+
+    async fn fetch_from(responses: &[ResponseTemplate], attempts: u32) -> (Result<String>, usize) {
+        let server = MockServer::start().await;
+        for response in responses {
+            Mock::given(method("GET"))
+                .respond_with(response.clone())
+                .up_to_n_times(1)
+                .mount(&server)
+                .await;
+        }
+        let result = retry_with_backoff(attempts, Duration::from_millis(1), || {
+            crate::clients::http::text(reqwest::Client::new().get(server.uri()))
+        })
+        .await;
+        let requests = server.received_requests().await.unwrap().len();
+        (result, requests)
+    }
+
+    #[tokio::test]
+    async fn retries_fetches_after_server_errors() {
+        let ok = ResponseTemplate::new(200).set_body_string("chapter");
+        let (result, requests) = fetch_from(&[ResponseTemplate::new(503), ok.clone()], 3).await;
+        assert_eq!(result.unwrap(), "chapter");
+        assert_eq!(requests, 2);
+
+        // A site still failing on the last attempt fails the fetch.
+        let failing = vec![ResponseTemplate::new(502); 3];
+        let (result, requests) = fetch_from(&[failing, vec![ok.clone()]].concat(), 3).await;
+        let err = result.unwrap_err();
+        assert!(is_transient_fetch_error(&err));
+        assert_eq!(requests, 3);
+
+        // Client errors are the site's answer, so aren't asked again.
+        let (result, requests) = fetch_from(&[ResponseTemplate::new(404), ok], 3).await;
+        assert!(result.is_err());
+        assert_eq!(requests, 1);
+
+        let result: Result<String> = retry_with_backoff(2, Duration::from_millis(1), || {
+            crate::clients::http::text(reqwest::Client::new().get("http://127.0.0.1:9"))
+        })
+        .await;
+        assert!(is_transient_fetch_error(&result.unwrap_err()));
+    }
+
+    #[test]
+    fn doubles_jittered_backoff() {
+        let base = Duration::from_millis(100);
+        for (attempt, doubled) in [(1, 100), (2, 200), (3, 400)] {
+            let delay = backoff_delay(base, attempt);
+            let doubled = Duration::from_millis(doubled);
+            assert!(delay >= doubled / 2 && delay <= doubled, "{:?}", delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_other_errors() {
+        let calls = AtomicU32::new(0);
+        let result: Result<()> = retry_read(|| async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(DieselError::NotFound.into())
+        })
+        .await;
+        assert!(!is_connection_lost(&result.unwrap_err()));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(!is_connection_lost(&ApiError::PoolExhausted.into()));
     }
 }